@@ -0,0 +1,12 @@
+DROP TABLE upload_chunks;
+DROP TABLE upload_sessions;
+DROP TABLE file_versions;
+DROP TABLE storage_usage;
+DROP TABLE blobs;
+DROP TABLE review_session_files;
+DROP TABLE review_sessions;
+DROP TABLE file_metadata;
+DROP TABLE file_tags;
+DROP TABLE tags;
+DROP TABLE idempotency_keys;
+DROP TABLE files;
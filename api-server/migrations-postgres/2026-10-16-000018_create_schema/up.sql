@@ -0,0 +1,106 @@
+-- The schema the SQLite migrations build up to 2026-10-16-000018, in one go.
+-- Unlike SQLite, PostgreSQL enforces the foreign keys.
+CREATE TABLE files (
+	file_name TEXT PRIMARY KEY NOT NULL,
+	file_type TEXT NULL,
+	file_upload_date INTEGER NOT NULL,
+	title TEXT NULL,
+	description TEXT NULL,
+	language TEXT NULL,
+	file_size BIGINT NULL,
+	duration_ms BIGINT NULL,
+	starred BOOLEAN NOT NULL DEFAULT FALSE,
+	download_count BIGINT NOT NULL DEFAULT 0,
+	last_accessed_at INTEGER,
+	storage_key TEXT,
+	sha256 TEXT,
+	compression TEXT,
+	stored_size BIGINT,
+	expires_at INTEGER
+);
+
+CREATE INDEX files_expires_at ON files (expires_at);
+
+CREATE TABLE idempotency_keys (
+	idempotency_key TEXT PRIMARY KEY NOT NULL,
+	response TEXT NOT NULL,
+	created_at INTEGER NOT NULL
+);
+
+CREATE TABLE tags (
+	tag_name TEXT PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE file_tags (
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	tag_name TEXT NOT NULL REFERENCES tags (tag_name) ON DELETE CASCADE,
+	PRIMARY KEY (file_name, tag_name)
+);
+
+CREATE TABLE file_metadata (
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	meta_key TEXT NOT NULL,
+	meta_value TEXT NOT NULL,
+	PRIMARY KEY (file_name, meta_key)
+);
+
+CREATE TABLE review_sessions (
+	token TEXT PRIMARY KEY NOT NULL,
+	created_at INTEGER NOT NULL,
+	expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE review_session_files (
+	token TEXT NOT NULL REFERENCES review_sessions (token) ON DELETE CASCADE,
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	PRIMARY KEY (token, file_name)
+);
+
+CREATE TABLE blobs (
+	storage_key TEXT PRIMARY KEY NOT NULL,
+	sha256 TEXT,
+	ref_count BIGINT NOT NULL,
+	stored_size BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX blobs_sha256 ON blobs (sha256);
+
+-- A single row holding the bytes in storage, kept up to date as objects are
+-- added and removed
+CREATE TABLE storage_usage (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	used_bytes BIGINT NOT NULL
+);
+
+INSERT INTO storage_usage (id, used_bytes) VALUES (0, 0);
+
+CREATE TABLE file_versions (
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	version INTEGER NOT NULL,
+	file_type TEXT,
+	file_upload_date INTEGER NOT NULL,
+	file_size BIGINT,
+	duration_ms BIGINT,
+	storage_key TEXT NOT NULL,
+	sha256 TEXT,
+	compression TEXT,
+	stored_size BIGINT,
+	replaced_at INTEGER NOT NULL,
+	PRIMARY KEY (file_name, version)
+);
+
+CREATE TABLE upload_sessions (
+	id TEXT PRIMARY KEY NOT NULL,
+	file_name TEXT NOT NULL,
+	file_type TEXT,
+	total_size BIGINT NOT NULL,
+	created_at INTEGER NOT NULL,
+	expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE upload_chunks (
+	session_id TEXT NOT NULL REFERENCES upload_sessions (id) ON DELETE CASCADE,
+	byte_offset BIGINT NOT NULL,
+	byte_length BIGINT NOT NULL,
+	PRIMARY KEY (session_id, byte_offset)
+);
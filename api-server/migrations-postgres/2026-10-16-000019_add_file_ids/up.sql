@@ -0,0 +1,4 @@
+ALTER TABLE files ADD COLUMN id TEXT NOT NULL DEFAULT gen_random_uuid()::text;
+ALTER TABLE files ALTER COLUMN id DROP DEFAULT;
+
+CREATE UNIQUE INDEX files_id ON files (id);
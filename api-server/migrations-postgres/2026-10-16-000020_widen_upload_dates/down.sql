@@ -0,0 +1,3 @@
+ALTER TABLE files ALTER COLUMN file_upload_date TYPE INTEGER;
+ALTER TABLE file_versions ALTER COLUMN file_upload_date TYPE INTEGER;
+ALTER TABLE file_versions ALTER COLUMN replaced_at TYPE INTEGER;
@@ -0,0 +1,4 @@
+-- SQLite integers are 64-bit already, so only PostgreSQL needs this
+ALTER TABLE files ALTER COLUMN file_upload_date TYPE BIGINT;
+ALTER TABLE file_versions ALTER COLUMN file_upload_date TYPE BIGINT;
+ALTER TABLE file_versions ALTER COLUMN replaced_at TYPE BIGINT;
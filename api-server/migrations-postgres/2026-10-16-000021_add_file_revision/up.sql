@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN revision INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,2 @@
+DROP INDEX files_search_vector;
+ALTER TABLE files DROP COLUMN search_vector;
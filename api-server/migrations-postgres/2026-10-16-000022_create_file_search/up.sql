@@ -0,0 +1,10 @@
+-- Words of the name, title and description, weighted in that order. Anything
+-- but letters and digits separates words, so names split up the way SQLite's
+-- FTS5 splits them.
+ALTER TABLE files ADD COLUMN search_vector tsvector GENERATED ALWAYS AS (
+	setweight(to_tsvector('simple', regexp_replace(file_name, '[^[:alnum:]]+', ' ', 'g')), 'A')
+	|| setweight(to_tsvector('simple', regexp_replace(coalesce(title, ''), '[^[:alnum:]]+', ' ', 'g')), 'B')
+	|| setweight(to_tsvector('simple', regexp_replace(coalesce(description, ''), '[^[:alnum:]]+', ' ', 'g')), 'C')
+) STORED;
+
+CREATE INDEX files_search_vector ON files USING GIN (search_vector);
@@ -0,0 +1,2 @@
+DROP INDEX files_file_upload_date;
+DROP INDEX files_file_type;
@@ -0,0 +1,2 @@
+CREATE INDEX files_file_type ON files (file_type);
+CREATE INDEX files_file_upload_date ON files (file_upload_date);
@@ -0,0 +1,7 @@
+DROP INDEX files_audio_format;
+ALTER TABLE file_versions DROP COLUMN bit_depth;
+ALTER TABLE file_versions DROP COLUMN channels;
+ALTER TABLE file_versions DROP COLUMN sample_rate;
+ALTER TABLE files DROP COLUMN bit_depth;
+ALTER TABLE files DROP COLUMN channels;
+ALTER TABLE files DROP COLUMN sample_rate;
@@ -0,0 +1,7 @@
+ALTER TABLE files ALTER COLUMN last_accessed_at TYPE INTEGER;
+ALTER TABLE files ALTER COLUMN expires_at TYPE INTEGER;
+ALTER TABLE idempotency_keys ALTER COLUMN created_at TYPE INTEGER;
+ALTER TABLE review_sessions ALTER COLUMN created_at TYPE INTEGER;
+ALTER TABLE review_sessions ALTER COLUMN expires_at TYPE INTEGER;
+ALTER TABLE upload_sessions ALTER COLUMN created_at TYPE INTEGER;
+ALTER TABLE upload_sessions ALTER COLUMN expires_at TYPE INTEGER;
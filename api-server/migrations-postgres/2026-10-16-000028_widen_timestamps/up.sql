@@ -0,0 +1,8 @@
+-- SQLite integers are 64-bit already, so only PostgreSQL needs this
+ALTER TABLE files ALTER COLUMN last_accessed_at TYPE BIGINT;
+ALTER TABLE files ALTER COLUMN expires_at TYPE BIGINT;
+ALTER TABLE idempotency_keys ALTER COLUMN created_at TYPE BIGINT;
+ALTER TABLE review_sessions ALTER COLUMN created_at TYPE BIGINT;
+ALTER TABLE review_sessions ALTER COLUMN expires_at TYPE BIGINT;
+ALTER TABLE upload_sessions ALTER COLUMN created_at TYPE BIGINT;
+ALTER TABLE upload_sessions ALTER COLUMN expires_at TYPE BIGINT;
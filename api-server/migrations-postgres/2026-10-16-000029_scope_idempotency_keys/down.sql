@@ -0,0 +1,8 @@
+DELETE FROM idempotency_keys WHERE response IS NULL;
+-- One owner's row per key survives
+DELETE FROM idempotency_keys a USING idempotency_keys b
+WHERE a.idempotency_key = b.idempotency_key AND a.owner > b.owner;
+ALTER TABLE idempotency_keys ALTER COLUMN response SET NOT NULL;
+ALTER TABLE idempotency_keys DROP CONSTRAINT idempotency_keys_pkey;
+ALTER TABLE idempotency_keys DROP COLUMN owner;
+ALTER TABLE idempotency_keys ADD PRIMARY KEY (idempotency_key);
@@ -0,0 +1,7 @@
+-- Keys are the client's own strings, so each caller gets a namespace of
+-- their own; '' holds the keys of callers without a user. A key is reserved
+-- with no response before its upload starts, so a concurrent retry sees it.
+ALTER TABLE idempotency_keys ADD COLUMN owner TEXT NOT NULL DEFAULT '';
+ALTER TABLE idempotency_keys DROP CONSTRAINT idempotency_keys_pkey;
+ALTER TABLE idempotency_keys ADD PRIMARY KEY (owner, idempotency_key);
+ALTER TABLE idempotency_keys ALTER COLUMN response DROP NOT NULL;
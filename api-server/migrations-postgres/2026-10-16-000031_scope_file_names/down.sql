@@ -0,0 +1,41 @@
+-- One file per name survives, the first uploaded
+DELETE FROM files a USING files b
+WHERE a.file_name = b.file_name
+	AND (a.file_upload_date, a.id) > (b.file_upload_date, b.id);
+
+ALTER TABLE file_tags ADD COLUMN file_name TEXT;
+ALTER TABLE file_metadata ADD COLUMN file_name TEXT;
+ALTER TABLE review_session_files ADD COLUMN file_name TEXT;
+ALTER TABLE file_versions ADD COLUMN file_name TEXT;
+
+UPDATE file_tags SET file_name = files.file_name FROM files WHERE files.id = file_tags.file_id;
+UPDATE file_metadata SET file_name = files.file_name FROM files WHERE files.id = file_metadata.file_id;
+UPDATE review_session_files SET file_name = files.file_name
+FROM files WHERE files.id = review_session_files.file_id;
+UPDATE file_versions SET file_name = files.file_name FROM files WHERE files.id = file_versions.file_id;
+
+ALTER TABLE file_tags DROP COLUMN file_id;
+ALTER TABLE file_metadata DROP COLUMN file_id;
+ALTER TABLE review_session_files DROP COLUMN file_id;
+ALTER TABLE file_versions DROP COLUMN file_id;
+
+DROP INDEX files_owner_file_name;
+ALTER TABLE files DROP CONSTRAINT files_pkey;
+ALTER TABLE files ADD PRIMARY KEY (file_name);
+CREATE UNIQUE INDEX files_id ON files (id);
+
+ALTER TABLE file_tags ALTER COLUMN file_name SET NOT NULL;
+ALTER TABLE file_tags ADD FOREIGN KEY (file_name) REFERENCES files (file_name) ON DELETE CASCADE;
+ALTER TABLE file_tags ADD PRIMARY KEY (file_name, tag_name);
+
+ALTER TABLE file_metadata ALTER COLUMN file_name SET NOT NULL;
+ALTER TABLE file_metadata ADD FOREIGN KEY (file_name) REFERENCES files (file_name) ON DELETE CASCADE;
+ALTER TABLE file_metadata ADD PRIMARY KEY (file_name, meta_key);
+
+ALTER TABLE review_session_files ALTER COLUMN file_name SET NOT NULL;
+ALTER TABLE review_session_files ADD FOREIGN KEY (file_name) REFERENCES files (file_name) ON DELETE CASCADE;
+ALTER TABLE review_session_files ADD PRIMARY KEY (token, file_name);
+
+ALTER TABLE file_versions ALTER COLUMN file_name SET NOT NULL;
+ALTER TABLE file_versions ADD FOREIGN KEY (file_name) REFERENCES files (file_name) ON DELETE CASCADE;
+ALTER TABLE file_versions ADD PRIMARY KEY (file_name, version);
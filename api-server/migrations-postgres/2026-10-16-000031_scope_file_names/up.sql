@@ -0,0 +1,40 @@
+-- Names are unique per owner rather than across the server, so a file is
+-- keyed by its id, and so is everything hanging off it. Files without an
+-- owner share one namespace.
+ALTER TABLE file_tags ADD COLUMN file_id TEXT;
+ALTER TABLE file_metadata ADD COLUMN file_id TEXT;
+ALTER TABLE review_session_files ADD COLUMN file_id TEXT;
+ALTER TABLE file_versions ADD COLUMN file_id TEXT;
+
+UPDATE file_tags SET file_id = files.id FROM files WHERE files.file_name = file_tags.file_name;
+UPDATE file_metadata SET file_id = files.id FROM files WHERE files.file_name = file_metadata.file_name;
+UPDATE review_session_files SET file_id = files.id
+FROM files WHERE files.file_name = review_session_files.file_name;
+UPDATE file_versions SET file_id = files.id FROM files WHERE files.file_name = file_versions.file_name;
+
+-- Dropping the name columns drops the keys and references built on them
+ALTER TABLE file_tags DROP COLUMN file_name;
+ALTER TABLE file_metadata DROP COLUMN file_name;
+ALTER TABLE review_session_files DROP COLUMN file_name;
+ALTER TABLE file_versions DROP COLUMN file_name;
+
+ALTER TABLE files DROP CONSTRAINT files_pkey;
+DROP INDEX files_id;
+ALTER TABLE files ADD PRIMARY KEY (id);
+CREATE UNIQUE INDEX files_owner_file_name ON files (coalesce(owner, ''), file_name);
+
+ALTER TABLE file_tags ALTER COLUMN file_id SET NOT NULL;
+ALTER TABLE file_tags ADD FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE;
+ALTER TABLE file_tags ADD PRIMARY KEY (file_id, tag_name);
+
+ALTER TABLE file_metadata ALTER COLUMN file_id SET NOT NULL;
+ALTER TABLE file_metadata ADD FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE;
+ALTER TABLE file_metadata ADD PRIMARY KEY (file_id, meta_key);
+
+ALTER TABLE review_session_files ALTER COLUMN file_id SET NOT NULL;
+ALTER TABLE review_session_files ADD FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE;
+ALTER TABLE review_session_files ADD PRIMARY KEY (token, file_id);
+
+ALTER TABLE file_versions ALTER COLUMN file_id SET NOT NULL;
+ALTER TABLE file_versions ADD FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE;
+ALTER TABLE file_versions ADD PRIMARY KEY (file_id, version);
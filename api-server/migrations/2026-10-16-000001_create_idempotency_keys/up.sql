@@ -0,0 +1,5 @@
+CREATE TABLE idempotency_keys (
+	idempotency_key TEXT PRIMARY KEY NOT NULL,
+	response TEXT NOT NULL,
+	created_at INTEGER NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE tags (
+	tag_name TEXT PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE file_tags (
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	tag_name TEXT NOT NULL REFERENCES tags (tag_name) ON DELETE CASCADE,
+	PRIMARY KEY (file_name, tag_name)
+);
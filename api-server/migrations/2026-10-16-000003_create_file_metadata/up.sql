@@ -0,0 +1,6 @@
+CREATE TABLE file_metadata (
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	meta_key TEXT NOT NULL,
+	meta_value TEXT NOT NULL,
+	PRIMARY KEY (file_name, meta_key)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE files DROP COLUMN language;
+ALTER TABLE files DROP COLUMN description;
+ALTER TABLE files DROP COLUMN title;
@@ -0,0 +1,3 @@
+ALTER TABLE files ADD COLUMN title TEXT NULL;
+ALTER TABLE files ADD COLUMN description TEXT NULL;
+ALTER TABLE files ADD COLUMN language TEXT NULL;
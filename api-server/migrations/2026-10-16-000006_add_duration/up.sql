@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN duration_ms BIGINT NULL;
@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN starred BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN download_count BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN last_accessed_at INTEGER;
@@ -0,0 +1,2 @@
+DROP TABLE review_session_files;
+DROP TABLE review_sessions;
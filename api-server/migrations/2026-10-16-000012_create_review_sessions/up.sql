@@ -0,0 +1,11 @@
+CREATE TABLE review_sessions (
+	token TEXT PRIMARY KEY NOT NULL,
+	created_at INTEGER NOT NULL,
+	expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE review_session_files (
+	token TEXT NOT NULL REFERENCES review_sessions (token) ON DELETE CASCADE,
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	PRIMARY KEY (token, file_name)
+);
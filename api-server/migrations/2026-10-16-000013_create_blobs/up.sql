@@ -0,0 +1,13 @@
+CREATE TABLE blobs (
+	storage_key TEXT PRIMARY KEY NOT NULL,
+	sha256 TEXT,
+	ref_count BIGINT NOT NULL
+);
+
+CREATE INDEX blobs_sha256 ON blobs (sha256);
+
+-- Every stored object so far belongs to exactly the rows that point at it
+INSERT INTO blobs (storage_key, sha256, ref_count)
+SELECT storage_key, MAX(sha256), COUNT(*) FROM files
+WHERE storage_key IS NOT NULL
+GROUP BY storage_key;
@@ -0,0 +1,2 @@
+ALTER TABLE files DROP COLUMN stored_size;
+ALTER TABLE files DROP COLUMN compression;
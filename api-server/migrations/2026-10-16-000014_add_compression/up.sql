@@ -0,0 +1,2 @@
+ALTER TABLE files ADD COLUMN compression TEXT;
+ALTER TABLE files ADD COLUMN stored_size BIGINT;
@@ -0,0 +1,2 @@
+DROP TABLE storage_usage;
+ALTER TABLE blobs DROP COLUMN stored_size;
@@ -0,0 +1,19 @@
+ALTER TABLE blobs ADD COLUMN stored_size BIGINT NOT NULL DEFAULT 0;
+
+UPDATE blobs SET stored_size = (
+	SELECT COALESCE(MAX(stored_size), MAX(file_size), 0) FROM files
+	WHERE files.storage_key = blobs.storage_key
+);
+
+-- A single row holding the bytes in storage, kept up to date as objects are
+-- added and removed
+CREATE TABLE storage_usage (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	used_bytes BIGINT NOT NULL
+);
+
+-- Rows from before sharding own the object stored under their name
+INSERT INTO storage_usage (id, used_bytes)
+SELECT 0,
+	(SELECT COALESCE(SUM(stored_size), 0) FROM blobs)
+	+ (SELECT COALESCE(SUM(file_size), 0) FROM files WHERE storage_key IS NULL);
@@ -0,0 +1,3 @@
+ALTER TABLE files ADD COLUMN expires_at INTEGER;
+
+CREATE INDEX files_expires_at ON files (expires_at);
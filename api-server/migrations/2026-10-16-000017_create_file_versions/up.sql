@@ -0,0 +1,16 @@
+-- Content an overwrite replaced. Each version holds a reference on its blob,
+-- so its bytes stay stored until the file is deleted.
+CREATE TABLE file_versions (
+	file_name TEXT NOT NULL REFERENCES files (file_name) ON DELETE CASCADE,
+	version INTEGER NOT NULL,
+	file_type TEXT,
+	file_upload_date INTEGER NOT NULL,
+	file_size BIGINT,
+	duration_ms BIGINT,
+	storage_key TEXT NOT NULL,
+	sha256 TEXT,
+	compression TEXT,
+	stored_size BIGINT,
+	replaced_at INTEGER NOT NULL,
+	PRIMARY KEY (file_name, version)
+);
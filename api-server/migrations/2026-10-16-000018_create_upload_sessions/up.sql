@@ -0,0 +1,17 @@
+-- Uploads sent in chunks. Received chunks are staged outside storage until
+-- the session completes.
+CREATE TABLE upload_sessions (
+	id TEXT PRIMARY KEY NOT NULL,
+	file_name TEXT NOT NULL,
+	file_type TEXT,
+	total_size BIGINT NOT NULL,
+	created_at INTEGER NOT NULL,
+	expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE upload_chunks (
+	session_id TEXT NOT NULL REFERENCES upload_sessions (id) ON DELETE CASCADE,
+	byte_offset BIGINT NOT NULL,
+	byte_length BIGINT NOT NULL,
+	PRIMARY KEY (session_id, byte_offset)
+);
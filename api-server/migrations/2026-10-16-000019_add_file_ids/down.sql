@@ -0,0 +1,2 @@
+DROP INDEX files_id;
+ALTER TABLE files DROP COLUMN id;
@@ -0,0 +1,10 @@
+ALTER TABLE files ADD COLUMN id TEXT NOT NULL DEFAULT '';
+
+-- Random version 4 UUIDs for the rows already there
+UPDATE files SET id = lower(
+	hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2) || '-'
+	|| substr('89ab', 1 + abs(random()) % 4, 1) || substr(hex(randomblob(2)), 2) || '-'
+	|| hex(randomblob(6))
+);
+
+CREATE UNIQUE INDEX files_id ON files (id);
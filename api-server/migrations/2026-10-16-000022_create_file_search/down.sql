@@ -0,0 +1,4 @@
+DROP TRIGGER files_search_update;
+DROP TRIGGER files_search_delete;
+DROP TRIGGER files_search_insert;
+DROP TABLE file_search;
@@ -0,0 +1,21 @@
+-- Full-text index over what people search files by, kept in step with files
+-- by triggers. Upserts fire the update trigger.
+CREATE VIRTUAL TABLE file_search USING fts5(file_name, title, description);
+
+INSERT INTO file_search (file_name, title, description)
+SELECT file_name, title, description FROM files;
+
+CREATE TRIGGER files_search_insert AFTER INSERT ON files BEGIN
+	INSERT INTO file_search (file_name, title, description)
+	VALUES (new.file_name, new.title, new.description);
+END;
+
+CREATE TRIGGER files_search_delete AFTER DELETE ON files BEGIN
+	DELETE FROM file_search WHERE file_name = old.file_name;
+END;
+
+CREATE TRIGGER files_search_update AFTER UPDATE OF file_name, title, description ON files BEGIN
+	DELETE FROM file_search WHERE file_name = old.file_name;
+	INSERT INTO file_search (file_name, title, description)
+	VALUES (new.file_name, new.title, new.description);
+END;
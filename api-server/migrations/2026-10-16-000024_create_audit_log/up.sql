@@ -0,0 +1,16 @@
+-- Every request that could change something, and every change made without
+-- one such as retention purges: who, from where, when, what was asked for
+-- and the status it got. Rows are only ever added.
+CREATE TABLE audit_log (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	occurred_at BIGINT NOT NULL,
+	actor TEXT,
+	client_ip TEXT,
+	forwarded_for TEXT,
+	method TEXT NOT NULL,
+	path TEXT NOT NULL,
+	status INTEGER,
+	changes TEXT
+);
+
+CREATE INDEX audit_log_occurred_at ON audit_log (occurred_at);
@@ -0,0 +1,2 @@
+DROP INDEX files_owner;
+ALTER TABLE files DROP COLUMN owner;
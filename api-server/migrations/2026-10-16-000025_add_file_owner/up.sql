@@ -0,0 +1,5 @@
+-- Who uploaded the file, as named by the authenticating proxy. Files from
+-- before ownership, or uploaded without a user, have none.
+ALTER TABLE files ADD COLUMN owner TEXT;
+
+CREATE INDEX files_owner ON files (owner);
@@ -0,0 +1,11 @@
+-- Read from the container when the file is uploaded. Files from before, and
+-- containers that don't say, have none; lossy codecs have no bit depth.
+ALTER TABLE files ADD COLUMN sample_rate INTEGER;
+ALTER TABLE files ADD COLUMN channels INTEGER;
+ALTER TABLE files ADD COLUMN bit_depth INTEGER;
+
+ALTER TABLE file_versions ADD COLUMN sample_rate INTEGER;
+ALTER TABLE file_versions ADD COLUMN channels INTEGER;
+ALTER TABLE file_versions ADD COLUMN bit_depth INTEGER;
+
+CREATE INDEX files_audio_format ON files (sample_rate, channels, bit_depth);
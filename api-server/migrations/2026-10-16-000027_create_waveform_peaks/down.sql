@@ -0,0 +1 @@
+DROP TABLE waveform_peaks;
@@ -0,0 +1,9 @@
+-- Waveform peaks computed for GET /audio/:file_name/peaks, one row per
+-- content hash and resolution. Keying on the content lets identical uploads
+-- share them and a replaced file miss them.
+CREATE TABLE waveform_peaks (
+	sha256 TEXT NOT NULL,
+	resolution INTEGER NOT NULL,
+	peaks TEXT NOT NULL,
+	PRIMARY KEY (sha256, resolution)
+);
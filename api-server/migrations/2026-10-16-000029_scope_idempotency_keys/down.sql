@@ -0,0 +1,12 @@
+CREATE TABLE unscoped_idempotency_keys (
+	idempotency_key TEXT PRIMARY KEY NOT NULL,
+	response TEXT NOT NULL,
+	created_at INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO unscoped_idempotency_keys
+SELECT idempotency_key, response, created_at FROM idempotency_keys
+WHERE response IS NOT NULL;
+
+DROP TABLE idempotency_keys;
+ALTER TABLE unscoped_idempotency_keys RENAME TO idempotency_keys;
@@ -0,0 +1,16 @@
+-- Keys are the client's own strings, so each caller gets a namespace of
+-- their own; '' holds the keys of callers without a user. A key is reserved
+-- with no response before its upload starts, so a concurrent retry sees it.
+CREATE TABLE scoped_idempotency_keys (
+	owner TEXT NOT NULL DEFAULT '',
+	idempotency_key TEXT NOT NULL,
+	response TEXT,
+	created_at INTEGER NOT NULL,
+	PRIMARY KEY (owner, idempotency_key)
+);
+
+INSERT INTO scoped_idempotency_keys (idempotency_key, response, created_at)
+SELECT idempotency_key, response, created_at FROM idempotency_keys;
+
+DROP TABLE idempotency_keys;
+ALTER TABLE scoped_idempotency_keys RENAME TO idempotency_keys;
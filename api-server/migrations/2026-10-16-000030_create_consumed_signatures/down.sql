@@ -0,0 +1 @@
+DROP TABLE consumed_signatures;
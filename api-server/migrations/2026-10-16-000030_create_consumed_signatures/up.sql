@@ -0,0 +1,6 @@
+-- Signed upload URLs that have been used, so each can only be used once.
+-- A row outlives its URL's expiry only until the next URL is used.
+CREATE TABLE consumed_signatures (
+	signature TEXT PRIMARY KEY NOT NULL,
+	expires_at INTEGER NOT NULL
+);
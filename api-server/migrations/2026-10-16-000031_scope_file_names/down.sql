@@ -0,0 +1,135 @@
+-- One file per name survives, the first uploaded
+CREATE TABLE named_files (
+	file_name TEXT PRIMARY KEY NOT NULL,
+	file_type TEXT NULL,
+	file_upload_date INTEGER NOT NULL,
+	title TEXT NULL,
+	description TEXT NULL,
+	language TEXT NULL,
+	file_size BIGINT NULL,
+	duration_ms BIGINT NULL,
+	starred BOOLEAN NOT NULL DEFAULT 0,
+	download_count BIGINT NOT NULL DEFAULT 0,
+	last_accessed_at INTEGER,
+	storage_key TEXT,
+	sha256 TEXT,
+	compression TEXT,
+	stored_size BIGINT,
+	expires_at INTEGER,
+	id TEXT NOT NULL DEFAULT '',
+	revision INTEGER NOT NULL DEFAULT 1,
+	owner TEXT,
+	sample_rate INTEGER,
+	channels INTEGER,
+	bit_depth INTEGER
+);
+
+INSERT OR IGNORE INTO named_files (
+	file_name, file_type, file_upload_date, title, description, language, file_size,
+	duration_ms, starred, download_count, last_accessed_at, storage_key, sha256, compression,
+	stored_size, expires_at, id, revision, owner, sample_rate, channels, bit_depth
+)
+SELECT
+	file_name, file_type, file_upload_date, title, description, language, file_size,
+	duration_ms, starred, download_count, last_accessed_at, storage_key, sha256, compression,
+	stored_size, expires_at, id, revision, owner, sample_rate, channels, bit_depth
+FROM files ORDER BY file_upload_date;
+
+CREATE TABLE named_file_tags (
+	file_name TEXT NOT NULL REFERENCES named_files (file_name) ON DELETE CASCADE,
+	tag_name TEXT NOT NULL REFERENCES tags (tag_name) ON DELETE CASCADE,
+	PRIMARY KEY (file_name, tag_name)
+);
+
+INSERT INTO named_file_tags (file_name, tag_name)
+SELECT named_files.file_name, tag_name FROM file_tags JOIN named_files ON named_files.id = file_id;
+
+CREATE TABLE named_file_metadata (
+	file_name TEXT NOT NULL REFERENCES named_files (file_name) ON DELETE CASCADE,
+	meta_key TEXT NOT NULL,
+	meta_value TEXT NOT NULL,
+	PRIMARY KEY (file_name, meta_key)
+);
+
+INSERT INTO named_file_metadata (file_name, meta_key, meta_value)
+SELECT named_files.file_name, meta_key, meta_value
+FROM file_metadata JOIN named_files ON named_files.id = file_id;
+
+CREATE TABLE named_review_session_files (
+	token TEXT NOT NULL REFERENCES review_sessions (token) ON DELETE CASCADE,
+	file_name TEXT NOT NULL REFERENCES named_files (file_name) ON DELETE CASCADE,
+	PRIMARY KEY (token, file_name)
+);
+
+INSERT INTO named_review_session_files (token, file_name)
+SELECT token, named_files.file_name
+FROM review_session_files JOIN named_files ON named_files.id = file_id;
+
+CREATE TABLE named_file_versions (
+	file_name TEXT NOT NULL REFERENCES named_files (file_name) ON DELETE CASCADE,
+	version INTEGER NOT NULL,
+	file_type TEXT,
+	file_upload_date INTEGER NOT NULL,
+	file_size BIGINT,
+	duration_ms BIGINT,
+	storage_key TEXT NOT NULL,
+	sha256 TEXT,
+	compression TEXT,
+	stored_size BIGINT,
+	replaced_at INTEGER NOT NULL,
+	sample_rate INTEGER,
+	channels INTEGER,
+	bit_depth INTEGER,
+	PRIMARY KEY (file_name, version)
+);
+
+INSERT INTO named_file_versions (
+	file_name, version, file_type, file_upload_date, file_size, duration_ms, storage_key,
+	sha256, compression, stored_size, replaced_at, sample_rate, channels, bit_depth
+)
+SELECT
+	named_files.file_name, version, file_versions.file_type, file_versions.file_upload_date,
+	file_versions.file_size, file_versions.duration_ms, file_versions.storage_key,
+	file_versions.sha256, file_versions.compression, file_versions.stored_size, replaced_at,
+	file_versions.sample_rate, file_versions.channels, file_versions.bit_depth
+FROM file_versions JOIN named_files ON named_files.id = file_id;
+
+DROP TABLE file_tags;
+DROP TABLE file_metadata;
+DROP TABLE review_session_files;
+DROP TABLE file_versions;
+DROP TABLE file_search;
+DROP TABLE files;
+
+ALTER TABLE named_files RENAME TO files;
+ALTER TABLE named_file_tags RENAME TO file_tags;
+ALTER TABLE named_file_metadata RENAME TO file_metadata;
+ALTER TABLE named_review_session_files RENAME TO review_session_files;
+ALTER TABLE named_file_versions RENAME TO file_versions;
+
+CREATE UNIQUE INDEX files_id ON files (id);
+CREATE INDEX files_expires_at ON files (expires_at);
+CREATE INDEX files_file_type ON files (file_type);
+CREATE INDEX files_file_upload_date ON files (file_upload_date);
+CREATE INDEX files_owner ON files (owner);
+CREATE INDEX files_audio_format ON files (sample_rate, channels, bit_depth);
+
+CREATE VIRTUAL TABLE file_search USING fts5(file_name, title, description);
+
+INSERT INTO file_search (file_name, title, description)
+SELECT file_name, title, description FROM files;
+
+CREATE TRIGGER files_search_insert AFTER INSERT ON files BEGIN
+	INSERT INTO file_search (file_name, title, description)
+	VALUES (new.file_name, new.title, new.description);
+END;
+
+CREATE TRIGGER files_search_delete AFTER DELETE ON files BEGIN
+	DELETE FROM file_search WHERE file_name = old.file_name;
+END;
+
+CREATE TRIGGER files_search_update AFTER UPDATE OF file_name, title, description ON files BEGIN
+	DELETE FROM file_search WHERE file_name = old.file_name;
+	INSERT INTO file_search (file_name, title, description)
+	VALUES (new.file_name, new.title, new.description);
+END;
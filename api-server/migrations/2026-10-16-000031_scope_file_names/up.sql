@@ -0,0 +1,136 @@
+-- Names are unique per owner rather than across the server, so a file is
+-- keyed by its id, and so is everything hanging off it. Files without an
+-- owner share one namespace.
+CREATE TABLE keyed_files (
+	id TEXT PRIMARY KEY NOT NULL,
+	file_name TEXT NOT NULL,
+	owner TEXT,
+	file_type TEXT,
+	file_upload_date INTEGER NOT NULL,
+	title TEXT,
+	description TEXT,
+	language TEXT,
+	file_size BIGINT,
+	duration_ms BIGINT,
+	starred BOOLEAN NOT NULL DEFAULT 0,
+	download_count BIGINT NOT NULL DEFAULT 0,
+	last_accessed_at INTEGER,
+	storage_key TEXT,
+	sha256 TEXT,
+	compression TEXT,
+	stored_size BIGINT,
+	expires_at INTEGER,
+	revision INTEGER NOT NULL DEFAULT 1,
+	sample_rate INTEGER,
+	channels INTEGER,
+	bit_depth INTEGER
+);
+
+INSERT INTO keyed_files (
+	id, file_name, owner, file_type, file_upload_date, title, description, language,
+	file_size, duration_ms, starred, download_count, last_accessed_at, storage_key, sha256,
+	compression, stored_size, expires_at, revision, sample_rate, channels, bit_depth
+)
+SELECT
+	id, file_name, owner, file_type, file_upload_date, title, description, language,
+	file_size, duration_ms, starred, download_count, last_accessed_at, storage_key, sha256,
+	compression, stored_size, expires_at, revision, sample_rate, channels, bit_depth
+FROM files;
+
+CREATE TABLE keyed_file_tags (
+	file_id TEXT NOT NULL REFERENCES keyed_files (id) ON DELETE CASCADE,
+	tag_name TEXT NOT NULL REFERENCES tags (tag_name) ON DELETE CASCADE,
+	PRIMARY KEY (file_id, tag_name)
+);
+
+INSERT INTO keyed_file_tags (file_id, tag_name)
+SELECT files.id, tag_name FROM file_tags JOIN files USING (file_name);
+
+CREATE TABLE keyed_file_metadata (
+	file_id TEXT NOT NULL REFERENCES keyed_files (id) ON DELETE CASCADE,
+	meta_key TEXT NOT NULL,
+	meta_value TEXT NOT NULL,
+	PRIMARY KEY (file_id, meta_key)
+);
+
+INSERT INTO keyed_file_metadata (file_id, meta_key, meta_value)
+SELECT files.id, meta_key, meta_value FROM file_metadata JOIN files USING (file_name);
+
+CREATE TABLE keyed_review_session_files (
+	token TEXT NOT NULL REFERENCES review_sessions (token) ON DELETE CASCADE,
+	file_id TEXT NOT NULL REFERENCES keyed_files (id) ON DELETE CASCADE,
+	PRIMARY KEY (token, file_id)
+);
+
+INSERT INTO keyed_review_session_files (token, file_id)
+SELECT token, files.id FROM review_session_files JOIN files USING (file_name);
+
+CREATE TABLE keyed_file_versions (
+	file_id TEXT NOT NULL REFERENCES keyed_files (id) ON DELETE CASCADE,
+	version INTEGER NOT NULL,
+	file_type TEXT,
+	file_upload_date INTEGER NOT NULL,
+	file_size BIGINT,
+	duration_ms BIGINT,
+	storage_key TEXT NOT NULL,
+	sha256 TEXT,
+	compression TEXT,
+	stored_size BIGINT,
+	replaced_at INTEGER NOT NULL,
+	sample_rate INTEGER,
+	channels INTEGER,
+	bit_depth INTEGER,
+	PRIMARY KEY (file_id, version)
+);
+
+INSERT INTO keyed_file_versions (
+	file_id, version, file_type, file_upload_date, file_size, duration_ms, storage_key,
+	sha256, compression, stored_size, replaced_at, sample_rate, channels, bit_depth
+)
+SELECT
+	files.id, version, file_versions.file_type, file_versions.file_upload_date,
+	file_versions.file_size, file_versions.duration_ms, file_versions.storage_key,
+	file_versions.sha256, file_versions.compression, file_versions.stored_size, replaced_at,
+	file_versions.sample_rate, file_versions.channels, file_versions.bit_depth
+FROM file_versions JOIN files USING (file_name);
+
+DROP TABLE file_tags;
+DROP TABLE file_metadata;
+DROP TABLE review_session_files;
+DROP TABLE file_versions;
+DROP TABLE file_search;
+DROP TABLE files;
+
+-- Renaming the parent rewrites the children's references to it
+ALTER TABLE keyed_files RENAME TO files;
+ALTER TABLE keyed_file_tags RENAME TO file_tags;
+ALTER TABLE keyed_file_metadata RENAME TO file_metadata;
+ALTER TABLE keyed_review_session_files RENAME TO review_session_files;
+ALTER TABLE keyed_file_versions RENAME TO file_versions;
+
+CREATE UNIQUE INDEX files_owner_file_name ON files (coalesce(owner, ''), file_name);
+CREATE INDEX files_expires_at ON files (expires_at);
+CREATE INDEX files_file_type ON files (file_type);
+CREATE INDEX files_file_upload_date ON files (file_upload_date);
+CREATE INDEX files_owner ON files (owner);
+CREATE INDEX files_audio_format ON files (sample_rate, channels, bit_depth);
+
+CREATE VIRTUAL TABLE file_search USING fts5(file_id UNINDEXED, file_name, title, description);
+
+INSERT INTO file_search (file_id, file_name, title, description)
+SELECT id, file_name, title, description FROM files;
+
+CREATE TRIGGER files_search_insert AFTER INSERT ON files BEGIN
+	INSERT INTO file_search (file_id, file_name, title, description)
+	VALUES (new.id, new.file_name, new.title, new.description);
+END;
+
+CREATE TRIGGER files_search_delete AFTER DELETE ON files BEGIN
+	DELETE FROM file_search WHERE file_id = old.id;
+END;
+
+CREATE TRIGGER files_search_update AFTER UPDATE OF file_name, title, description ON files BEGIN
+	DELETE FROM file_search WHERE file_id = old.id;
+	INSERT INTO file_search (file_id, file_name, title, description)
+	VALUES (new.id, new.file_name, new.title, new.description);
+END;
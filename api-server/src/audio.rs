@@ -0,0 +1,527 @@
+#[cfg(feature = "decoding")]
+use crate::storage::ByteStream;
+use axum::body::Bytes;
+#[cfg(feature = "decoding")]
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+#[cfg(feature = "decoding")]
+use symphonia::core::{
+    codecs::CodecParameters,
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::{MediaSourceStream, ReadOnlySource},
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+    probe::{Hint, ProbeResult},
+    units::TimeBase,
+};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Container probing for stored uploads. Only what can be read from headers or
+// by demuxing, without decoding audio, lives here. Demuxing needs the
+// decoding feature; without it only WAV headers are read.
+
+/// Containers whose duration can be probed.
+#[cfg(feature = "decoding")]
+pub const DURATION_FORMATS: &[&str] = &["wav", "mp3", "flac", "ogg"];
+
+/// Containers whose duration can be probed.
+#[cfg(not(feature = "decoding"))]
+pub const DURATION_FORMATS: &[&str] = &["wav"];
+
+/// Types there is a decoder for, to draw waveforms and spectrograms from.
+#[cfg(feature = "decoding")]
+pub const DECODED_TYPES: &[&str] = &["audio/wav", "audio/mpeg", "audio/flac", "audio/ogg"];
+
+/// Types there is a decoder for, to draw waveforms and spectrograms from.
+#[cfg(not(feature = "decoding"))]
+pub const DECODED_TYPES: &[&str] = &[];
+
+/// Whether `file_type` is audio that no decoder is compiled in for, as
+/// opposed to no audio at all.
+#[cfg(feature = "decoding")]
+pub fn is_undecodable_audio(file_type: Option<&str>) -> bool {
+    file_type.map(canonical_file_type).is_some_and(|file_type| {
+        file_type.starts_with("audio/") && !DECODED_TYPES.contains(&file_type.as_str())
+    })
+}
+
+/// How much of the start of an upload is kept for probing.
+pub const HEADER_PROBE_LEN: usize = 64 * 1024;
+
+/// Best guess at a MIME type from a file name's extension.
+pub fn file_type_for_name(file_name: &str) -> Option<&'static str> {
+    let extension = std::path::Path::new(file_name)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+    Some(match extension.as_str() {
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "flac" => "audio/flac",
+        "m4a" | "mp4" => "audio/mp4",
+        "aac" => "audio/aac",
+        "webm" => "audio/webm",
+        _ => return None,
+    })
+}
+
+/// The type the content itself shows in its first bytes, for the containers
+/// the catalog knows.
+pub fn sniff_file_type(header: &[u8]) -> Option<&'static str> {
+    if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE" {
+        return Some("audio/wav");
+    }
+    if header.starts_with(b"fLaC") {
+        return Some("audio/flac");
+    }
+    if header.starts_with(b"OggS") {
+        // The first page carries the codec's identification header
+        return Some(match header.get(28..36) {
+            Some(b"OpusHead") => "audio/opus",
+            _ => "audio/ogg",
+        });
+    }
+    if header.starts_with(b"ID3") || is_mpeg_frame_header(header) {
+        return Some("audio/mpeg");
+    }
+    None
+}
+
+// Frame sync, then a version and a layer that aren't reserved. ADTS (AAC)
+// shares the sync but has layer 0.
+fn is_mpeg_frame_header(header: &[u8]) -> bool {
+    match header {
+        [0xff, second, ..] => {
+            second & 0xe0 == 0xe0 && (second >> 3) & 0b11 != 0b01 && (second >> 1) & 0b11 != 0
+        }
+        _ => false,
+    }
+}
+
+// The usual spelling of a type, so aliases such as audio/x-wav compare equal
+fn canonical_file_type(file_type: &str) -> String {
+    let file_type = file_type.split(';').next().unwrap_or("").trim();
+    match file_type.to_ascii_lowercase().as_str() {
+        "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => "audio/wav".to_owned(),
+        "audio/mp3" | "audio/mpeg3" | "audio/x-mpeg" => "audio/mpeg".to_owned(),
+        "audio/x-flac" => "audio/flac".to_owned(),
+        "application/ogg" | "audio/vorbis" => "audio/ogg".to_owned(),
+        other => other.to_owned(),
+    }
+}
+
+/// Content that isn't the type its upload declared.
+#[derive(Debug, thiserror::Error)]
+#[error("upload declared as {declared} is {detected}")]
+pub struct TypeMismatch {
+    pub declared: String,
+    pub detected: &'static str,
+}
+
+/// Checks a declared type against the sniffed one. Only a known type can
+/// contradict a declaration, and application/octet-stream declares nothing.
+pub fn check_declared_type(
+    declared: Option<&str>,
+    detected: Option<&'static str>,
+) -> Result<(), TypeMismatch> {
+    let (Some(declared), Some(detected)) = (declared, detected) else {
+        return Ok(());
+    };
+    let canonical = canonical_file_type(declared);
+    if canonical == "application/octet-stream" || canonical == detected {
+        return Ok(());
+    }
+    Err(TypeMismatch {
+        declared: declared.to_owned(),
+        detected,
+    })
+}
+
+fn read_u32_le(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Duration of a RIFF/WAVE file of `len` bytes from its `fmt ` byte rate and
+/// `data` chunk size. Returns `None` for anything that isn't a well-formed WAV file.
+pub fn wav_duration_ms<R: Read + Seek>(mut file: R, len: u64) -> io::Result<Option<i64>> {
+    let mut riff = [0u8; 12];
+    if file.read_exact(&mut riff).is_err() || &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Ok(None);
+    }
+    let mut byte_rate = None;
+    loop {
+        let mut id = [0u8; 4];
+        if file.read_exact(&mut id).is_err() {
+            return Ok(None);
+        }
+        let size = read_u32_le(&mut file)?;
+        match &id {
+            b"fmt " => {
+                // Only the fixed 16 bytes matter; the size comes from the file
+                // and isn't trusted with an allocation
+                if size < 16 {
+                    return Ok(None);
+                }
+                let mut fmt = [0u8; 16];
+                file.read_exact(&mut fmt)?;
+                byte_rate = Some(u32::from_le_bytes([fmt[8], fmt[9], fmt[10], fmt[11]]));
+                file.seek(SeekFrom::Current(size as i64 - 16))?;
+            }
+            b"data" => {
+                // Streaming writers leave the size at 0 or u32::MAX, so trust the file length
+                let remaining = len.saturating_sub(file.stream_position()?);
+                let size = match size {
+                    0 | u32::MAX => remaining,
+                    size => (size as u64).min(remaining),
+                };
+                return Ok(match byte_rate {
+                    Some(byte_rate) if byte_rate > 0 => {
+                        Some((size * 1000 / byte_rate as u64) as i64)
+                    }
+                    _ => None,
+                });
+            }
+            _ => {
+                file.seek(SeekFrom::Current(size as i64))?;
+            }
+        }
+        // Chunks are padded to an even length
+        if size % 2 == 1 {
+            file.seek(SeekFrom::Current(1))?;
+        }
+    }
+}
+
+/// Probes the duration from the first [`HEADER_PROBE_LEN`] bytes of an upload
+/// of `len` bytes, so it works the same whichever storage backend holds it.
+pub fn probe_duration_ms(header: &[u8], len: u64) -> Option<i64> {
+    match wav_duration_ms(Cursor::new(header), len) {
+        Ok(duration) => duration,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// What demuxing a file tells about its audio.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AudioInfo {
+    pub duration_ms: Option<i64>,
+    /// In Hz
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+    /// Bits per sample; lossy codecs have none
+    pub bit_depth: Option<i32>,
+    /// Embedded ID3 or Vorbis comment tags, keyed as in the metadata store
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Describes the whole file read from `source` by demuxing rather than
+/// decoding it. The format and tags come from the headers, as does the
+/// duration for containers that state their length (FLAC, WAV, MP3 with a
+/// Xing header); for the rest it is counted packet by packet. `file_name`
+/// only hints at the container. Blocks, so run it off the runtime.
+#[cfg(feature = "decoding")]
+pub fn measure(source: impl Read + Send + Sync + 'static, file_name: &str) -> AudioInfo {
+    let Ok(mut probed) = open(source, file_name) else {
+        return AudioInfo::default();
+    };
+    let mut tags = BTreeMap::new();
+    // ID3 tags sit in front of the container, so the probe reads them
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|log| log.current()) {
+        collect_tags(revision, &mut tags);
+    }
+    let mut format = probed.format;
+    let Some(track) = format.default_track() else {
+        return AudioInfo::default();
+    };
+    let (track_id, params) = (track.id, track.codec_params.clone());
+    let frames = match params.n_frames {
+        Some(frames) if frames > 0 => Some(frames),
+        _ => count_frames(format.as_mut(), track_id).map(|end| end.saturating_sub(params.start_ts)),
+    };
+    if let Some(revision) = format.metadata().current() {
+        collect_tags(revision, &mut tags);
+    }
+    AudioInfo {
+        duration_ms: frames.and_then(|frames| frames_to_ms(&params, frames)),
+        sample_rate: params.sample_rate.map(|rate| rate as i32),
+        channels: params.channels.map(|channels| channels.count() as i32),
+        bit_depth: params.bits_per_sample.map(|bits| bits as i32),
+        tags,
+    }
+}
+
+/// Without the decoding feature nothing is known until the file is stored,
+/// when WAV headers give the duration.
+#[cfg(not(feature = "decoding"))]
+pub fn measure(_source: impl Read + Send + Sync + 'static, _file_name: &str) -> AudioInfo {
+    AudioInfo::default()
+}
+
+/// Recognises the container of the file read from `source`, reading no
+/// further than its headers. `file_name` only hints at the container.
+#[cfg(feature = "decoding")]
+pub fn open(
+    source: impl Read + Send + Sync + 'static,
+    file_name: &str,
+) -> Result<ProbeResult, SymphoniaError> {
+    let source = MediaSourceStream::new(Box::new(ReadOnlySource::new(source)), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = std::path::Path::new(file_name).extension() {
+        hint.with_extension(&extension.to_string_lossy());
+    }
+    symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )
+}
+
+// The tags worth cataloguing; the first of each kind wins
+#[cfg(feature = "decoding")]
+fn collect_tags(revision: &MetadataRevision, tags: &mut BTreeMap<String, String>) {
+    for tag in revision.tags() {
+        let key = match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => "title",
+            Some(StandardTagKey::Artist) => "artist",
+            Some(StandardTagKey::Album) => "album",
+            Some(StandardTagKey::Date) => "date",
+            Some(StandardTagKey::Comment) => "comment",
+            _ => continue,
+        };
+        let value = tag.value.to_string();
+        let value = value.trim();
+        if !value.is_empty() {
+            tags.entry(key.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
+    }
+}
+
+// Where the track's last packet ends, in its time base
+#[cfg(feature = "decoding")]
+fn count_frames(format: &mut dyn FormatReader, track_id: u32) -> Option<u64> {
+    let mut end = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => end = end.max(packet.ts + packet.dur),
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Some(end)
+            }
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "decoding")]
+fn frames_to_ms(params: &CodecParameters, frames: u64) -> Option<i64> {
+    let time_base = params
+        .time_base
+        .or_else(|| params.sample_rate.map(|rate| TimeBase::new(1, rate)))?;
+    let time = time_base.calc_time(frames);
+    match time.seconds * 1000 + (time.frac * 1000.0) as u64 {
+        0 => None,
+        ms => Some(ms as i64),
+    }
+}
+
+/// Measures an upload as its bytes stream past, demuxing on a blocking thread
+/// so it never holds up the transfer's task.
+pub struct AudioProbe {
+    sender: mpsc::Sender<Bytes>,
+    measured: JoinHandle<AudioInfo>,
+}
+
+impl AudioProbe {
+    pub fn start(file_name: &str) -> Self {
+        let (sender, reader) = ChannelReader::new();
+        let file_name = file_name.to_owned();
+        AudioProbe {
+            sender,
+            measured: tokio::task::spawn_blocking(move || measure(reader, &file_name)),
+        }
+    }
+
+    /// Hands over the next bytes, waiting while the probe is behind. Once it
+    /// has what it needs the bytes are dropped.
+    pub async fn feed(&self, bytes: Bytes) {
+        let _ = self.sender.send(bytes).await;
+    }
+
+    /// What everything fed says about its audio.
+    pub async fn finish(self) -> AudioInfo {
+        drop(self.sender);
+        self.measured.await.unwrap_or_default()
+    }
+}
+
+/// Runs `read` on a blocking thread over the bytes of `stream`, for the
+/// synchronous demuxers and decoders, without holding the whole object in
+/// memory.
+#[cfg(feature = "decoding")]
+pub async fn read_blocking<T: Send + 'static>(
+    mut stream: ByteStream<'static>,
+    read: impl FnOnce(ChannelReader) -> T + Send + 'static,
+) -> io::Result<T> {
+    let (sender, reader) = ChannelReader::new();
+    let result = tokio::task::spawn_blocking(move || read(reader));
+    while let Some(bytes) = stream.next().await {
+        // The reader stops early on content it can't make sense of
+        if sender.send(bytes?).await.is_err() {
+            break;
+        }
+    }
+    drop(sender);
+    result.await.map_err(io::Error::other)
+}
+
+/// Reads the bytes sent to it, blocking until they arrive.
+pub struct ChannelReader {
+    receiver: mpsc::Receiver<Bytes>,
+    pending: Bytes,
+}
+
+impl ChannelReader {
+    fn new() -> (mpsc::Sender<Bytes>, Self) {
+        let (sender, receiver) = mpsc::channel(16);
+        let reader = ChannelReader {
+            receiver,
+            pending: Bytes::new(),
+        };
+        (sender, reader)
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(bytes) => self.pending = bytes,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending.split_to(len));
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MPEG-1 layer III frames at 128 kbit/s and 44.1 kHz, each 1152 samples.
+    // Their audio is silence, which is all zeros after the header.
+    fn mp3_frames(count: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+        frame.repeat(count)
+    }
+
+    #[test]
+    #[cfg(feature = "decoding")]
+    fn describes_an_mp3_without_a_length_header() {
+        let info = measure(Cursor::new(mp3_frames(100)), "a.mp3");
+        assert_eq!(info.duration_ms, Some(100 * 1152 * 1000 / 44100));
+        assert_eq!(info.sample_rate, Some(44100));
+        assert_eq!(info.channels, Some(2));
+        assert_eq!(info.bit_depth, None);
+    }
+
+    #[cfg(feature = "decoding")]
+    fn id3_frame(id: &str, text: &str) -> Vec<u8> {
+        let mut frame = id.as_bytes().to_vec();
+        frame.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0]);
+        frame.extend_from_slice(text.as_bytes());
+        frame
+    }
+
+    #[test]
+    #[cfg(feature = "decoding")]
+    fn reads_id3_tags_in_front_of_an_mp3() {
+        let frames = [id3_frame("TIT2", "Take one"), id3_frame("TPE1", " Ana ")].concat();
+        let mut bytes = b"ID3\x03\x00\x00\x00\x00\x00".to_vec();
+        bytes.push(frames.len() as u8);
+        bytes.extend(frames);
+        bytes.extend(mp3_frames(10));
+        let info = measure(Cursor::new(bytes), "a.mp3");
+        assert_eq!(info.tags.get("title").map(String::as_str), Some("Take one"));
+        assert_eq!(info.tags.get("artist").map(String::as_str), Some("Ana"));
+        assert_eq!(info.sample_rate, Some(44100));
+    }
+
+    #[test]
+    fn sniffs_containers_from_their_first_bytes() {
+        assert_eq!(
+            sniff_file_type(&crate::demo::sine_wav(440, 1)),
+            Some("audio/wav")
+        );
+        assert_eq!(sniff_file_type(&mp3_frames(1)), Some("audio/mpeg"));
+        assert_eq!(sniff_file_type(b"ID3\x04\0\0"), Some("audio/mpeg"));
+        assert_eq!(sniff_file_type(b"fLaC\0\0\0\x22"), Some("audio/flac"));
+        assert_eq!(sniff_file_type(b"OggS\0\x02"), Some("audio/ogg"));
+        // ADTS shares the MPEG sync word
+        assert_eq!(sniff_file_type(&[0xff, 0xf1, 0x50, 0x80]), None);
+        assert_eq!(sniff_file_type(b"%PDF-1.7"), None);
+    }
+
+    #[test]
+    fn declared_types_are_checked_against_the_content() {
+        assert!(check_declared_type(Some("audio/x-wav"), Some("audio/wav")).is_ok());
+        assert!(check_declared_type(Some("audio/mpeg"), Some("audio/wav")).is_err());
+        assert!(check_declared_type(Some("application/octet-stream"), Some("audio/wav")).is_ok());
+        assert!(check_declared_type(Some("audio/mpeg"), None).is_ok());
+        assert!(check_declared_type(None, Some("audio/wav")).is_ok());
+    }
+
+    #[test]
+    fn unknown_bytes_describe_nothing() {
+        let info = measure(Cursor::new(vec![7u8; 4096]), "a.mp3");
+        assert_eq!(info, AudioInfo::default());
+    }
+
+    // A WAV header with a fmt chunk claiming `fmt_size` bytes, of which the
+    // usual 16 are there, and one second of 8 kHz 16-bit mono after it
+    fn wav_with_fmt_size(fmt_size: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        bytes.extend_from_slice(&fmt_size.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 1, 0]);
+        bytes.extend_from_slice(&8000u32.to_le_bytes());
+        bytes.extend_from_slice(&16000u32.to_le_bytes());
+        bytes.extend_from_slice(&[2, 0, 16, 0]);
+        bytes.extend_from_slice(&[0; 2]);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&16000u32.to_le_bytes());
+        bytes.extend_from_slice(&[0; 16000]);
+        bytes
+    }
+
+    #[test]
+    fn fmt_chunks_are_read_no_further_than_needed() {
+        let wav = wav_with_fmt_size(18);
+        let len = wav.len() as u64;
+        assert_eq!(wav_duration_ms(Cursor::new(wav), len).unwrap(), Some(1000));
+        // Sizes from the file don't decide how much is allocated
+        let wav = wav_with_fmt_size(u32::MAX - 1);
+        let len = wav.len() as u64;
+        assert_eq!(wav_duration_ms(Cursor::new(wav), len).unwrap(), None);
+        let wav = wav_with_fmt_size(12);
+        let len = wav.len() as u64;
+        assert_eq!(wav_duration_ms(Cursor::new(wav), len).unwrap(), None);
+    }
+}
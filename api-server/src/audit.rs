@@ -0,0 +1,206 @@
+use crate::db::NewAuditEntry;
+use crate::repository::Repository;
+use crate::timestamp;
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::header::{CONTENT_TYPE, FORWARDED};
+use axum::http::{HeaderMap, Method, Request, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Every request that could change something is written to the audit log,
+// whatever its outcome, because recordings can hold customer calls. An entry
+// keeps what the request asked to change: the query, JSON bodies and the
+// fields of forms, except that long values such as audio are recorded by
+// size only. The server has no accounts of its own, so who made a change is
+// the user an authenticating proxy in front of it names in X-Forwarded-User.
+
+pub const ACTOR_HEADER: &str = "x-forwarded-user";
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+// As large as JSON uploads get; larger bodies are recorded by size only
+const MAX_PARSED_JSON: usize = 64 * 1024 * 1024;
+// Longer values are recorded by size only
+const MAX_RECORDED_VALUE: usize = 1024;
+// Query parameters that grant access rather than describe a change
+const UNRECORDED_PARAMS: &[&str] = &["signature"];
+
+pub async fn record_changes(
+    State(db): State<Repository>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+    let (parts, body) = request.into_parts();
+    let header = |name| header_value(&parts.headers, name);
+    let mut entry = NewAuditEntry {
+        occurred_at: timestamp::now(),
+        actor: header(ACTOR_HEADER),
+        client_ip: parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        forwarded_for: header(FORWARDED_FOR_HEADER).or_else(|| header(FORWARDED.as_str())),
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_owned(),
+        status: None,
+        changes: None,
+    };
+    let content_type = header(CONTENT_TYPE.as_str());
+    let query = recorded_query(&parts.uri);
+
+    // The body is read from a copy as the handler consumes it
+    let (tx, rx) = mpsc::unbounded();
+    let summary = match content_type.as_deref() {
+        Some(value) if is_json(value) => Some(tokio::spawn(json_body(rx))),
+        Some(value) => multer::parse_boundary(value)
+            .ok()
+            .map(|boundary| tokio::spawn(form_fields(rx, boundary))),
+        None => None,
+    };
+    let tx = summary.is_some().then_some(tx);
+    let body_len = Arc::new(AtomicU64::new(0));
+    let counted = body_len.clone();
+    let body = body.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            counted.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            if let Some(tx) = &tx {
+                let _ = tx.unbounded_send(chunk.clone());
+            }
+        }
+        chunk
+    });
+    let response = next
+        .run(Request::from_parts(parts, Body::wrap_stream(body)))
+        .await;
+
+    entry.status = Some(response.status().as_u16() as i32);
+    let body_len = body_len.load(Ordering::Relaxed);
+    let body = match summary {
+        Some(summary) => summary.await.unwrap_or(Value::Null),
+        None if body_len > 0 => json!({ "content_type": content_type, "bytes": body_len }),
+        None => Value::Null,
+    };
+    let mut changes = Map::new();
+    if !query.is_empty() {
+        changes.insert("query".to_owned(), Value::Object(query));
+    }
+    if !body.is_null() {
+        changes.insert("body".to_owned(), body);
+    }
+    if !changes.is_empty() {
+        entry.changes = Some(Value::Object(changes).to_string());
+    }
+    if let Err(e) = db.record_audit(&entry).await {
+        eprintln!("{:?}", e);
+    }
+    response
+}
+
+/// Records a change the server made on its own, such as a retention purge.
+pub async fn record_internal(db: &Repository, actor: &str, method: &str, path: String) {
+    let entry = NewAuditEntry {
+        occurred_at: timestamp::now(),
+        actor: Some(actor.to_owned()),
+        client_ip: None,
+        forwarded_for: None,
+        method: method.to_owned(),
+        path,
+        status: None,
+        changes: None,
+    };
+    if let Err(e) = db.record_audit(&entry).await {
+        eprintln!("{:?}", e);
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+    Some(value.to_owned())
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+fn recorded_query(uri: &Uri) -> Map<String, Value> {
+    let Ok(Query(params)) = Query::<BTreeMap<String, String>>::try_from_uri(uri) else {
+        return Map::new();
+    };
+    params
+        .into_iter()
+        .filter(|(name, _)| !UNRECORDED_PARAMS.contains(&name.as_str()))
+        .map(|(name, value)| (name, Value::String(value)))
+        .collect()
+}
+
+async fn json_body(mut chunks: mpsc::UnboundedReceiver<Bytes>) -> Value {
+    let mut body = Vec::new();
+    let mut len = 0;
+    while let Some(chunk) = chunks.next().await {
+        len += chunk.len();
+        if len <= MAX_PARSED_JSON {
+            body.extend_from_slice(&chunk);
+        }
+    }
+    match serde_json::from_slice(&body) {
+        Ok(value) if len <= MAX_PARSED_JSON => shorten(value),
+        _ => size_only(len),
+    }
+}
+
+fn shorten(value: Value) -> Value {
+    match value {
+        Value::String(text) if text.len() > MAX_RECORDED_VALUE => size_only(text.len()),
+        Value::Array(values) => Value::Array(values.into_iter().map(shorten).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, shorten(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn size_only(len: usize) -> Value {
+    json!({ "bytes": len })
+}
+
+// Files by their name and size; text fields as for JSON
+async fn form_fields(chunks: mpsc::UnboundedReceiver<Bytes>, boundary: String) -> Value {
+    let mut multipart = multer::Multipart::new(chunks.map(Ok::<_, Infallible>), boundary);
+    let mut fields = Map::new();
+    while let Ok(Some(mut field)) = multipart.next_field().await {
+        let name = field.name().unwrap_or_default().to_owned();
+        let file_name = field.file_name().map(str::to_owned);
+        let mut text = Vec::new();
+        let mut len = 0;
+        while let Ok(Some(chunk)) = field.chunk().await {
+            len += chunk.len();
+            if file_name.is_none() && len <= MAX_RECORDED_VALUE {
+                text.extend_from_slice(&chunk);
+            }
+        }
+        let value = match file_name {
+            Some(file_name) => json!({ "file_name": file_name, "bytes": len }),
+            None if len > MAX_RECORDED_VALUE => size_only(len),
+            None => Value::String(String::from_utf8_lossy(&text).into_owned()),
+        };
+        fields.insert(name, value);
+    }
+    Value::Object(fields)
+}
@@ -0,0 +1,419 @@
+use crate::db::{establish_connection, File};
+use crate::schema::{file_versions, files};
+use crate::storage::SharedStorage;
+use crate::timestamp;
+use anyhow::Context;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Nullable, Text};
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+// A backup is a directory under BACKUP_DIR holding a consistent SQLite
+// snapshot, a manifest of every file's key and hash, and optionally a tarball
+// of the stored objects. Restoring puts back any missing objects, then
+// replaces every table's rows with the snapshot's.
+
+const SNAPSHOT: &str = "sqlite.db";
+const MANIFEST: &str = "manifest.json";
+const OBJECTS: &str = "objects.tar";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("no such backup")]
+    NotFound,
+    /// The snapshot was taken under a different schema
+    #[error("backup is incompatible: {0}")]
+    Incompatible(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<diesel::result::Error> for BackupError {
+    fn from(e: diesel::result::Error) -> Self {
+        BackupError::Other(e.into())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file_name: String,
+    pub storage_key: String,
+    pub sha256: Option<String>,
+    pub file_size: Option<i64>,
+    pub stored_size: Option<i64>,
+    pub compression: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    pub created_at: i64,
+    pub objects_included: bool,
+    pub files: Vec<ManifestEntry>,
+    /// Objects holding earlier versions of the files
+    #[serde(default)]
+    pub version_keys: Vec<String>,
+}
+
+impl Manifest {
+    fn storage_keys(&self) -> BTreeSet<&str> {
+        self.files
+            .iter()
+            .map(|entry| entry.storage_key.as_str())
+            .chain(self.version_keys.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupSummary {
+    pub id: String,
+    pub created_at: i64,
+    pub objects_included: bool,
+    pub files: usize,
+}
+
+impl From<&Manifest> for BackupSummary {
+    fn from(manifest: &Manifest) -> Self {
+        BackupSummary {
+            id: manifest.id.clone(),
+            created_at: manifest.created_at,
+            objects_included: manifest.objects_included,
+            files: manifest.files.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreSummary {
+    pub files: usize,
+    /// Objects copied back into storage from the backup's tarball
+    pub objects_restored: usize,
+}
+
+/// From BACKUP_DIR, defaulting to ./backups.
+pub fn backup_root() -> PathBuf {
+    std::env::var("BACKUP_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("backups"))
+}
+
+// Ids become directory names, so anything else could escape the backup root
+fn backup_dir(root: &Path, id: &str) -> Result<PathBuf, BackupError> {
+    let valid = !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+    let dir = root.join(id);
+    if !valid || !dir.join(MANIFEST).exists() {
+        return Err(BackupError::NotFound);
+    }
+    Ok(dir)
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest, anyhow::Error> {
+    let json = std::fs::read(dir.join(MANIFEST))?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+pub fn list_backups(root: &Path) -> Result<Vec<BackupSummary>, anyhow::Error> {
+    let mut backups = Vec::new();
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let dir = entry?.path();
+        // Directories without a manifest are backups that never finished
+        if let Ok(manifest) = read_manifest(&dir) {
+            backups.push(BackupSummary::from(&manifest));
+        }
+    }
+    backups.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(backups)
+}
+
+pub async fn create_backup(
+    root: &Path,
+    database_url: &str,
+    storage: &SharedStorage,
+    include_objects: bool,
+) -> Result<BackupSummary, anyhow::Error> {
+    let mut suffix = [0u8; 4];
+    getrandom::getrandom(&mut suffix).map_err(|e| anyhow::anyhow!(e))?;
+    let created_at = timestamp::now();
+    let id = format!("{}-{}", created_at, hex::encode(suffix));
+    let dir = root.join(&id);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("creating {:?}", dir))?;
+    // VACUUM INTO reads inside one transaction, so the copy is consistent
+    // even while uploads carry on
+    let snapshot = dir.join(SNAPSHOT);
+    sql_query("VACUUM INTO ?")
+        .bind::<Text, _>(snapshot.to_string_lossy())
+        .execute(&mut establish_connection(database_url)?)
+        .context("snapshotting the database")?;
+    let mut conn = SqliteConnection::establish(&snapshot.to_string_lossy())?;
+    let files = files::table.load::<File>(&mut conn)?;
+    let version_keys = file_versions::table
+        .select(file_versions::storage_key)
+        .load::<String>(&mut conn)?;
+    let entries: Vec<ManifestEntry> = files
+        .into_iter()
+        .map(|file| ManifestEntry {
+            storage_key: file.storage_key.unwrap_or_else(|| file.file_name.clone()),
+            file_name: file.file_name,
+            sha256: file.sha256,
+            file_size: file.file_size,
+            stored_size: file.stored_size,
+            compression: file.compression,
+        })
+        .collect();
+    let manifest = Manifest {
+        id,
+        created_at,
+        objects_included: include_objects,
+        files: entries,
+        version_keys,
+    };
+    if include_objects {
+        write_objects(&dir, storage, manifest.storage_keys()).await?;
+    }
+    // Written last; its presence marks the backup as complete
+    tokio::fs::write(dir.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?).await?;
+    Ok(BackupSummary::from(&manifest))
+}
+
+// Each object is spooled to disk before going into the tarball, so none has
+// to fit in memory
+async fn write_objects(
+    dir: &Path,
+    storage: &SharedStorage,
+    keys: BTreeSet<&str>,
+) -> Result<(), anyhow::Error> {
+    let mut tarball = tar::Builder::new(std::fs::File::create(dir.join(OBJECTS))?);
+    let spool = dir.join("object.tmp");
+    for key in keys {
+        let mut stream = match storage.stream(key).await {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("backup: {} is missing from storage", key);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = tokio::fs::File::create(&spool).await?;
+        while let Some(bytes) = stream.next().await {
+            file.write_all(&bytes?).await?;
+        }
+        file.flush().await?;
+        tarball.append_path_with_name(&spool, key)?;
+    }
+    tarball.finish()?;
+    tokio::fs::remove_file(&spool).await.ok();
+    Ok(())
+}
+
+#[derive(QueryableByName, PartialEq, Eq, Debug)]
+struct TableColumns {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    columns: Option<String>,
+}
+
+// Only ordinary tables: the full-text index is virtual, with shadow tables
+// behind it, and triggers rebuild it as the files table is refilled. The
+// audit log is left out so a restore can't rewrite it; the restore itself is
+// one more entry.
+fn table_columns(conn: &mut SqliteConnection, schema: &str) -> QueryResult<Vec<TableColumns>> {
+    // Schema names can't be bound, and only "main" and "snapshot" get here
+    sql_query(format!(
+        "SELECT m.name AS name, \
+         (SELECT group_concat(p.name, ',') FROM pragma_table_info(m.name, '{schema}') p) AS columns \
+         FROM {schema}.sqlite_master m \
+         JOIN pragma_table_list t ON t.schema = '{schema}' AND t.name = m.name \
+         WHERE m.type = 'table' AND t.type = 'table' AND m.name NOT LIKE 'sqlite_%' \
+         AND m.name NOT IN ('__diesel_schema_migrations', 'audit_log') \
+         ORDER BY m.name"
+    ))
+    .load(conn)
+}
+
+pub async fn restore_backup(
+    root: &Path,
+    id: &str,
+    database_url: &str,
+    storage: &SharedStorage,
+) -> Result<RestoreSummary, BackupError> {
+    let dir = backup_dir(root, id)?;
+    let manifest = read_manifest(&dir)?;
+    let objects_restored = if manifest.objects_included {
+        restore_objects(&dir, &manifest, storage).await?
+    } else {
+        0
+    };
+    let mut conn = establish_connection(database_url)?;
+    // Tables are emptied and refilled one at a time, which foreign keys
+    // would refuse or cascade through; the snapshot is consistent anyway
+    sql_query("PRAGMA foreign_keys = OFF")
+        .execute(&mut conn)
+        .context("disabling foreign keys")?;
+    sql_query("ATTACH DATABASE ? AS snapshot")
+        .bind::<Text, _>(dir.join(SNAPSHOT).to_string_lossy())
+        .execute(&mut conn)
+        .context("attaching the snapshot")?;
+    let restored = conn.transaction::<_, BackupError, _>(|conn| {
+        let tables = table_columns(conn, "snapshot").context("reading the snapshot schema")?;
+        if tables != table_columns(conn, "main").context("reading the schema")? {
+            return Err(BackupError::Incompatible(
+                "its tables differ from the current schema".to_owned(),
+            ));
+        }
+        for table in &tables {
+            sql_query(format!("DELETE FROM main.\"{}\"", table.name))
+                .execute(conn)
+                .context("clearing the current rows")?;
+            sql_query(format!(
+                "INSERT INTO main.\"{0}\" SELECT * FROM snapshot.\"{0}\"",
+                table.name
+            ))
+            .execute(conn)
+            .context("copying the snapshot rows")?;
+        }
+        Ok(())
+    });
+    if let Err(e) = sql_query("DETACH DATABASE snapshot").execute(&mut conn) {
+        eprintln!("{:?}", e);
+    }
+    restored?;
+    Ok(RestoreSummary {
+        files: manifest.files.len(),
+        objects_restored,
+    })
+}
+
+// Puts back the objects storage no longer has; existing ones are left alone
+async fn restore_objects(
+    dir: &Path,
+    manifest: &Manifest,
+    storage: &SharedStorage,
+) -> Result<usize, anyhow::Error> {
+    let unpacked = dir.join("restore.tmp");
+    let tarball = dir.join(OBJECTS);
+    let target = unpacked.clone();
+    // tar refuses entries that would land outside the target directory
+    tokio::task::spawn_blocking(move || {
+        tar::Archive::new(std::fs::File::open(tarball)?).unpack(target)
+    })
+    .await??;
+    let mut restored = 0;
+    let result: Result<(), anyhow::Error> = async {
+        for key in manifest.storage_keys() {
+            let path = unpacked.join(key);
+            if storage.exists(key).await? || !path.exists() {
+                continue;
+            }
+            let file = tokio::fs::File::open(&path).await?;
+            storage.put(key, ReaderStream::new(file).boxed()).await?;
+            restored += 1;
+        }
+        Ok(())
+    }
+    .await;
+    tokio::fs::remove_dir_all(&unpacked).await.ok();
+    result?;
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{establish_pool, new_file_id, run_migrations, SqliteRepository};
+    use crate::repository::FileRepository;
+    use crate::storage::memory::MemoryStorage;
+    use axum::body::Bytes;
+    use futures::stream;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn file(file_name: &str) -> File {
+        File {
+            file_name: file_name.to_owned(),
+            file_type: Some("audio/wav".to_owned()),
+            file_upload_date: 0,
+            title: None,
+            description: None,
+            language: None,
+            file_size: Some(4),
+            duration_ms: None,
+            starred: false,
+            download_count: 0,
+            last_accessed_at: None,
+            storage_key: Some(format!("objects/{}", file_name)),
+            sha256: None,
+            compression: None,
+            stored_size: None,
+            expires_at: None,
+            id: new_file_id(),
+            revision: 1,
+            owner: None,
+            sample_rate: None,
+            channels: None,
+            bit_depth: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn restoring_puts_back_rows_and_objects() {
+        let dir = std::env::temp_dir().join(format!("backup-{}", new_file_id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let url = dir.join("db.sqlite").to_string_lossy().into_owned();
+        run_migrations(&url).unwrap();
+        let db = SqliteRepository::new(establish_pool(&url).unwrap());
+        let storage: SharedStorage = Arc::new(MemoryStorage::default());
+        let a = file("a.wav");
+        db.insert_file(&a, &BTreeMap::new()).await.unwrap();
+        let bytes = stream::once(async { Ok(Bytes::from_static(b"RIFF")) }).boxed();
+        storage.put("objects/a.wav", bytes).await.unwrap();
+        let root = dir.join("backups");
+        let backup = create_backup(&root, &url, &storage, true).await.unwrap();
+        assert_eq!(backup.files, 1);
+
+        db.delete_file(&a.id).await.unwrap();
+        storage.delete("objects/a.wav").await.unwrap();
+        db.insert_file(&file("b.wav"), &BTreeMap::new())
+            .await
+            .unwrap();
+        let restored = restore_backup(&root, &backup.id, &url, &storage)
+            .await
+            .unwrap();
+        assert_eq!((restored.files, restored.objects_restored), (1, 1));
+        assert_eq!(db.list_file_names().await.unwrap(), ["a.wav"]);
+        assert!(storage.exists("objects/a.wav").await.unwrap());
+
+        // A snapshot from another schema is refused and changes nothing
+        let mut snapshot =
+            SqliteConnection::establish(&root.join(&backup.id).join(SNAPSHOT).to_string_lossy())
+                .unwrap();
+        sql_query("ALTER TABLE files ADD COLUMN extra TEXT")
+            .execute(&mut snapshot)
+            .unwrap();
+        db.delete_file(&a.id).await.unwrap();
+        let refused = restore_backup(&root, &backup.id, &url, &storage).await;
+        assert!(matches!(refused, Err(BackupError::Incompatible(_))));
+        assert!(db.list_file_names().await.unwrap().is_empty());
+        assert!(matches!(
+            restore_backup(&root, "../backups", &url, &storage).await,
+            Err(BackupError::NotFound)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use crate::db::{File, FileVersion};
+use async_trait::async_trait;
+
+// Rows share stored objects by content. Each object has a blob row counting
+// the file and version rows that point at it, and is only deleted with the
+// last of them. The bookkeeping is the same for every database; each
+// repository supplies the queries it is made of, run on the connection of
+// the transaction it is writing in.
+
+/// The queries reference counting is built on, for one backend's
+/// connections.
+#[async_trait]
+pub trait BlobQueries: Send {
+    type Error: Send;
+
+    /// Called before reference counts are read to be changed. SQLite
+    /// transactions hold the write lock from the start and need nothing.
+    async fn lock_blobs(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// The key of an object already stored with this content.
+    async fn blob_with_sha256(&mut self, sha256: &str) -> Result<Option<String>, Self::Error>;
+
+    /// The codec and stored size of the object under `storage_key`, as a
+    /// row pointing at it recorded them.
+    async fn stored_format(
+        &mut self,
+        storage_key: &str,
+    ) -> Result<Option<(Option<String>, Option<i64>)>, Self::Error>;
+
+    /// Adds `delta` to the object's reference count, returning whether it
+    /// has a blob row.
+    async fn add_references(&mut self, storage_key: &str, delta: i64) -> Result<bool, Self::Error>;
+
+    /// A blob row holding one reference.
+    async fn insert_blob(
+        &mut self,
+        storage_key: &str,
+        sha256: Option<&str>,
+        stored_size: i64,
+    ) -> Result<(), Self::Error>;
+
+    /// The stored size of the object if nothing references it any more.
+    async fn unreferenced_size(&mut self, storage_key: &str) -> Result<Option<i64>, Self::Error>;
+
+    async fn delete_blob(&mut self, storage_key: &str) -> Result<(), Self::Error>;
+
+    async fn add_storage_usage(&mut self, bytes: i64) -> Result<(), Self::Error>;
+
+    /// The highest version kept of the file.
+    async fn latest_version(&mut self, file_id: &str) -> Result<Option<i32>, Self::Error>;
+
+    async fn insert_version(&mut self, version: &FileVersion) -> Result<(), Self::Error>;
+}
+
+/// Takes a reference on the object holding `file`'s bytes, switching the row
+/// to an already stored object with the same content if there is one.
+pub async fn acquire_blob<C: BlobQueries>(conn: &mut C, file: &mut File) -> Result<(), C::Error> {
+    let Some(mut key) = file.storage_key.clone() else {
+        return Ok(());
+    };
+    conn.lock_blobs().await?;
+    if let Some(sha256) = &file.sha256 {
+        if let Some(existing) = conn.blob_with_sha256(sha256).await? {
+            // The existing object may have been stored with another codec
+            if let Some((compression, stored_size)) = conn.stored_format(&existing).await? {
+                file.compression = compression;
+                file.stored_size = stored_size;
+            }
+            key = existing;
+        }
+    }
+    if !conn.add_references(&key, 1).await? {
+        conn.insert_blob(&key, file.sha256.as_deref(), file.stored_bytes())
+            .await?;
+        conn.add_storage_usage(file.stored_bytes()).await?;
+    }
+    file.storage_key = Some(key);
+    Ok(())
+}
+
+/// Drops the reference `file` held, returning its object's key if that was
+/// the last one.
+pub async fn release_blob<C: BlobQueries>(
+    conn: &mut C,
+    file: &File,
+) -> Result<Option<String>, C::Error> {
+    let Some(key) = &file.storage_key else {
+        // Rows from before sharding own the object stored under their name
+        conn.add_storage_usage(-file.stored_bytes()).await?;
+        return Ok(Some(file.file_name.clone()));
+    };
+    conn.lock_blobs().await?;
+    conn.add_references(key, -1).await?;
+    let Some(stored_size) = conn.unreferenced_size(key).await? else {
+        return Ok(None);
+    };
+    conn.delete_blob(key).await?;
+    conn.add_storage_usage(-stored_size).await?;
+    Ok(Some(key.clone()))
+}
+
+/// Drops the references a deleted file and its versions, stored under
+/// `version_keys`, held, returning the objects nothing points at any more.
+pub async fn release_file<C: BlobQueries>(
+    conn: &mut C,
+    file: &File,
+    version_keys: Vec<String>,
+) -> Result<Vec<String>, C::Error> {
+    let mut unreferenced: Vec<String> = release_blob(conn, file).await?.into_iter().collect();
+    for storage_key in version_keys {
+        let version = File {
+            storage_key: Some(storage_key),
+            ..file.clone()
+        };
+        unreferenced.extend(release_blob(conn, &version).await?);
+    }
+    Ok(unreferenced)
+}
+
+/// The key of `file`'s object. Rows from before sharding own the object
+/// stored under their name, which gets a blob row holding the file's
+/// reference, so it can be shared like any other. Its bytes are already part
+/// of the usage.
+pub async fn adopt_legacy_object<C: BlobQueries>(
+    conn: &mut C,
+    file: &File,
+) -> Result<String, C::Error> {
+    if let Some(key) = &file.storage_key {
+        return Ok(key.clone());
+    }
+    conn.lock_blobs().await?;
+    conn.insert_blob(&file.file_name, file.sha256.as_deref(), file.stored_bytes())
+        .await?;
+    Ok(file.file_name.clone())
+}
+
+/// Keeps `previous`'s content as the file's next version, moving its blob
+/// reference over to the version row.
+pub async fn archive_version<C: BlobQueries>(
+    conn: &mut C,
+    previous: &File,
+    replaced_at: i64,
+) -> Result<(), C::Error> {
+    let storage_key = adopt_legacy_object(conn, previous).await?;
+    let latest = conn.latest_version(&previous.id).await?;
+    conn.insert_version(&FileVersion {
+        file_id: previous.id.clone(),
+        version: latest.unwrap_or(0) + 1,
+        file_type: previous.file_type.clone(),
+        file_upload_date: previous.file_upload_date,
+        file_size: previous.file_size,
+        duration_ms: previous.duration_ms,
+        storage_key,
+        sha256: previous.sha256.clone(),
+        compression: previous.compression.clone(),
+        stored_size: previous.stored_size,
+        replaced_at,
+        sample_rate: previous.sample_rate,
+        channels: previous.channels,
+        bit_depth: previous.bit_depth,
+    })
+    .await
+}
+
+/// Deals with the content `file` was written over, returning the objects
+/// left unreferenced.
+pub async fn retire_replaced<C: BlobQueries>(
+    conn: &mut C,
+    previous: Option<File>,
+    file: &File,
+) -> Result<Vec<String>, C::Error> {
+    match previous {
+        // Uploading the same bytes again isn't worth a version
+        Some(previous) if previous.sha256.is_some() && previous.sha256 == file.sha256 => {
+            Ok(release_blob(conn, &previous).await?.into_iter().collect())
+        }
+        Some(previous) => {
+            archive_version(conn, &previous, file.file_upload_date).await?;
+            Ok(vec![])
+        }
+        None => Ok(vec![]),
+    }
+}
+
+/// Takes another reference on an object that is already stored.
+pub async fn add_reference<C: BlobQueries>(
+    conn: &mut C,
+    storage_key: &str,
+) -> Result<(), C::Error> {
+    conn.lock_blobs().await?;
+    conn.add_references(storage_key, 1).await?;
+    Ok(())
+}
+
+/// `current` with `restored`'s content, which it takes a reference on. The
+/// content it had is kept as a version in turn.
+pub async fn restore_version<C: BlobQueries>(
+    conn: &mut C,
+    current: File,
+    restored: FileVersion,
+    restored_at: i64,
+) -> Result<File, C::Error> {
+    // The version row keeps its own reference
+    add_reference(conn, &restored.storage_key).await?;
+    archive_version(conn, &current, restored_at).await?;
+    Ok(File {
+        file_type: restored.file_type,
+        file_upload_date: restored_at,
+        file_size: restored.file_size,
+        duration_ms: restored.duration_ms,
+        sample_rate: restored.sample_rate,
+        channels: restored.channels,
+        bit_depth: restored.bit_depth,
+        storage_key: Some(restored.storage_key),
+        sha256: restored.sha256,
+        compression: restored.compression,
+        stored_size: restored.stored_size,
+        ..current
+    })
+}
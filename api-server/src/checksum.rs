@@ -0,0 +1,81 @@
+use axum::http::HeaderMap;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use md5::{Digest, Md5};
+
+// Digests a client sends with an upload. They are checked against the bytes
+// as received, before any compression, so a transfer corrupted on the way in
+// never reaches the catalog.
+
+const CONTENT_MD5: &str = "content-md5";
+const CHECKSUM_SHA256: &str = "x-checksum-sha256";
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0} header is not a valid digest")]
+pub struct MalformedChecksum(&'static str);
+
+#[derive(Debug, thiserror::Error)]
+#[error("upload does not match its {0} header")]
+pub struct ChecksumMismatch(&'static str);
+
+#[derive(Debug, Clone, Default)]
+pub struct ExpectedChecksum {
+    /// From Content-MD5, base64 as in RFC 1864
+    md5: Option<Vec<u8>>,
+    /// From X-Checksum-SHA256, hex or base64
+    sha256: Option<Vec<u8>>,
+}
+
+fn decode(value: &str, len: usize) -> Option<Vec<u8>> {
+    let value = value.trim();
+    let decoded = if value.len() == len * 2 {
+        hex::decode(value).ok()?
+    } else {
+        BASE64_STANDARD.decode(value).ok()?
+    };
+    (decoded.len() == len).then_some(decoded)
+}
+
+fn header_digest(
+    headers: &HeaderMap,
+    name: &'static str,
+    len: usize,
+) -> Result<Option<Vec<u8>>, MalformedChecksum> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| decode(value, len))
+        .map(Some)
+        .ok_or(MalformedChecksum(name))
+}
+
+impl ExpectedChecksum {
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, MalformedChecksum> {
+        Ok(ExpectedChecksum {
+            md5: header_digest(headers, CONTENT_MD5, 16)?,
+            sha256: header_digest(headers, CHECKSUM_SHA256, 32)?,
+        })
+    }
+
+    /// An MD5 hasher, if there is an MD5 digest to check; SHA-256 is always
+    /// computed for dedup anyway.
+    pub fn md5_hasher(&self) -> Option<Md5> {
+        self.md5.as_ref().map(|_| Md5::new())
+    }
+
+    pub fn verify(&self, md5: Option<Md5>, sha256: &[u8]) -> Result<(), ChecksumMismatch> {
+        if let (Some(expected), Some(md5)) = (&self.md5, md5) {
+            if md5.finalize().as_slice() != expected.as_slice() {
+                return Err(ChecksumMismatch(CONTENT_MD5));
+            }
+        }
+        match &self.sha256 {
+            Some(expected) if expected.as_slice() != sha256 => {
+                Err(ChecksumMismatch(CHECKSUM_SHA256))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::storage::ByteStream;
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use futures::stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+// Optional compression of stored bytes. Rows record which codec their object
+// was written with, so changing the setting never breaks existing files.
+
+/// Codec for new uploads, from AUDIO_COMPRESSION (`none` or `zstd`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+const ZSTD: &str = "zstd";
+
+// Formats that are already compressed and gain next to nothing from zstd
+const PRECOMPRESSED_EXTENSIONS: &[&str] = &[
+    "aac", "flac", "m4a", "mp3", "mp4", "oga", "ogg", "opus", "webm", "wma",
+];
+const PRECOMPRESSED_TYPES: &[&str] = &[
+    "aac", "flac", "m4a", "mp3", "mp4", "mpeg", "ogg", "opus", "webm", "wma",
+];
+
+impl Compression {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        match std::env::var("AUDIO_COMPRESSION").as_deref() {
+            Err(_) | Ok("none") => Ok(Compression::None),
+            Ok(ZSTD) => Ok(Compression::Zstd),
+            Ok(other) => anyhow::bail!("unknown AUDIO_COMPRESSION {:?}", other),
+        }
+    }
+
+    /// The codec to record for an upload, or `None` if it should be stored as is.
+    pub fn codec_for(self, file_name: &str, file_type: Option<&str>) -> Option<&'static str> {
+        if self == Compression::None {
+            return None;
+        }
+        let extension = std::path::Path::new(file_name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if extension.is_some_and(|extension| PRECOMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+        {
+            return None;
+        }
+        let file_type = file_type.unwrap_or_default().to_lowercase();
+        if PRECOMPRESSED_TYPES
+            .iter()
+            .any(|precompressed| file_type.contains(precompressed))
+        {
+            return None;
+        }
+        Some(ZSTD)
+    }
+}
+
+pub fn compress(stream: ByteStream<'_>) -> ByteStream<'_> {
+    ReaderStream::new(ZstdEncoder::new(StreamReader::new(stream))).boxed()
+}
+
+/// Undoes `codec` on a stored object's bytes; `None` passes them through.
+pub fn decompress(codec: Option<&str>, stream: ByteStream<'static>) -> ByteStream<'static> {
+    match codec {
+        Some(ZSTD) => ReaderStream::new(ZstdDecoder::new(StreamReader::new(stream))).boxed(),
+        _ => stream,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn already_compressed_formats_are_stored_as_is() {
+        let zstd = Compression::Zstd;
+        assert_eq!(zstd.codec_for("a.wav", Some("audio/wav")), Some(ZSTD));
+        assert_eq!(zstd.codec_for("a.mp3", None), None);
+        assert_eq!(zstd.codec_for("a.OGG", None), None);
+        assert_eq!(zstd.codec_for("a", Some("audio/mpeg")), None);
+        assert_eq!(zstd.codec_for("a", Some("audio/ogg")), None);
+        assert_eq!(Compression::None.codec_for("a.wav", None), None);
+    }
+}
@@ -1,76 +1,2098 @@
-use crate::schema::files;
+use crate::blobs::{
+    acquire_blob, add_reference, adopt_legacy_object, release_file, restore_version,
+    retire_replaced, BlobQueries,
+};
+use crate::repository::FileRepository;
+use crate::schema::{
+    audit_log, blobs, consumed_signatures, file_metadata, file_tags, file_versions, files,
+    idempotency_keys, review_session_files, review_sessions, storage_usage, tags, upload_chunks,
+    upload_sessions, waveform_peaks,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
+use diesel::sqlite::{Sqlite, SqliteConnection};
+use diesel::{ConnectionError, ConnectionResult};
+use diesel_async::pooled_connection::deadpool::{Object, Pool};
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::sync_connection_wrapper::SyncConnectionWrapper;
+use diesel_async::{AsyncConnection, RunQueryDsl, SimpleAsyncConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dotenvy::dotenv;
+use futures::future::{BoxFuture, FutureExt};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 
-#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Queryable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(table_name = files)]
-#[diesel(treat_none_as_default_value = false)]
+#[diesel(treat_none_as_default_value = false, treat_none_as_null = true)]
 pub struct File {
     pub file_name: String,
     pub file_type: Option<String>,
-    pub file_upload_date: i32,
+    #[serde(with = "crate::timestamp")]
+    pub file_upload_date: i64,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub language: Option<String>,
+    pub file_size: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub starred: bool,
+    pub download_count: i64,
+    pub last_accessed_at: Option<i64>,
+    /// Where the bytes live in storage; rows from before sharding are stored
+    /// under their file name.
+    #[serde(skip)]
+    pub storage_key: Option<String>,
+    /// Hex encoded SHA-256 of the uploaded bytes, before any compression
+    pub sha256: Option<String>,
+    /// Codec the stored object is compressed with, if any
+    pub compression: Option<String>,
+    /// Bytes the object takes up in storage; `file_size` is the original size
+    pub stored_size: Option<i64>,
+    /// When the retention sweep deletes the file, overriding any per-type TTL
+    pub expires_at: Option<i64>,
+    /// Stable UUID the file keeps through renames and overwrites, unlike its
+    /// name, which is only unique among its owner's files
+    pub id: String,
+    /// Counts edits to the file's details, so clients can make sure they
+    /// aren't overwriting a change they haven't seen
+    pub revision: i32,
+    /// The user who uploaded it; see [`crate::tenancy`]
+    pub owner: Option<String>,
+    /// In Hz, as the container states it
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+    /// Bits per sample; lossy codecs have none
+    pub bit_depth: Option<i32>,
 }
 
-// Right now all of these functions block async threads because diesel predates tokio
-// Todo: use tokio_diesel
+/// A random UUID for a new file row.
+pub fn new_file_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Whether `value` has the shape of a file id.
+pub fn is_file_id(value: &str) -> bool {
+    uuid::Uuid::parse_str(value).is_ok()
+}
+
+impl File {
+    /// Bytes the file's object takes up in storage. Rows from before
+    /// compression only know their original size, which is the same thing.
+    pub fn stored_bytes(&self) -> i64 {
+        self.stored_size.or(self.file_size).unwrap_or(0)
+    }
+}
+
+/// Content an overwrite replaced, kept until the file itself is deleted.
+#[derive(Queryable, Insertable, Serialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = file_versions)]
+pub struct FileVersion {
+    pub file_id: String,
+    /// Numbered from 1 per file, oldest first
+    pub version: i32,
+    pub file_type: Option<String>,
+    /// When this content was uploaded
+    #[serde(serialize_with = "crate::timestamp::serialize")]
+    pub file_upload_date: i64,
+    pub file_size: Option<i64>,
+    pub duration_ms: Option<i64>,
+    #[serde(skip)]
+    pub storage_key: String,
+    pub sha256: Option<String>,
+    pub compression: Option<String>,
+    pub stored_size: Option<i64>,
+    #[serde(serialize_with = "crate::timestamp::serialize")]
+    pub replaced_at: i64,
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+    pub bit_depth: Option<i32>,
+}
+
+/// Stored bytes per file type. Rows sharing an object each count it.
+#[derive(Queryable, Serialize, Debug, Clone, PartialEq)]
+pub struct TypeUsage {
+    pub file_type: Option<String>,
+    pub files: i64,
+    pub bytes: i64,
+}
+
+/// What writing or removing a file row did to the stored objects. Objects
+/// are shared by every row with the same content and reference counted, so
+/// callers only delete the ones listed in `unreferenced`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BlobChanges {
+    /// The key the row now points at. Differs from the one it was written
+    /// with when identical bytes were already stored.
+    pub storage_key: Option<String>,
+    /// How the object the row points at is stored, which follows the key
+    pub compression: Option<String>,
+    pub stored_size: Option<i64>,
+    /// The row's id and revision, which an overwrite carries on from the
+    /// file it replaced
+    pub id: Option<String>,
+    pub revision: Option<i32>,
+    /// Objects nothing points at any more
+    pub unreferenced: Vec<String>,
+}
+
+impl BlobChanges {
+    /// What inserting `file` did, once it holds its object's reference.
+    pub fn inserted(file: File) -> Self {
+        BlobChanges {
+            storage_key: file.storage_key,
+            compression: file.compression,
+            stored_size: file.stored_size,
+            id: Some(file.id),
+            revision: Some(file.revision),
+            unreferenced: vec![],
+        }
+    }
+}
+
+/// Rows a batched insert writes per statement. SQLite takes at most 32766
+/// bound values in one and PostgreSQL 65535, so this stays well clear of
+/// both.
+pub const INSERT_BATCH_ROWS: usize = 500;
+
+/// Rows of one batch that share an object take how it is stored from the
+/// first of them. Each row looks the object up before any of them is
+/// written, so later ones can't find it in the table as they would inserted
+/// one at a time.
+pub fn align_shared_objects(rows: &mut [File]) {
+    let mut first = BTreeMap::new();
+    for row in rows {
+        let Some(key) = &row.storage_key else {
+            continue;
+        };
+        match first.get(key) {
+            Some((compression, stored_size)) => {
+                row.compression = Option::clone(compression);
+                row.stored_size = *stored_size;
+            }
+            None => {
+                first.insert(key.clone(), (row.compression.clone(), row.stored_size));
+            }
+        }
+    }
+}
+
+/// A row of a full-text search.
+#[derive(QueryableByName, Debug)]
+pub struct SearchMatch {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub file_id: String,
+}
+
+/// Details a PATCH can change; fields left out keep their value.
+#[derive(AsChangeset, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = files)]
+pub struct FileDetails {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub language: Option<String>,
+}
+
+/// An upload's Idempotency-Key, reserved before the upload starts and
+/// holding its response once it is done.
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = idempotency_keys)]
+pub struct IdempotencyKey {
+    /// The caller's user, or '' for callers without one
+    pub owner: String,
+    pub idempotency_key: String,
+    /// `None` while the upload is still running
+    pub response: Option<String>,
+    pub created_at: i64,
+}
+
+/// Peaks computed for some content at some resolution, kept as the JSON
+/// they are served as.
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = waveform_peaks)]
+pub struct WaveformPeaks {
+    pub sha256: String,
+    pub resolution: i32,
+    pub peaks: String,
+}
+
+#[derive(Queryable, Insertable, Serialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = review_sessions)]
+pub struct ReviewSession {
+    pub token: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Queryable, Insertable, Serialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = upload_sessions)]
+pub struct UploadSession {
+    pub id: String,
+    pub file_name: String,
+    pub file_type: Option<String>,
+    pub total_size: i64,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+/// A received byte range of an upload session.
+#[derive(Queryable, Insertable, Serialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = upload_chunks)]
+pub struct UploadChunk {
+    #[serde(skip)]
+    pub session_id: String,
+    pub byte_offset: i64,
+    pub byte_length: i64,
+}
+
+impl UploadChunk {
+    pub fn end(&self) -> i64 {
+        self.byte_offset + self.byte_length
+    }
+
+    pub fn overlaps(&self, other: &UploadChunk) -> bool {
+        self.byte_offset < other.end() && other.byte_offset < self.end()
+    }
+}
+
+/// A recorded change: the request that asked for it, or for changes made
+/// without one, the part of the server that made it.
+#[derive(Queryable, Serialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: i64,
+    #[serde(serialize_with = "crate::timestamp::serialize")]
+    pub occurred_at: i64,
+    /// Who made the change, as far as the server knows
+    pub actor: Option<String>,
+    pub client_ip: Option<String>,
+    /// The X-Forwarded-For header as sent, which clients can forge
+    pub forwarded_for: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// The response's status; none for changes made without a request
+    pub status: Option<i32>,
+    /// What was asked to change, as JSON
+    #[serde(serialize_with = "serialize_json_text")]
+    pub changes: Option<String>,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditEntry {
+    pub occurred_at: i64,
+    pub actor: Option<String>,
+    pub client_ip: Option<String>,
+    pub forwarded_for: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: Option<i32>,
+    pub changes: Option<String>,
+}
+
+// Stored as text, but part of the entry's JSON rather than a string in it
+fn serialize_json_text<S: serde::Serializer>(
+    text: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let value = text
+        .as_deref()
+        .and_then(|text| serde_json::from_str::<serde_json::Value>(text).ok());
+    value.serialize(serializer)
+}
+
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+/// Which audit entries to list, newest first.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    /// Part of the path, such as a file's id or name
+    pub path: Option<String>,
+    #[serde(default, deserialize_with = "crate::timestamp::deserialize_some")]
+    pub since: Option<i64>,
+    #[serde(default, deserialize_with = "crate::timestamp::deserialize_some")]
+    pub until: Option<i64>,
+    /// Only entries older than this id, to page through the log
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl AuditQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_AUDIT_LIMIT)
+            .clamp(1, MAX_AUDIT_LIMIT)
+    }
+
+    /// [`path`](Self::path) as a LIKE pattern escaped with `\`.
+    pub fn path_pattern(&self) -> Option<String> {
+        let path = self.path.as_ref()?;
+        let mut pattern = String::from("%");
+        for c in path.chars() {
+            if matches!(c, '%' | '_' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('%');
+        Some(pattern)
+    }
+}
 
-pub fn establish_connection() -> SqliteConnection {
+/// What a maintenance run did to the database's size.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct MaintenanceReport {
+    pub size_before: i64,
+    pub size_after: i64,
+    pub reclaimed_bytes: i64,
+    /// Write-ahead log frames copied into the database file, where the
+    /// database keeps a log the server can checkpoint
+    pub checkpointed_frames: Option<i64>,
+}
+
+#[derive(QueryableByName, Debug)]
+pub struct DatabaseSize {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub bytes: i64,
+}
+
+#[derive(QueryableByName, Debug)]
+struct WalCheckpoint {
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    busy: i32,
+    /// -1 when the database isn't in WAL mode
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    checkpointed: i32,
+}
+
+/// The index keeping each owner's file names unique.
+const FILE_NAME_INDEX: &str = "files_owner_file_name";
+
+/// Failures the handlers need to tell apart; everything else is `Other`.
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    #[error("record not found")]
+    NotFound,
+    #[error("conflicting record: {0}")]
+    Conflict(String),
+    #[error("a record with this {field} already exists")]
+    Duplicate { field: String },
+    #[error("record changed since it was read")]
+    Stale,
+    #[error("database is busy")]
+    Busy,
+    #[error("database is corrupt: {0}")]
+    Corrupt(String),
+    #[error("no database connection available: {0}")]
+    Unavailable(#[from] diesel_async::pooled_connection::deadpool::PoolError),
+    #[error(transparent)]
+    Other(diesel::result::Error),
+}
+
+/// The column, or comma separated columns, a unique violation is about. SQLite
+/// names them in the message ("UNIQUE constraint failed: files.id"),
+/// PostgreSQL in the detail ("Key (id)=(...) already exists.").
+pub fn conflicting_field(message: &str, details: Option<&str>) -> String {
+    // Names are unique per owner through an index over an expression, which
+    // both report by the index's name rather than by column
+    if message.contains(FILE_NAME_INDEX) {
+        return "file_name".to_owned();
+    }
+    if let Some(columns) = message.strip_prefix("UNIQUE constraint failed: ") {
+        return columns
+            .split(", ")
+            .map(|column| column.rsplit('.').next().unwrap_or(column))
+            .collect::<Vec<_>>()
+            .join(", ");
+    }
+    details
+        .and_then(|details| details.strip_prefix("Key ("))
+        .and_then(|details| details.split_once(")="))
+        .map(|(columns, _)| columns.to_owned())
+        .unwrap_or_else(|| "record".to_owned())
+}
+
+impl From<diesel::result::Error> for DbError {
+    fn from(e: diesel::result::Error) -> Self {
+        use diesel::result::{DatabaseErrorKind, Error};
+        match e {
+            Error::NotFound => DbError::NotFound,
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => DbError::Duplicate {
+                field: conflicting_field(info.message(), info.details()),
+            },
+            Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => DbError::NotFound,
+            // SQLite only reports these through the error message
+            Error::DatabaseError(_, ref info) => {
+                let message = info.message();
+                if message.contains("database is locked") || message.contains("database is busy") {
+                    DbError::Busy
+                } else if message.contains("malformed") || message.contains("not a database") {
+                    DbError::Corrupt(message.to_owned())
+                } else {
+                    DbError::Other(e)
+                }
+            }
+            e => DbError::Other(e),
+        }
+    }
+}
+
+/// Diesel's SQLite connection behind diesel-async's interface. SQLite has no
+/// async driver, so the wrapper runs each query on tokio's blocking threads.
+pub type AsyncSqliteConnection = SyncConnectionWrapper<SqliteConnection>;
+pub type SqlitePool = Pool<AsyncSqliteConnection>;
+
+// How long a connection waits on another's write lock before giving up with
+// "database is locked"
+pub const BUSY_TIMEOUT_MS: u32 = 5000;
+
+// Applied to every connection. In WAL mode readers don't wait on a writer,
+// and NORMAL only syncs at checkpoints, which WAL keeps crash safe. SQLite
+// leaves foreign keys unenforced unless asked, per connection.
+fn connection_pragmas() -> String {
+    format!(
+        "PRAGMA journal_mode = WAL; \
+         PRAGMA synchronous = NORMAL; \
+         PRAGMA busy_timeout = {}; \
+         PRAGMA foreign_keys = ON;",
+        BUSY_TIMEOUT_MS
+    )
+}
+
+// Readers may be opened on a replica another process keeps up to date, so
+// they set nothing that writes, and refuse to write themselves
+fn read_connection_pragmas() -> String {
+    format!(
+        "PRAGMA busy_timeout = {}; \
+         PRAGMA query_only = ON;",
+        BUSY_TIMEOUT_MS
+    )
+}
+
+fn setup_connection(database_url: &str) -> BoxFuture<'_, ConnectionResult<AsyncSqliteConnection>> {
+    configure_connection(database_url, connection_pragmas())
+}
+
+fn setup_read_connection(
+    database_url: &str,
+) -> BoxFuture<'_, ConnectionResult<AsyncSqliteConnection>> {
+    configure_connection(database_url, read_connection_pragmas())
+}
+
+fn configure_connection(
+    database_url: &str,
+    pragmas: String,
+) -> BoxFuture<'_, ConnectionResult<AsyncSqliteConnection>> {
+    let database_url = database_url.to_owned();
+    async move {
+        let mut conn = AsyncSqliteConnection::establish(&database_url).await?;
+        conn.batch_execute(&pragmas)
+            .await
+            .map_err(ConnectionError::CouldntSetupConfiguration)?;
+        Ok(conn)
+    }
+    .boxed()
+}
+
+// The schema lives and dies with the connection, so it is applied here
+// rather than by run_migrations
+fn setup_memory_connection(
+    database_url: &str,
+) -> BoxFuture<'_, ConnectionResult<AsyncSqliteConnection>> {
+    async move {
+        let mut conn = setup_connection(database_url).await?;
+        conn.spawn_blocking(|conn| {
+            conn.run_pending_migrations(MIGRATIONS)
+                .map(|_| ())
+                .map_err(diesel::result::Error::QueryBuilderError)
+        })
+        .await
+        .map_err(ConnectionError::CouldntSetupConfiguration)?;
+        Ok(conn)
+    }
+    .boxed()
+}
+
+pub fn database_url() -> String {
     dotenv().ok();
+    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+}
+
+/// `:memory:` keeps the whole database in memory, for development and
+/// tests. Every SQLite connection to it gets a database of its own, so the
+/// pool holds just one and nothing outside the pool can see the data.
+pub fn is_memory_url(database_url: &str) -> bool {
+    database_url == ":memory:"
+}
+
+pub fn is_postgres_url(database_url: &str) -> bool {
+    database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")
+}
+
+/// How patiently to connect: the database may not be reachable yet when the
+/// server starts, e.g. while its volume is still being mounted or its
+/// container is starting. The wait doubles after each failed attempt.
+#[derive(Debug, Clone)]
+pub struct ConnectRetry {
+    /// Attempts after the first, DATABASE_CONNECT_RETRIES
+    pub retries: u32,
+    /// Wait before the first retry, DATABASE_CONNECT_BACKOFF_MS
+    pub initial_backoff: Duration,
+}
+
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(10);
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        ConnectRetry {
+            retries: 7,
+            initial_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl ConnectRetry {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let mut retry = ConnectRetry::default();
+        if let Ok(retries) = env::var("DATABASE_CONNECT_RETRIES") {
+            retry.retries = retries
+                .parse()
+                .context("DATABASE_CONNECT_RETRIES must be a number of retries")?;
+        }
+        if let Ok(millis) = env::var("DATABASE_CONNECT_BACKOFF_MS") {
+            let millis = millis
+                .parse()
+                .context("DATABASE_CONNECT_BACKOFF_MS must be a number of milliseconds")?;
+            retry.initial_backoff = Duration::from_millis(millis);
+        }
+        Ok(retry)
+    }
+
+    /// Calls `connect` until it succeeds, sleeping between attempts. A
+    /// malformed URL fails at once, since waiting won't fix it. Blocks, so
+    /// it must not run on an async worker thread.
+    pub fn connect<C>(
+        &self,
+        mut connect: impl FnMut() -> ConnectionResult<C>,
+    ) -> Result<C, anyhow::Error> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            match connect() {
+                Ok(conn) => return Ok(conn),
+                Err(e) if attempt < self.retries && !is_bad_url(&e) => {
+                    attempt += 1;
+                    eprintln!(
+                        "database not reachable ({}), retry {} of {} in {:?}",
+                        e.to_string().trim(),
+                        attempt,
+                        self.retries,
+                        backoff
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::new(e).context(format!(
+                        "connecting to the database failed after {} attempts",
+                        attempt + 1
+                    )))
+                }
+            }
+        }
+    }
+}
+
+// diesel-async reports a refused PostgreSQL connection as a failure to
+// configure it, so everything else counts as not reachable yet
+fn is_bad_url(e: &ConnectionError) -> bool {
+    matches!(
+        e,
+        ConnectionError::InvalidConnectionUrl(_) | ConnectionError::InvalidCString(_)
+    )
+}
+
+/// A single blocking connection, for work outside the repository such as
+/// backups.
+pub fn establish_connection(database_url: &str) -> Result<SqliteConnection, anyhow::Error> {
+    let mut conn =
+        ConnectRetry::from_env()?.connect(|| SqliteConnection::establish(database_url))?;
+    conn.batch_execute(&connection_pragmas())
+        .with_context(|| format!("configuring {}", database_url))?;
+    Ok(conn)
+}
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Applies the migrations the database hasn't had yet, returning how many.
+/// Waits for the database as [`ConnectRetry`] says, since this is the first
+/// connection the server makes.
+pub fn run_migrations(database_url: &str) -> Result<usize, anyhow::Error> {
+    let mut conn =
+        ConnectRetry::from_env()?.connect(|| SqliteConnection::establish(database_url))?;
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(anyhow::Error::msg)?;
+    Ok(applied.len())
+}
+
+/// Connections for [`SqliteRepository`], at most DATABASE_POOL_SIZE of them.
+/// An in-memory database is a single connection that migrates itself.
+pub fn establish_pool(database_url: &str) -> Result<SqlitePool, anyhow::Error> {
+    let memory = is_memory_url(database_url);
+    let mut config = ManagerConfig::default();
+    config.custom_setup = if memory {
+        Box::new(setup_memory_connection)
+    } else {
+        Box::new(setup_connection)
+    };
+    build_pool(database_url, config, memory)
+}
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    SqliteConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+/// Read-only connections for [`SqliteRepository::with_read_pool`], as many
+/// as the main pool holds.
+pub fn establish_read_pool(database_url: &str) -> Result<SqlitePool, anyhow::Error> {
+    let mut config = ManagerConfig::default();
+    config.custom_setup = Box::new(setup_read_connection);
+    build_pool(database_url, config, false)
 }
 
-pub async fn insert_file(
-    conn: Arc<Mutex<SqliteConnection>>,
-    file: &File,
-) -> Result<(), anyhow::Error> {
-    file.insert_into(files::table)
-        .execute(&mut *conn.lock().await)?;
+fn build_pool(
+    database_url: &str,
+    config: ManagerConfig<AsyncSqliteConnection>,
+    single: bool,
+) -> Result<SqlitePool, anyhow::Error> {
+    let manager = AsyncDieselConnectionManager::new_with_config(database_url, config);
+    let mut builder = Pool::builder(manager);
+    if single {
+        builder = builder.max_size(1);
+    } else if let Ok(size) = env::var("DATABASE_POOL_SIZE") {
+        let size = size
+            .parse()
+            .context("DATABASE_POOL_SIZE must be a number of connections")?;
+        builder = builder.max_size(size);
+    }
+    Ok(builder.build()?)
+}
+
+/// Diesel/SQLite implementation of [`FileRepository`].
+pub struct SqliteRepository {
+    pool: SqlitePool,
+    /// Serves listings, filters and searches when set
+    read_pool: Option<SqlitePool>,
+}
+
+impl SqliteRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteRepository {
+            pool,
+            read_pool: None,
+        }
+    }
+
+    /// Sends the queries [`FileRepository`] says may read a replica to
+    /// `read_pool`, so they don't hold up writes.
+    pub fn with_read_pool(self, read_pool: SqlitePool) -> Self {
+        SqliteRepository {
+            read_pool: Some(read_pool),
+            ..self
+        }
+    }
+
+    async fn conn(&self) -> Result<Object<AsyncSqliteConnection>, DbError> {
+        Ok(self.pool.get().await?)
+    }
+
+    async fn read_conn(&self) -> Result<Object<AsyncSqliteConnection>, DbError> {
+        Ok(self.read_pool.as_ref().unwrap_or(&self.pool).get().await?)
+    }
+}
+
+#[async_trait]
+impl BlobQueries for AsyncSqliteConnection {
+    type Error = diesel::result::Error;
+
+    async fn blob_with_sha256(&mut self, sha256: &str) -> QueryResult<Option<String>> {
+        blobs::table
+            .filter(blobs::sha256.eq(sha256))
+            .select(blobs::storage_key)
+            .first(self)
+            .await
+            .optional()
+    }
+
+    async fn stored_format(
+        &mut self,
+        storage_key: &str,
+    ) -> QueryResult<Option<(Option<String>, Option<i64>)>> {
+        files::table
+            .filter(files::storage_key.eq(storage_key))
+            .select((files::compression, files::stored_size))
+            .first(self)
+            .await
+            .optional()
+    }
+
+    async fn add_references(&mut self, storage_key: &str, delta: i64) -> QueryResult<bool> {
+        let updated = diesel::update(blobs::table.find(storage_key))
+            .set(blobs::ref_count.eq(blobs::ref_count + delta))
+            .execute(self)
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn insert_blob(
+        &mut self,
+        storage_key: &str,
+        sha256: Option<&str>,
+        stored_size: i64,
+    ) -> QueryResult<()> {
+        diesel::insert_into(blobs::table)
+            .values((
+                blobs::storage_key.eq(storage_key),
+                blobs::sha256.eq(sha256),
+                blobs::ref_count.eq(1),
+                blobs::stored_size.eq(stored_size),
+            ))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn unreferenced_size(&mut self, storage_key: &str) -> QueryResult<Option<i64>> {
+        blobs::table
+            .find(storage_key)
+            .filter(blobs::ref_count.le(0))
+            .select(blobs::stored_size)
+            .first(self)
+            .await
+            .optional()
+    }
+
+    async fn delete_blob(&mut self, storage_key: &str) -> QueryResult<()> {
+        diesel::delete(blobs::table.find(storage_key))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_storage_usage(&mut self, bytes: i64) -> QueryResult<()> {
+        diesel::update(storage_usage::table)
+            .set(storage_usage::used_bytes.eq(storage_usage::used_bytes + bytes))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn latest_version(&mut self, file_id: &str) -> QueryResult<Option<i32>> {
+        file_versions::table
+            .filter(file_versions::file_id.eq(file_id))
+            .select(diesel::dsl::max(file_versions::version))
+            .first(self)
+            .await
+    }
+
+    async fn insert_version(&mut self, version: &FileVersion) -> QueryResult<()> {
+        diesel::insert_into(file_versions::table)
+            .values(version)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+}
+
+// Replaces the file's custom metadata with `metadata`
+async fn replace_metadata(
+    conn: &mut AsyncSqliteConnection,
+    file_id: &str,
+    metadata: &BTreeMap<String, String>,
+) -> QueryResult<()> {
+    diesel::delete(file_metadata::table.filter(file_metadata::file_id.eq(file_id)))
+        .execute(conn)
+        .await?;
+    for (key, value) in metadata {
+        diesel::insert_into(file_metadata::table)
+            .values((
+                file_metadata::file_id.eq(file_id),
+                file_metadata::meta_key.eq(key),
+                file_metadata::meta_value.eq(value),
+            ))
+            .execute(conn)
+            .await?;
+    }
     Ok(())
 }
 
-pub async fn list_file_names(
-    conn: Arc<Mutex<SqliteConnection>>,
-) -> Result<Vec<String>, anyhow::Error> {
-    use super::schema::files::dsl::*;
-    Ok(files
-        .select(file_name)
-        .load::<String>(&mut *conn.lock().await)?)
-}
-
-pub async fn find_file_by_file_name(
-    conn: Arc<Mutex<SqliteConnection>>,
-    target: &str,
-) -> Result<Vec<File>, anyhow::Error> {
-    use super::schema::files::dsl::*;
-    Ok(files
-        .filter(file_name.eq(target))
-        .load::<File>(&mut *conn.lock().await)?)
-}
-
-pub async fn find_file_by_file_type(
-    conn: Arc<Mutex<SqliteConnection>>,
-    target: &str,
-) -> Result<Vec<File>, anyhow::Error> {
-    use super::schema::files::dsl::*;
-    Ok(files
-        .filter(file_type.eq(target))
-        .load::<File>(&mut *conn.lock().await)?)
-}
-
-pub async fn find_file_by_file_upload_date(
-    conn: Arc<Mutex<SqliteConnection>>,
-    target: &i32,
-) -> Result<Vec<File>, anyhow::Error> {
-    use super::schema::files::dsl::*;
-    Ok(files
-        .filter(file_upload_date.eq(target))
-        .load::<File>(&mut *conn.lock().await)?)
+// The filters' queries, kept apart so the tests can check their plans
+fn files_by_type(target: &str) -> files::BoxedQuery<'_, Sqlite> {
+    files::table
+        .filter(files::file_type.eq(target))
+        .into_boxed()
+}
+
+fn files_by_upload_date(target: i64) -> files::BoxedQuery<'static, Sqlite> {
+    files::table
+        .filter(files::file_upload_date.eq(target))
+        .into_boxed()
+}
+
+// Write transactions take the write lock up front, so they wait on
+// busy_timeout where upgrading a read transaction would fail at once with
+// SQLITE_BUSY
+#[async_trait]
+impl FileRepository for SqliteRepository {
+    async fn insert_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError> {
+        let mut file = file.clone();
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    acquire_blob(conn, &mut file).await?;
+                    diesel::insert_into(files::table)
+                        .values(&file)
+                        .execute(conn)
+                        .await?;
+                    replace_metadata(conn, &file.id, metadata).await?;
+                    Ok(BlobChanges {
+                        storage_key: file.storage_key,
+                        compression: file.compression,
+                        stored_size: file.stored_size,
+                        id: Some(file.id),
+                        revision: Some(file.revision),
+                        unreferenced: vec![],
+                    })
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    // The rows go in as multi-row INSERTs, which diesel only runs on its
+    // blocking connection
+    async fn insert_files(
+        &self,
+        files: &[(File, BTreeMap<String, String>)],
+    ) -> Result<Vec<BlobChanges>, DbError> {
+        let mut rows = files
+            .iter()
+            .map(|(file, _)| file.clone())
+            .collect::<Vec<_>>();
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    for row in &mut rows {
+                        acquire_blob(conn, row).await?;
+                    }
+                    align_shared_objects(&mut rows);
+                    let inserted = rows.clone();
+                    conn.spawn_blocking(move |conn| {
+                        for batch in inserted.chunks(INSERT_BATCH_ROWS) {
+                            diesel::RunQueryDsl::execute(
+                                diesel::insert_into(files::table).values(batch),
+                                conn,
+                            )?;
+                        }
+                        Ok(())
+                    })
+                    .await?;
+                    for (file, metadata) in files {
+                        replace_metadata(conn, &file.id, metadata).await?;
+                    }
+                    Ok(rows.into_iter().map(BlobChanges::inserted).collect())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn replace_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError> {
+        let mut file = file.clone();
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let previous = files::table
+                        .filter(files::owner.is(file.owner.as_deref()))
+                        .filter(files::file_name.eq(&file.file_name))
+                        .first::<File>(conn)
+                        .await
+                        .optional()?;
+                    if let Some(previous) = &previous {
+                        file.id = previous.id.clone();
+                        file.revision = previous.revision + 1;
+                    }
+                    acquire_blob(conn, &mut file).await?;
+                    // Updated in place rather than replaced, as a delete
+                    // would cascade to the file's tags, metadata and versions
+                    if previous.is_some() {
+                        diesel::update(files::table.find(&file.id))
+                            .set(&file)
+                            .execute(conn)
+                            .await?;
+                    } else {
+                        diesel::insert_into(files::table)
+                            .values(&file)
+                            .execute(conn)
+                            .await?;
+                    }
+                    replace_metadata(conn, &file.id, metadata).await?;
+                    let unreferenced = retire_replaced(conn, previous, &file).await?;
+                    Ok(BlobChanges {
+                        storage_key: file.storage_key,
+                        compression: file.compression,
+                        stored_size: file.stored_size,
+                        id: Some(file.id),
+                        revision: Some(file.revision),
+                        unreferenced,
+                    })
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn delete_file(&self, target: &str) -> Result<BlobChanges, DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let file = files::table
+                        .find(target)
+                        .first::<File>(conn)
+                        .await
+                        .optional()?
+                        .ok_or(DbError::NotFound)?;
+                    diesel::delete(file_tags::table.filter(file_tags::file_id.eq(target)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(file_metadata::table.filter(file_metadata::file_id.eq(target)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(
+                        review_session_files::table
+                            .filter(review_session_files::file_id.eq(target)),
+                    )
+                    .execute(conn)
+                    .await?;
+                    let versions = file_versions::table
+                        .filter(file_versions::file_id.eq(target))
+                        .select(file_versions::storage_key)
+                        .load::<String>(conn)
+                        .await?;
+                    diesel::delete(file_versions::table.filter(file_versions::file_id.eq(target)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(files::table.find(target))
+                        .execute(conn)
+                        .await?;
+                    let unreferenced = release_file(conn, &file, versions).await?;
+                    Ok(BlobChanges {
+                        unreferenced,
+                        ..Default::default()
+                    })
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn list_file_versions(&self, target: &str) -> Result<Vec<FileVersion>, DbError> {
+        use super::schema::file_versions::dsl::*;
+        Ok(file_versions
+            .filter(file_id.eq(target))
+            .order(version)
+            .load::<FileVersion>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn list_all_versions(&self) -> Result<Vec<FileVersion>, DbError> {
+        use super::schema::file_versions::dsl::*;
+        Ok(file_versions
+            .load::<FileVersion>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn find_file_version(
+        &self,
+        target: &str,
+        target_version: i32,
+    ) -> Result<Option<FileVersion>, DbError> {
+        Ok(file_versions::table
+            .find((target, target_version))
+            .first::<FileVersion>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn restore_file_version(
+        &self,
+        target: &str,
+        target_version: i32,
+        restored_at: i64,
+    ) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let current = files::table.find(target).first::<File>(conn).await?;
+                    let restored = file_versions::table
+                        .find((target, target_version))
+                        .first::<FileVersion>(conn)
+                        .await?;
+                    let file = restore_version(conn, current, restored, restored_at).await?;
+                    diesel::update(files::table.find(target))
+                        .set(&file)
+                        .execute(conn)
+                        .await?;
+                    Ok(file)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn copy_file(
+        &self,
+        source: &str,
+        destination: &str,
+        copied_at: i64,
+    ) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let file = files::table.find(source).first::<File>(conn).await?;
+                    let key = adopt_legacy_object(conn, &file).await?;
+                    if file.storage_key.is_none() {
+                        diesel::update(files::table.find(source))
+                            .set(files::storage_key.eq(&key))
+                            .execute(conn)
+                            .await?;
+                    }
+                    add_reference(conn, &key).await?;
+                    let copy = File {
+                        file_name: destination.to_owned(),
+                        file_upload_date: copied_at,
+                        starred: false,
+                        download_count: 0,
+                        last_accessed_at: None,
+                        storage_key: Some(key),
+                        id: new_file_id(),
+                        revision: 1,
+                        ..file
+                    };
+                    diesel::insert_into(files::table)
+                        .values(&copy)
+                        .execute(conn)
+                        .await?;
+                    let tags = file_tags::table
+                        .filter(file_tags::file_id.eq(source))
+                        .select(file_tags::tag_name)
+                        .load::<String>(conn)
+                        .await?;
+                    for tag in &tags {
+                        diesel::insert_into(file_tags::table)
+                            .values((file_tags::file_id.eq(&copy.id), file_tags::tag_name.eq(tag)))
+                            .execute(conn)
+                            .await?;
+                    }
+                    let metadata = file_metadata::table
+                        .filter(file_metadata::file_id.eq(source))
+                        .select((file_metadata::meta_key, file_metadata::meta_value))
+                        .load::<(String, String)>(conn)
+                        .await?;
+                    for (key, value) in &metadata {
+                        diesel::insert_into(file_metadata::table)
+                            .values((
+                                file_metadata::file_id.eq(&copy.id),
+                                file_metadata::meta_key.eq(key),
+                                file_metadata::meta_value.eq(value),
+                            ))
+                            .execute(conn)
+                            .await?;
+                    }
+                    Ok(copy)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn adopt_legacy_object(&self, id: &str) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let file = files::table.find(id).first::<File>(conn).await?;
+                    if file.storage_key.is_none() {
+                        let key = adopt_legacy_object(conn, &file).await?;
+                        diesel::update(files::table.find(id))
+                            .set(files::storage_key.eq(&key))
+                            .execute(conn)
+                            .await?;
+                    }
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn rename_file(&self, id: &str, destination: &str) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let file = files::table.find(id).first::<File>(conn).await?;
+                    // A legacy object stays where it is; only the row's name changes
+                    let key = adopt_legacy_object(conn, &file).await?;
+                    let renamed = File {
+                        file_name: destination.to_owned(),
+                        storage_key: Some(key),
+                        ..file
+                    };
+                    diesel::update(files::table.find(id))
+                        .set((
+                            files::file_name.eq(&renamed.file_name),
+                            files::storage_key.eq(&renamed.storage_key),
+                        ))
+                        .execute(conn)
+                        .await?;
+                    Ok(renamed)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn list_file_names(&self) -> Result<Vec<String>, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files
+            .select(file_name)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn list_all_files(&self) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files.load::<File>(&mut self.conn().await?).await?)
+    }
+
+    async fn find_files_by_ids(&self, targets: &[String]) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files
+            .filter(id.eq_any(targets))
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn count_files(&self) -> Result<i64, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files
+            .count()
+            .get_result(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn storage_usage(&self) -> Result<i64, DbError> {
+        Ok(storage_usage::table
+            .select(storage_usage::used_bytes)
+            .first(&mut self.conn().await?)
+            .await
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    async fn usage_by_file_type(&self) -> Result<Vec<TypeUsage>, DbError> {
+        use super::schema::files::dsl::*;
+        use diesel::dsl::{count_star, sql};
+        use diesel::sql_types::BigInt;
+        Ok(files
+            .group_by(file_type)
+            .select((
+                file_type,
+                count_star(),
+                sql::<BigInt>("COALESCE(SUM(COALESCE(stored_size, file_size)), 0)"),
+            ))
+            .order(file_type)
+            .load::<TypeUsage>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_file_name(&self, target: &str) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files
+            .filter(file_name.eq(target))
+            .load::<File>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_id(&self, target: &str) -> Result<Option<File>, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files
+            .filter(id.eq(target))
+            .first::<File>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn find_file_by_file_type(&self, target: &str) -> Result<Vec<File>, DbError> {
+        Ok(files_by_type(target)
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_file_upload_date(&self, target: &i64) -> Result<Vec<File>, DbError> {
+        Ok(files_by_upload_date(*target)
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_file_size_range(
+        &self,
+        min_size: Option<i64>,
+        max_size: Option<i64>,
+    ) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        let mut query = files.filter(file_size.is_not_null()).into_boxed();
+        if let Some(min_size) = min_size {
+            query = query.filter(file_size.ge(min_size));
+        }
+        if let Some(max_size) = max_size {
+            query = query.filter(file_size.le(max_size));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_duration_range(
+        &self,
+        min_duration_ms: Option<i64>,
+        max_duration_ms: Option<i64>,
+    ) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        let mut query = files.filter(duration_ms.is_not_null()).into_boxed();
+        if let Some(min_duration_ms) = min_duration_ms {
+            query = query.filter(duration_ms.ge(min_duration_ms));
+        }
+        if let Some(max_duration_ms) = max_duration_ms {
+            query = query.filter(duration_ms.le(max_duration_ms));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_audio_format(
+        &self,
+        target_sample_rate: Option<i32>,
+        target_channels: Option<i32>,
+        target_bit_depth: Option<i32>,
+    ) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        let mut query = files.into_boxed();
+        if let Some(target) = target_sample_rate {
+            query = query.filter(sample_rate.eq(target));
+        }
+        if let Some(target) = target_channels {
+            query = query.filter(channels.eq(target));
+        }
+        if let Some(target) = target_bit_depth {
+            query = query.filter(bit_depth.eq(target));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_starred(&self, target: bool) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files
+            .filter(starred.eq(target))
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_owner(&self, target: Option<&str>) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        let query = match target {
+            Some(target) => files.filter(owner.eq(target)).order(file_name).into_boxed(),
+            None => files.filter(owner.is_null()).order(file_name).into_boxed(),
+        };
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_last_access_before(&self, cutoff: i64) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        Ok(files
+            .filter(
+                last_accessed_at
+                    .lt(cutoff)
+                    .or(last_accessed_at.is_null().and(file_upload_date.lt(cutoff))),
+            )
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_expired_files(
+        &self,
+        now: i64,
+        file_type_ttls: &BTreeMap<String, i64>,
+    ) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        let mut query = files.filter(expires_at.le(now)).into_boxed();
+        for (target, ttl) in file_type_ttls {
+            query = query.or_filter(
+                expires_at
+                    .is_null()
+                    .and(file_type.eq(target))
+                    .and(file_upload_date.le(now.saturating_sub(*ttl))),
+            );
+        }
+        Ok(query.load::<File>(&mut self.conn().await?).await?)
+    }
+
+    async fn update_file_details(
+        &self,
+        target: &str,
+        revision: i32,
+        details: &FileDetails,
+        metadata: Option<&BTreeMap<String, String>>,
+    ) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let updated = diesel::update(
+                        files::table
+                            .find(target)
+                            .filter(files::revision.eq(revision)),
+                    )
+                    .set((details, files::revision.eq(files::revision + 1)))
+                    .execute(conn)
+                    .await?;
+                    if updated == 0 {
+                        // Missing altogether is NotFound rather than stale
+                        files::table.find(target).first::<File>(conn).await?;
+                        return Err(DbError::Stale);
+                    }
+                    if let Some(metadata) = metadata {
+                        replace_metadata(conn, target, metadata).await?;
+                    }
+                    Ok(files::table.find(target).first::<File>(conn).await?)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn toggle_starred(&self, target: &str) -> Result<bool, DbError> {
+        use super::schema::files::dsl::*;
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let updated = diesel::update(files.find(target))
+                        .set(starred.eq(diesel::dsl::not(starred)))
+                        .execute(conn)
+                        .await?;
+                    if updated == 0 {
+                        return Err(DbError::NotFound);
+                    }
+                    Ok(files
+                        .find(target)
+                        .select(starred)
+                        .first::<bool>(conn)
+                        .await?)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn record_download(&self, target: &str, accessed_at: i64) -> Result<(), DbError> {
+        use super::schema::files::dsl::*;
+        diesel::update(files.find(target))
+            .set((
+                download_count.eq(download_count + 1),
+                last_accessed_at.eq(accessed_at),
+            ))
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn most_downloaded(&self, limit: Option<u32>) -> Result<Vec<File>, DbError> {
+        use super::schema::files::dsl::*;
+        let mut query = files.order((download_count.desc(), file_name)).into_boxed();
+        if let Some(limit) = limit {
+            query = query.limit(i64::from(limit));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn create_upload_session(&self, session: &UploadSession) -> Result<(), DbError> {
+        diesel::insert_into(upload_sessions::table)
+            .values(session)
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_upload_session(&self, id: &str) -> Result<Option<UploadSession>, DbError> {
+        Ok(upload_sessions::table
+            .find(id)
+            .first::<UploadSession>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn list_upload_chunks(&self, id: &str) -> Result<Vec<UploadChunk>, DbError> {
+        Ok(upload_chunks::table
+            .filter(upload_chunks::session_id.eq(id))
+            .order(upload_chunks::byte_offset)
+            .load::<UploadChunk>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn record_upload_chunk(&self, chunk: &UploadChunk) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    let overlapping = upload_chunks::table
+                        .filter(upload_chunks::session_id.eq(&chunk.session_id))
+                        .filter(upload_chunks::byte_offset.ne(chunk.byte_offset))
+                        .load::<UploadChunk>(conn)
+                        .await?
+                        .into_iter()
+                        .any(|received| received.overlaps(chunk));
+                    if overlapping {
+                        return Err(DbError::Conflict(
+                            "chunk overlaps one already received".to_owned(),
+                        ));
+                    }
+                    diesel::replace_into(upload_chunks::table)
+                        .values(chunk)
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn delete_upload_session(&self, id: &str) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    diesel::delete(upload_chunks::table.filter(upload_chunks::session_id.eq(id)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(upload_sessions::table.find(id))
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn find_expired_upload_sessions(&self, now: i64) -> Result<Vec<UploadSession>, DbError> {
+        Ok(upload_sessions::table
+            .filter(upload_sessions::expires_at.le(now))
+            .load::<UploadSession>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn find_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyKey>, DbError> {
+        Ok(idempotency_keys::table
+            .find((owner, key))
+            .first::<IdempotencyKey>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn insert_idempotency_key(&self, key: &IdempotencyKey) -> Result<(), DbError> {
+        key.insert_into(idempotency_keys::table)
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+        response: &str,
+    ) -> Result<(), DbError> {
+        let updated = diesel::update(idempotency_keys::table.find((owner, key)))
+            .set(idempotency_keys::response.eq(response))
+            .execute(&mut self.conn().await?)
+            .await?;
+        if updated == 0 {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn delete_idempotency_key(&self, owner: &str, key: &str) -> Result<(), DbError> {
+        diesel::delete(idempotency_keys::table.find((owner, key)))
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn consume_signature(
+        &self,
+        signature: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), DbError> {
+        let mut conn = self.conn().await?;
+        diesel::delete(consumed_signatures::table.filter(consumed_signatures::expires_at.lt(now)))
+            .execute(&mut conn)
+            .await?;
+        diesel::insert_into(consumed_signatures::table)
+            .values((
+                consumed_signatures::signature.eq(signature),
+                consumed_signatures::expires_at.eq(expires_at),
+            ))
+            .execute(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_signature(&self, signature: &str) -> Result<(), DbError> {
+        diesel::delete(consumed_signatures::table.find(signature))
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_waveform_peaks(
+        &self,
+        target_sha256: &str,
+        target_resolution: i32,
+    ) -> Result<Option<WaveformPeaks>, DbError> {
+        use super::schema::waveform_peaks::dsl::*;
+        Ok(waveform_peaks
+            .filter(sha256.eq(target_sha256))
+            .filter(resolution.eq(target_resolution))
+            .first::<WaveformPeaks>(&mut self.read_conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn insert_waveform_peaks(&self, peaks: &WaveformPeaks) -> Result<(), DbError> {
+        peaks
+            .insert_into(waveform_peaks::table)
+            .on_conflict_do_nothing()
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_file_tag(
+        &self,
+        target_file_id: &str,
+        target_tag_name: &str,
+    ) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    diesel::insert_or_ignore_into(tags::table)
+                        .values(tags::tag_name.eq(target_tag_name))
+                        .execute(conn)
+                        .await?;
+                    diesel::insert_or_ignore_into(file_tags::table)
+                        .values((
+                            file_tags::file_id.eq(target_file_id),
+                            file_tags::tag_name.eq(target_tag_name),
+                        ))
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn remove_file_tag(
+        &self,
+        target_file_id: &str,
+        target_tag_name: &str,
+    ) -> Result<bool, DbError> {
+        use super::schema::file_tags::dsl::*;
+        let deleted = diesel::delete(
+            file_tags
+                .filter(file_id.eq(target_file_id))
+                .filter(tag_name.eq(target_tag_name)),
+        )
+        .execute(&mut self.conn().await?)
+        .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn list_file_tags(&self, target: &str) -> Result<Vec<String>, DbError> {
+        use super::schema::file_tags::dsl::*;
+        Ok(file_tags
+            .filter(file_id.eq(target))
+            .select(tag_name)
+            .order(tag_name)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_ids_by_tag(&self, target: &str) -> Result<Vec<String>, DbError> {
+        use super::schema::file_tags::dsl::*;
+        Ok(file_tags
+            .filter(tag_name.eq(target))
+            .select(file_id)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn set_file_metadata(
+        &self,
+        target: &str,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move { Ok(replace_metadata(conn, target, metadata).await?) }.scope_boxed()
+            })
+            .await
+    }
+
+    async fn get_file_metadata(&self, target: &str) -> Result<BTreeMap<String, String>, DbError> {
+        use super::schema::file_metadata::dsl::*;
+        Ok(file_metadata
+            .filter(file_id.eq(target))
+            .select((meta_key, meta_value))
+            .load::<(String, String)>(&mut self.read_conn().await?)
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    async fn find_file_ids_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<String>, DbError> {
+        use super::schema::file_metadata::dsl::*;
+        Ok(file_metadata
+            .filter(meta_key.eq(key))
+            .filter(meta_value.eq(value))
+            .select(file_id)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn search_files(&self, terms: &[String]) -> Result<Vec<String>, DbError> {
+        // Each term quoted and matched as a prefix, all of them required
+        let query = terms
+            .iter()
+            .map(|term| format!("\"{}\"*", term))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let matches = diesel::sql_query(
+            "SELECT file_id FROM file_search WHERE file_search MATCH ? \
+             ORDER BY bm25(file_search, 0.0, 10.0, 5.0, 1.0), file_name",
+        )
+        .bind::<diesel::sql_types::Text, _>(query)
+        .load::<SearchMatch>(&mut self.read_conn().await?)
+        .await?;
+        Ok(matches.into_iter().map(|found| found.file_id).collect())
+    }
+
+    async fn create_review_session(
+        &self,
+        session: &ReviewSession,
+        file_ids: &[String],
+    ) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .immediate_transaction(|conn| {
+                async move {
+                    diesel::insert_into(review_sessions::table)
+                        .values(session)
+                        .execute(conn)
+                        .await?;
+                    for file_id in file_ids {
+                        diesel::insert_into(review_session_files::table)
+                            .values((
+                                review_session_files::token.eq(&session.token),
+                                review_session_files::file_id.eq(file_id),
+                            ))
+                            .execute(conn)
+                            .await?;
+                    }
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn find_review_session(&self, target: &str) -> Result<Option<ReviewSession>, DbError> {
+        use super::schema::review_sessions::dsl::*;
+        Ok(review_sessions
+            .filter(token.eq(target))
+            .first::<ReviewSession>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn list_review_session_files(&self, target: &str) -> Result<Vec<String>, DbError> {
+        use super::schema::review_session_files::dsl::*;
+        Ok(review_session_files
+            .filter(token.eq(target))
+            .select(file_id)
+            .load::<String>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn run_maintenance(&self) -> Result<MaintenanceReport, DbError> {
+        let size = "SELECT page_count * page_size AS bytes \
+                    FROM pragma_page_count(), pragma_page_size()";
+        let mut conn = self.conn().await?;
+        let size_before = diesel::sql_query(size)
+            .get_result::<DatabaseSize>(&mut conn)
+            .await?
+            .bytes;
+        // VACUUM rewrites the database through the log, so the checkpoint
+        // comes after it
+        conn.batch_execute("ANALYZE; VACUUM;").await?;
+        let checkpoint = diesel::sql_query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .get_result::<WalCheckpoint>(&mut conn)
+            .await?;
+        if checkpoint.busy != 0 {
+            eprintln!("maintenance: readers kept the write-ahead log from being truncated");
+        }
+        let size_after = diesel::sql_query(size)
+            .get_result::<DatabaseSize>(&mut conn)
+            .await?
+            .bytes;
+        Ok(MaintenanceReport {
+            size_before,
+            size_after,
+            reclaimed_bytes: size_before - size_after,
+            checkpointed_frames: (checkpoint.checkpointed >= 0)
+                .then_some(checkpoint.checkpointed as i64),
+        })
+    }
+
+    async fn record_audit(&self, entry: &NewAuditEntry) -> Result<(), DbError> {
+        diesel::insert_into(audit_log::table)
+            .values(entry)
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_audit_entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, DbError> {
+        let mut entries = audit_log::table.into_boxed();
+        if let Some(actor) = &query.actor {
+            entries = entries.filter(audit_log::actor.eq(actor));
+        }
+        if let Some(pattern) = query.path_pattern() {
+            entries = entries.filter(audit_log::path.like(pattern).escape('\\'));
+        }
+        if let Some(since) = query.since {
+            entries = entries.filter(audit_log::occurred_at.ge(since));
+        }
+        if let Some(until) = query.until {
+            entries = entries.filter(audit_log::occurred_at.lt(until));
+        }
+        if let Some(before) = query.before {
+            entries = entries.filter(audit_log::id.lt(before));
+        }
+        Ok(entries
+            .order(audit_log::id.desc())
+            .limit(query.limit())
+            .load::<AuditEntry>(&mut self.read_conn().await?)
+            .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::query_builder::QueryFragment;
+
+    #[derive(QueryableByName)]
+    struct PlanStep {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        detail: String,
+    }
+
+    // How SQLite would run `query` against a freshly migrated database
+    fn query_plan<Q: QueryFragment<Sqlite>>(query: Q) -> Vec<String> {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        conn.run_pending_migrations(MIGRATIONS).unwrap();
+        let sql = diesel::debug_query::<Sqlite, _>(&query).to_string();
+        // The binds are listed after the SQL; planning doesn't need them
+        let sql = sql.split(" -- binds:").next().unwrap();
+        let plan = diesel::sql_query(format!("EXPLAIN QUERY PLAN {}", sql));
+        // The blocking connection, not diesel-async's
+        diesel::RunQueryDsl::load::<PlanStep>(plan, &mut conn)
+            .unwrap()
+            .into_iter()
+            .map(|step| step.detail)
+            .collect()
+    }
+
+    #[test]
+    fn file_type_filter_uses_its_index() {
+        let plan = query_plan(files_by_type("audio/wav"));
+        assert!(
+            plan.iter()
+                .any(|step| step.contains("USING INDEX files_file_type")),
+            "{:?}",
+            plan
+        );
+    }
+
+    #[test]
+    fn upload_date_filter_uses_its_index() {
+        let plan = query_plan(files_by_upload_date(0));
+        assert!(
+            plan.iter()
+                .any(|step| step.contains("USING INDEX files_file_upload_date")),
+            "{:?}",
+            plan
+        );
+    }
+
+    #[tokio::test]
+    async fn listings_come_from_the_read_pool_which_cannot_write() {
+        let path = std::env::temp_dir().join(format!("read-pool-{}.db", new_file_id()));
+        let url = path.to_string_lossy().into_owned();
+        run_migrations(&url).unwrap();
+        let read_pool = establish_read_pool(&url).unwrap();
+        let db =
+            SqliteRepository::new(establish_pool(&url).unwrap()).with_read_pool(read_pool.clone());
+        let file = File {
+            file_name: "standup.wav".to_owned(),
+            file_type: None,
+            file_upload_date: 0,
+            title: None,
+            description: None,
+            language: None,
+            file_size: None,
+            duration_ms: None,
+            starred: false,
+            download_count: 0,
+            last_accessed_at: None,
+            storage_key: None,
+            sha256: None,
+            compression: None,
+            stored_size: None,
+            expires_at: None,
+            id: new_file_id(),
+            revision: 1,
+            owner: None,
+            sample_rate: None,
+            channels: None,
+            bit_depth: None,
+        };
+        db.insert_file(&file, &BTreeMap::new()).await.unwrap();
+        assert_eq!(db.list_file_names().await.unwrap(), ["standup.wav"]);
+        let write = diesel::delete(files::table)
+            .execute(&mut read_pool.get().await.unwrap())
+            .await;
+        assert!(write.is_err());
+        assert_eq!(db.count_files().await.unwrap(), 1);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", url, suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn names_the_field_a_unique_violation_is_about() {
+        assert_eq!(
+            conflicting_field("UNIQUE constraint failed: files.file_name", None),
+            "file_name"
+        );
+        assert_eq!(
+            conflicting_field(
+                "UNIQUE constraint failed: file_tags.file_name, file_tags.tag_id",
+                None
+            ),
+            "file_name, tag_id"
+        );
+        assert_eq!(
+            conflicting_field(
+                "duplicate key value violates unique constraint \"files_pkey\"",
+                Some("Key (id)=(abc) already exists.")
+            ),
+            "id"
+        );
+        assert_eq!(
+            conflicting_field(
+                "UNIQUE constraint failed: index 'files_owner_file_name'",
+                None
+            ),
+            "file_name"
+        );
+        assert_eq!(
+            conflicting_field(
+                "duplicate key value violates unique constraint \"files_owner_file_name\"",
+                Some("Key (COALESCE(owner, ''::text), file_name)=(, a.wav) already exists.")
+            ),
+            "file_name"
+        );
+    }
+
+    fn no_wait(retries: u32) -> ConnectRetry {
+        ConnectRetry {
+            retries,
+            initial_backoff: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn connect_retries_until_the_database_is_reachable() {
+        let mut attempts = 0;
+        let conn = no_wait(3).connect(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(ConnectionError::BadConnection("unable to open".into()))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(conn.unwrap(), 3);
+    }
+
+    #[test]
+    fn connect_gives_up_with_an_error() {
+        let mut attempts = 0;
+        let result = no_wait(2).connect(|| -> ConnectionResult<()> {
+            attempts += 1;
+            Err(ConnectionError::BadConnection("unable to open".into()))
+        });
+        assert_eq!(attempts, 3);
+        let message = format!("{:#}", result.unwrap_err());
+        assert!(message.contains("after 3 attempts"), "{}", message);
+        assert!(message.contains("unable to open"), "{}", message);
+    }
+
+    #[test]
+    fn connect_does_not_retry_a_malformed_url() {
+        let mut attempts = 0;
+        let result = no_wait(5).connect(|| -> ConnectionResult<()> {
+            attempts += 1;
+            Err(ConnectionError::InvalidConnectionUrl("nonsense".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn inserts_batches_larger_than_one_statement() {
+        let db = SqliteRepository::new(establish_pool(":memory:").unwrap());
+        let file = |file_name: String, sha256: &str| File {
+            file_name,
+            file_type: Some("audio/wav".to_owned()),
+            file_upload_date: 0,
+            title: None,
+            description: None,
+            language: None,
+            file_size: Some(10),
+            duration_ms: None,
+            starred: false,
+            download_count: 0,
+            last_accessed_at: None,
+            storage_key: Some(format!("objects/{}", sha256)),
+            sha256: Some(sha256.to_owned()),
+            compression: None,
+            stored_size: Some(10),
+            expires_at: None,
+            id: new_file_id(),
+            revision: 1,
+            owner: None,
+            sample_rate: None,
+            channels: None,
+            bit_depth: None,
+        };
+        let metadata = BTreeMap::from([("artist".to_owned(), "Ana".to_owned())]);
+        let rows: Vec<_> = (0..INSERT_BATCH_ROWS + 1)
+            .map(|i| {
+                let sha256 = if i < 2 {
+                    "shared".to_owned()
+                } else {
+                    i.to_string()
+                };
+                (file(format!("{}.wav", i), &sha256), metadata.clone())
+            })
+            .collect();
+        let changes = db.insert_files(&rows).await.unwrap();
+        assert_eq!(changes.len(), INSERT_BATCH_ROWS + 1);
+        // Rows in the same batch share content too
+        assert_eq!(changes[1].storage_key.as_deref(), Some("objects/shared"));
+        assert_eq!(
+            db.count_files().await.unwrap(),
+            INSERT_BATCH_ROWS as i64 + 1
+        );
+        assert_eq!(
+            db.storage_usage().await.unwrap(),
+            INSERT_BATCH_ROWS as i64 * 10
+        );
+        let last = &rows[INSERT_BATCH_ROWS].0.id;
+        assert_eq!(db.get_file_metadata(last).await.unwrap(), metadata);
+
+        // A conflict in a later statement leaves none of the batch behind
+        let mut clash: Vec<_> = (0..INSERT_BATCH_ROWS)
+            .map(|i| (file(format!("new-{}.wav", i), "new"), BTreeMap::new()))
+            .collect();
+        clash.push((file("0.wav".to_owned(), "other"), BTreeMap::new()));
+        let result = db.insert_files(&clash).await;
+        assert!(matches!(result, Err(DbError::Duplicate { .. })));
+        assert_eq!(
+            db.count_files().await.unwrap(),
+            INSERT_BATCH_ROWS as i64 + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn signatures_are_consumed_once_until_they_expire() {
+        let db = SqliteRepository::new(establish_pool(":memory:").unwrap());
+        db.consume_signature("a1", 100, 50).await.unwrap();
+        assert!(matches!(
+            db.consume_signature("a1", 100, 60).await,
+            Err(DbError::Duplicate { .. })
+        ));
+        db.release_signature("a1").await.unwrap();
+        db.consume_signature("a1", 100, 70).await.unwrap();
+        // Once past its expiry a signature is forgotten by the next use
+        db.consume_signature("b2", 300, 200).await.unwrap();
+        let remaining: Vec<String> = consumed_signatures::table
+            .select(consumed_signatures::signature)
+            .load(&mut db.conn().await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(remaining, ["b2"]);
+    }
 }
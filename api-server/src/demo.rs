@@ -0,0 +1,114 @@
+use crate::audio;
+use crate::db::{new_file_id, File};
+use crate::repository::Repository;
+use crate::storage::{SharedStorage, StorageLayout};
+use crate::timestamp;
+use anyhow::Context;
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// `--demo` keeps the metadata and the audio in memory and starts with a few
+// generated sample files, so the API can be tried without a database, an
+// audio root or any credentials. Everything is gone once the server stops.
+
+const DEFAULT_LATENCY: Duration = Duration::from_millis(150);
+const SAMPLE_RATE: u32 = 8000;
+
+// (file name, title, tone in Hz, seconds)
+const SAMPLES: &[(&str, &str, u32, u32)] = &[
+    ("concert-a.wav", "Concert A", 440, 2),
+    ("middle-c.wav", "Middle C", 262, 3),
+    ("low-e.wav", "Low E", 82, 1),
+];
+
+/// Delay added to every request, from DEMO_LATENCY_MS, so clients see
+/// something closer to a real deployment than loopback.
+pub fn latency_from_env() -> Result<Duration, anyhow::Error> {
+    let Ok(value) = std::env::var("DEMO_LATENCY_MS") else {
+        return Ok(DEFAULT_LATENCY);
+    };
+    let millis = value
+        .parse()
+        .context("DEMO_LATENCY_MS must be a number of milliseconds")?;
+    Ok(Duration::from_millis(millis))
+}
+
+pub async fn simulate_latency(
+    State(latency): State<Duration>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    tokio::time::sleep(latency).await;
+    next.run(request).await
+}
+
+/// 16-bit mono PCM. A frequency of 0 gives silence.
+pub fn sine_wav(frequency: u32, seconds: u32) -> Vec<u8> {
+    let samples = SAMPLE_RATE * seconds;
+    let data_len = samples * 2;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for n in 0..samples {
+        let t = n as f64 / SAMPLE_RATE as f64;
+        let sample = (t * frequency as f64 * std::f64::consts::TAU).sin() * i16::MAX as f64 / 4.0;
+        wav.extend_from_slice(&(sample as i16).to_le_bytes());
+    }
+    wav
+}
+
+/// Stores the sample files, returning how many were added.
+pub async fn seed(db: &Repository, storage: &SharedStorage) -> Result<usize, anyhow::Error> {
+    for (file_name, title, frequency, seconds) in SAMPLES {
+        let wav = sine_wav(*frequency, *seconds);
+        let key = StorageLayout::Sharded.object_key(file_name, Some("audio/wav"));
+        let size = wav.len() as i64;
+        let format = audio::measure(std::io::Cursor::new(wav.clone()), file_name);
+        let file = File {
+            file_name: file_name.to_string(),
+            file_type: Some("audio/wav".to_owned()),
+            file_upload_date: timestamp::now(),
+            title: Some(title.to_string()),
+            description: Some(format!("{} Hz test tone", frequency)),
+            language: None,
+            file_size: Some(size),
+            duration_ms: audio::probe_duration_ms(&wav, wav.len() as u64),
+            starred: false,
+            download_count: 0,
+            last_accessed_at: None,
+            storage_key: Some(key.clone()),
+            sha256: Some(hex::encode(Sha256::digest(&wav))),
+            compression: None,
+            stored_size: Some(size),
+            expires_at: None,
+            id: new_file_id(),
+            revision: 1,
+            owner: None,
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+            bit_depth: format.bit_depth,
+        };
+        storage
+            .put(&key, stream::once(async { Ok(Bytes::from(wav)) }).boxed())
+            .await?;
+        db.insert_file(&file, &BTreeMap::new()).await?;
+    }
+    Ok(SAMPLES.len())
+}
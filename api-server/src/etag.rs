@@ -0,0 +1,70 @@
+use crate::storage::ByteStream;
+use axum::http::header::{IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use axum::http::HeaderMap;
+use futures::stream::StreamExt;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::time::{Duration, SystemTime};
+
+// Strong ETags are the hex encoded SHA-256 of the representation, quoted as
+// required by RFC 7232.
+
+pub fn etag_for_bytes(bytes: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Sha256::digest(bytes)))
+}
+
+pub async fn etag_for_stream(mut stream: ByteStream<'_>) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    while let Some(bytes) = stream.next().await {
+        hasher.update(&bytes?);
+    }
+    Ok(format!("\"{}\"", hex::encode(hasher.finalize())))
+}
+
+pub fn epoch_seconds(secs: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+// Weak tags never match, as If-Match uses the strong comparison.
+pub fn if_match_allows(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(Ok(if_match)) = headers.get(IF_MATCH).map(|value| value.to_str()) else {
+        return false;
+    };
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate == etag)
+}
+
+// If-None-Match takes precedence over If-Modified-Since when both are sent.
+pub fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag);
+    }
+    if let Some(if_modified_since) = headers.get(IF_MODIFIED_SINCE) {
+        let Some(since) = if_modified_since
+            .to_str()
+            .ok()
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+        else {
+            return false;
+        };
+        // HTTP dates only have second precision
+        let last_modified = last_modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let since = since
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        return last_modified <= since;
+    }
+    false
+}
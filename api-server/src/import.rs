@@ -0,0 +1,127 @@
+use crate::audio;
+use crate::db::{new_file_id, File};
+use crate::repository::Repository;
+use crate::service;
+use crate::storage::{is_object_key, ObjectInfo, SharedStorage};
+use futures::stream::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+
+// Adopts audio already sitting in storage without a row, so the server can be
+// pointed at an existing archive. Imported files keep their path as both file
+// name and storage key.
+
+/// Registers every unknown object, returning the names imported. Objects
+/// shaped like upload keys are skipped: without a row they are leftovers of
+/// failed uploads, which reconciliation deals with.
+pub async fn import_existing(
+    db: &Repository,
+    storage: &SharedStorage,
+) -> Result<Vec<String>, anyhow::Error> {
+    let files = db.list_all_files().await?;
+    let mut known: BTreeSet<String> = files
+        .iter()
+        .filter_map(|file| file.storage_key.clone())
+        .collect();
+    known.extend(files.into_iter().map(|file| file.file_name));
+    known.extend(
+        db.list_all_versions()
+            .await?
+            .into_iter()
+            .map(|version| version.storage_key),
+    );
+    let mut found = Vec::new();
+    for (key, info) in storage.list().await? {
+        if known.contains(&key) || is_object_key(&key) {
+            continue;
+        }
+        found.push(describe(storage, &key, info).await?);
+    }
+    // One transaction for the lot, so an interrupted import leaves no rows
+    let changes = db.insert_files(&found).await?;
+    // Identical bytes were already stored, so these copies are redundant
+    let redundant = found
+        .iter()
+        .zip(&changes)
+        .filter(|((file, _), changes)| changes.storage_key != file.storage_key)
+        .map(|((file, _), _)| file.file_name.clone())
+        .collect::<Vec<_>>();
+    if !redundant.is_empty() {
+        service::delete_objects(storage, redundant).await;
+    }
+    Ok(found.into_iter().map(|(file, _)| file.file_name).collect())
+}
+
+/// Gives rows from before sharding, whose objects sit under their file names,
+/// those names as storage keys, returning how many were adopted. Requests only
+/// ever read objects by key, so until then such files can't be served.
+pub async fn adopt_legacy_objects(
+    db: &Repository,
+    storage: &SharedStorage,
+) -> Result<usize, anyhow::Error> {
+    let mut adopted = 0;
+    for file in db.list_all_files().await? {
+        if file.storage_key.is_some() {
+            continue;
+        }
+        // Names that aren't valid keys can't have an object under them
+        if !storage.exists(&file.file_name).await.unwrap_or(false) {
+            continue;
+        }
+        db.adopt_legacy_object(&file.id).await?;
+        adopted += 1;
+    }
+    Ok(adopted)
+}
+
+async fn describe(
+    storage: &SharedStorage,
+    key: &str,
+    info: ObjectInfo,
+) -> Result<(File, BTreeMap<String, String>), anyhow::Error> {
+    let mut stream = storage.stream(key).await?;
+    let mut hasher = Sha256::new();
+    let mut header = Vec::new();
+    let probe = audio::AudioProbe::start(key);
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes?;
+        hasher.update(&bytes);
+        let wanted = audio::HEADER_PROBE_LEN.saturating_sub(header.len());
+        header.extend_from_slice(&bytes[..wanted.min(bytes.len())]);
+        probe.feed(bytes).await;
+    }
+    let measured = probe.finish().await;
+    let uploaded = info
+        .modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since| since.as_secs() as i64)
+        .unwrap_or(0);
+    let file = File {
+        file_name: key.to_owned(),
+        file_type: audio::sniff_file_type(&header)
+            .or_else(|| audio::file_type_for_name(key))
+            .map(str::to_owned),
+        file_upload_date: uploaded,
+        title: None,
+        description: None,
+        language: None,
+        file_size: Some(info.size as i64),
+        duration_ms: audio::probe_duration_ms(&header, info.size).or(measured.duration_ms),
+        starred: false,
+        download_count: 0,
+        last_accessed_at: None,
+        storage_key: Some(key.to_owned()),
+        sha256: Some(hex::encode(hasher.finalize())),
+        compression: None,
+        stored_size: Some(info.size as i64),
+        expires_at: None,
+        id: new_file_id(),
+        revision: 1,
+        owner: None,
+        sample_rate: measured.sample_rate,
+        channels: measured.channels,
+        bit_depth: measured.bit_depth,
+    };
+    Ok((file, measured.tags))
+}
@@ -1,242 +1,4420 @@
+mod audio;
+mod audit;
+mod backup;
+mod blobs;
+mod checksum;
+mod compression;
 mod db;
+mod demo;
+mod etag;
+mod import;
+mod maintenance;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod progress;
+mod reconcile;
+mod remote;
+mod replay;
+mod repository;
+mod retention;
+mod review;
 mod schema;
-use anyhow::{bail, Context};
-use axum::body::StreamBody;
-use axum::extract::multipart::Field;
+mod seed;
+mod service;
+mod signing;
+#[cfg(feature = "decoding")]
+mod spectrogram;
+#[cfg(feature = "sqlx")]
+mod sqlx_sqlite;
+mod storage;
+mod tenancy;
+mod timestamp;
+mod upload_session;
+mod warmup;
+#[cfg(feature = "decoding")]
+mod waveform;
+use anyhow::{anyhow, Context};
+use axum::body::{Bytes, StreamBody};
+use axum::extract::BodyStream;
 use axum::extract::DefaultBodyLimit;
+use axum::extract::FromRef;
 use axum::extract::Multipart;
 use axum::extract::Path;
 use axum::extract::Query;
 use axum::extract::State;
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::{routing::get, Router};
-use db::{
-    establish_connection, find_file_by_file_name, find_file_by_file_type,
-    find_file_by_file_upload_date, insert_file, list_file_names,
-};
-use diesel::SqliteConnection;
-use futures::stream::StreamExt;
+use axum::http::header::{CONTENT_TYPE, ETAG, IF_MATCH, LAST_MODIFIED};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::Json;
+use axum::{middleware, Router};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use checksum::{ChecksumMismatch, ExpectedChecksum};
+use compression::Compression;
+use db::{DbError, SqliteRepository, TypeUsage};
+use dotenvy::dotenv;
+use etag::{epoch_seconds, etag_for_bytes, etag_for_stream, if_match_allows, is_not_modified};
+use futures::stream::{Stream, StreamExt};
+use progress::{Progress, ProgressTracker, UploadProgress, UploadState};
+use repository::{MemoryRepository, Repository};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use signing::{Signature, UrlSigner};
 use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::fs::{create_dir_all, File};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
-use tokio_util::io::ReaderStream;
+use std::time::Duration;
+use storage::memory::MemoryStorage;
+use storage::{LocalStorage, SharedStorage, StorageLayout};
+use tenancy::{Admins, Caller};
+use warmup::Readiness;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct FileUploadRequest {
     pub file_name: String,
     pub file_type: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub language: Option<String>,
+    /// Any other upload fields are kept as custom metadata
+    #[serde(flatten)]
+    pub metadata: BTreeMap<String, String>,
+    /// Set from the `ttl_seconds` upload option, never from form fields
+    #[serde(skip)]
+    pub expires_at: Option<i64>,
+    /// Digests the upload must match, from its headers
+    #[serde(skip)]
+    pub checksum: ExpectedChecksum,
 }
 
-async fn write_file<'a>(
-    upload_request: &FileUploadRequest,
-    mut file_field: Field<'a>,
-) -> Result<(), anyhow::Error> {
+#[derive(Clone)]
+struct AppState {
+    db: Repository,
+    storage: SharedStorage,
+    uploads: UploadConfig,
+    readiness: Readiness,
+    signer: UrlSigner,
+    progress: UploadProgress,
+    admins: Admins,
+}
+
+impl FromRef<AppState> for Repository {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for SharedStorage {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for Readiness {
+    fn from_ref(state: &AppState) -> Self {
+        state.readiness.clone()
+    }
+}
+
+impl FromRef<AppState> for UrlSigner {
+    fn from_ref(state: &AppState) -> Self {
+        state.signer.clone()
+    }
+}
+
+impl FromRef<AppState> for UploadProgress {
+    fn from_ref(state: &AppState) -> Self {
+        state.progress.clone()
+    }
+}
+
+impl FromRef<AppState> for Admins {
+    fn from_ref(state: &AppState) -> Self {
+        state.admins.clone()
+    }
+}
+
+impl FromRef<AppState> for UploadConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.uploads
+    }
+}
+
+/// Settings applied to every upload, read from the environment at startup.
+#[derive(Debug, Clone, Copy)]
+struct UploadConfig {
+    compression: Compression,
+    layout: StorageLayout,
+    /// Total bytes storage may hold, from AUDIO_QUOTA_BYTES
+    quota_bytes: Option<u64>,
+    /// Refuse content that isn't its declared type, when
+    /// AUDIO_REJECT_MISMATCHED_TYPES is set
+    reject_mismatched_types: bool,
+}
+
+impl UploadConfig {
+    fn from_env() -> Result<Self, anyhow::Error> {
+        let quota_bytes = match std::env::var("AUDIO_QUOTA_BYTES") {
+            Ok(value) => Some(
+                value
+                    .parse()
+                    .context("AUDIO_QUOTA_BYTES must be a byte count")?,
+            ),
+            Err(_) => None,
+        };
+        Ok(UploadConfig {
+            compression: Compression::from_env()?,
+            layout: StorageLayout::from_env()?,
+            quota_bytes,
+            reject_mismatched_types: std::env::var("AUDIO_REJECT_MISMATCHED_TYPES").is_ok(),
+        })
+    }
+}
+
+/// Where storage stands against the configured quota.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct StorageQuota {
+    quota_bytes: u64,
+    used_bytes: u64,
+}
+
+impl StorageQuota {
+    fn remaining_bytes(&self) -> u64 {
+        self.quota_bytes.saturating_sub(self.used_bytes)
+    }
+}
+
+// Usage is tracked as objects are stored and deleted, so this is a single row
+// read rather than a scan
+async fn storage_quota(
+    db: &Repository,
+    uploads: UploadConfig,
+) -> Result<Option<StorageQuota>, StatusCode> {
+    let Some(quota_bytes) = uploads.quota_bytes else {
+        return Ok(None);
+    };
+    match db.storage_usage().await {
+        Ok(used_bytes) => Ok(Some(StorageQuota {
+            quota_bytes,
+            used_bytes: used_bytes.max(0) as u64,
+        })),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("upload would exceed the storage quota of {} bytes", .0.quota_bytes)]
+struct QuotaExceeded(StorageQuota);
+
+#[derive(Serialize)]
+struct QuotaExceededBody {
+    error: String,
+    #[serde(flatten)]
+    quota: StorageQuota,
+}
+
+impl IntoResponse for QuotaExceeded {
+    fn into_response(self) -> Response {
+        let body = QuotaExceededBody {
+            error: self.to_string(),
+            quota: self.0,
+        };
+        (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response()
+    }
+}
+
+/// Handlers that store or name files explain quota rejections and clashes with
+/// a unique constraint; every other failure is a bare status.
+#[derive(Debug)]
+enum WriteError {
+    Status(StatusCode),
+    QuotaExceeded(QuotaExceeded),
+    Duplicate { field: String },
+}
+
+#[derive(Serialize)]
+struct DuplicateBody {
+    error: String,
+    field: String,
+}
+
+impl From<DbError> for WriteError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::Duplicate { field } => {
+                eprintln!("duplicate {}", field);
+                WriteError::Duplicate { field }
+            }
+            e => WriteError::Status(db_error_status(e)),
+        }
+    }
+}
+
+impl From<StatusCode> for WriteError {
+    fn from(status: StatusCode) -> Self {
+        WriteError::Status(status)
+    }
+}
+
+impl IntoResponse for WriteError {
+    fn into_response(self) -> Response {
+        match self {
+            WriteError::Status(status) => status.into_response(),
+            WriteError::QuotaExceeded(e) => e.into_response(),
+            WriteError::Duplicate { field } => {
+                let body = DuplicateBody {
+                    error: format!("a file with this {} already exists", field),
+                    field,
+                };
+                (StatusCode::CONFLICT, Json(body)).into_response()
+            }
+        }
+    }
+}
+
+// Logs a failed write_file and turns it into the response the upload gets
+fn write_error(e: anyhow::Error) -> WriteError {
+    eprintln!("{:?}", e);
+    if e.is::<ChecksumMismatch>() || e.is::<audio::TypeMismatch>() {
+        return WriteError::Status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let quota = e
+        .downcast_ref::<io::Error>()
+        .and_then(|e| e.get_ref())
+        .and_then(|inner| inner.downcast_ref::<QuotaExceeded>());
+    match quota {
+        Some(QuotaExceeded(quota)) => WriteError::QuotaExceeded(QuotaExceeded(*quota)),
+        None => WriteError::Status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// Free space the local audio root must have for the server to start
+const DEFAULT_MIN_FREE_BYTES: u64 = 256 * 1024 * 1024;
+
+// `--audio-root <dir>` wins over AUDIO_ROOT, which wins over ./audio
+fn audio_root(args: &[String]) -> Result<PathBuf, io::Error> {
+    let flag = args
+        .iter()
+        .position(|arg| arg == "--audio-root")
+        .and_then(|i| args.get(i + 1));
+    if let Some(root) = flag.cloned().or_else(|| std::env::var("AUDIO_ROOT").ok()) {
+        return Ok(PathBuf::from(root));
+    }
     let mut path = std::env::current_dir()?;
     path.push("audio");
-    path.push(&upload_request.file_name);
-    println!("writing file to path: {:?}", path);
-    if let Some(parent) = path.parent() {
-        create_dir_all(parent).await?;
+    Ok(path)
+}
+
+// Uses S3 when built with the `s3` feature and S3_BUCKET is set, otherwise
+// the local audio root
+async fn configure_database(args: &[String]) -> Result<Repository, anyhow::Error> {
+    let database_url = db::database_url();
+    let postgres = db::is_postgres_url(&database_url);
+    #[cfg(not(feature = "postgres"))]
+    if postgres {
+        anyhow::bail!(
+            "DATABASE_URL is a PostgreSQL URL but this build lacks the `postgres` feature"
+        );
+    }
+    let sqlx = uses_sqlx(&database_url)?;
+    #[cfg(not(feature = "sqlx"))]
+    if sqlx {
+        anyhow::bail!("DATABASE_DRIVER is sqlx but this build lacks the `sqlx` feature");
+    }
+    let read_url = read_database_url(&database_url)?;
+    if db::is_memory_url(&database_url) {
+        println!("keeping metadata in memory: nothing is persisted");
+        return Ok(Arc::new(SqliteRepository::new(db::establish_pool(
+            &database_url,
+        )?)));
+    }
+    // Deployments that migrate out of band can opt out
+    if !args.iter().any(|arg| arg == "--skip-migrations")
+        && std::env::var("SKIP_MIGRATIONS").is_err()
+    {
+        let url = database_url.clone();
+        let applied = tokio::task::spawn_blocking(move || {
+            #[cfg(feature = "postgres")]
+            if postgres {
+                return postgres::run_migrations(&url);
+            }
+            db::run_migrations(&url)
+        })
+        .await?
+        .context("running database migrations")?;
+        if applied > 0 {
+            println!("applied {} database migrations", applied);
+        }
+    }
+    if read_url.is_some() {
+        println!("reading listings and searches from DATABASE_READ_URL");
+    }
+    #[cfg(feature = "postgres")]
+    if postgres {
+        println!("storing metadata in PostgreSQL");
+        let mut repository = postgres::PgRepository::new(postgres::establish_pool(&database_url)?);
+        if let Some(read_url) = &read_url {
+            repository = repository.with_read_pool(postgres::establish_pool(read_url)?);
+        }
+        return Ok(Arc::new(repository));
+    }
+    #[cfg(feature = "sqlx")]
+    if sqlx {
+        println!("querying SQLite through sqlx");
+        let pool = sqlx_sqlite::establish_pool(&database_url)?;
+        let mut repository = sqlx_sqlite::SqlxRepository::new(pool);
+        if let Some(read_url) = &read_url {
+            repository = repository.with_read_pool(sqlx_sqlite::establish_read_pool(read_url)?);
+        }
+        return Ok(Arc::new(repository));
+    }
+    let mut repository = SqliteRepository::new(db::establish_pool(&database_url)?);
+    if let Some(read_url) = &read_url {
+        repository = repository.with_read_pool(db::establish_read_pool(read_url)?);
     }
-    let mut file = File::create(path).await?;
-    while let Some(bytes) = file_field.next().await {
+    Ok(Arc::new(repository))
+}
+
+// DATABASE_READ_URL names a database to serve listings, filters and searches
+// from, so heavy query traffic doesn't contend with uploads: a PostgreSQL
+// read replica, or for SQLite the same file or a replica of it, opened for
+// reading only. It is never migrated; replicas follow the primary's schema.
+fn read_database_url(database_url: &str) -> Result<Option<String>, anyhow::Error> {
+    let Ok(read_url) = std::env::var("DATABASE_READ_URL") else {
+        return Ok(None);
+    };
+    if db::is_memory_url(database_url) || db::is_memory_url(&read_url) {
+        anyhow::bail!("DATABASE_READ_URL needs database files, not :memory:");
+    }
+    if db::is_postgres_url(&read_url) != db::is_postgres_url(database_url) {
+        anyhow::bail!("DATABASE_READ_URL must be the same kind of database as DATABASE_URL");
+    }
+    Ok(Some(read_url))
+}
+
+// DATABASE_DRIVER=sqlx queries SQLite through sqlx rather than diesel, in
+// builds with the `sqlx` feature. diesel still migrates the database, so an
+// in-memory one, which only its own connection can see, stays with diesel.
+fn uses_sqlx(database_url: &str) -> Result<bool, anyhow::Error> {
+    match std::env::var("DATABASE_DRIVER").as_deref() {
+        Err(_) | Ok("diesel") => Ok(false),
+        Ok("sqlx") if db::is_postgres_url(database_url) => {
+            anyhow::bail!("DATABASE_DRIVER=sqlx only supports SQLite")
+        }
+        Ok("sqlx") if db::is_memory_url(database_url) => {
+            anyhow::bail!("DATABASE_DRIVER=sqlx needs a database file, not :memory:")
+        }
+        Ok("sqlx") => Ok(true),
+        Ok(driver) => anyhow::bail!("DATABASE_DRIVER must be diesel or sqlx, not {}", driver),
+    }
+}
+
+async fn configure_storage(args: &[String]) -> Result<SharedStorage, anyhow::Error> {
+    #[cfg(feature = "s3")]
+    if let Ok(bucket) = std::env::var("S3_BUCKET") {
+        println!("storing audio in S3 bucket {}", bucket);
+        return Ok(Arc::new(storage::s3::S3Storage::from_env(&bucket)?));
+    }
+    // Quietly falling back to local disk would lose uploads on a stateless container
+    #[cfg(not(feature = "s3"))]
+    if std::env::var("S3_BUCKET").is_ok() {
+        anyhow::bail!("S3_BUCKET is set but this build lacks the `s3` feature");
+    }
+    let root = audio_root(args)?;
+    let min_free_bytes = match std::env::var("AUDIO_MIN_FREE_BYTES") {
+        Ok(value) => value
+            .parse()
+            .context("AUDIO_MIN_FREE_BYTES must be a byte count")?,
+        Err(_) => DEFAULT_MIN_FREE_BYTES,
+    };
+    let storage = LocalStorage::new(root.clone());
+    storage.validate(min_free_bytes).await?;
+    println!("storing audio in {:?}", root);
+    Ok(Arc::new(storage))
+}
+
+/// Facts about the stored bytes gathered while streaming them to storage.
+#[derive(Default)]
+struct WrittenFile {
+    storage_key: String,
+    size: u64,
+    /// What the content sniffed as, whatever the client declared
+    file_type: Option<&'static str>,
+    audio: audio::AudioInfo,
+    sha256: String,
+    compression: Option<String>,
+    stored_size: u64,
+    /// Deletes the bytes again unless the upload gets recorded
+    stored: Option<StoredObject>,
+}
+
+async fn write_file<S>(
+    storage: &SharedStorage,
+    uploads: UploadConfig,
+    quota: Option<StorageQuota>,
+    upload_request: &FileUploadRequest,
+    file_stream: S,
+) -> Result<WrittenFile, anyhow::Error>
+where
+    S: Stream<Item = io::Result<Bytes>> + Send,
+{
+    let file_type = upload_request
+        .file_type
+        .as_deref()
+        .or_else(|| audio::file_type_for_name(&upload_request.file_name));
+    let codec = uploads
+        .compression
+        .codec_for(&upload_request.file_name, file_type);
+    let mut written = WrittenFile {
+        storage_key: uploads
+            .layout
+            .object_key(&upload_request.file_name, file_type),
+        compression: codec.map(str::to_owned),
+        ..Default::default()
+    };
+    let mut header = Vec::new();
+    let mut hasher = Sha256::new();
+    let mut md5 = upload_request.checksum.md5_hasher();
+    let counted = file_stream.map(|bytes| {
+        if let Ok(bytes) = &bytes {
+            written.size += bytes.len() as u64;
+            hasher.update(bytes);
+            if let Some(md5) = &mut md5 {
+                md5.update(bytes);
+            }
+            let wanted = audio::HEADER_PROBE_LEN.saturating_sub(header.len());
+            header.extend_from_slice(&bytes[..wanted.min(bytes.len())]);
+        }
+        bytes
+    });
+    let probe = audio::AudioProbe::start(&upload_request.file_name);
+    let feed = &probe;
+    let probed = counted.then(move |bytes| async move {
+        if let Ok(bytes) = &bytes {
+            feed.feed(bytes.clone()).await;
+        }
+        bytes
+    });
+    // Size, hash and duration all describe the bytes as uploaded
+    let encoded = match codec {
+        Some(_) => compression::compress(probed.boxed()),
+        None => probed.boxed(),
+    };
+    // Concurrent uploads each see the usage from before any of them, so they
+    // can overshoot the quota together by up to one upload each
+    let mut stored_size = 0;
+    let stored = encoded.map(|bytes| {
         let bytes = bytes?;
-        file.write_all(&bytes).await?;
+        stored_size += bytes.len() as u64;
+        match quota {
+            Some(quota) if stored_size > quota.remaining_bytes() => {
+                Err(io::Error::other(QuotaExceeded(quota)))
+            }
+            _ => Ok(bytes),
+        }
+    });
+    storage.put(&written.storage_key, stored.boxed()).await?;
+    // From here on anything short of recording the upload, including the
+    // request being dropped, removes the object again
+    written.stored = Some(StoredObject {
+        storage: storage.clone(),
+        key: Some(written.storage_key.clone()),
+    });
+    written.stored_size = stored_size;
+    // The WAV header is trusted over demuxing, as streaming writers leave its
+    // data size unset
+    let measured = probe.finish().await;
+    written.audio = audio::AudioInfo {
+        duration_ms: audio::probe_duration_ms(&header, written.size).or(measured.duration_ms),
+        ..measured
+    };
+    let sha256 = hasher.finalize();
+    upload_request.checksum.verify(md5, &sha256)?;
+    written.file_type = audio::sniff_file_type(&header);
+    if uploads.reject_mismatched_types {
+        audio::check_declared_type(upload_request.file_type.as_deref(), written.file_type)?;
+    }
+    written.sha256 = hex::encode(sha256);
+    Ok(written)
+}
+
+/// What to do when an upload targets a name that is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DuplicatePolicy {
+    Reject,
+    Overwrite,
+    Rename,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct UploadOptions {
+    #[serde(default)]
+    overwrite: bool,
+    #[serde(default)]
+    rename: bool,
+    /// Deletes the file this many seconds after upload
+    ttl_seconds: Option<u32>,
+}
+
+impl UploadOptions {
+    fn duplicate_policy(&self) -> DuplicatePolicy {
+        if self.overwrite {
+            DuplicatePolicy::Overwrite
+        } else if self.rename {
+            DuplicatePolicy::Rename
+        } else {
+            DuplicatePolicy::Reject
+        }
+    }
+
+    fn expires_at(&self) -> Option<i64> {
+        self.ttl_seconds
+            .map(|ttl| timestamp::now().saturating_add(i64::from(ttl)))
+    }
+}
+
+// Uploads never land under their own name, so only the DB decides whether a
+// name is taken. Names are only unique among one owner's files.
+async fn file_name_taken(
+    db: &Repository,
+    owner: Option<&str>,
+    file_name: &str,
+) -> Result<bool, anyhow::Error> {
+    Ok(db
+        .find_file_by_file_name(file_name)
+        .await?
+        .iter()
+        .any(|file| file.owner.as_deref() == owner))
+}
+
+// The file a name stands for: the caller's own, or for an admin, whoever has
+// the only file by that name. Files the caller can't see are as missing as
+// files that don't exist.
+async fn visible_file(
+    db: &Repository,
+    caller: &Caller,
+    file_name: &str,
+) -> Result<db::File, StatusCode> {
+    let mut files = db
+        .find_file_by_file_name(file_name)
+        .await
+        .map_err(db_error_status)?;
+    if let Some(position) = files.iter().position(|file| file.owner == caller.user) {
+        return Ok(files.swap_remove(position));
+    }
+    if !caller.admin {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    match files.len() {
+        0 => Err(StatusCode::NOT_FOUND),
+        1 => Ok(files.swap_remove(0)),
+        // Several owners use the name, so it takes an id to say which
+        _ => Err(StatusCode::CONFLICT),
+    }
+}
+
+// "name.wav" -> "name (1).wav", "name (2).wav", ...
+fn numbered_file_name(file_name: &str, n: usize) -> String {
+    let path = std::path::Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let renamed = match path.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, n, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) => parent.join(renamed).to_string_lossy().into_owned(),
+        None => renamed,
+    }
+}
+
+// Routes that take an id also take a file name, so a name shaped like an id
+// could stand for some other file
+fn check_not_file_id(file_name: &str) -> Result<(), StatusCode> {
+    if db::is_file_id(file_name) {
+        return Err(StatusCode::BAD_REQUEST);
     }
     Ok(())
 }
 
-async fn process_file_stream(mut data: Multipart) -> Result<FileUploadRequest, anyhow::Error> {
+async fn resolve_file_name(
+    db: &Repository,
+    owner: Option<&str>,
+    file_name: String,
+    policy: DuplicatePolicy,
+) -> Result<String, StatusCode> {
+    check_not_file_id(&file_name)?;
+    let taken = |name: String| async move {
+        file_name_taken(db, owner, &name).await.map_err(|e| {
+            eprintln!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    };
+    if !taken(file_name.clone()).await? {
+        return Ok(file_name);
+    }
+    match policy {
+        DuplicatePolicy::Reject => Err(StatusCode::CONFLICT),
+        DuplicatePolicy::Overwrite => Ok(file_name),
+        DuplicatePolicy::Rename => {
+            for n in 1.. {
+                let candidate = numbered_file_name(&file_name, n);
+                if !taken(candidate.clone()).await? {
+                    return Ok(candidate);
+                }
+            }
+            unreachable!()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_file_stream(
+    db: &Repository,
+    storage: &SharedStorage,
+    uploads: UploadConfig,
+    caller: &Caller,
+    mut data: Multipart,
+    policy: DuplicatePolicy,
+    checksum: ExpectedChecksum,
+    tracker: Option<&ProgressTracker>,
+) -> Result<(FileUploadRequest, WrittenFile), WriteError> {
+    let internal_error = |e: anyhow::Error| {
+        eprintln!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
     let mut fields = BTreeMap::<String, Value>::new();
     let file_field = loop {
-        if let Some(field) = data.next_field().await? {
-            let name = field.name().context("missing field name")?.to_owned();
+        if let Some(field) = data
+            .next_field()
+            .await
+            .map_err(|e| internal_error(e.into()))?
+        {
+            let name = field
+                .name()
+                .context("missing field name")
+                .map_err(internal_error)?
+                .to_owned();
             if name == "file" {
                 break field;
             }
-            let data = field.bytes().await?;
-            fields.insert(name, std::str::from_utf8(&data)?.to_owned().into());
+            let data = field.bytes().await.map_err(|e| internal_error(e.into()))?;
+            let value = std::str::from_utf8(&data).map_err(|e| internal_error(e.into()))?;
+            fields.insert(name, value.to_owned().into());
         } else {
-            bail!("File upload ended early");
+            return Err(internal_error(anyhow!("File upload ended early")).into());
         }
     };
-    let json = serde_json::to_string(&fields)?;
-    let upload_request = serde_json::from_str::<FileUploadRequest>(&json)?;
-    write_file(&upload_request, file_field).await?;
-    Ok(upload_request)
+    let mut upload_request = serde_json::to_string(&fields)
+        .and_then(|json| serde_json::from_str::<FileUploadRequest>(&json))
+        .map_err(|e| internal_error(e.into()))?;
+    upload_request.file_name =
+        resolve_file_name(db, caller.user.as_deref(), upload_request.file_name, policy).await?;
+    // Checked against the file field alone, not the whole multipart body
+    upload_request.checksum = checksum;
+    let quota = storage_quota(db, uploads).await?;
+    let file_stream = file_field.map(|bytes| bytes.map_err(io::Error::other));
+    let written = match tracker {
+        Some(tracker) => {
+            let file_stream = tracker.observe(file_stream);
+            write_file(storage, uploads, quota, &upload_request, file_stream).await
+        }
+        None => write_file(storage, uploads, quota, &upload_request, file_stream).await,
+    }
+    .map_err(write_error)?;
+    Ok((upload_request, written))
 }
 
-async fn accept_file_stream(
-    db: State<Arc<Mutex<SqliteConnection>>>,
-    data: Multipart,
-) -> Result<impl IntoResponse, StatusCode> {
-    let result = process_file_stream(data).await;
-    match result {
-        Ok(response) => {
-            let file = db::File {
-                file_name: response.file_name,
-                file_type: response.file_type,
-                file_upload_date: SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as i32,
-            };
-            if let Err(e) = insert_file(db.0, &file).await {
-                eprintln!("{:?}", e);
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
-            }
-            Ok(format!("{:?}", file))
-        }
-        Err(e) => {
-            eprintln!("{:?}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
+// Logs the error and picks the status a handler should answer with
+fn db_error_status(e: DbError) -> StatusCode {
+    eprintln!("{:?}", e);
+    match e {
+        DbError::NotFound => StatusCode::NOT_FOUND,
+        DbError::Conflict(_) | DbError::Duplicate { .. } => StatusCode::CONFLICT,
+        DbError::Stale => StatusCode::PRECONDITION_FAILED,
+        DbError::Busy | DbError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        DbError::Corrupt(_) | DbError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
-async fn list_files(
-    db: State<Arc<Mutex<SqliteConnection>>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let files = list_file_names(db.0).await;
-    match files {
-        Ok(files) => match serde_json::to_string(&files) {
-            Ok(json_str) => Ok(json_str),
-            Err(e) => {
-                eprintln!("{:?}", e);
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
+fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("idempotency-key")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+fn expected_checksum(headers: &HeaderMap) -> Result<ExpectedChecksum, StatusCode> {
+    ExpectedChecksum::from_headers(headers).map_err(|e| {
+        eprintln!("{:?}", e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+// How long a reserved Idempotency-Key waits on its upload before a retry may
+// take it over, for uploads the server stopped in the middle of
+const IDEMPOTENCY_RESERVATION_SECONDS: i64 = 2 * FETCH_TIMEOUT.as_secs() as i64;
+
+/// What an upload's Idempotency-Key says to do.
+enum Idempotency {
+    /// Go ahead, holding the key if one was sent
+    Upload(Option<ReservedKey>),
+    /// An upload with this key already finished with this response
+    Replay(String),
+}
+
+// Reserves the caller's Idempotency-Key before the upload starts, so a retry
+// racing it gets 409 rather than writing the file a second time. Keys are
+// the caller's own, so nobody is answered with someone else's upload.
+async fn claim_idempotency_key(
+    db: &Repository,
+    caller: &Caller,
+    headers: &HeaderMap,
+) -> Result<Idempotency, StatusCode> {
+    let Some(key) = idempotency_key(headers) else {
+        return Ok(Idempotency::Upload(None));
+    };
+    let owner = caller.user.clone().unwrap_or_default();
+    let reservation = db::IdempotencyKey {
+        owner: owner.clone(),
+        idempotency_key: key.clone(),
+        response: None,
+        created_at: timestamp::now(),
+    };
+    // The second attempt follows taking over an abandoned reservation
+    for _ in 0..2 {
+        match db.insert_idempotency_key(&reservation).await {
+            Ok(()) => {
+                return Ok(Idempotency::Upload(Some(ReservedKey {
+                    db: db.clone(),
+                    owner,
+                    key: Some(key),
+                })))
             }
-        },
-        Err(e) => {
-            eprintln!("{:?}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            Err(DbError::Duplicate { .. }) => {}
+            Err(e) => return Err(db_error_status(e)),
+        }
+        let existing = db
+            .find_idempotency_key(&owner, &key)
+            .await
+            .map_err(db_error_status)?;
+        match existing {
+            Some(db::IdempotencyKey {
+                response: Some(response),
+                ..
+            }) => return Ok(Idempotency::Replay(response)),
+            Some(reserved)
+                if reserved.created_at > timestamp::now() - IDEMPOTENCY_RESERVATION_SECONDS =>
+            {
+                return Err(StatusCode::CONFLICT)
+            }
+            Some(_) => db
+                .delete_idempotency_key(&owner, &key)
+                .await
+                .map_err(db_error_status)?,
+            // Released by a failed upload in the meantime
+            None => {}
         }
     }
+    Err(StatusCode::CONFLICT)
 }
 
-#[derive(Debug, Deserialize)]
-struct FileFilterAttributes {
-    file_name: Option<String>,
-    file_type: Option<String>,
-    file_upload_date: Option<i32>,
+// An Idempotency-Key held while its upload runs. Unless the upload completes
+// it, dropping it releases the key, so a retry after a failure starts over.
+struct ReservedKey {
+    db: Repository,
+    owner: String,
+    key: Option<String>,
 }
 
-async fn filter_files(
-    db: State<Arc<Mutex<SqliteConnection>>>,
-    Query(attributes): Query<FileFilterAttributes>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let mut results = Vec::<std::collections::BTreeSet<String>>::new();
-    if let Some(ref file_name) = attributes.file_name {
-        match find_file_by_file_name(db.0.clone(), file_name).await {
-            Ok(files) => {
-                results.push(files.into_iter().map(|file| file.file_name).collect());
-            }
+impl ReservedKey {
+    async fn complete(mut self, response: &str) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        // The upload itself succeeded, so a failure here only loses replay
+        // protection, once the drop releases the key
+        match self
+            .db
+            .complete_idempotency_key(&self.owner, &key, response)
+            .await
+        {
+            Ok(()) => {}
             Err(e) => {
                 eprintln!("{:?}", e);
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                self.key = Some(key);
             }
         }
     }
-    if let Some(ref file_type) = attributes.file_type {
-        match find_file_by_file_type(db.0.clone(), file_type).await {
-            Ok(files) => {
-                results.push(files.into_iter().map(|file| file.file_name).collect());
-            }
-            Err(e) => {
+}
+
+impl Drop for ReservedKey {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let (db, owner) = (self.db.clone(), std::mem::take(&mut self.owner));
+        tokio::spawn(async move {
+            if let Err(e) = db.delete_idempotency_key(&owner, &key).await {
                 eprintln!("{:?}", e);
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
             }
-        }
+        });
     }
-    if let Some(ref file_upload_date) = attributes.file_upload_date {
-        match find_file_by_file_upload_date(db.0.clone(), file_upload_date).await {
-            Ok(files) => {
-                results.push(files.into_iter().map(|file| file.file_name).collect());
+}
+
+// Deletes a freshly stored object unless its upload gets recorded, so uploads
+// that fail (or are abandoned) after their bytes were written leave nothing
+// behind
+struct StoredObject {
+    storage: SharedStorage,
+    key: Option<String>,
+}
+
+impl StoredObject {
+    fn keep(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for StoredObject {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let storage = self.storage.clone();
+        tokio::spawn(async move {
+            if let Err(e) = storage.delete(&key).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    eprintln!("{:?}", e);
+                }
             }
-            Err(e) => {
-                eprintln!("{:?}", e);
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        });
+    }
+}
+
+async fn record_upload(
+    db: &Repository,
+    storage: &SharedStorage,
+    upload_request: FileUploadRequest,
+    written: WrittenFile,
+    policy: DuplicatePolicy,
+    reserved: Option<ReservedKey>,
+    caller: &Caller,
+) -> Result<String, WriteError> {
+    // Overwriting replaces the caller's own file by that name; an admin may
+    // overwrite anyone's, which keeps its owner
+    let owner = match policy {
+        DuplicatePolicy::Overwrite if caller.admin => {
+            match visible_file(db, caller, &upload_request.file_name).await {
+                Ok(existing) => existing.owner,
+                Err(StatusCode::NOT_FOUND) => caller.user.clone(),
+                Err(status) => return Err(status.into()),
             }
         }
+        _ => caller.user.clone(),
+    };
+    let mut file = db::File {
+        file_name: upload_request.file_name,
+        file_type: written
+            .file_type
+            .map(str::to_owned)
+            .or(upload_request.file_type),
+        file_upload_date: timestamp::now(),
+        title: upload_request.title,
+        description: upload_request.description,
+        language: upload_request.language,
+        file_size: Some(written.size as i64),
+        duration_ms: written.audio.duration_ms,
+        starred: false,
+        download_count: 0,
+        last_accessed_at: None,
+        storage_key: Some(written.storage_key),
+        sha256: Some(written.sha256),
+        compression: written.compression,
+        stored_size: Some(written.stored_size as i64),
+        expires_at: upload_request.expires_at,
+        id: db::new_file_id(),
+        revision: 1,
+        owner,
+        sample_rate: written.audio.sample_rate,
+        channels: written.audio.channels,
+        bit_depth: written.audio.bit_depth,
+    };
+    // Tags embedded in the file are kept as metadata, under any the client sent
+    let mut metadata = written.audio.tags;
+    metadata.extend(upload_request.metadata);
+    // The row, its metadata and its blob reference are written together; if
+    // that fails the guard takes the bytes back out of storage
+    let result = match policy {
+        DuplicatePolicy::Overwrite => db.replace_file(&file, &metadata).await,
+        _ => db.insert_file(&file, &metadata).await,
+    };
+    let changes = result?;
+    // When identical bytes were already stored the row points at those, and
+    // the guard drops the copy just written
+    if changes.storage_key == file.storage_key {
+        if let Some(stored) = written.stored {
+            stored.keep();
+        }
     }
-    while results.len() > 1 {
-        let set_a = results.pop().unwrap();
-        let set_b = results.pop().unwrap();
-        results.push(set_a.intersection(&set_b).map(|s| s.to_owned()).collect());
+    file.storage_key = changes.storage_key;
+    file.compression = changes.compression;
+    file.stored_size = changes.stored_size;
+    file.id = changes.id.unwrap_or(file.id);
+    file.revision = changes.revision.unwrap_or(file.revision);
+    service::delete_objects(storage, changes.unreferenced).await;
+    let response = format!("{:?}", file);
+    if let Some(reserved) = reserved {
+        reserved.complete(&response).await;
     }
-    let result: Vec<String> = if results.len() == 1 {
-        results.pop().unwrap().into_iter().collect()
-    } else {
-        vec![]
+    Ok(response)
+}
+
+// Axum extractors, one per argument
+#[allow(clippy::too_many_arguments)]
+async fn accept_file_stream(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    uploads: State<UploadConfig>,
+    progress: State<UploadProgress>,
+    caller: Caller,
+    Query(options): Query<UploadOptions>,
+    headers: HeaderMap,
+    data: Multipart,
+) -> Result<impl IntoResponse, WriteError> {
+    let reserved = match claim_idempotency_key(&db.0, &caller, &headers).await? {
+        Idempotency::Replay(response) => return Ok(response),
+        Idempotency::Upload(reserved) => reserved,
     };
-    match serde_json::to_string(&result) {
-        Ok(json_str) => Ok(json_str),
-        Err(e) => {
-            eprintln!("{:?}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
+    let checksum = expected_checksum(&headers)?;
+    let policy = options.duplicate_policy();
+    let tracker = progress.track(&headers);
+    let (mut upload_request, written) = process_file_stream(
+        &db.0,
+        &storage.0,
+        uploads.0,
+        &caller,
+        data,
+        policy,
+        checksum,
+        tracker.as_ref(),
+    )
+    .await?;
+    upload_request.expires_at = options.expires_at();
+    let response = record_upload(
+        &db.0,
+        &storage.0,
+        upload_request,
+        written,
+        policy,
+        reserved,
+        &caller,
+    )
+    .await?;
+    if let Some(tracker) = tracker {
+        tracker.complete();
     }
+    Ok(response)
 }
 
-async fn get_file_info(
-    db: State<Arc<Mutex<SqliteConnection>>>,
+// Axum extractors, one per argument
+#[allow(clippy::too_many_arguments)]
+async fn put_file(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    uploads: State<UploadConfig>,
+    progress: State<UploadProgress>,
+    caller: Caller,
     Path(file_name): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let results = find_file_by_file_name(db.0, &file_name).await;
-    let result: Option<db::File>;
-    match results {
-        Ok(mut results) => {
-            result = results.pop();
-        }
-        Err(e) => {
-            eprintln!("{:?}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    Query(options): Query<UploadOptions>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<impl IntoResponse, WriteError> {
+    let reserved = match claim_idempotency_key(&db.0, &caller, &headers).await? {
+        Idempotency::Replay(response) => return Ok(response),
+        Idempotency::Upload(reserved) => reserved,
     };
-    match serde_json::to_string(&result) {
-        Ok(json_str) => Ok(json_str),
-        Err(e) => {
-            eprintln!("{:?}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    // Content-Type parameters such as charset aren't part of the file type
+    let file_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_owned());
+    let policy = options.duplicate_policy();
+    let upload_request = FileUploadRequest {
+        file_name: resolve_file_name(&db.0, caller.user.as_deref(), file_name, policy).await?,
+        file_type,
+        expires_at: options.expires_at(),
+        checksum: expected_checksum(&headers)?,
+        ..Default::default()
+    };
+    let quota = storage_quota(&db.0, uploads.0).await?;
+    let body = body.map(|bytes| bytes.map_err(io::Error::other));
+    let tracker = progress.track(&headers);
+    let written = match &tracker {
+        Some(tracker) => {
+            let body = tracker.observe(body);
+            write_file(&storage.0, uploads.0, quota, &upload_request, body).await
         }
+        None => write_file(&storage.0, uploads.0, quota, &upload_request, body).await,
     }
+    .map_err(write_error)?;
+    let response = record_upload(
+        &db.0,
+        &storage.0,
+        upload_request,
+        written,
+        policy,
+        reserved,
+        &caller,
+    )
+    .await?;
+    if let Some(tracker) = tracker {
+        tracker.complete();
+    }
+    Ok(response)
 }
 
-async fn download_file(Path(file_name): Path<String>) -> Result<impl IntoResponse, StatusCode> {
-    let mut path = std::env::current_dir().map_err(|e| {
-        eprintln!("{:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    path.push("audio");
-    path.push(&file_name);
-    println!("Reading file from path: {:?}", path);
-    let file = match tokio::fs::File::open(path).await {
-        Ok(file) => file,
-        Err(_) => return Err(StatusCode::NOT_FOUND),
-    };
-    let stream = ReaderStream::new(file);
-    let body = StreamBody::new(stream);
-    Ok(body)
+// Encoded size cap for JSON uploads; larger files should use multipart or PUT
+const MAX_JSON_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+// Decode in chunks so the decoded file is never held in memory all at once.
+// Must be a multiple of 4 to stay aligned to base64 quanta.
+const BASE64_CHUNK_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct JsonFileUploadRequest {
+    file_name: String,
+    file_type: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    language: Option<String>,
+    data_base64: String,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
 }
 
-#[tokio::main]
-async fn main() {
-    let db = Arc::new(Mutex::new(establish_connection()));
-    let app = Router::new()
-        .route("/", get(|| async { "Hello, World!" }))
-        .route("/audio", get(list_files).post(accept_file_stream))
+async fn accept_json_upload(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    uploads: State<UploadConfig>,
+    caller: Caller,
+    Query(options): Query<UploadOptions>,
+    headers: HeaderMap,
+    Json(request): Json<JsonFileUploadRequest>,
+) -> Result<impl IntoResponse, WriteError> {
+    let reserved = match claim_idempotency_key(&db.0, &caller, &headers).await? {
+        Idempotency::Replay(response) => return Ok(response),
+        Idempotency::Upload(reserved) => reserved,
+    };
+    let policy = options.duplicate_policy();
+    let upload_request = FileUploadRequest {
+        file_name: resolve_file_name(&db.0, caller.user.as_deref(), request.file_name, policy)
+            .await?,
+        file_type: request.file_type,
+        title: request.title,
+        description: request.description,
+        language: request.language,
+        metadata: request.metadata,
+        expires_at: options.expires_at(),
+        checksum: expected_checksum(&headers)?,
+    };
+    let decoded =
+        futures::stream::iter(request.data_base64.as_bytes().chunks(BASE64_CHUNK_LEN).map(
+            |chunk| {
+                BASE64_STANDARD
+                    .decode(chunk)
+                    .map(Bytes::from)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            },
+        ));
+    let quota = storage_quota(&db.0, uploads.0).await?;
+    let written = match write_file(&storage.0, uploads.0, quota, &upload_request, decoded).await {
+        Ok(written) => written,
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::InvalidData) =>
+        {
+            eprintln!("{:?}", e);
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        Err(e) => return Err(write_error(e)),
+    };
+    record_upload(
+        &db.0,
+        &storage.0,
+        upload_request,
+        written,
+        policy,
+        reserved,
+        &caller,
+    )
+    .await
+}
+
+const MAX_FETCH_BYTES: usize = 1024 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Deserialize)]
+struct FetchRequest {
+    url: String,
+    file_name: Option<String>,
+    file_type: Option<String>,
+}
+
+fn is_audio_content_type(content_type: &str) -> bool {
+    content_type.starts_with("audio/")
+        || content_type.starts_with("video/")
+        || content_type == "application/octet-stream"
+        || content_type == "application/ogg"
+}
+
+async fn fetch_remote_file(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    uploads: State<UploadConfig>,
+    caller: Caller,
+    Query(options): Query<UploadOptions>,
+    headers: HeaderMap,
+    Json(request): Json<FetchRequest>,
+) -> Result<impl IntoResponse, WriteError> {
+    let reserved = match claim_idempotency_key(&db.0, &caller, &headers).await? {
+        Idempotency::Replay(response) => return Ok(response),
+        Idempotency::Upload(reserved) => reserved,
+    };
+    let url = reqwest::Url::parse(&request.url).map_err(|_| StatusCode::BAD_REQUEST)?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let file_name = match request.file_name {
+        Some(file_name) => file_name,
+        None => url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|segment| !segment.is_empty())
+            .ok_or(StatusCode::BAD_REQUEST)?
+            .to_owned(),
+    };
+    let addresses = remote::public_addresses(&url).await.map_err(|e| {
+        eprintln!("{:?}", e);
+        match e {
+            remote::RemoteError::Unresolved(..) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    })?;
+    // Pinned to the checked addresses, and redirects aren't followed as they
+    // could lead anywhere
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .resolve_to_addrs(url.host_str().unwrap_or_default(), &addresses)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+    if response.status().is_redirection() {
+        return Err(StatusCode::BAD_GATEWAY.into());
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_FETCH_BYTES as u64)
+    {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_owned());
+    if !content_type.as_deref().is_some_and(is_audio_content_type) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    }
+    let policy = options.duplicate_policy();
+    let upload_request = FileUploadRequest {
+        file_name: resolve_file_name(&db.0, caller.user.as_deref(), file_name, policy).await?,
+        file_type: request.file_type.or(content_type),
+        expires_at: options.expires_at(),
+        ..Default::default()
+    };
+    // Content-Length can be missing or wrong, so the cap is enforced while streaming too
+    let mut received = 0;
+    let stream = response.bytes_stream().map(move |chunk| {
+        let chunk = chunk.map_err(io::Error::other)?;
+        received += chunk.len();
+        if received > MAX_FETCH_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "remote file exceeds size limit",
+            ));
+        }
+        Ok(chunk)
+    });
+    let quota = storage_quota(&db.0, uploads.0).await?;
+    let written = match write_file(&storage.0, uploads.0, quota, &upload_request, stream).await {
+        Ok(written) => written,
+        Err(e) => {
+            let status = match e.downcast_ref::<io::Error>() {
+                Some(e) if e.kind() == io::ErrorKind::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                Some(e)
+                    if e.get_ref()
+                        .is_some_and(|inner| inner.is::<reqwest::Error>()) =>
+                {
+                    StatusCode::BAD_GATEWAY
+                }
+                _ => return Err(write_error(e)),
+            };
+            eprintln!("{:?}", e);
+            return Err(status.into());
+        }
+    };
+    record_upload(
+        &db.0,
+        &storage.0,
+        upload_request,
+        written,
+        policy,
+        reserved,
+        &caller,
+    )
+    .await
+}
+
+// Generous for a base64 encoded header sample, which only needs
+// audio::HEADER_PROBE_LEN bytes
+const MAX_VALIDATE_BYTES: usize = 1024 * 1024;
+
+/// Describes an upload before it is sent; the file itself never is.
+#[derive(Debug, Deserialize)]
+struct ValidateRequest {
+    file_name: String,
+    file_type: Option<String>,
+    /// Size of the whole file, needed to estimate its duration
+    file_size: Option<u64>,
+    /// The first bytes of the file
+    sample_base64: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ValidateResponse {
+    accepted: bool,
+    /// The name the upload would be stored under, after any rename
+    file_name: Option<String>,
+    duration_ms: Option<i64>,
+    problems: Vec<String>,
+}
+
+// Dry run of an upload, so clients can fail fast before sending the bytes.
+// Takes the same duplicate options as the upload endpoints.
+async fn validate_upload(
+    db: State<Repository>,
+    uploads: State<UploadConfig>,
+    caller: Caller,
+    Query(options): Query<UploadOptions>,
+    Json(request): Json<ValidateRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let mut problems = Vec::new();
+    let file_type = request
+        .file_type
+        .as_deref()
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    if let Some(file_type) = file_type.filter(|file_type| !is_audio_content_type(file_type)) {
+        problems.push(format!("{} is not an audio type", file_type));
+    }
+    // Compressed uploads may well fit even when their original size doesn't
+    let compressed = uploads
+        .compression
+        .codec_for(&request.file_name, file_type)
+        .is_some();
+    if let (Some(quota), Some(file_size)) =
+        (storage_quota(&db.0, uploads.0).await?, request.file_size)
+    {
+        if !compressed && file_size > quota.remaining_bytes() {
+            problems.push(QuotaExceeded(quota).to_string());
+        }
+    }
+    let policy = options.duplicate_policy();
+    let file_name =
+        match resolve_file_name(&db.0, caller.user.as_deref(), request.file_name, policy).await {
+            Ok(file_name) => Some(file_name),
+            Err(StatusCode::CONFLICT) => {
+                problems.push("file name is already taken".to_owned());
+                None
+            }
+            Err(StatusCode::BAD_REQUEST) => {
+                problems.push("file name has the shape of a file id".to_owned());
+                None
+            }
+            Err(status) => return Err(status),
+        };
+    let sample = match &request.sample_base64 {
+        Some(sample) => BASE64_STANDARD
+            .decode(sample)
+            .map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => vec![],
+    };
+    if uploads.reject_mismatched_types {
+        if let Err(e) = audio::check_declared_type(file_type, audio::sniff_file_type(&sample)) {
+            problems.push(e.to_string());
+        }
+    }
+    let duration_ms = request
+        .file_size
+        .and_then(|file_size| audio::probe_duration_ms(&sample, file_size));
+    Ok(Json(ValidateResponse {
+        accepted: problems.is_empty(),
+        file_name,
+        duration_ms,
+        problems,
+    }))
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, StatusCode> {
+    serde_json::to_string(value).map_err(|e| {
+        eprintln!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    /// Most downloaded first
+    Downloads,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListingOptions {
+    /// Return full file records instead of just names
+    #[serde(default)]
+    details: bool,
+    sort: Option<SortOrder>,
+}
+
+// Shapes a set of file records as either full records or just names
+fn listing_json(files: Vec<db::File>, details: bool) -> Result<String, StatusCode> {
+    if details {
+        return to_json(&files);
+    }
+    let names: Vec<String> = files.into_iter().map(|file| file.file_name).collect();
+    to_json(&names)
+}
+
+async fn list_files(
+    db: State<Repository>,
+    caller: Caller,
+    Query(options): Query<ListingOptions>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if let Some(owner) = caller.scope() {
+        let mut files = db
+            .find_file_by_owner(owner)
+            .await
+            .map_err(db_error_status)?;
+        if options.sort == Some(SortOrder::Downloads) {
+            files.sort_by_key(|file| std::cmp::Reverse(file.download_count));
+        }
+        return listing_json(files, options.details);
+    }
+    if options.sort == Some(SortOrder::Downloads) {
+        let files = db.most_downloaded(None).await.map_err(db_error_status)?;
+        return listing_json(files, options.details);
+    }
+    if options.details {
+        let files = db.list_all_files().await.map_err(db_error_status)?;
+        return to_json(&files);
+    }
+    let files = db.list_file_names().await;
+    match files {
+        Ok(files) => to_json(&files),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FileFilterAttributes {
+    /// Full-text search; matches come best first
+    q: Option<String>,
+    file_name: Option<String>,
+    file_type: Option<String>,
+    /// Epoch seconds or RFC3339
+    #[serde(default, deserialize_with = "timestamp::deserialize_some")]
+    file_upload_date: Option<i64>,
+    /// Comma separated; files must carry every listed tag
+    tags: Option<String>,
+    /// Inclusive byte bounds
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    /// Inclusive bounds in milliseconds
+    min_duration: Option<i64>,
+    max_duration: Option<i64>,
+    /// Exact matches, e.g. `sample_rate=16000&channels=1`
+    sample_rate: Option<i32>,
+    channels: Option<i32>,
+    bit_depth: Option<i32>,
+    starred: Option<bool>,
+    /// Files not downloaded in this many days (or since upload, if never)
+    stale_days: Option<u32>,
+    /// Return full file records instead of just names
+    #[serde(default)]
+    details: bool,
+    sort: Option<SortOrder>,
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Custom metadata is filtered with `meta.<key>=<value>` query parameters
+const METADATA_FILTER_PREFIX: &str = "meta.";
+
+async fn filter_files(
+    db: State<Repository>,
+    caller: Caller,
+    Query(attributes): Query<FileFilterAttributes>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let mut results = Vec::<std::collections::BTreeSet<String>>::new();
+    let mut ranked = None;
+    if let Some(ref q) = attributes.q {
+        let terms = repository::search_terms(q);
+        let ids = if terms.is_empty() {
+            vec![]
+        } else {
+            db.search_files(&terms).await.map_err(db_error_status)?
+        };
+        results.push(ids.iter().cloned().collect());
+        ranked = Some(ids);
+    }
+    if let Some(ref file_name) = attributes.file_name {
+        match db.find_file_by_file_name(file_name).await {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if let Some(ref file_type) = attributes.file_type {
+        match db.find_file_by_file_type(file_type).await {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if let Some(ref file_upload_date) = attributes.file_upload_date {
+        match db.find_file_by_file_upload_date(file_upload_date).await {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if attributes.min_size.is_some() || attributes.max_size.is_some() {
+        match db
+            .find_file_by_file_size_range(attributes.min_size, attributes.max_size)
+            .await
+        {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if attributes.min_duration.is_some() || attributes.max_duration.is_some() {
+        match db
+            .find_file_by_duration_range(attributes.min_duration, attributes.max_duration)
+            .await
+        {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if attributes.sample_rate.is_some()
+        || attributes.channels.is_some()
+        || attributes.bit_depth.is_some()
+    {
+        match db
+            .find_file_by_audio_format(
+                attributes.sample_rate,
+                attributes.channels,
+                attributes.bit_depth,
+            )
+            .await
+        {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if let Some(starred) = attributes.starred {
+        match db.find_file_by_starred(starred).await {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if let Some(stale_days) = attributes.stale_days {
+        let cutoff =
+            timestamp::now().saturating_sub(i64::from(stale_days).saturating_mul(SECONDS_PER_DAY));
+        match db.find_file_by_last_access_before(cutoff).await {
+            Ok(files) => {
+                results.push(files.into_iter().map(|file| file.id).collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    if let Some(ref tags) = attributes.tags {
+        for tag in tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+            match db.find_file_ids_by_tag(tag).await {
+                Ok(ids) => {
+                    results.push(ids.into_iter().collect());
+                }
+                Err(e) => return Err(db_error_status(e)),
+            }
+        }
+    }
+    for (key, value) in &params {
+        let Some(key) = key.strip_prefix(METADATA_FILTER_PREFIX) else {
+            continue;
+        };
+        match db.find_file_ids_by_metadata(key, value).await {
+            Ok(ids) => {
+                results.push(ids.into_iter().collect());
+            }
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    while results.len() > 1 {
+        let set_a = results.pop().unwrap();
+        let set_b = results.pop().unwrap();
+        results.push(set_a.intersection(&set_b).map(|s| s.to_owned()).collect());
+    }
+    let mut result: Vec<String> = if results.len() == 1 {
+        results.pop().unwrap().into_iter().collect()
+    } else {
+        vec![]
+    };
+    if let Some(owner) = caller.scope() {
+        let owned: std::collections::BTreeSet<String> = db
+            .find_file_by_owner(owner)
+            .await
+            .map_err(db_error_status)?
+            .into_iter()
+            .map(|file| file.id)
+            .collect();
+        result.retain(|id| owned.contains(id));
+    }
+    let mut files = db
+        .find_files_by_ids(&result)
+        .await
+        .map_err(db_error_status)?;
+    // Search matches come best first, everything else by name
+    match ranked {
+        Some(ranked) => {
+            let position: BTreeMap<&str, usize> = ranked
+                .iter()
+                .enumerate()
+                .map(|(position, id)| (id.as_str(), position))
+                .collect();
+            files.sort_by_key(|file| position.get(file.id.as_str()).copied());
+        }
+        None => files.sort_by(|a, b| a.file_name.cmp(&b.file_name)),
+    }
+    if attributes.sort == Some(SortOrder::Downloads) {
+        files.sort_by_key(|file| std::cmp::Reverse(file.download_count));
+    }
+    listing_json(files, attributes.details)
+}
+
+#[derive(Debug, Deserialize)]
+struct TopOptions {
+    /// Unsigned, so a negative limit is refused with 400 rather than left to
+    /// each backend
+    limit: Option<u32>,
+}
+
+const DEFAULT_TOP_LIMIT: u32 = 10;
+
+async fn top_downloads(
+    db: State<Repository>,
+    caller: Caller,
+    Query(options): Query<TopOptions>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let limit = options.limit.unwrap_or(DEFAULT_TOP_LIMIT);
+    if let Some(owner) = caller.scope() {
+        let mut files = db
+            .find_file_by_owner(owner)
+            .await
+            .map_err(db_error_status)?;
+        files.sort_by(|a, b| {
+            b.download_count
+                .cmp(&a.download_count)
+                .then_with(|| a.file_name.cmp(&b.file_name))
+        });
+        files.truncate(limit as usize);
+        return to_json(&files);
+    }
+    match db.most_downloaded(Some(limit)).await {
+        Ok(files) => to_json(&files),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn get_custom_metadata(
+    db: State<Repository>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    match db.get_file_metadata(&file.id).await {
+        Ok(metadata) => Ok(Json(metadata)),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StarredResponse {
+    starred: bool,
+}
+
+async fn toggle_star(
+    db: State<Repository>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    match db.toggle_starred(&file.id).await {
+        Ok(starred) => Ok(Json(StarredResponse { starred })),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn get_file_tags(
+    db: State<Repository>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    match db.list_file_tags(&file.id).await {
+        Ok(tags) => Ok(Json(tags)),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn tag_file(
+    db: State<Repository>,
+    caller: Caller,
+    Path((file_name, tag)): Path<(String, String)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    match db.add_file_tag(&file.id, &tag).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn untag_file(
+    db: State<Repository>,
+    caller: Caller,
+    Path((file_name, tag)): Path<(String, String)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    match db.remove_file_tag(&file.id, &tag).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn get_file_info(
+    db: State<Repository>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let result = match visible_file(&db.0, &caller, &file_name).await {
+        Ok(file) => Some(file),
+        Err(StatusCode::NOT_FOUND) => None,
+        Err(status) => return Err(status),
+    };
+    file_info_response(result, &headers)
+}
+
+async fn file_by_id(db: &Repository, caller: &Caller, id: &str) -> Result<db::File, StatusCode> {
+    match db.find_file_by_id(id).await {
+        Ok(Some(file)) if caller.can_see(file.owner.as_deref()) => Ok(file),
+        Ok(_) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn get_file(
+    db: State<Repository>,
+    caller: Caller,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file = file_by_id(&db.0, &caller, &id).await?;
+    file_info_response(Some(file), &headers)
+}
+
+// Files looked up by name that don't exist come back as null, as they
+// always have
+fn file_info_response(
+    result: Option<db::File>,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let json_str = match serde_json::to_string(&result) {
+        Ok(json_str) => json_str,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let Some(file) = result else {
+        return Ok(json_str.into_response());
+    };
+    let etag = etag_for_bytes(json_str.as_bytes());
+    let last_modified = epoch_seconds(file.file_upload_date);
+    let validators = [
+        (ETAG, etag.clone()),
+        (LAST_MODIFIED, httpdate::fmt_http_date(last_modified)),
+    ];
+    if is_not_modified(headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+    }
+    Ok((validators, json_str).into_response())
+}
+
+// Routes that take an id also take a file name, for clients from before ids.
+// New names can't be shaped like ids, so a name never shadows another file.
+async fn file_by_id_or_name(
+    db: &Repository,
+    caller: &Caller,
+    id: &str,
+) -> Result<db::File, StatusCode> {
+    match db.find_file_by_id(id).await {
+        Ok(Some(file)) if caller.can_see(file.owner.as_deref()) => Ok(file),
+        Ok(Some(_)) => Err(StatusCode::NOT_FOUND),
+        Ok(None) => visible_file(db, caller, id).await,
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FilePatch {
+    /// The revision the edit was made against, if not sent as If-Match
+    revision: Option<i32>,
+    #[serde(flatten)]
+    details: db::FileDetails,
+    /// Replaces all custom metadata when present
+    metadata: Option<BTreeMap<String, String>>,
+}
+
+// An edit only applies on top of the revision the client last saw, named by
+// If-Match with the ETag of a GET, or by `revision` in the body, so two
+// clients editing the same file can't silently undo each other's changes
+async fn update_file(
+    db: State<Repository>,
+    caller: Caller,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(patch): Json<FilePatch>,
+) -> Result<Response, StatusCode> {
+    let current = file_by_id_or_name(&db.0, &caller, &id).await?;
+    let revision = if headers.contains_key(IF_MATCH) {
+        let etag = etag_for_bytes(to_json(&current)?.as_bytes());
+        if !if_match_allows(&headers, &etag) {
+            return Err(StatusCode::PRECONDITION_FAILED);
+        }
+        current.revision
+    } else {
+        patch.revision.ok_or(StatusCode::PRECONDITION_REQUIRED)?
+    };
+    let file = db
+        .update_file_details(
+            &current.id,
+            revision,
+            &patch.details,
+            patch.metadata.as_ref(),
+        )
+        .await
+        .map_err(db_error_status)?;
+    let json_str = to_json(&file)?;
+    Ok(([(ETAG, etag_for_bytes(json_str.as_bytes()))], json_str).into_response())
+}
+
+async fn delete_file(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = file_by_id_or_name(&db.0, &caller, &id).await?;
+    match service::delete_file(&db.0, &storage.0, &file.id).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FileDestination {
+    destination: String,
+}
+
+async fn check_destination(
+    db: &Repository,
+    owner: Option<&str>,
+    destination: &str,
+) -> Result<(), StatusCode> {
+    check_not_file_id(destination)?;
+    match file_name_taken(db, owner, destination).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(StatusCode::CONFLICT),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// The copy shares the original's stored bytes, so nothing is written to storage
+async fn copy_file(
+    db: State<Repository>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+    Json(request): Json<FileDestination>,
+) -> Result<impl IntoResponse, WriteError> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    check_destination(&db.0, file.owner.as_deref(), &request.destination).await?;
+    let copy = db
+        .copy_file(&file.id, &request.destination, timestamp::now())
+        .await?;
+    Ok(Json(copy))
+}
+
+// Storage keys don't depend on file names, so only the rows change
+async fn move_file(
+    db: State<Repository>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+    Json(request): Json<FileDestination>,
+) -> Result<impl IntoResponse, WriteError> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    check_destination(&db.0, file.owner.as_deref(), &request.destination).await?;
+    let renamed = db.rename_file(&file.id, &request.destination).await?;
+    Ok(Json(renamed))
+}
+
+const DEFAULT_SIGNED_URL_TTL_SECONDS: u32 = 15 * 60;
+const MAX_SIGNED_URL_TTL_SECONDS: u32 = 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct PresignUploadRequest {
+    file_name: String,
+    ttl_seconds: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct SignedUrl {
+    method: &'static str,
+    url: String,
+    expires_at: i64,
+}
+
+// The name is checked again on upload; this only spares the client a
+// transfer that would be rejected. The file will belong to the caller.
+async fn presign_upload(
+    db: State<Repository>,
+    signer: State<UrlSigner>,
+    caller: Caller,
+    Json(request): Json<PresignUploadRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    check_destination(&db.0, caller.user.as_deref(), &request.file_name).await?;
+    let ttl_seconds = request
+        .ttl_seconds
+        .unwrap_or(DEFAULT_SIGNED_URL_TTL_SECONDS)
+        .min(MAX_SIGNED_URL_TTL_SECONDS);
+    let expires_at = timestamp::now().saturating_add(i64::from(ttl_seconds));
+    Ok((
+        StatusCode::CREATED,
+        Json(SignedUrl {
+            method: "PUT",
+            url: signer.sign(
+                "/audio",
+                &request.file_name,
+                caller.user.as_deref(),
+                expires_at,
+            ),
+            expires_at,
+        }),
+    ))
+}
+
+async fn list_versions(
+    db: State<Repository>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    match db.list_file_versions(&file.id).await {
+        Ok(versions) => Ok(Json(versions)),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn download_version(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    caller: Caller,
+    Path((file_name, version)): Path<(String, i32)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    let version = match db.find_file_version(&file.id, version).await {
+        Ok(Some(version)) => version,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => return Err(db_error_status(e)),
+    };
+    let stream = storage
+        .stream(&version.storage_key)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            _ => {
+                eprintln!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    Ok(StreamBody::new(compression::decompress(
+        version.compression.as_deref(),
+        stream,
+    )))
+}
+
+// The current content becomes a version in turn, so restoring can be undone
+async fn restore_version(
+    db: State<Repository>,
+    caller: Caller,
+    Path((file_name, version)): Path<(String, i32)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    match db
+        .restore_file_version(&file.id, version, timestamp::now())
+        .await
+    {
+        Ok(file) => Ok(Json(file)),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn reconcile_storage(
+    db: &Repository,
+    storage: &SharedStorage,
+    repair: bool,
+) -> Result<impl IntoResponse, StatusCode> {
+    match reconcile::reconcile(db, storage, repair).await {
+        Ok(drift) => Ok(Json(drift)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// Vacuums the database, refreshes the planner's statistics and, for SQLite,
+// checkpoints the write-ahead log
+async fn maintain_database(db: State<Repository>) -> Result<impl IntoResponse, StatusCode> {
+    match maintenance::maintain(&db.0).await {
+        Ok(run) => Ok(Json(run)),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+// Newest first; see db::AuditQuery for the filters
+async fn list_audit_entries(
+    db: State<Repository>,
+    Query(query): Query<db::AuditQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match db.find_audit_entries(&query).await {
+        Ok(entries) => to_json(&entries),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+// Reports drift between the files table and storage without changing anything
+async fn report_drift(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+) -> Result<impl IntoResponse, StatusCode> {
+    reconcile_storage(&db.0, &storage.0, false).await
+}
+
+// Deletes orphaned objects and the rows of files whose bytes are gone. Check
+// the report first: against a misconfigured audio root every row looks
+// missing.
+async fn repair_drift(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+) -> Result<impl IntoResponse, StatusCode> {
+    reconcile_storage(&db.0, &storage.0, true).await
+}
+
+#[derive(Serialize)]
+struct StorageReport {
+    /// Bytes held in storage, counting shared objects once
+    used_bytes: i64,
+    quota_bytes: Option<u64>,
+    /// Space left on the volume; absent for object stores
+    free_bytes: Option<u64>,
+    by_type: Vec<TypeUsage>,
+}
+
+async fn storage_report(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    uploads: State<UploadConfig>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let used_bytes = db.storage_usage().await.map_err(db_error_status)?;
+    let by_type = db.usage_by_file_type().await.map_err(db_error_status)?;
+    let free_bytes = storage.free_space().await.map_err(|e| {
+        eprintln!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(StorageReport {
+        used_bytes,
+        quota_bytes: uploads.quota_bytes,
+        free_bytes,
+        by_type,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BackupOptions {
+    /// Also archive the stored objects, not just the database and manifest
+    #[serde(default)]
+    objects: bool,
+}
+
+async fn create_backup(
+    storage: State<SharedStorage>,
+    Query(options): Query<BackupOptions>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let database_url = db::database_url();
+    match backup::create_backup(
+        &backup::backup_root(),
+        &database_url,
+        &storage.0,
+        options.objects,
+    )
+    .await
+    {
+        Ok(summary) => Ok((StatusCode::CREATED, Json(summary))),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// Backups snapshot a SQLite file, which PostgreSQL, :memory: and demo mode
+// don't have
+async fn backups_unavailable() -> impl IntoResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        "backups need a SQLite database file",
+    )
+}
+
+async fn list_backups() -> Result<impl IntoResponse, StatusCode> {
+    match backup::list_backups(&backup::backup_root()) {
+        Ok(backups) => Ok(Json(backups)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn restore_backup(
+    storage: State<SharedStorage>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Restoring replaces every row, so it checks for itself rather than
+    // relying on where the route is mounted
+    if !caller.admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let database_url = db::database_url();
+    match backup::restore_backup(&backup::backup_root(), &id, &database_url, &storage.0).await {
+        Ok(summary) => Ok(Json(summary)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(match e {
+                backup::BackupError::NotFound => StatusCode::NOT_FOUND,
+                backup::BackupError::Incompatible(_) => StatusCode::CONFLICT,
+                backup::BackupError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            })
+        }
+    }
+}
+
+async fn download_file(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    caller: Caller,
+    method: Method,
+    Path(file_name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    serve_file(&db.0, &storage.0, method, file, headers).await
+}
+
+async fn download_file_by_id(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    caller: Caller,
+    method: Method,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file = file_by_id(&db.0, &caller, &id).await?;
+    serve_file(&db.0, &storage.0, method, file, headers).await
+}
+
+async fn serve_file(
+    db: &Repository,
+    storage: &SharedStorage,
+    method: Method,
+    file: db::File,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    println!("Reading file: {:?}", file.file_name);
+    // Only files with a row have an object; objects from before sharding are
+    // given keys at startup, and ones without a row by --import-existing
+    let key = file.storage_key.ok_or(StatusCode::NOT_FOUND)?;
+    let codec = file.compression;
+    let info = match storage.stat(&key).await {
+        Ok(info) => info,
+        Err(_) => return Err(StatusCode::NOT_FOUND),
+    };
+    let internal_error = |e: io::Error| {
+        eprintln!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    // The digest recorded at upload is the hash of exactly these bytes; only
+    // rows from before digests were recorded need the object read for it
+    let etag = match file.sha256 {
+        Some(sha256) => format!("\"{}\"", sha256),
+        None => {
+            let stream = storage.stream(&key).await.map_err(internal_error)?;
+            etag_for_stream(compression::decompress(codec.as_deref(), stream))
+                .await
+                .map_err(internal_error)?
+        }
+    };
+    let last_modified = info.modified;
+    let validators = [
+        (ETAG, etag.clone()),
+        (LAST_MODIFIED, httpdate::fmt_http_date(last_modified)),
+    ];
+    if is_not_modified(&headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+    }
+    // Counting is best effort; a failure shouldn't block the download itself.
+    // HEAD is routed here too but doesn't transfer the file.
+    if method == Method::GET {
+        if let Err(e) = db.record_download(&file.id, timestamp::now()).await {
+            eprintln!("{:?}", e);
+        }
+    }
+    let stream = storage.stream(&key).await.map_err(internal_error)?;
+    let body = StreamBody::new(compression::decompress(codec.as_deref(), stream));
+    Ok((validators, body).into_response())
+}
+
+#[cfg(feature = "decoding")]
+#[derive(Debug, Deserialize)]
+struct PeaksOptions {
+    resolution: Option<usize>,
+}
+
+#[cfg(feature = "decoding")]
+#[derive(Debug, Serialize)]
+struct Peaks {
+    resolution: usize,
+    /// (min, max) amplitude per bucket, from -1 to 1
+    peaks: Vec<[f32; 2]>,
+}
+
+// Runs a decoder over a stored file's content on a blocking thread. A decoder
+// finding nothing it can decode means the content isn't audio, or not in a
+// format there's a decoder for.
+#[cfg(feature = "decoding")]
+async fn decode_file<T: Send + 'static>(
+    storage: &SharedStorage,
+    file: &db::File,
+    decode: impl FnOnce(audio::ChannelReader, &str) -> Option<T> + Send + 'static,
+) -> Result<T, StatusCode> {
+    let Some(key) = &file.storage_key else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let stream = match storage.stream(key).await {
+        Ok(stream) => stream,
+        Err(_) => return Err(StatusCode::NOT_FOUND),
+    };
+    let stream = compression::decompress(file.compression.as_deref(), stream);
+    let file_name = file.file_name.clone();
+    match audio::read_blocking(stream, move |reader| decode(reader, &file_name)).await {
+        Ok(Some(decoded)) => Ok(decoded),
+        Ok(None) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(not(feature = "decoding"))]
+async fn decoding_not_built() -> impl IntoResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        "this build has no audio decoding; rebuild with the decoding feature",
+    )
+}
+
+// Audio in a format without a decoder is a limit of the server, not a fault
+// in the file
+#[cfg(feature = "decoding")]
+fn no_decoder_for(file: &db::File) -> Option<Response> {
+    let file_type = file
+        .file_type
+        .as_deref()
+        .or_else(|| audio::file_type_for_name(&file.file_name));
+    audio::is_undecodable_audio(file_type).then(|| {
+        let message = format!("no decoder for {}", file_type.unwrap_or_default());
+        (StatusCode::NOT_IMPLEMENTED, message).into_response()
+    })
+}
+
+// Decoding a long file takes a while, so peaks are kept per content hash and
+// resolution once computed
+#[cfg(feature = "decoding")]
+async fn get_peaks(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+    Query(options): Query<PeaksOptions>,
+) -> Result<Response, StatusCode> {
+    let resolution = options.resolution.unwrap_or(waveform::DEFAULT_RESOLUTION);
+    if resolution == 0 || resolution > waveform::MAX_RESOLUTION {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    if let Some(response) = no_decoder_for(&file) {
+        return Ok(response);
+    }
+    let json = [(CONTENT_TYPE, "application/json")];
+    if let Some(sha256) = &file.sha256 {
+        match db.find_waveform_peaks(sha256, resolution as i32).await {
+            Ok(Some(cached)) => return Ok((json, cached.peaks).into_response()),
+            Ok(None) => {}
+            Err(e) => return Err(db_error_status(e)),
+        }
+    }
+    let peaks = decode_file(&storage.0, &file, move |reader, file_name| {
+        waveform::peaks(reader, file_name, resolution)
+    })
+    .await?;
+    let body = to_json(&Peaks { resolution, peaks })?;
+    if let Some(sha256) = file.sha256 {
+        let cached = db::WaveformPeaks {
+            sha256,
+            resolution: resolution as i32,
+            peaks: body.clone(),
+        };
+        // A failure to cache only costs decoding again next time
+        if let Err(e) = db.insert_waveform_peaks(&cached).await {
+            eprintln!("{:?}", e);
+        }
+    }
+    Ok((json, body).into_response())
+}
+
+// Cached on disk by content hash, like peaks are in the database, as images
+// are too big to want in it
+#[cfg(feature = "decoding")]
+async fn get_spectrogram(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+) -> Result<Response, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    if let Some(response) = no_decoder_for(&file) {
+        return Ok(response);
+    }
+    let png = [(CONTENT_TYPE, "image/png")];
+    let cached = file
+        .sha256
+        .as_deref()
+        .map(|sha256| spectrogram::cache_path(&spectrogram::cache_root(), sha256));
+    if let Some(path) = &cached {
+        if let Ok(image) = tokio::fs::read(path).await {
+            return Ok((png, image).into_response());
+        }
+    }
+    let image = decode_file(&storage.0, &file, spectrogram::render).await?;
+    if let Some(path) = cached {
+        // A failure to cache only costs rendering again next time
+        if let Err(e) = spectrogram::store(&path, &image).await {
+            eprintln!("{:?}", e);
+        }
+    }
+    Ok((png, image).into_response())
+}
+
+const UPLOAD_SESSION_TTL_SECONDS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct UploadSessionRequest {
+    file_name: String,
+    file_type: Option<String>,
+    total_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadSessionStatus {
+    #[serde(flatten)]
+    session: db::UploadSession,
+    received_bytes: i64,
+    /// `[start, end)` byte ranges still to be sent
+    missing: Vec<(i64, i64)>,
+}
+
+async fn upload_session_status(
+    db: &Repository,
+    session: db::UploadSession,
+) -> Result<UploadSessionStatus, StatusCode> {
+    let chunks = db
+        .list_upload_chunks(&session.id)
+        .await
+        .map_err(db_error_status)?;
+    Ok(UploadSessionStatus {
+        received_bytes: chunks.iter().map(|chunk| chunk.byte_length).sum(),
+        missing: upload_session::missing_ranges(&chunks, session.total_size),
+        session,
+    })
+}
+
+// Like review sessions, expired ones are 410 rather than 404
+async fn active_upload_session(db: &Repository, id: &str) -> Result<db::UploadSession, StatusCode> {
+    match db.find_upload_session(id).await {
+        Ok(Some(session)) if session.expires_at > timestamp::now() => Ok(session),
+        Ok(Some(_)) => Err(StatusCode::GONE),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn remove_upload_session(db: &Repository, id: &str) -> Result<(), StatusCode> {
+    db.delete_upload_session(id)
+        .await
+        .map_err(db_error_status)?;
+    upload_session::remove_staged(&upload_session::staging_root(), id).await;
+    Ok(())
+}
+
+// Abandoned sessions are cleared out whenever a new one starts, which keeps
+// the staging directory bounded without a background task
+async fn purge_expired_upload_sessions(db: &Repository) {
+    let expired = match db.find_expired_upload_sessions(timestamp::now()).await {
+        Ok(expired) => expired,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return;
+        }
+    };
+    for session in expired {
+        // Already logged
+        let _ = remove_upload_session(db, &session.id).await;
+    }
+}
+
+async fn create_upload_session(
+    db: State<Repository>,
+    uploads: State<UploadConfig>,
+    caller: Caller,
+    Json(request): Json<UploadSessionRequest>,
+) -> Result<impl IntoResponse, WriteError> {
+    check_destination(&db.0, caller.user.as_deref(), &request.file_name).await?;
+    // Compressed sizes aren't known up front
+    let codec = uploads
+        .compression
+        .codec_for(&request.file_name, request.file_type.as_deref());
+    if let Some(quota) = storage_quota(&db.0, uploads.0).await? {
+        if codec.is_none() && request.total_size > quota.remaining_bytes() {
+            return Err(WriteError::QuotaExceeded(QuotaExceeded(quota)));
+        }
+    }
+    purge_expired_upload_sessions(&db.0).await;
+    let id = upload_session::new_session_id().map_err(|e| {
+        eprintln!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let created_at = timestamp::now();
+    let session = db::UploadSession {
+        id,
+        file_name: request.file_name,
+        file_type: request.file_type,
+        total_size: request.total_size.min(i64::MAX as u64) as i64,
+        created_at,
+        expires_at: created_at.saturating_add(UPLOAD_SESSION_TTL_SECONDS),
+    };
+    db.create_upload_session(&session)
+        .await
+        .map_err(db_error_status)?;
+    let status = upload_session_status(&db.0, session).await?;
+    Ok((StatusCode::CREATED, Json(status)))
+}
+
+async fn get_upload_session(
+    db: State<Repository>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let session = active_upload_session(&db.0, &id).await?;
+    Ok(Json(upload_session_status(&db.0, session).await?))
+}
+
+async fn abort_upload_session(
+    db: State<Repository>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match db.find_upload_session(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => return Err(db_error_status(e)),
+    }
+    remove_upload_session(&db.0, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkOptions {
+    offset: u64,
+}
+
+// Resending a chunk at the same offset replaces it, so a failed chunk can
+// simply be retried
+async fn upload_chunk(
+    db: State<Repository>,
+    progress: State<UploadProgress>,
+    Path(id): Path<String>,
+    Query(options): Query<ChunkOptions>,
+    body: BodyStream,
+) -> Result<impl IntoResponse, StatusCode> {
+    let session = active_upload_session(&db.0, &id).await?;
+    let total_size = session.total_size as u64;
+    if options.offset >= total_size {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let byte_offset = options.offset as i64;
+    let root = upload_session::staging_root();
+    let body = body.map(|bytes| bytes.map_err(io::Error::other));
+    // Counted towards the session's progress until recorded or discarded
+    let tracker = progress.track_chunk(&id);
+    let body = tracker.observe(body);
+    let partial =
+        upload_session::write_chunk(&root, &id, byte_offset, total_size - options.offset, body)
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+                _ => {
+                    eprintln!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+    if partial.length == 0 {
+        upload_session::discard_chunk(partial).await;
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let chunk = db::UploadChunk {
+        session_id: id.clone(),
+        byte_offset,
+        byte_length: partial.length as i64,
+    };
+    if let Err(e) = db.record_upload_chunk(&chunk).await {
+        upload_session::discard_chunk(partial).await;
+        return Err(db_error_status(e));
+    }
+    upload_session::keep_chunk(&root, &id, byte_offset, partial)
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    drop(tracker);
+    Ok(Json(upload_session_status(&db.0, session).await?))
+}
+
+#[derive(Debug, Serialize)]
+struct UploadId {
+    upload_id: String,
+    progress_url: String,
+}
+
+// Issued before an upload starts, so the client can poll its progress while
+// the upload request is still running
+async fn issue_upload_id(progress: State<UploadProgress>) -> Result<impl IntoResponse, StatusCode> {
+    let upload_id = progress.issue().map_err(|e| {
+        eprintln!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let progress_url = format!("/audio/uploads/{}/progress", upload_id);
+    Ok((
+        StatusCode::CREATED,
+        Json(UploadId {
+            upload_id,
+            progress_url,
+        }),
+    ))
+}
+
+// Takes an issued upload id or an upload session id
+async fn upload_progress(
+    db: State<Repository>,
+    progress: State<UploadProgress>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if let Some(found) = progress.get(&id) {
+        return Ok(Json(found));
+    }
+    let session = active_upload_session(&db.0, &id).await?;
+    let chunks = db.list_upload_chunks(&id).await.map_err(db_error_status)?;
+    let recorded: i64 = chunks.iter().map(|chunk| chunk.byte_length).sum();
+    Ok(Json(Progress {
+        received_bytes: recorded as u64 + progress.in_flight(&id),
+        total_bytes: Some(session.total_size as u64),
+        state: UploadState::Receiving,
+    }))
+}
+
+// Streams the chunks through the same path as any other upload, so the file
+// is hashed, compressed and deduplicated as usual
+async fn complete_upload_session(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    uploads: State<UploadConfig>,
+    caller: Caller,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, WriteError> {
+    let checksum = expected_checksum(&headers)?;
+    let session = active_upload_session(&db.0, &id).await?;
+    let chunks = db.list_upload_chunks(&id).await.map_err(db_error_status)?;
+    if !upload_session::missing_ranges(&chunks, session.total_size).is_empty() {
+        return Err(StatusCode::CONFLICT.into());
+    }
+    let upload_request = FileUploadRequest {
+        file_name: resolve_file_name(
+            &db.0,
+            caller.user.as_deref(),
+            session.file_name,
+            DuplicatePolicy::Reject,
+        )
+        .await?,
+        file_type: session.file_type,
+        checksum,
+        ..Default::default()
+    };
+    let quota = storage_quota(&db.0, uploads.0).await?;
+    let assembled = upload_session::assemble(&upload_session::staging_root(), &id, chunks);
+    let written = write_file(&storage.0, uploads.0, quota, &upload_request, assembled)
+        .await
+        .map_err(write_error)?;
+    let response = record_upload(
+        &db.0,
+        &storage.0,
+        upload_request,
+        written,
+        DuplicatePolicy::Reject,
+        None,
+        &caller,
+    )
+    .await?;
+    remove_upload_session(&db.0, &id).await?;
+    Ok(response)
+}
+
+const DEFAULT_REVIEW_TTL_SECONDS: u32 = 7 * 24 * 60 * 60;
+const MAX_REVIEW_TTL_SECONDS: u32 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct ReviewSessionRequest {
+    file_names: Vec<String>,
+    ttl_seconds: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReviewSessionResponse {
+    token: String,
+    expires_at: i64,
+    url: String,
+}
+
+async fn create_review_session(
+    db: State<Repository>,
+    caller: Caller,
+    Json(request): Json<ReviewSessionRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file_names: Vec<String> = request
+        .file_names
+        .into_iter()
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if file_names.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut file_ids = Vec::with_capacity(file_names.len());
+    for file_name in &file_names {
+        file_ids.push(visible_file(&db.0, &caller, file_name).await?.id);
+    }
+    let token = review::new_token().map_err(|e| {
+        eprintln!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let ttl_seconds = request
+        .ttl_seconds
+        .unwrap_or(DEFAULT_REVIEW_TTL_SECONDS)
+        .min(MAX_REVIEW_TTL_SECONDS);
+    let created_at = timestamp::now();
+    let session = db::ReviewSession {
+        token,
+        created_at,
+        expires_at: created_at.saturating_add(i64::from(ttl_seconds)),
+    };
+    if let Err(e) = db.create_review_session(&session, &file_ids).await {
+        return Err(db_error_status(e));
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(ReviewSessionResponse {
+            url: format!("/review-sessions/{}", session.token),
+            token: session.token,
+            expires_at: session.expires_at,
+        }),
+    ))
+}
+
+// Unknown tokens are 404; expired ones are 410 so reviewers can tell the
+// link was real but has run out
+async fn active_review_session(
+    db: &Repository,
+    token: &str,
+) -> Result<db::ReviewSession, StatusCode> {
+    match db.find_review_session(token).await {
+        Ok(Some(session)) if session.expires_at > timestamp::now() => Ok(session),
+        Ok(Some(_)) => Err(StatusCode::GONE),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+async fn view_review_session(
+    db: State<Repository>,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let session = active_review_session(&db.0, &token).await?;
+    let file_ids = db
+        .list_review_session_files(&token)
+        .await
+        .map_err(db_error_status)?;
+    let mut files = db
+        .find_files_by_ids(&file_ids)
+        .await
+        .map_err(db_error_status)?;
+    files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(Html(review::render_page(&session, &files)))
+}
+
+async fn download_review_file(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    method: Method,
+    Path((token, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    active_review_session(&db.0, &token).await?;
+    let file_ids = db
+        .list_review_session_files(&token)
+        .await
+        .map_err(db_error_status)?;
+    if !file_ids.contains(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    match db.find_file_by_id(&id).await {
+        Ok(Some(file)) => serve_file(&db.0, &storage.0, method, file, headers).await,
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+const DEFAULT_SHARE_TTL_SECONDS: u32 = 24 * 60 * 60;
+const MAX_SHARE_TTL_SECONDS: u32 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Default, Deserialize)]
+struct ShareOptions {
+    ttl_seconds: Option<u32>,
+}
+
+async fn share_file(
+    db: State<Repository>,
+    signer: State<UrlSigner>,
+    caller: Caller,
+    Path(file_name): Path<String>,
+    Query(options): Query<ShareOptions>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let file = visible_file(&db.0, &caller, &file_name).await?;
+    let ttl_seconds = options
+        .ttl_seconds
+        .unwrap_or(DEFAULT_SHARE_TTL_SECONDS)
+        .min(MAX_SHARE_TTL_SECONDS);
+    let expires_at = timestamp::now().saturating_add(i64::from(ttl_seconds));
+    Ok((
+        StatusCode::CREATED,
+        Json(SignedUrl {
+            method: "GET",
+            url: signer.sign_file("/shared", &file.id, file.revision, expires_at),
+            expires_at,
+        }),
+    ))
+}
+
+async fn download_shared_file(
+    db: State<Repository>,
+    storage: State<SharedStorage>,
+    signer: State<UrlSigner>,
+    method: Method,
+    Path(id): Path<String>,
+    Query(signature): Query<Signature>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let revision = signer
+        .verify_file(&id, &signature)
+        .ok_or(StatusCode::FORBIDDEN)?;
+    // The link is to the file as it was shared; once it has been edited,
+    // overwritten or deleted the link has nothing left to serve
+    match db.find_file_by_id(&id).await {
+        Ok(Some(file)) if file.revision == revision => {
+            serve_file(&db.0, &storage.0, method, file, headers).await
+        }
+        Ok(_) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(db_error_status(e)),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Capabilities {
+    storage_backend: &'static str,
+    /// Backends compiled into this build
+    storage_backends: Vec<&'static str>,
+    /// Containers whose duration is recorded on upload
+    duration_formats: &'static [&'static str],
+    /// Types waveform peaks and spectrograms can be drawn from
+    decoded_types: &'static [&'static str],
+    /// Whether /admin/backups works, which needs a SQLite database file
+    backups: bool,
+    /// Cargo features compiled into this build
+    features: BTreeMap<&'static str, bool>,
+}
+
+async fn capabilities(storage: State<SharedStorage>, backups: bool) -> impl IntoResponse {
+    let mut storage_backends = vec!["local"];
+    if cfg!(feature = "s3") {
+        storage_backends.push("s3");
+    }
+    Json(Capabilities {
+        storage_backend: storage.backend(),
+        storage_backends,
+        duration_formats: audio::DURATION_FORMATS,
+        decoded_types: audio::DECODED_TYPES,
+        backups,
+        features: BTreeMap::from([
+            ("decoding", cfg!(feature = "decoding")),
+            ("postgres", cfg!(feature = "postgres")),
+            ("s3", cfg!(feature = "s3")),
+            ("sqlx", cfg!(feature = "sqlx")),
+        ]),
+    })
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ComponentStatus {
+    Operational,
+    Degraded,
+}
+
+#[derive(Debug, Serialize)]
+struct ComponentReport {
+    name: &'static str,
+    status: ComponentStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusReport {
+    status: ComponentStatus,
+    components: Vec<ComponentReport>,
+    file_count: Option<i64>,
+}
+
+// Human-oriented summary for a status page; only reports healthy/degraded per
+// component and never exposes error details to unauthenticated callers.
+async fn status(db: State<Repository>, storage: State<SharedStorage>) -> impl IntoResponse {
+    let file_count = match db.count_files().await {
+        Ok(count) => Some(count),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            None
+        }
+    };
+    let storage_ok = match storage.writable().await {
+        Ok(writable) => writable,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            false
+        }
+    };
+    let component = |name, ok| ComponentReport {
+        name,
+        status: if ok {
+            ComponentStatus::Operational
+        } else {
+            ComponentStatus::Degraded
+        },
+    };
+    let components = vec![
+        component("database", file_count.is_some()),
+        component("storage", storage_ok),
+    ];
+    let status = if components
+        .iter()
+        .all(|component| component.status == ComponentStatus::Operational)
+    {
+        ComponentStatus::Operational
+    } else {
+        ComponentStatus::Degraded
+    };
+    Json(StatusReport {
+        status,
+        components,
+        file_count,
+    })
+}
+
+async fn readyz(readiness: State<Readiness>) -> impl IntoResponse {
+    if readiness.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "warming up")
+    }
+}
+
+async fn seed_fixture(args: &[String], fixture: &str) -> Result<seed::Seeded, anyhow::Error> {
+    let db = configure_database(args).await?;
+    let storage = configure_storage(args).await?;
+    let generate_audio = args.iter().any(|arg| arg == "--generate-audio");
+    seed::load_fixture(
+        &db,
+        &storage,
+        StorageLayout::from_env()?,
+        std::path::Path::new(fixture),
+        generate_audio,
+    )
+    .await
+}
+
+/// Every route, with backups only where there is a database file to snapshot.
+fn router(state: AppState, backups: bool) -> Router {
+    let routes = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .route("/status", get(status))
+        .route("/readyz", get(readyz))
+        .route(
+            "/capabilities",
+            get(move |storage| capabilities(storage, backups)),
+        )
+        .route("/audio", get(list_files).post(accept_file_stream))
+        .route(
+            "/audio/json",
+            post(accept_json_upload).layer(DefaultBodyLimit::max(MAX_JSON_UPLOAD_BYTES)),
+        )
+        .route("/audio/fetch", post(fetch_remote_file))
+        .route("/audio/presign", post(presign_upload))
+        .route("/audio/uploads", post(issue_upload_id))
+        .route("/audio/uploads/:id/progress", get(upload_progress))
+        .route(
+            "/audio/validate",
+            post(validate_upload).layer(DefaultBodyLimit::max(MAX_VALIDATE_BYTES)),
+        )
         .route("/audio/query", get(filter_files))
+        .route("/audio/top", get(top_downloads))
         .route("/audio/info/:file_name", get(get_file_info))
         .route("/audio/download/:file_name", get(download_file))
-        .with_state(db)
-        .layer(DefaultBodyLimit::disable());
+        .route("/audio/metadata/:file_name", get(get_custom_metadata))
+        .route("/audio/star/:file_name", post(toggle_star))
+        .route("/audio/tags/:file_name", get(get_file_tags))
+        .route(
+            "/audio/tags/:file_name/:tag",
+            put(tag_file).delete(untag_file),
+        )
+        .route("/audio/:file_name/share", post(share_file))
+        .route("/audio/:file_name/download", get(download_file_by_id))
+        .route("/audio/copy/:file_name", post(copy_file))
+        .route("/audio/move/:file_name", post(move_file))
+        .route("/audio/versions/:file_name", get(list_versions))
+        .route("/audio/versions/:file_name/:version", get(download_version))
+        .route(
+            "/audio/versions/:file_name/:version/restore",
+            post(restore_version),
+        )
+        .route(
+            "/audio/:file_name",
+            put(put_file)
+                .route_layer(middleware::from_fn_with_state(
+                    (state.signer.clone(), state.db.clone()),
+                    signing::verify_upload,
+                ))
+                .get(get_file)
+                .patch(update_file)
+                .delete(delete_file),
+        )
+        .route("/upload-sessions", post(create_upload_session))
+        .route(
+            "/upload-sessions/:id",
+            get(get_upload_session).delete(abort_upload_session),
+        )
+        .route("/upload-sessions/:id/chunks", put(upload_chunk))
+        .route(
+            "/upload-sessions/:id/complete",
+            post(complete_upload_session),
+        )
+        .route("/shared/:id", get(download_shared_file))
+        .route("/review-sessions", post(create_review_session))
+        .route("/review-sessions/:token", get(view_review_session))
+        .route(
+            "/review-sessions/:token/audio/:id",
+            get(download_review_file),
+        );
+    #[cfg(feature = "decoding")]
+    let routes = routes
+        .route("/audio/:file_name/peaks", get(get_peaks))
+        .route("/audio/:file_name/spectrogram.png", get(get_spectrogram));
+    #[cfg(not(feature = "decoding"))]
+    let routes = routes
+        .route("/audio/:file_name/peaks", get(decoding_not_built))
+        .route("/audio/:file_name/spectrogram.png", get(decoding_not_built));
+    let mut admin = Router::new()
+        .route("/admin/storage", get(storage_report))
+        .route("/admin/reconcile", get(report_drift).post(repair_drift))
+        .route("/admin/audit", get(list_audit_entries))
+        .route("/admin/db/maintenance", post(maintain_database));
+    if backups {
+        admin = admin
+            .route("/admin/backups", get(list_backups).post(create_backup))
+            .route("/admin/backups/:id/restore", post(restore_backup));
+    } else {
+        admin = admin
+            .route(
+                "/admin/backups",
+                get(backups_unavailable).post(backups_unavailable),
+            )
+            .route("/admin/backups/:id/restore", post(backups_unavailable));
+    }
+    routes
+        .merge(admin.route_layer(middleware::from_fn_with_state(
+            state.admins.clone(),
+            tenancy::require_admin,
+        )))
+        .layer(middleware::from_fn_with_state(
+            state.db.clone(),
+            audit::record_changes,
+        ))
+        .with_state(state)
+        .layer(DefaultBodyLimit::disable())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("replay") {
+        let recording = args
+            .get(2)
+            .expect("usage: api-server replay <recording> [base_url]");
+        let base_url = args
+            .get(3)
+            .map(String::as_str)
+            .unwrap_or("http://127.0.0.1:8080");
+        if let Err(e) = replay::replay(recording, base_url).await {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    dotenv().ok();
+    if args.get(1).map(String::as_str) == Some("seed") {
+        let fixture = args
+            .get(2)
+            .expect("usage: api-server seed <fixture.json> [--generate-audio]");
+        match seed_fixture(&args, fixture).await {
+            Ok(seeded) => println!(
+                "seeded {} files, skipped {} already present",
+                seeded.inserted, seeded.skipped
+            ),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // Everything in memory with sample files, for trying the API without setup
+    let demo = args.iter().any(|arg| arg == "--demo");
+    let (db, storage): (Repository, SharedStorage) = if demo {
+        println!("demo mode: nothing is persisted");
+        (
+            Arc::new(MemoryRepository::default()),
+            Arc::new(MemoryStorage::default()),
+        )
+    } else {
+        let configured = match configure_database(&args).await {
+            Ok(db) => configure_storage(&args).await.map(|storage| (db, storage)),
+            Err(e) => Err(e),
+        };
+        match configured {
+            Ok(configured) => configured,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let uploads = match UploadConfig::from_env() {
+        Ok(uploads) => uploads,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let signer = match UrlSigner::from_env() {
+        Ok(signer) => signer,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let state = AppState {
+        db,
+        storage,
+        uploads,
+        readiness: Readiness::default(),
+        signer,
+        progress: UploadProgress::default(),
+        admins: Admins::from_env(),
+    };
+    let retention = match retention::RetentionPolicy::from_env() {
+        Ok(retention) => retention,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    };
+    if demo {
+        match demo::seed(&state.db, &state.storage).await {
+            Ok(seeded) => println!("demo mode: seeded {} sample files", seeded),
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+    match import::adopt_legacy_objects(&state.db, &state.storage).await {
+        Ok(0) => {}
+        Ok(adopted) => println!("adopted {} files stored under their names", adopted),
+        Err(e) => eprintln!("{:?}", e),
+    }
+    // Registers audio already in storage, e.g. when adopting an existing archive
+    if args.iter().any(|arg| arg == "--import-existing")
+        || std::env::var("AUDIO_IMPORT_EXISTING").is_ok()
+    {
+        match import::import_existing(&state.db, &state.storage).await {
+            Ok(imported) => println!("imported {} existing files", imported.len()),
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+    // Without warmup the server is ready as soon as it starts
+    if args.iter().any(|arg| arg == "--warmup") || std::env::var("AUDIO_WARMUP").is_ok() {
+        tokio::spawn(warmup::run(
+            state.db.clone(),
+            state.storage.clone(),
+            state.readiness.clone(),
+        ));
+    } else {
+        state.readiness.mark_ready();
+    }
+    tokio::spawn(retention::run(
+        state.db.clone(),
+        state.storage.clone(),
+        retention,
+    ));
+    if let Ok(seconds) = std::env::var("DATABASE_MAINTENANCE_INTERVAL_SECONDS") {
+        match seconds.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => {
+                tokio::spawn(maintenance::run(
+                    state.db.clone(),
+                    Duration::from_secs(seconds),
+                ));
+            }
+            _ => {
+                eprintln!(
+                    "DATABASE_MAINTENANCE_INTERVAL_SECONDS must be a positive number of seconds"
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    // Listing all of storage isn't free, so periodic checks are opt-in
+    if let Ok(seconds) = std::env::var("RECONCILE_INTERVAL_SECONDS") {
+        match seconds.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => {
+                tokio::spawn(reconcile::run(
+                    state.db.clone(),
+                    state.storage.clone(),
+                    Duration::from_secs(seconds),
+                ));
+            }
+            _ => {
+                eprintln!("RECONCILE_INTERVAL_SECONDS must be a positive number of seconds");
+                std::process::exit(1);
+            }
+        }
+    }
+    // Backups snapshot the SQLite database file, which demo mode, in-memory
+    // databases and PostgreSQL deployments don't have
+    let database_url = db::database_url();
+    let backups = !demo && !db::is_postgres_url(&database_url) && !db::is_memory_url(&database_url);
+    let mut app = router(state, backups);
+    if demo {
+        let latency = match demo::latency_from_env() {
+            Ok(latency) => latency,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        };
+        app = app.layer(middleware::from_fn_with_state(
+            latency,
+            demo::simulate_latency,
+        ));
+    }
+    if let Ok(recording) = std::env::var("RECORD_FAILED_REQUESTS") {
+        println!("recording failed requests to {}", recording);
+        app = app.route_layer(middleware::from_fn_with_state(
+            Arc::new(PathBuf::from(recording)),
+            replay::record_failed_requests,
+        ));
+    }
     axum::Server::bind(&"127.0.0.1:8080".parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{Body, HttpBody};
+    use axum::http::Request;
+    use repository::{FileRepository, MemoryRepository};
+    use tower::ServiceExt;
+
+    async fn repository_with(files: &[(&str, &str)]) -> Repository {
+        let repo = MemoryRepository::default();
+        for (file_name, file_type) in files {
+            let file = db::File {
+                file_name: file_name.to_string(),
+                file_type: Some(file_type.to_string()),
+                file_upload_date: 0,
+                title: None,
+                description: None,
+                language: None,
+                file_size: None,
+                duration_ms: None,
+                starred: false,
+                download_count: 0,
+                last_accessed_at: None,
+                storage_key: None,
+                sha256: None,
+                compression: None,
+                stored_size: None,
+                expires_at: None,
+                id: db::new_file_id(),
+                revision: 1,
+                owner: None,
+                sample_rate: None,
+                channels: None,
+                bit_depth: None,
+            };
+            repo.insert_file(&file, &BTreeMap::new()).await.unwrap();
+        }
+        Arc::new(repo)
+    }
+
+    // An in-memory database and storage, with "ops" as the admin
+    fn memory_state() -> AppState {
+        let pool = db::establish_pool(":memory:").unwrap();
+        let state = AppState {
+            db: Arc::new(SqliteRepository::new(pool)),
+            storage: Arc::new(MemoryStorage::default()),
+            uploads: UploadConfig::from_env().unwrap(),
+            readiness: Readiness::default(),
+            signer: UrlSigner::from_env().unwrap(),
+            progress: UploadProgress::default(),
+            admins: Admins::parse("ops"),
+        };
+        state.readiness.mark_ready();
+        state
+    }
+
+    // The full router over an in-memory database, for exercising handlers
+    // through HTTP
+    fn memory_app() -> Router {
+        router(memory_state(), false)
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> Response {
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn body_bytes(response: Response) -> Vec<u8> {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    async fn body_string(response: Response) -> String {
+        String::from_utf8(body_bytes(response).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn names_without_a_row_are_never_read_from_disk() {
+        let root = std::env::temp_dir().join(format!("traversal-{}", db::new_file_id()));
+        let inside = root.join("audio");
+        std::fs::create_dir_all(&inside).unwrap();
+        std::fs::write(root.join("secret.txt"), b"secret").unwrap();
+        std::fs::write(inside.join("stray.wav"), b"stray").unwrap();
+        let state = AppState {
+            storage: Arc::new(LocalStorage::new(inside)),
+            ..memory_state()
+        };
+        let app = router(state, false);
+        for path in [
+            "/audio/download/..%2Fsecret.txt",
+            "/audio/download/%2Fetc%2Fhostname",
+            "/audio/download/stray.wav",
+        ] {
+            let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let response = send(&app, request).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tagging_a_missing_file_is_not_found() {
+        let repo = repository_with(&[]).await;
+        let result = tag_file(
+            State(repo),
+            Caller::default(),
+            Path(("a.wav".into(), "meeting".into())),
+        )
+        .await;
+        assert_eq!(result.err(), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn filters_intersect() {
+        let repo = repository_with(&[("a.wav", "wav"), ("b.wav", "wav"), ("c.mp3", "mp3")]).await;
+        for file_name in ["a.wav", "c.mp3"] {
+            let tagged = tag_file(
+                State(repo.clone()),
+                Caller::default(),
+                Path((file_name.into(), "meeting".into())),
+            )
+            .await;
+            assert!(tagged.is_ok());
+        }
+        let attributes = FileFilterAttributes {
+            file_type: Some("wav".into()),
+            tags: Some("meeting".into()),
+            ..Default::default()
+        };
+        let response = filter_files(
+            State(repo),
+            Caller::default(),
+            Query(attributes),
+            Query(vec![]),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(body_string(response).await, r#"["a.wav"]"#);
+    }
+
+    #[tokio::test]
+    async fn recording_a_taken_name_conflicts() {
+        let repo = repository_with(&[("a.wav", "wav")]).await;
+        let upload_request = FileUploadRequest {
+            file_name: "a.wav".into(),
+            ..Default::default()
+        };
+        let storage: SharedStorage = Arc::new(LocalStorage::new(
+            std::env::temp_dir().join("api-server-tests"),
+        ));
+        let result = record_upload(
+            &repo,
+            &storage,
+            upload_request,
+            WrittenFile::default(),
+            DuplicatePolicy::Reject,
+            None,
+            &Caller::default(),
+        )
+        .await;
+        let response = result.unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["field"], "file_name");
+    }
+
+    #[tokio::test]
+    async fn uploads_are_listed_and_described() {
+        let app = memory_app();
+        let upload = Request::put("/audio/a.wav")
+            .header(CONTENT_TYPE, "audio/wav")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let listing = send(&app, Request::get("/audio").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(listing).await, r#"["a.wav"]"#);
+        let info = Request::get("/audio/info/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        let info: serde_json::Value =
+            serde_json::from_str(&body_string(send(&app, info).await).await).unwrap();
+        assert_eq!(info["file_type"], "audio/wav");
+        assert_eq!(info["file_size"], 4);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "decoding")]
+    async fn peaks_are_computed_once_per_resolution() {
+        let app = memory_app();
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from(demo::sine_wav(440, 1)))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let peaks = |query: &str| {
+            Request::get(format!("/audio/a.wav/peaks{}", query))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let first = body_string(send(&app, peaks("?resolution=50")).await).await;
+        let parsed: serde_json::Value = serde_json::from_str(&first).unwrap();
+        assert_eq!(parsed["peaks"].as_array().unwrap().len(), 50);
+        assert!(parsed["peaks"][0][1].as_f64().unwrap() > 0.0);
+        let again = body_string(send(&app, peaks("?resolution=50")).await).await;
+        assert_eq!(again, first);
+        let response = send(&app, peaks("?resolution=0")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let upload = Request::put("/audio/b.wav")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let response = send(
+            &app,
+            Request::get("/audio/b.wav/peaks")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn each_app_has_its_own_database() {
+        let app = memory_app();
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let listing = Request::get("/audio").body(Body::empty()).unwrap();
+        assert_eq!(body_string(send(&memory_app(), listing).await).await, "[]");
+    }
+
+    #[tokio::test]
+    async fn unconditional_updates_are_refused() {
+        let app = memory_app();
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let info = Request::get("/audio/info/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        let info: serde_json::Value =
+            serde_json::from_str(&body_string(send(&app, info).await).await).unwrap();
+        let update = |body: &str| {
+            Request::patch(format!("/audio/{}", info["id"].as_str().unwrap()))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
+        let response = send(&app, update(r#"{"title":"Standup"}"#)).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+        let response = send(&app, update(r#"{"revision":1,"title":"Standup"}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&app, update(r#"{"revision":1,"title":"Retro"}"#)).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn changes_are_audited() {
+        let app = memory_app();
+        let upload = Request::put("/audio/a.wav?overwrite=true")
+            .header("x-forwarded-user", "ana")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let listing = Request::get("/audio").body(Body::empty()).unwrap();
+        send(&app, listing).await;
+        let update = Request::patch("/audio/a.wav")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"title":"Standup"}"#))
+            .unwrap();
+        send(&app, update).await;
+        let audit = Request::get("/admin/audit")
+            .header("x-forwarded-user", "ops")
+            .body(Body::empty())
+            .unwrap();
+        let audit: Value =
+            serde_json::from_str(&body_string(send(&app, audit).await).await).unwrap();
+        let entries = audit.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["method"], "PATCH");
+        assert_eq!(entries[0]["changes"]["body"]["title"], "Standup");
+        assert_eq!(entries[0]["actor"], Value::Null);
+        assert_eq!(entries[1]["actor"], "ana");
+        assert_eq!(entries[1]["status"], 200);
+        assert_eq!(entries[1]["changes"]["query"]["overwrite"], "true");
+        assert_eq!(entries[1]["changes"]["body"]["bytes"], 4);
+        let by_ana = Request::get("/admin/audit?actor=ana")
+            .header("x-forwarded-user", "ops")
+            .body(Body::empty())
+            .unwrap();
+        let by_ana: Value =
+            serde_json::from_str(&body_string(send(&app, by_ana).await).await).unwrap();
+        assert_eq!(by_ana.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn files_are_scoped_to_their_owner() {
+        let app = memory_app();
+        let request = |method: Method, uri: &str, user: Option<&str>| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(user) = user {
+                request = request.header("x-forwarded-user", user);
+            }
+            request.body(Body::from("RIFF")).unwrap()
+        };
+        for (file_name, user) in [("a.wav", "ana"), ("b.wav", "ben")] {
+            let upload = request(Method::PUT, &format!("/audio/{}", file_name), Some(user));
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        }
+        let listing = |user| send(&app, request(Method::GET, "/audio", user));
+        assert_eq!(
+            body_string(listing(Some("ana")).await).await,
+            r#"["a.wav"]"#
+        );
+        assert_eq!(
+            body_string(listing(Some("ops")).await).await,
+            r#"["a.wav","b.wav"]"#
+        );
+        assert_eq!(body_string(listing(None).await).await, "[]");
+        let download = request(Method::GET, "/audio/download/b.wav", Some("ana"));
+        assert_eq!(send(&app, download).await.status(), StatusCode::NOT_FOUND);
+        let delete = request(Method::DELETE, "/audio/b.wav", Some("ana"));
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NOT_FOUND);
+        let query = request(Method::GET, "/audio/query?file_name=b.wav", Some("ana"));
+        assert_eq!(body_string(send(&app, query).await).await, "[]");
+        // Admins act on anyone's files, which keep their owner
+        let overwrite = request(Method::PUT, "/audio/b.wav?overwrite=true", Some("ops"));
+        assert_eq!(send(&app, overwrite).await.status(), StatusCode::OK);
+        assert_eq!(
+            body_string(listing(Some("ben")).await).await,
+            r#"["b.wav"]"#
+        );
+        let delete = request(Method::DELETE, "/audio/b.wav", Some("ops"));
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn file_names_are_unique_per_owner() {
+        let app = memory_app();
+        let request = |method: Method, uri: &str, user: &str, content: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("x-forwarded-user", user)
+                .body(Body::from(content))
+                .unwrap()
+        };
+        for (user, content) in [("ana", "RIFF ana"), ("ben", "RIFF ben")] {
+            let upload = request(Method::PUT, "/audio/a.wav", user, content);
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        }
+        let again = request(Method::PUT, "/audio/a.wav", "ana", "RIFF again");
+        assert_eq!(send(&app, again).await.status(), StatusCode::CONFLICT);
+        let download = request(Method::GET, "/audio/download/a.wav", "ben", "");
+        assert_eq!(body_string(send(&app, download).await).await, "RIFF ben");
+        // Overwriting, tagging and moving only touch the caller's own file
+        let overwrite = request(
+            Method::PUT,
+            "/audio/a.wav?overwrite=true",
+            "ana",
+            "RIFF new",
+        );
+        assert_eq!(send(&app, overwrite).await.status(), StatusCode::OK);
+        let tag = request(Method::PUT, "/audio/tags/a.wav/mine", "ana", "");
+        assert_eq!(send(&app, tag).await.status(), StatusCode::NO_CONTENT);
+        let tags = request(Method::GET, "/audio/tags/a.wav", "ben", "");
+        assert_eq!(body_string(send(&app, tags).await).await, "[]");
+        let download = request(Method::GET, "/audio/download/a.wav", "ben", "");
+        assert_eq!(body_string(send(&app, download).await).await, "RIFF ben");
+        // An admin has to say by id which of several owners' files is meant
+        let info = request(Method::GET, "/audio/info/a.wav", "ops", "");
+        assert_eq!(send(&app, info).await.status(), StatusCode::CONFLICT);
+        let delete = request(Method::DELETE, "/audio/a.wav", "ana", "");
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        let listing = request(Method::GET, "/audio", "ben", "");
+        assert_eq!(body_string(send(&app, listing).await).await, r#"["a.wav"]"#);
+        let info = request(Method::GET, "/audio/info/a.wav", "ops", "");
+        let file: Value = serde_json::from_str(&body_string(send(&app, info).await).await).unwrap();
+        assert_eq!(file["owner"], "ben");
+    }
+
+    #[tokio::test]
+    async fn admin_routes_are_for_admins_only() {
+        let app = memory_app();
+        for user in [None, Some("ana")] {
+            let mut request = Request::get("/admin/audit");
+            if let Some(user) = user {
+                request = request.header("x-forwarded-user", user);
+            }
+            let response = send(&app, request.body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let request = Request::post("/admin/reconcile")
+            .header("x-forwarded-user", "ana")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::FORBIDDEN);
+        let request = Request::get("/admin/audit")
+            .header("x-forwarded-user", "ops")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn names_without_a_row_are_missing() {
+        let app = memory_app();
+        let tags = |user: &str| {
+            Request::get("/audio/tags/nowhere.wav")
+                .header("x-forwarded-user", user)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(
+            send(&app, tags("ana")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let anonymous = Request::get("/audio/metadata/nowhere.wav")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, anonymous).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            send(&app, tags("ops")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_restore_backups() {
+        let app = router(memory_state(), true);
+        let restore = |user: &str| {
+            Request::post("/admin/backups/0-missing/restore")
+                .header("x-forwarded-user", user)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(
+            send(&app, restore("ana")).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(&app, restore("ops")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn maintenance_reports_the_database_size() {
+        let app = memory_app();
+        let request = Request::post("/admin/db/maintenance")
+            .header("x-forwarded-user", "ops")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let run: Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert!(run["size_after"].as_i64().unwrap() > 0, "{}", run);
+        assert_eq!(run["checkpointed_frames"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn shared_objects_outlive_all_but_the_last_file() {
+        let state = memory_state();
+        let storage = state.storage.clone();
+        let app = router(state, false);
+        for file_name in ["a.wav", "b.wav"] {
+            let upload = Request::put(format!("/audio/{}", file_name))
+                .body(Body::from("RIFF same bytes"))
+                .unwrap();
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        }
+        // The second upload's own copy is deleted in the background
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(storage.list().await.unwrap().len(), 1);
+        let delete = Request::delete("/audio/a.wav").body(Body::empty()).unwrap();
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(storage.list().await.unwrap().len(), 1);
+        let download = Request::get("/audio/download/b.wav")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, download).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "RIFF same bytes");
+        let delete = Request::delete("/audio/b.wav").body(Body::empty()).unwrap();
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleting_frees_the_bytes_counted_against_the_quota() {
+        let app = memory_app();
+        let used_bytes = || async {
+            let report = Request::get("/admin/storage")
+                .header("x-forwarded-user", "ops")
+                .body(Body::empty())
+                .unwrap();
+            let report: Value =
+                serde_json::from_str(&body_string(send(&app, report).await).await).unwrap();
+            report["used_bytes"].as_i64().unwrap()
+        };
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from("RIFF and more"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        assert_eq!(used_bytes().await, 13);
+        let delete = Request::delete("/audio/a.wav").body(Body::empty()).unwrap();
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(used_bytes().await, 0);
+    }
+
+    #[tokio::test]
+    async fn uploads_that_fail_their_checksum_leave_nothing_behind() {
+        let state = memory_state();
+        let storage = state.storage.clone();
+        let app = router(state, false);
+        // The MD5 of no bytes at all
+        let upload = Request::put("/audio/a.wav")
+            .header("content-md5", "1B2M2Y8AsgTpgAmY7PhCfg==")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(
+            send(&app, upload).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        // The written object is deleted in the background
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(storage.list().await.unwrap().is_empty());
+        let listing = Request::get("/audio").body(Body::empty()).unwrap();
+        assert_eq!(body_string(send(&app, listing).await).await, "[]");
+    }
+
+    #[tokio::test]
+    async fn overwritten_content_can_be_restored() {
+        let app = memory_app();
+        for content in ["RIFF first", "RIFF second"] {
+            let upload = Request::put("/audio/a.wav?overwrite=true")
+                .body(Body::from(content))
+                .unwrap();
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        }
+        let versions = Request::get("/audio/versions/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        let versions: Value =
+            serde_json::from_str(&body_string(send(&app, versions).await).await).unwrap();
+        assert_eq!(versions.as_array().unwrap().len(), 1);
+        assert_eq!(versions[0]["version"], 1);
+        let restore = Request::post("/audio/versions/a.wav/1/restore")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, restore).await.status(), StatusCode::OK);
+        let download = Request::get("/audio/download/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(body_string(send(&app, download).await).await, "RIFF first");
+        // What was replaced by the restore is kept as a version in turn
+        let old = Request::get("/audio/versions/a.wav/2")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(body_string(send(&app, old).await).await, "RIFF second");
+        let missing = Request::post("/audio/versions/a.wav/9/restore")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, missing).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn files_are_copied_and_moved_under_new_names() {
+        let app = memory_app();
+        let json = |uri: &str, body: &str| {
+            Request::post(uri)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
+        let download = |file_name: &str| {
+            Request::get(format!("/audio/download/{}", file_name))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let copy = json("/audio/copy/a.wav", r#"{"destination":"b.wav"}"#);
+        assert_eq!(send(&app, copy).await.status(), StatusCode::OK);
+        let copy = json("/audio/copy/a.wav", r#"{"destination":"b.wav"}"#);
+        assert_eq!(send(&app, copy).await.status(), StatusCode::CONFLICT);
+        let moved = json("/audio/move/b.wav", r#"{"destination":"c.wav"}"#);
+        assert_eq!(send(&app, moved).await.status(), StatusCode::OK);
+        let listing = Request::get("/audio").body(Body::empty()).unwrap();
+        assert_eq!(
+            body_string(send(&app, listing).await).await,
+            r#"["a.wav","c.wav"]"#
+        );
+        assert_eq!(
+            body_string(send(&app, download("c.wav")).await).await,
+            "RIFF"
+        );
+        assert_eq!(
+            send(&app, download("b.wav")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let moved = json("/audio/move/b.wav", r#"{"destination":"d.wav"}"#);
+        assert_eq!(send(&app, moved).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn chunks_sent_out_of_order_are_assembled_into_one_file() {
+        let app = memory_app();
+        let create = Request::post("/upload-sessions")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"file_name":"a.wav","total_size":10}"#))
+            .unwrap();
+        let response = send(&app, create).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let session: Value = serde_json::from_str(&body_string(response).await).unwrap();
+        let id = session["id"].as_str().unwrap().to_owned();
+        let chunk = |offset: u64, bytes: &'static str| {
+            Request::put(format!("/upload-sessions/{}/chunks?offset={}", id, offset))
+                .body(Body::from(bytes))
+                .unwrap()
+        };
+        let complete = || {
+            Request::post(format!("/upload-sessions/{}/complete", id))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = send(&app, chunk(5, "world")).await;
+        let status: Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(status["missing"], serde_json::json!([[0, 5]]));
+        assert_eq!(send(&app, complete()).await.status(), StatusCode::CONFLICT);
+        assert_eq!(send(&app, chunk(0, "RIFF ")).await.status(), StatusCode::OK);
+        assert_eq!(send(&app, complete()).await.status(), StatusCode::OK);
+        let download = Request::get("/audio/download/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(body_string(send(&app, download).await).await, "RIFF world");
+        // The session is gone once its file is stored
+        assert_eq!(send(&app, complete()).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn reconciling_repairs_drift_between_rows_and_storage() {
+        let root = std::env::temp_dir().join(format!("reconcile-{}", db::new_file_id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let state = AppState {
+            storage: Arc::new(LocalStorage::new(root.clone())),
+            ..memory_state()
+        };
+        let storage = state.storage.clone();
+        let app = router(state, false);
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from("RIFF a"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let before = storage.list().await.unwrap();
+        let upload = Request::put("/audio/b.wav")
+            .body(Body::from("RIFF b"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let (b_key, _) = storage
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|(key, _)| before.iter().all(|(known, _)| known != key))
+            .unwrap();
+        storage.delete(&b_key).await.unwrap();
+        // Only orphans older than the grace period count
+        let stale = std::fs::File::create(root.join("stale.wav")).unwrap();
+        stale
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+            .unwrap();
+        std::fs::write(root.join("fresh.wav"), b"RIFF").unwrap();
+        let reconcile = |method: Method| {
+            Request::builder()
+                .method(method)
+                .uri("/admin/reconcile")
+                .header("x-forwarded-user", "ops")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let drift: Value =
+            serde_json::from_str(&body_string(send(&app, reconcile(Method::GET)).await).await)
+                .unwrap();
+        assert_eq!(drift["orphaned_objects"], serde_json::json!(["stale.wav"]));
+        assert_eq!(drift["missing_objects"], serde_json::json!(["b.wav"]));
+        assert_eq!(drift["repaired"], false);
+        let drift: Value =
+            serde_json::from_str(&body_string(send(&app, reconcile(Method::POST)).await).await)
+                .unwrap();
+        assert_eq!(drift["repaired"], true);
+        assert!(!root.join("stale.wav").exists());
+        assert!(root.join("fresh.wav").exists());
+        let listing = Request::get("/audio").body(Body::empty()).unwrap();
+        assert_eq!(body_string(send(&app, listing).await).await, r#"["a.wav"]"#);
+        let drift: Value =
+            serde_json::from_str(&body_string(send(&app, reconcile(Method::GET)).await).await)
+                .unwrap();
+        assert_eq!(drift["orphaned_objects"], serde_json::json!([]));
+        assert_eq!(drift["missing_objects"], serde_json::json!([]));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_retention_sweep_purges_expired_uploads() {
+        let state = memory_state();
+        let (db, storage) = (state.db.clone(), state.storage.clone());
+        let app = router(state, false);
+        for (uri, content) in [
+            ("/audio/a.wav?ttl_seconds=0", "RIFF a"),
+            ("/audio/b.wav?ttl_seconds=3600", "RIFF b"),
+            ("/audio/c.wav", "RIFF c"),
+        ] {
+            let upload = Request::put(uri).body(Body::from(content)).unwrap();
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        }
+        // Another owner's file by the same name isn't expiring
+        let theirs = Request::put("/audio/a.wav")
+            .header("x-forwarded-user", "ben")
+            .body(Body::from("RIFF ben"))
+            .unwrap();
+        assert_eq!(send(&app, theirs).await.status(), StatusCode::OK);
+        let policy = retention::RetentionPolicy {
+            file_type_ttls: BTreeMap::new(),
+            sweep_interval: Duration::from_secs(60),
+        };
+        let purged = retention::sweep(&db, &storage, &policy).await.unwrap();
+        assert_eq!(purged, ["a.wav"]);
+        let listing = Request::get("/audio").body(Body::empty()).unwrap();
+        assert_eq!(
+            body_string(send(&app, listing).await).await,
+            r#"["b.wav","c.wav"]"#
+        );
+        let theirs = Request::get("/audio")
+            .header("x-forwarded-user", "ben")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(body_string(send(&app, theirs).await).await, r#"["a.wav"]"#);
+        assert_eq!(storage.list().await.unwrap().len(), 3);
+        let audit = Request::get("/admin/audit?actor=retention")
+            .header("x-forwarded-user", "ops")
+            .body(Body::empty())
+            .unwrap();
+        let audit: Value =
+            serde_json::from_str(&body_string(send(&app, audit).await).await).unwrap();
+        assert_eq!(audit[0]["method"], "DELETE");
+    }
+
+    #[tokio::test]
+    async fn file_names_cannot_pass_for_ids() {
+        let app = memory_app();
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let info = Request::get("/audio/info/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        let file: Value = serde_json::from_str(&body_string(send(&app, info).await).await).unwrap();
+        let id = file["id"].as_str().unwrap().to_owned();
+        let upload = Request::put(format!("/audio/{}", id))
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::BAD_REQUEST);
+        let copy = Request::post("/audio/copy/a.wav")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"destination":"{}"}}"#, id)))
+            .unwrap();
+        assert_eq!(send(&app, copy).await.status(), StatusCode::BAD_REQUEST);
+        // So deleting by id only ever means the file with that id
+        let delete = Request::delete(format!("/audio/{}", id))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        let listing = Request::get("/audio").body(Body::empty()).unwrap();
+        assert_eq!(body_string(send(&app, listing).await).await, "[]");
+    }
+
+    #[tokio::test]
+    async fn operations_this_server_cannot_do_are_not_implemented() {
+        let app = memory_app();
+        let capabilities = Request::get("/capabilities").body(Body::empty()).unwrap();
+        let capabilities: serde_json::Value =
+            serde_json::from_str(&body_string(send(&app, capabilities).await).await).unwrap();
+        assert_eq!(capabilities["backups"], false);
+        assert_eq!(capabilities["features"]["sqlx"], cfg!(feature = "sqlx"));
+        let backup = Request::post("/admin/backups")
+            .header("x-forwarded-user", "ops")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, backup).await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(
+            body_string(response).await,
+            "backups need a SQLite database file"
+        );
+        let upload = Request::put("/audio/a.m4a")
+            .body(Body::from("ftyp"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        for path in ["/audio/a.m4a/peaks", "/audio/a.m4a/spectrogram.png"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            let response = send(&app, request).await;
+            assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_only_reach_public_addresses() {
+        let app = memory_app();
+        for url in [
+            "http://127.0.0.1:8080/audio/download/a.wav",
+            "http://localhost/a.wav",
+            "http://[::1]/a.wav",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.1/a.wav",
+        ] {
+            let fetch = Request::post("/audio/fetch")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::json!({ "url": url }).to_string()))
+                .unwrap();
+            assert_eq!(
+                send(&app, fetch).await.status(),
+                StatusCode::BAD_REQUEST,
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn equally_downloaded_files_rank_by_name() {
+        let repo = repository_with(&[("c.wav", "wav"), ("a.wav", "wav"), ("b.wav", "wav")]).await;
+        let b = repo.find_file_by_file_name("b.wav").await.unwrap();
+        repo.record_download(&b[0].id, 0).await.unwrap();
+        let ranked: Vec<String> = repo
+            .most_downloaded(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|file| file.file_name)
+            .collect();
+        assert_eq!(ranked, ["b.wav", "a.wav", "c.wav"]);
+    }
+
+    #[tokio::test]
+    async fn searches_rank_titles_first_and_follow_edits() {
+        let app = memory_app();
+        let mut ids = BTreeMap::new();
+        for file_name in ["a.wav", "b.wav"] {
+            let upload = Request::put(format!("/audio/{}", file_name))
+                .body(Body::from("RIFF"))
+                .unwrap();
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+            let info = Request::get(format!("/audio/info/{}", file_name))
+                .body(Body::empty())
+                .unwrap();
+            let info: serde_json::Value =
+                serde_json::from_str(&body_string(send(&app, info).await).await).unwrap();
+            ids.insert(file_name, info["id"].as_str().unwrap().to_owned());
+        }
+        let edit = |file_name: &str, revision: i32, details: serde_json::Value| {
+            let mut body = details;
+            body["revision"] = revision.into();
+            Request::patch(format!("/audio/{}", ids[file_name]))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let search = |q: &str| {
+            Request::get(format!("/audio/query?q={}", q))
+                .body(Body::empty())
+                .unwrap()
+        };
+        // Titled b.wav ranks ahead of described a.wav, though ties go by name
+        let described = edit("a.wav", 1, serde_json::json!({ "description": "standup" }));
+        assert_eq!(send(&app, described).await.status(), StatusCode::OK);
+        let titled = edit("b.wav", 1, serde_json::json!({ "title": "standup" }));
+        assert_eq!(send(&app, titled).await.status(), StatusCode::OK);
+        assert_eq!(
+            body_string(send(&app, search("standup")).await).await,
+            r#"["b.wav","a.wav"]"#
+        );
+
+        let edited = edit("a.wav", 2, serde_json::json!({ "description": "retro" }));
+        assert_eq!(send(&app, edited).await.status(), StatusCode::OK);
+        assert_eq!(
+            body_string(send(&app, search("standup")).await).await,
+            r#"["b.wav"]"#
+        );
+        assert_eq!(
+            body_string(send(&app, search("retro")).await).await,
+            r#"["a.wav"]"#
+        );
+
+        let delete = Request::delete(format!("/audio/{}", ids["b.wav"]))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(body_string(send(&app, search("standup")).await).await, "[]");
+    }
+
+    #[tokio::test]
+    async fn compressed_uploads_serve_their_original_bytes() {
+        let mut state = memory_state();
+        state.uploads.compression = Compression::Zstd;
+        let (db, storage) = (state.db.clone(), state.storage.clone());
+        let app = router(state, false);
+        let original = vec![7u8; 64 * 1024];
+        // Different bytes each, so they don't share an object
+        for (file_name, byte) in [("a.wav", 7), ("a.mp3", 8)] {
+            let upload = Request::put(format!("/audio/{}", file_name))
+                .body(Body::from(vec![byte; original.len()]))
+                .unwrap();
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        }
+
+        let file = db.find_file_by_file_name("a.wav").await.unwrap().remove(0);
+        assert_eq!(file.compression.as_deref(), Some("zstd"));
+        assert_eq!(file.file_size, Some(original.len() as i64));
+        let stored = storage
+            .get(file.storage_key.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(file.stored_size, Some(stored.len() as i64));
+        assert!(stored.len() < original.len() / 100);
+        let download = Request::get("/audio/download/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, download).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, original);
+
+        // MP3 is compressed already, so it is stored as sent
+        let file = db.find_file_by_file_name("a.mp3").await.unwrap().remove(0);
+        assert_eq!(file.compression, None);
+        assert_eq!(file.stored_size, Some(original.len() as i64));
+    }
+
+    #[tokio::test]
+    async fn top_download_limits_must_not_be_negative() {
+        let app = memory_app();
+        for file_name in ["a.wav", "b.wav"] {
+            let upload = Request::put(format!("/audio/{}", file_name))
+                .body(Body::from(file_name))
+                .unwrap();
+            assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        }
+        let top = |query: &str| {
+            Request::get(format!("/audio/top{}", query))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = send(&app, top("?limit=-1")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let top: serde_json::Value =
+            serde_json::from_str(&body_string(send(&app, top("?limit=1")).await).await).unwrap();
+        assert_eq!(top.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn downloads_are_tagged_with_the_recorded_digest() {
+        let state = memory_state();
+        let (db, storage) = (state.db.clone(), state.storage.clone());
+        let app = router(state, false);
+        let upload = Request::put("/audio/a.wav")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let file = db.find_file_by_file_name("a.wav").await.unwrap().remove(0);
+        let etag = format!("\"{}\"", file.sha256.unwrap());
+        assert_eq!(etag, etag_for_bytes(b"RIFF"));
+        // Replaced behind the row's back, so a tag hashed from storage would
+        // differ
+        let key = file.storage_key.unwrap();
+        let replaced = futures::stream::once(async { Ok(Bytes::from("WAVE")) }).boxed();
+        storage.put(&key, replaced).await.unwrap();
+        let download = Request::get("/audio/download/a.wav")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, download).await;
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+        let revalidate = Request::get("/audio/download/a.wav")
+            .header(axum::http::header::IF_NONE_MATCH, &etag)
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, revalidate).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_are_reserved_per_caller() {
+        let state = memory_state();
+        let db = state.db.clone();
+        let app = router(state, false);
+        let upload = |user: &str, file_name: &str, key: &str| {
+            Request::put(format!("/audio/{}", file_name))
+                .header("x-forwarded-user", user)
+                .header("idempotency-key", key)
+                .body(Body::from(file_name.to_owned()))
+                .unwrap()
+        };
+        let first = send(&app, upload("ann", "a.wav", "k1")).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let first = body_string(first).await;
+        // A retry is answered with the first response and writes nothing
+        let retry = send(&app, upload("ann", "again.wav", "k1")).await;
+        assert_eq!(body_string(retry).await, first);
+        assert!(db
+            .find_file_by_file_name("again.wav")
+            .await
+            .unwrap()
+            .is_empty());
+        // Someone else's key of the same name is theirs
+        let other = send(&app, upload("bob", "b.wav", "k1")).await;
+        assert_eq!(other.status(), StatusCode::OK);
+        let other = body_string(other).await;
+        assert!(
+            other.contains("b.wav") && !other.contains("a.wav"),
+            "{}",
+            other
+        );
+
+        // While an upload holds its key, a concurrent retry is refused
+        let mut reservation = db::IdempotencyKey {
+            owner: "ann".to_owned(),
+            idempotency_key: "k2".to_owned(),
+            response: None,
+            created_at: timestamp::now(),
+        };
+        db.insert_idempotency_key(&reservation).await.unwrap();
+        let racing = send(&app, upload("ann", "c.wav", "k2")).await;
+        assert_eq!(racing.status(), StatusCode::CONFLICT);
+        assert!(db.find_file_by_file_name("c.wav").await.unwrap().is_empty());
+        // but one abandoned long ago is taken over
+        reservation.idempotency_key = "k3".to_owned();
+        reservation.created_at = 0;
+        db.insert_idempotency_key(&reservation).await.unwrap();
+        let taken_over = send(&app, upload("ann", "c.wav", "k3")).await;
+        assert_eq!(taken_over.status(), StatusCode::OK);
+
+        // A failed upload releases its key for the retry
+        let failing = Request::put("/audio/d.wav")
+            .header("x-forwarded-user", "ann")
+            .header("idempotency-key", "k4")
+            .header("content-md5", "1B2M2Y8AsgTpgAmY7PhCfg==")
+            .body(Body::from("RIFF"))
+            .unwrap();
+        assert_eq!(
+            send(&app, failing).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let retry = send(&app, upload("ann", "d.wav", "k4")).await;
+        assert_eq!(retry.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn share_links_do_not_follow_a_reused_name() {
+        let app = memory_app();
+        let request = |method: Method, uri: &str, body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("x-forwarded-user", "ann")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let share = || async {
+            let response = send(&app, request(Method::POST, "/audio/a.wav/share", "")).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let link: Value = serde_json::from_str(&body_string(response).await).unwrap();
+            link["url"].as_str().unwrap().to_owned()
+        };
+        let upload = request(Method::PUT, "/audio/a.wav", "first");
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        let first = share().await;
+        let download = |url: &str| send(&app, Request::get(url).body(Body::empty()).unwrap());
+        assert_eq!(body_string(download(&first).await).await, "first");
+
+        let delete = request(Method::DELETE, "/audio/a.wav", "");
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let upload = request(Method::PUT, "/audio/a.wav", "second");
+        assert_eq!(send(&app, upload).await.status(), StatusCode::OK);
+        assert_eq!(download(&first).await.status(), StatusCode::NOT_FOUND);
+        let second = share().await;
+        assert_ne!(first, second);
+        assert_eq!(body_string(download(&second).await).await, "second");
+
+        // Nor does a link outlive an edit to the file it was made for
+        let id = second["/shared/".len()..].split('?').next().unwrap();
+        let edit = Request::patch(format!("/audio/{}", id))
+            .header("x-forwarded-user", "ann")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"revision":1,"title":"Retro"}"#))
+            .unwrap();
+        assert_eq!(send(&app, edit).await.status(), StatusCode::OK);
+        assert_eq!(download(&second).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn signed_upload_urls_are_single_use_and_owned_by_their_issuer() {
+        let state = memory_state();
+        let db = state.db.clone();
+        let app = router(state, false);
+        let presign = Request::post("/audio/presign")
+            .header("x-forwarded-user", "ann")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"file_name":"a.wav"}"#))
+            .unwrap();
+        let response = send(&app, presign).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let signed: Value = serde_json::from_str(&body_string(response).await).unwrap();
+        let url = signed["url"].as_str().unwrap().to_owned();
+        let upload = |uri: String, content_md5: Option<&str>| {
+            let mut request = Request::put(uri).header("x-forwarded-user", "bob");
+            if let Some(content_md5) = content_md5 {
+                request = request.header("content-md5", content_md5);
+            }
+            request.body(Body::from("RIFF")).unwrap()
+        };
+
+        // Options the signature doesn't cover can't be added to it
+        for option in ["overwrite=true", "rename=true", "ttl_seconds=5"] {
+            let response = send(&app, upload(format!("{}&{}", url, option), None)).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", option);
+        }
+        // A failed upload leaves the URL usable
+        let failing = upload(url.clone(), Some("1B2M2Y8AsgTpgAmY7PhCfg=="));
+        assert_eq!(
+            send(&app, failing).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            send(&app, upload(url.clone(), None)).await.status(),
+            StatusCode::OK
+        );
+        let files = db.find_file_by_file_name("a.wav").await.unwrap();
+        assert_eq!(files[0].owner.as_deref(), Some("ann"));
+        // but a successful one spends it
+        let delete = Request::delete("/audio/a.wav")
+            .header("x-forwarded-user", "ann")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            send(&app, upload(url, None)).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use crate::db::{DbError, MaintenanceReport};
+use crate::repository::Repository;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+// Space freed by deleted and overwritten rows stays in the database file
+// until it is vacuumed, so a long-running instance's file only grows. A run
+// vacuums, refreshes the query planner's statistics and, for SQLite,
+// truncates the write-ahead log. Runs are on demand from the admin endpoint,
+// or every DATABASE_MAINTENANCE_INTERVAL_SECONDS when that is set.
+
+#[derive(Debug, Serialize)]
+pub struct MaintenanceRun {
+    #[serde(flatten)]
+    pub report: MaintenanceReport,
+    pub duration_ms: u64,
+}
+
+pub async fn maintain(db: &Repository) -> Result<MaintenanceRun, DbError> {
+    let started = Instant::now();
+    let report = db.run_maintenance().await?;
+    Ok(MaintenanceRun {
+        report,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+/// Runs maintenance on an interval for as long as the server runs.
+pub async fn run(db: Repository, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick is immediate, and startup has enough going on
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match maintain(&db).await {
+            Ok(run) => println!(
+                "maintenance: reclaimed {} bytes in {} ms",
+                run.report.reclaimed_bytes, run.duration_ms
+            ),
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+}
@@ -0,0 +1,1206 @@
+use crate::blobs::{
+    acquire_blob, add_reference, adopt_legacy_object, release_file, restore_version,
+    retire_replaced, BlobQueries,
+};
+use crate::db::{
+    align_shared_objects, new_file_id, AuditEntry, AuditQuery, BlobChanges, ConnectRetry,
+    DatabaseSize, DbError, File, FileDetails, FileVersion, IdempotencyKey, MaintenanceReport,
+    NewAuditEntry, ReviewSession, SearchMatch, TypeUsage, UploadChunk, UploadSession,
+    WaveformPeaks, INSERT_BATCH_ROWS,
+};
+use crate::repository::FileRepository;
+use crate::schema::{
+    audit_log, blobs, consumed_signatures, file_metadata, file_tags, file_versions, files,
+    idempotency_keys, review_session_files, review_sessions, storage_usage, tags, upload_chunks,
+    upload_sessions, waveform_peaks,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
+use diesel_async::pooled_connection::deadpool::{Object, Pool};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::collections::BTreeMap;
+
+// The same queries as the SQLite repository, for deployments that need
+// concurrent writers or a hosted database. Selected by a postgres:// or
+// postgresql:// DATABASE_URL; the schema is in migrations-postgres.
+
+pub type PgPool = Pool<AsyncPgConnection>;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations-postgres");
+
+/// Applies the migrations the database hasn't had yet, returning how many,
+/// once the database accepts connections. Blocks, so it must not run on an
+/// async worker thread.
+pub fn run_migrations(database_url: &str) -> Result<usize, anyhow::Error> {
+    let mut conn = ConnectRetry::from_env()?.connect(|| {
+        <AsyncConnectionWrapper<AsyncPgConnection> as diesel::Connection>::establish(database_url)
+    })?;
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(anyhow::Error::msg)?;
+    Ok(applied.len())
+}
+
+/// Connections for [`PgRepository`], at most DATABASE_POOL_SIZE of them.
+pub fn establish_pool(database_url: &str) -> Result<PgPool, anyhow::Error> {
+    let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(database_url);
+    let mut builder = Pool::builder(manager);
+    if let Ok(size) = std::env::var("DATABASE_POOL_SIZE") {
+        let size = size
+            .parse()
+            .context("DATABASE_POOL_SIZE must be a number of connections")?;
+        builder = builder.max_size(size);
+    }
+    Ok(builder.build()?)
+}
+
+/// Diesel/PostgreSQL implementation of [`FileRepository`].
+pub struct PgRepository {
+    pool: PgPool,
+    /// A read replica's connections, serving listings, filters and searches
+    read_pool: Option<PgPool>,
+}
+
+impl PgRepository {
+    pub fn new(pool: PgPool) -> Self {
+        PgRepository {
+            pool,
+            read_pool: None,
+        }
+    }
+
+    /// Sends the queries [`FileRepository`] says may read a replica to
+    /// `read_pool`, so they don't hold up writes.
+    pub fn with_read_pool(self, read_pool: PgPool) -> Self {
+        PgRepository {
+            read_pool: Some(read_pool),
+            ..self
+        }
+    }
+
+    async fn conn(&self) -> Result<Object<AsyncPgConnection>, DbError> {
+        Ok(self.pool.get().await?)
+    }
+
+    async fn read_conn(&self) -> Result<Object<AsyncPgConnection>, DbError> {
+        Ok(self.read_pool.as_ref().unwrap_or(&self.pool).get().await?)
+    }
+}
+
+// Transactions run on the pooled connection itself
+#[async_trait]
+impl BlobQueries for Object<AsyncPgConnection> {
+    type Error = diesel::result::Error;
+
+    // Reference counts are read and then written, so transactions that
+    // change them take turns, as every writer does under SQLite
+    async fn lock_blobs(&mut self) -> QueryResult<()> {
+        diesel::sql_query("LOCK TABLE blobs IN SHARE ROW EXCLUSIVE MODE")
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn blob_with_sha256(&mut self, sha256: &str) -> QueryResult<Option<String>> {
+        blobs::table
+            .filter(blobs::sha256.eq(sha256))
+            .select(blobs::storage_key)
+            .first(self)
+            .await
+            .optional()
+    }
+
+    async fn stored_format(
+        &mut self,
+        storage_key: &str,
+    ) -> QueryResult<Option<(Option<String>, Option<i64>)>> {
+        files::table
+            .filter(files::storage_key.eq(storage_key))
+            .select((files::compression, files::stored_size))
+            .first(self)
+            .await
+            .optional()
+    }
+
+    async fn add_references(&mut self, storage_key: &str, delta: i64) -> QueryResult<bool> {
+        let updated = diesel::update(blobs::table.find(storage_key))
+            .set(blobs::ref_count.eq(blobs::ref_count + delta))
+            .execute(self)
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn insert_blob(
+        &mut self,
+        storage_key: &str,
+        sha256: Option<&str>,
+        stored_size: i64,
+    ) -> QueryResult<()> {
+        diesel::insert_into(blobs::table)
+            .values((
+                blobs::storage_key.eq(storage_key),
+                blobs::sha256.eq(sha256),
+                blobs::ref_count.eq(1),
+                blobs::stored_size.eq(stored_size),
+            ))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn unreferenced_size(&mut self, storage_key: &str) -> QueryResult<Option<i64>> {
+        blobs::table
+            .find(storage_key)
+            .filter(blobs::ref_count.le(0))
+            .select(blobs::stored_size)
+            .first(self)
+            .await
+            .optional()
+    }
+
+    async fn delete_blob(&mut self, storage_key: &str) -> QueryResult<()> {
+        diesel::delete(blobs::table.find(storage_key))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_storage_usage(&mut self, bytes: i64) -> QueryResult<()> {
+        diesel::update(storage_usage::table)
+            .set(storage_usage::used_bytes.eq(storage_usage::used_bytes + bytes))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn latest_version(&mut self, file_id: &str) -> QueryResult<Option<i32>> {
+        file_versions::table
+            .filter(file_versions::file_id.eq(file_id))
+            .select(diesel::dsl::max(file_versions::version))
+            .first(self)
+            .await
+    }
+
+    async fn insert_version(&mut self, version: &FileVersion) -> QueryResult<()> {
+        diesel::insert_into(file_versions::table)
+            .values(version)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+}
+
+// Replaces the file's custom metadata with `metadata`
+async fn replace_metadata(
+    conn: &mut AsyncPgConnection,
+    file_id: &str,
+    metadata: &BTreeMap<String, String>,
+) -> QueryResult<()> {
+    diesel::delete(file_metadata::table.filter(file_metadata::file_id.eq(file_id)))
+        .execute(conn)
+        .await?;
+    for (key, value) in metadata {
+        diesel::insert_into(file_metadata::table)
+            .values((
+                file_metadata::file_id.eq(file_id),
+                file_metadata::meta_key.eq(key),
+                file_metadata::meta_value.eq(value),
+            ))
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl FileRepository for PgRepository {
+    async fn insert_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError> {
+        let mut file = file.clone();
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    acquire_blob(conn, &mut file).await?;
+                    diesel::insert_into(files::table)
+                        .values(&file)
+                        .execute(conn)
+                        .await?;
+                    replace_metadata(conn, &file.id, metadata).await?;
+                    Ok(BlobChanges {
+                        storage_key: file.storage_key,
+                        compression: file.compression,
+                        stored_size: file.stored_size,
+                        id: Some(file.id),
+                        revision: Some(file.revision),
+                        unreferenced: vec![],
+                    })
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn insert_files(
+        &self,
+        files: &[(File, BTreeMap<String, String>)],
+    ) -> Result<Vec<BlobChanges>, DbError> {
+        let mut rows = files
+            .iter()
+            .map(|(file, _)| file.clone())
+            .collect::<Vec<_>>();
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    for row in &mut rows {
+                        acquire_blob(conn, row).await?;
+                    }
+                    align_shared_objects(&mut rows);
+                    for batch in rows.chunks(INSERT_BATCH_ROWS) {
+                        diesel::insert_into(files::table)
+                            .values(batch)
+                            .execute(conn)
+                            .await?;
+                    }
+                    for (file, metadata) in files {
+                        replace_metadata(conn, &file.id, metadata).await?;
+                    }
+                    Ok(rows.into_iter().map(BlobChanges::inserted).collect())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn replace_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError> {
+        let mut file = file.clone();
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let previous = files::table
+                        .filter(files::owner.is_not_distinct_from(file.owner.as_deref()))
+                        .filter(files::file_name.eq(&file.file_name))
+                        .first::<File>(conn)
+                        .await
+                        .optional()?;
+                    if let Some(previous) = &previous {
+                        file.id = previous.id.clone();
+                        file.revision = previous.revision + 1;
+                    }
+                    acquire_blob(conn, &mut file).await?;
+                    if previous.is_some() {
+                        diesel::update(files::table.find(&file.id))
+                            .set(&file)
+                            .execute(conn)
+                            .await?;
+                    } else {
+                        diesel::insert_into(files::table)
+                            .values(&file)
+                            .execute(conn)
+                            .await?;
+                    }
+                    replace_metadata(conn, &file.id, metadata).await?;
+                    let unreferenced = retire_replaced(conn, previous, &file).await?;
+                    Ok(BlobChanges {
+                        storage_key: file.storage_key,
+                        compression: file.compression,
+                        stored_size: file.stored_size,
+                        id: Some(file.id),
+                        revision: Some(file.revision),
+                        unreferenced,
+                    })
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn delete_file(&self, target: &str) -> Result<BlobChanges, DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let file = files::table
+                        .find(target)
+                        .first::<File>(conn)
+                        .await
+                        .optional()?
+                        .ok_or(DbError::NotFound)?;
+                    diesel::delete(file_tags::table.filter(file_tags::file_id.eq(target)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(file_metadata::table.filter(file_metadata::file_id.eq(target)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(
+                        review_session_files::table
+                            .filter(review_session_files::file_id.eq(target)),
+                    )
+                    .execute(conn)
+                    .await?;
+                    let versions = file_versions::table
+                        .filter(file_versions::file_id.eq(target))
+                        .select(file_versions::storage_key)
+                        .load::<String>(conn)
+                        .await?;
+                    diesel::delete(file_versions::table.filter(file_versions::file_id.eq(target)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(files::table.find(target))
+                        .execute(conn)
+                        .await?;
+                    let unreferenced = release_file(conn, &file, versions).await?;
+                    Ok(BlobChanges {
+                        unreferenced,
+                        ..Default::default()
+                    })
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn list_file_versions(&self, target: &str) -> Result<Vec<FileVersion>, DbError> {
+        use crate::schema::file_versions::dsl::*;
+        Ok(file_versions
+            .filter(file_id.eq(target))
+            .order(version)
+            .load::<FileVersion>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn list_all_versions(&self) -> Result<Vec<FileVersion>, DbError> {
+        use crate::schema::file_versions::dsl::*;
+        Ok(file_versions
+            .load::<FileVersion>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn find_file_version(
+        &self,
+        target: &str,
+        target_version: i32,
+    ) -> Result<Option<FileVersion>, DbError> {
+        Ok(file_versions::table
+            .find((target, target_version))
+            .first::<FileVersion>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn restore_file_version(
+        &self,
+        target: &str,
+        target_version: i32,
+        restored_at: i64,
+    ) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let current = files::table.find(target).first::<File>(conn).await?;
+                    let restored = file_versions::table
+                        .find((target, target_version))
+                        .first::<FileVersion>(conn)
+                        .await?;
+                    let file = restore_version(conn, current, restored, restored_at).await?;
+                    diesel::update(files::table.find(target))
+                        .set(&file)
+                        .execute(conn)
+                        .await?;
+                    Ok(file)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn copy_file(
+        &self,
+        source: &str,
+        destination: &str,
+        copied_at: i64,
+    ) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let file = files::table.find(source).first::<File>(conn).await?;
+                    let key = adopt_legacy_object(conn, &file).await?;
+                    if file.storage_key.is_none() {
+                        diesel::update(files::table.find(source))
+                            .set(files::storage_key.eq(&key))
+                            .execute(conn)
+                            .await?;
+                    }
+                    add_reference(conn, &key).await?;
+                    let copy = File {
+                        file_name: destination.to_owned(),
+                        file_upload_date: copied_at,
+                        starred: false,
+                        download_count: 0,
+                        last_accessed_at: None,
+                        storage_key: Some(key),
+                        id: new_file_id(),
+                        revision: 1,
+                        ..file
+                    };
+                    diesel::insert_into(files::table)
+                        .values(&copy)
+                        .execute(conn)
+                        .await?;
+                    let tags = file_tags::table
+                        .filter(file_tags::file_id.eq(source))
+                        .select(file_tags::tag_name)
+                        .load::<String>(conn)
+                        .await?;
+                    for tag in &tags {
+                        diesel::insert_into(file_tags::table)
+                            .values((file_tags::file_id.eq(&copy.id), file_tags::tag_name.eq(tag)))
+                            .execute(conn)
+                            .await?;
+                    }
+                    let metadata = file_metadata::table
+                        .filter(file_metadata::file_id.eq(source))
+                        .select((file_metadata::meta_key, file_metadata::meta_value))
+                        .load::<(String, String)>(conn)
+                        .await?;
+                    for (key, value) in &metadata {
+                        diesel::insert_into(file_metadata::table)
+                            .values((
+                                file_metadata::file_id.eq(&copy.id),
+                                file_metadata::meta_key.eq(key),
+                                file_metadata::meta_value.eq(value),
+                            ))
+                            .execute(conn)
+                            .await?;
+                    }
+                    Ok(copy)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn adopt_legacy_object(&self, id: &str) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let file = files::table.find(id).first::<File>(conn).await?;
+                    if file.storage_key.is_none() {
+                        let key = adopt_legacy_object(conn, &file).await?;
+                        diesel::update(files::table.find(id))
+                            .set(files::storage_key.eq(&key))
+                            .execute(conn)
+                            .await?;
+                    }
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn rename_file(&self, id: &str, destination: &str) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let file = files::table.find(id).first::<File>(conn).await?;
+                    // A legacy object stays where it is; only the row's name changes
+                    let key = adopt_legacy_object(conn, &file).await?;
+                    let renamed = File {
+                        file_name: destination.to_owned(),
+                        storage_key: Some(key),
+                        ..file
+                    };
+                    diesel::update(files::table.find(id))
+                        .set((
+                            files::file_name.eq(&renamed.file_name),
+                            files::storage_key.eq(&renamed.storage_key),
+                        ))
+                        .execute(conn)
+                        .await?;
+                    Ok(renamed)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn list_file_names(&self) -> Result<Vec<String>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .select(file_name)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn list_all_files(&self) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files.load::<File>(&mut self.conn().await?).await?)
+    }
+
+    async fn find_files_by_ids(&self, targets: &[String]) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .filter(id.eq_any(targets))
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn count_files(&self) -> Result<i64, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .count()
+            .get_result(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn storage_usage(&self) -> Result<i64, DbError> {
+        Ok(storage_usage::table
+            .select(storage_usage::used_bytes)
+            .first(&mut self.conn().await?)
+            .await
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    async fn usage_by_file_type(&self) -> Result<Vec<TypeUsage>, DbError> {
+        use crate::schema::files::dsl::*;
+        use diesel::dsl::{count_star, sql};
+        use diesel::sql_types::BigInt;
+        Ok(files
+            .group_by(file_type)
+            .select((
+                file_type,
+                count_star(),
+                // SUM over BIGINT is NUMERIC in PostgreSQL
+                sql::<BigInt>("CAST(COALESCE(SUM(COALESCE(stored_size, file_size)), 0) AS BIGINT)"),
+            ))
+            .order(file_type)
+            .load::<TypeUsage>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_file_name(&self, target: &str) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .filter(file_name.eq(target))
+            .load::<File>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_id(&self, target: &str) -> Result<Option<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .filter(id.eq(target))
+            .first::<File>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn find_file_by_file_type(&self, target: &str) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .filter(file_type.eq(target))
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_file_upload_date(&self, target: &i64) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .filter(file_upload_date.eq(target))
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_file_size_range(
+        &self,
+        min_size: Option<i64>,
+        max_size: Option<i64>,
+    ) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        let mut query = files.filter(file_size.is_not_null()).into_boxed();
+        if let Some(min_size) = min_size {
+            query = query.filter(file_size.ge(min_size));
+        }
+        if let Some(max_size) = max_size {
+            query = query.filter(file_size.le(max_size));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_duration_range(
+        &self,
+        min_duration_ms: Option<i64>,
+        max_duration_ms: Option<i64>,
+    ) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        let mut query = files.filter(duration_ms.is_not_null()).into_boxed();
+        if let Some(min_duration_ms) = min_duration_ms {
+            query = query.filter(duration_ms.ge(min_duration_ms));
+        }
+        if let Some(max_duration_ms) = max_duration_ms {
+            query = query.filter(duration_ms.le(max_duration_ms));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_audio_format(
+        &self,
+        target_sample_rate: Option<i32>,
+        target_channels: Option<i32>,
+        target_bit_depth: Option<i32>,
+    ) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        let mut query = files.into_boxed();
+        if let Some(target) = target_sample_rate {
+            query = query.filter(sample_rate.eq(target));
+        }
+        if let Some(target) = target_channels {
+            query = query.filter(channels.eq(target));
+        }
+        if let Some(target) = target_bit_depth {
+            query = query.filter(bit_depth.eq(target));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_starred(&self, target: bool) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .filter(starred.eq(target))
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_by_owner(&self, target: Option<&str>) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        let query = match target {
+            Some(target) => files.filter(owner.eq(target)).order(file_name).into_boxed(),
+            None => files.filter(owner.is_null()).order(file_name).into_boxed(),
+        };
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn find_file_by_last_access_before(&self, cutoff: i64) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        Ok(files
+            .filter(
+                last_accessed_at
+                    .lt(cutoff)
+                    .or(last_accessed_at.is_null().and(file_upload_date.lt(cutoff))),
+            )
+            .load::<File>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_expired_files(
+        &self,
+        now: i64,
+        file_type_ttls: &BTreeMap<String, i64>,
+    ) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        let mut query = files.filter(expires_at.le(now)).into_boxed();
+        for (target, ttl) in file_type_ttls {
+            query = query.or_filter(
+                expires_at
+                    .is_null()
+                    .and(file_type.eq(target))
+                    .and(file_upload_date.le(now.saturating_sub(*ttl))),
+            );
+        }
+        Ok(query.load::<File>(&mut self.conn().await?).await?)
+    }
+
+    async fn update_file_details(
+        &self,
+        target: &str,
+        revision: i32,
+        details: &FileDetails,
+        metadata: Option<&BTreeMap<String, String>>,
+    ) -> Result<File, DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let updated = diesel::update(
+                        files::table
+                            .find(target)
+                            .filter(files::revision.eq(revision)),
+                    )
+                    .set((details, files::revision.eq(files::revision + 1)))
+                    .execute(conn)
+                    .await?;
+                    if updated == 0 {
+                        // Missing altogether is NotFound rather than stale
+                        files::table.find(target).first::<File>(conn).await?;
+                        return Err(DbError::Stale);
+                    }
+                    if let Some(metadata) = metadata {
+                        replace_metadata(conn, target, metadata).await?;
+                    }
+                    Ok(files::table.find(target).first::<File>(conn).await?)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn toggle_starred(&self, target: &str) -> Result<bool, DbError> {
+        use crate::schema::files::dsl::*;
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let updated = diesel::update(files.find(target))
+                        .set(starred.eq(diesel::dsl::not(starred)))
+                        .execute(conn)
+                        .await?;
+                    if updated == 0 {
+                        return Err(DbError::NotFound);
+                    }
+                    Ok(files
+                        .find(target)
+                        .select(starred)
+                        .first::<bool>(conn)
+                        .await?)
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn record_download(&self, target: &str, accessed_at: i64) -> Result<(), DbError> {
+        use crate::schema::files::dsl::*;
+        diesel::update(files.find(target))
+            .set((
+                download_count.eq(download_count + 1),
+                last_accessed_at.eq(accessed_at),
+            ))
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn most_downloaded(&self, limit: Option<u32>) -> Result<Vec<File>, DbError> {
+        use crate::schema::files::dsl::*;
+        let mut query = files.order((download_count.desc(), file_name)).into_boxed();
+        if let Some(limit) = limit {
+            query = query.limit(i64::from(limit));
+        }
+        Ok(query.load::<File>(&mut self.read_conn().await?).await?)
+    }
+
+    async fn create_upload_session(&self, session: &UploadSession) -> Result<(), DbError> {
+        diesel::insert_into(upload_sessions::table)
+            .values(session)
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_upload_session(&self, id: &str) -> Result<Option<UploadSession>, DbError> {
+        Ok(upload_sessions::table
+            .find(id)
+            .first::<UploadSession>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn list_upload_chunks(&self, id: &str) -> Result<Vec<UploadChunk>, DbError> {
+        Ok(upload_chunks::table
+            .filter(upload_chunks::session_id.eq(id))
+            .order(upload_chunks::byte_offset)
+            .load::<UploadChunk>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn record_upload_chunk(&self, chunk: &UploadChunk) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    let overlapping = upload_chunks::table
+                        .filter(upload_chunks::session_id.eq(&chunk.session_id))
+                        .filter(upload_chunks::byte_offset.ne(chunk.byte_offset))
+                        .load::<UploadChunk>(conn)
+                        .await?
+                        .into_iter()
+                        .any(|received| received.overlaps(chunk));
+                    if overlapping {
+                        return Err(DbError::Conflict(
+                            "chunk overlaps one already received".to_owned(),
+                        ));
+                    }
+                    diesel::insert_into(upload_chunks::table)
+                        .values(chunk)
+                        .on_conflict((upload_chunks::session_id, upload_chunks::byte_offset))
+                        .do_update()
+                        .set(upload_chunks::byte_length.eq(excluded(upload_chunks::byte_length)))
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn delete_upload_session(&self, id: &str) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    diesel::delete(upload_chunks::table.filter(upload_chunks::session_id.eq(id)))
+                        .execute(conn)
+                        .await?;
+                    diesel::delete(upload_sessions::table.find(id))
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn find_expired_upload_sessions(&self, now: i64) -> Result<Vec<UploadSession>, DbError> {
+        Ok(upload_sessions::table
+            .filter(upload_sessions::expires_at.le(now))
+            .load::<UploadSession>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn find_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyKey>, DbError> {
+        Ok(idempotency_keys::table
+            .find((owner, key))
+            .first::<IdempotencyKey>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn insert_idempotency_key(&self, key: &IdempotencyKey) -> Result<(), DbError> {
+        key.insert_into(idempotency_keys::table)
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+        response: &str,
+    ) -> Result<(), DbError> {
+        let updated = diesel::update(idempotency_keys::table.find((owner, key)))
+            .set(idempotency_keys::response.eq(response))
+            .execute(&mut self.conn().await?)
+            .await?;
+        if updated == 0 {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn delete_idempotency_key(&self, owner: &str, key: &str) -> Result<(), DbError> {
+        diesel::delete(idempotency_keys::table.find((owner, key)))
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn consume_signature(
+        &self,
+        signature: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), DbError> {
+        let mut conn = self.conn().await?;
+        diesel::delete(consumed_signatures::table.filter(consumed_signatures::expires_at.lt(now)))
+            .execute(&mut conn)
+            .await?;
+        diesel::insert_into(consumed_signatures::table)
+            .values((
+                consumed_signatures::signature.eq(signature),
+                consumed_signatures::expires_at.eq(expires_at),
+            ))
+            .execute(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_signature(&self, signature: &str) -> Result<(), DbError> {
+        diesel::delete(consumed_signatures::table.find(signature))
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_waveform_peaks(
+        &self,
+        target_sha256: &str,
+        target_resolution: i32,
+    ) -> Result<Option<WaveformPeaks>, DbError> {
+        use crate::schema::waveform_peaks::dsl::*;
+        Ok(waveform_peaks
+            .filter(sha256.eq(target_sha256))
+            .filter(resolution.eq(target_resolution))
+            .first::<WaveformPeaks>(&mut self.read_conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn insert_waveform_peaks(&self, peaks: &WaveformPeaks) -> Result<(), DbError> {
+        peaks
+            .insert_into(waveform_peaks::table)
+            .on_conflict_do_nothing()
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_file_tag(
+        &self,
+        target_file_id: &str,
+        target_tag_name: &str,
+    ) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    diesel::insert_into(tags::table)
+                        .values(tags::tag_name.eq(target_tag_name))
+                        .on_conflict_do_nothing()
+                        .execute(conn)
+                        .await?;
+                    diesel::insert_into(file_tags::table)
+                        .values((
+                            file_tags::file_id.eq(target_file_id),
+                            file_tags::tag_name.eq(target_tag_name),
+                        ))
+                        .on_conflict_do_nothing()
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn remove_file_tag(
+        &self,
+        target_file_id: &str,
+        target_tag_name: &str,
+    ) -> Result<bool, DbError> {
+        use crate::schema::file_tags::dsl::*;
+        let deleted = diesel::delete(
+            file_tags
+                .filter(file_id.eq(target_file_id))
+                .filter(tag_name.eq(target_tag_name)),
+        )
+        .execute(&mut self.conn().await?)
+        .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn list_file_tags(&self, target: &str) -> Result<Vec<String>, DbError> {
+        use crate::schema::file_tags::dsl::*;
+        Ok(file_tags
+            .filter(file_id.eq(target))
+            .select(tag_name)
+            .order(tag_name)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn find_file_ids_by_tag(&self, target: &str) -> Result<Vec<String>, DbError> {
+        use crate::schema::file_tags::dsl::*;
+        Ok(file_tags
+            .filter(tag_name.eq(target))
+            .select(file_id)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn set_file_metadata(
+        &self,
+        target: &str,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move { Ok(replace_metadata(conn, target, metadata).await?) }.scope_boxed()
+            })
+            .await
+    }
+
+    async fn get_file_metadata(&self, target: &str) -> Result<BTreeMap<String, String>, DbError> {
+        use crate::schema::file_metadata::dsl::*;
+        Ok(file_metadata
+            .filter(file_id.eq(target))
+            .select((meta_key, meta_value))
+            .load::<(String, String)>(&mut self.read_conn().await?)
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    async fn find_file_ids_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<String>, DbError> {
+        use crate::schema::file_metadata::dsl::*;
+        Ok(file_metadata
+            .filter(meta_key.eq(key))
+            .filter(meta_value.eq(value))
+            .select(file_id)
+            .load::<String>(&mut self.read_conn().await?)
+            .await?)
+    }
+
+    async fn search_files(&self, terms: &[String]) -> Result<Vec<String>, DbError> {
+        // Every term, each matched as a prefix
+        let query = terms
+            .iter()
+            .map(|term| format!("{}:*", term))
+            .collect::<Vec<_>>()
+            .join(" & ");
+        let matches = diesel::sql_query(
+            "SELECT id AS file_id FROM files, to_tsquery('simple', $1) query \
+             WHERE search_vector @@ query \
+             ORDER BY ts_rank(search_vector, query) DESC, file_name",
+        )
+        .bind::<diesel::sql_types::Text, _>(query)
+        .load::<SearchMatch>(&mut self.read_conn().await?)
+        .await?;
+        Ok(matches.into_iter().map(|found| found.file_id).collect())
+    }
+
+    async fn create_review_session(
+        &self,
+        session: &ReviewSession,
+        file_ids: &[String],
+    ) -> Result<(), DbError> {
+        self.conn()
+            .await?
+            .transaction(|conn| {
+                async move {
+                    diesel::insert_into(review_sessions::table)
+                        .values(session)
+                        .execute(conn)
+                        .await?;
+                    for file_id in file_ids {
+                        diesel::insert_into(review_session_files::table)
+                            .values((
+                                review_session_files::token.eq(&session.token),
+                                review_session_files::file_id.eq(file_id),
+                            ))
+                            .execute(conn)
+                            .await?;
+                    }
+                    Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    async fn find_review_session(&self, target: &str) -> Result<Option<ReviewSession>, DbError> {
+        use crate::schema::review_sessions::dsl::*;
+        Ok(review_sessions
+            .filter(token.eq(target))
+            .first::<ReviewSession>(&mut self.conn().await?)
+            .await
+            .optional()?)
+    }
+
+    async fn list_review_session_files(&self, target: &str) -> Result<Vec<String>, DbError> {
+        use crate::schema::review_session_files::dsl::*;
+        Ok(review_session_files
+            .filter(token.eq(target))
+            .select(file_id)
+            .load::<String>(&mut self.conn().await?)
+            .await?)
+    }
+
+    async fn run_maintenance(&self) -> Result<MaintenanceReport, DbError> {
+        let size = "SELECT pg_database_size(current_database()) AS bytes";
+        let mut conn = self.conn().await?;
+        let size_before = diesel::sql_query(size)
+            .get_result::<DatabaseSize>(&mut conn)
+            .await?
+            .bytes;
+        // Plain VACUUM makes dead rows' space reusable without the exclusive
+        // lock VACUUM FULL takes, so the files rarely shrink; checkpoints are
+        // the server's business
+        conn.batch_execute("VACUUM (ANALYZE)").await?;
+        let size_after = diesel::sql_query(size)
+            .get_result::<DatabaseSize>(&mut conn)
+            .await?
+            .bytes;
+        Ok(MaintenanceReport {
+            size_before,
+            size_after,
+            reclaimed_bytes: size_before - size_after,
+            checkpointed_frames: None,
+        })
+    }
+
+    async fn record_audit(&self, entry: &NewAuditEntry) -> Result<(), DbError> {
+        diesel::insert_into(audit_log::table)
+            .values(entry)
+            .execute(&mut self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_audit_entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, DbError> {
+        let mut entries = audit_log::table.into_boxed();
+        if let Some(actor) = &query.actor {
+            entries = entries.filter(audit_log::actor.eq(actor));
+        }
+        if let Some(pattern) = query.path_pattern() {
+            entries = entries.filter(audit_log::path.like(pattern).escape('\\'));
+        }
+        if let Some(since) = query.since {
+            entries = entries.filter(audit_log::occurred_at.ge(since));
+        }
+        if let Some(until) = query.until {
+            entries = entries.filter(audit_log::occurred_at.lt(until));
+        }
+        if let Some(before) = query.before {
+            entries = entries.filter(audit_log::id.lt(before));
+        }
+        Ok(entries
+            .order(audit_log::id.desc())
+            .limit(query.limit())
+            .load::<AuditEntry>(&mut self.read_conn().await?)
+            .await?)
+    }
+}
@@ -0,0 +1,227 @@
+use axum::body::Bytes;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::HeaderMap;
+use futures::stream::{Stream, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Bytes received so far by uploads still in flight, for progress bars. A
+// client asks for an upload id first, sends it with the upload as
+// X-Upload-Id, and polls the id's progress meanwhile. Chunked upload
+// sessions need no id of their own: their progress is the chunks recorded
+// plus whatever chunks are still arriving. Nothing here is persisted.
+
+const UPLOAD_ID_HEADER: &str = "x-upload-id";
+// How long finished uploads stay visible, so the last poll sees the outcome
+const RETAIN_FINISHED: Duration = Duration::from_secs(5 * 60);
+// Ids never used for an upload are dropped after this
+const RETAIN_UNUSED: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    /// Issued, but no upload has used it yet
+    Pending,
+    Receiving,
+    Complete,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub received_bytes: u64,
+    /// The request's Content-Length where sent, which for multipart uploads
+    /// also counts the other form fields
+    pub total_bytes: Option<u64>,
+    pub state: UploadState,
+}
+
+#[derive(Debug)]
+struct Entry {
+    progress: Progress,
+    updated_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Uploads {
+    tracked: HashMap<String, Entry>,
+    // Upload session id -> bytes of chunks still being received
+    in_flight: HashMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UploadProgress(Arc<Mutex<Uploads>>);
+
+impl UploadProgress {
+    /// Registers a fresh upload id.
+    pub fn issue(&self) -> Result<String, getrandom::Error> {
+        let id = crate::review::new_token()?;
+        let mut uploads = self.0.lock().unwrap();
+        uploads.tracked.retain(|_, entry| {
+            let retain = match entry.progress.state {
+                UploadState::Pending => RETAIN_UNUSED,
+                UploadState::Receiving => return true,
+                UploadState::Complete | UploadState::Failed => RETAIN_FINISHED,
+            };
+            entry.updated_at.elapsed() < retain
+        });
+        uploads.tracked.insert(
+            id.clone(),
+            Entry {
+                progress: Progress {
+                    received_bytes: 0,
+                    total_bytes: None,
+                    state: UploadState::Pending,
+                },
+                updated_at: Instant::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Progress> {
+        let uploads = self.0.lock().unwrap();
+        uploads.tracked.get(id).map(|entry| entry.progress.clone())
+    }
+
+    /// Bytes of the session's chunks that are still arriving.
+    pub fn in_flight(&self, session_id: &str) -> u64 {
+        let uploads = self.0.lock().unwrap();
+        uploads.in_flight.get(session_id).copied().unwrap_or(0)
+    }
+
+    /// Starts tracking the upload named by the request's X-Upload-Id. Ids
+    /// that weren't issued, or were used already, aren't tracked.
+    pub fn track(&self, headers: &HeaderMap) -> Option<ProgressTracker> {
+        let id = headers.get(UPLOAD_ID_HEADER)?.to_str().ok()?;
+        let total_bytes = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        let mut uploads = self.0.lock().unwrap();
+        let entry = uploads.tracked.get_mut(id)?;
+        if entry.progress.state != UploadState::Pending {
+            return None;
+        }
+        entry.progress.state = UploadState::Receiving;
+        entry.progress.total_bytes = total_bytes;
+        entry.updated_at = Instant::now();
+        Some(ProgressTracker {
+            uploads: self.clone(),
+            id: id.to_owned(),
+            finished: false,
+        })
+    }
+
+    /// Counts a chunk of an upload session while it arrives.
+    pub fn track_chunk(&self, session_id: &str) -> ChunkTracker {
+        ChunkTracker {
+            uploads: self.clone(),
+            session_id: session_id.to_owned(),
+            received_bytes: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Uploads)) {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
+/// Counts the bytes of one upload. Dropping it before [`complete`] marks
+/// the upload failed.
+///
+/// [`complete`]: ProgressTracker::complete
+pub struct ProgressTracker {
+    uploads: UploadProgress,
+    id: String,
+    finished: bool,
+}
+
+impl ProgressTracker {
+    pub fn observe<S>(&self, stream: S) -> impl Stream<Item = io::Result<Bytes>>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        let uploads = self.uploads.clone();
+        let id = self.id.clone();
+        stream.map(move |bytes| {
+            if let Ok(bytes) = &bytes {
+                uploads.update(|uploads| {
+                    if let Some(entry) = uploads.tracked.get_mut(&id) {
+                        entry.progress.received_bytes += bytes.len() as u64;
+                        entry.updated_at = Instant::now();
+                    }
+                });
+            }
+            bytes
+        })
+    }
+
+    pub fn complete(mut self) {
+        self.finish(UploadState::Complete);
+    }
+
+    fn finish(&mut self, state: UploadState) {
+        self.finished = true;
+        self.uploads.update(|uploads| {
+            if let Some(entry) = uploads.tracked.get_mut(&self.id) {
+                entry.progress.state = state;
+                entry.updated_at = Instant::now();
+            }
+        });
+    }
+}
+
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish(UploadState::Failed);
+        }
+    }
+}
+
+/// Counts a chunk's bytes towards its session until dropped, by which time
+/// the chunk is either recorded or discarded.
+pub struct ChunkTracker {
+    uploads: UploadProgress,
+    session_id: String,
+    received_bytes: Arc<Mutex<u64>>,
+}
+
+impl ChunkTracker {
+    pub fn observe<S>(&self, stream: S) -> impl Stream<Item = io::Result<Bytes>>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        let uploads = self.uploads.clone();
+        let session_id = self.session_id.clone();
+        let received_bytes = self.received_bytes.clone();
+        stream.map(move |bytes| {
+            if let Ok(bytes) = &bytes {
+                let len = bytes.len() as u64;
+                *received_bytes.lock().unwrap() += len;
+                uploads.update(|uploads| {
+                    *uploads.in_flight.entry(session_id.clone()).or_default() += len;
+                });
+            }
+            bytes
+        })
+    }
+}
+
+impl Drop for ChunkTracker {
+    fn drop(&mut self) {
+        let received_bytes = *self.received_bytes.lock().unwrap();
+        self.uploads.update(|uploads| {
+            if let Some(in_flight) = uploads.in_flight.get_mut(&self.session_id) {
+                *in_flight = in_flight.saturating_sub(received_bytes);
+                if *in_flight == 0 {
+                    uploads.in_flight.remove(&self.session_id);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,102 @@
+use crate::db::{DbError, File};
+use crate::repository::Repository;
+use crate::service;
+use crate::storage::SharedStorage;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime};
+
+// Finds drift between the files table and storage: objects no row points at
+// (left by uploads that failed after writing), and rows whose object is gone.
+
+// Objects younger than this may belong to an upload whose row isn't written yet
+const ORPHAN_GRACE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Default, Serialize)]
+pub struct Drift {
+    /// Storage keys no file row points at
+    pub orphaned_objects: Vec<String>,
+    /// Files whose stored bytes are missing
+    pub missing_objects: Vec<String>,
+    /// Whether the drift was repaired: orphaned objects deleted and rows
+    /// without bytes removed
+    pub repaired: bool,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.orphaned_objects.is_empty() && self.missing_objects.is_empty()
+    }
+}
+
+pub async fn reconcile(
+    db: &Repository,
+    storage: &SharedStorage,
+    repair: bool,
+) -> Result<Drift, anyhow::Error> {
+    // Listed before the rows, so an upload finishing in between shows up as
+    // a young object rather than as a row with nothing stored
+    let objects = storage.list().await?;
+    let files = db.list_all_files().await?;
+    let mut referenced: BTreeSet<String> = files
+        .iter()
+        .map(|file| {
+            file.storage_key
+                .clone()
+                .unwrap_or_else(|| file.file_name.clone())
+        })
+        .collect();
+    referenced.extend(
+        db.list_all_versions()
+            .await?
+            .into_iter()
+            .map(|version| version.storage_key),
+    );
+    let stored: BTreeSet<&str> = objects.iter().map(|(key, _)| key.as_str()).collect();
+    let missing: Vec<&File> = files
+        .iter()
+        .filter(|file| {
+            let key = file.storage_key.as_deref().unwrap_or(&file.file_name);
+            !stored.contains(key)
+        })
+        .collect();
+    let cutoff = SystemTime::now() - ORPHAN_GRACE;
+    let mut drift = Drift {
+        orphaned_objects: objects
+            .iter()
+            .filter(|(key, info)| !referenced.contains(key) && info.modified < cutoff)
+            .map(|(key, _)| key.clone())
+            .collect(),
+        missing_objects: missing.iter().map(|file| file.file_name.clone()).collect(),
+        repaired: false,
+    };
+    if repair {
+        service::delete_objects(storage, drift.orphaned_objects.clone()).await;
+        // Names are only unique per owner, so rows are deleted by id
+        for file in missing {
+            match service::delete_file(db, storage, &file.id).await {
+                Ok(()) | Err(DbError::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        drift.repaired = true;
+    }
+    Ok(drift)
+}
+
+/// Logs drift on an interval without repairing it.
+pub async fn run(db: Repository, storage: SharedStorage, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match reconcile(&db, &storage, false).await {
+            Ok(drift) if !drift.is_empty() => println!(
+                "reconcile: {} orphaned objects, {} files missing their bytes",
+                drift.orphaned_objects.len(),
+                drift.missing_objects.len()
+            ),
+            Ok(_) => {}
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+}
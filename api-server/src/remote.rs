@@ -0,0 +1,119 @@
+use reqwest::Url;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// /audio/fetch makes the server request a URL of the caller's choosing, so
+// the URL must not lead anywhere the caller couldn't reach themselves: the
+// server's own loopback interface, link-local cloud metadata such as
+// 169.254.169.254, or the private network around it. The host is resolved
+// once, every address checked, and the request then pinned to those
+// addresses, so a name can't resolve to a public address for the check and a
+// private one for the request.
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteError {
+    #[error("URL has no host")]
+    NoHost,
+    #[error("resolving {0}")]
+    Unresolved(String, #[source] io::Error),
+    #[error("{0} is not a public address")]
+    NotPublic(IpAddr),
+}
+
+/// Resolves the host of `url`, refusing it unless every address it
+/// resolves to is public.
+pub async fn public_addresses(url: &Url) -> Result<Vec<SocketAddr>, RemoteError> {
+    let port = url.port_or_known_default().ok_or(RemoteError::NoHost)?;
+    let host = url.host_str().ok_or(RemoteError::NoHost)?;
+    // IPv6 hosts come bracketed, as in http://[::1]/
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<SocketAddr> = match literal.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| RemoteError::Unresolved(host.to_owned(), e))?
+            .collect(),
+    };
+    if addresses.is_empty() {
+        let e = io::Error::from(io::ErrorKind::NotFound);
+        return Err(RemoteError::Unresolved(host.to_owned(), e));
+    }
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        return Err(RemoteError::NotPublic(address.ip()));
+    }
+    Ok(addresses)
+}
+
+// Stands in for the unstable IpAddr::is_global
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network"
+        || first == 0
+        // 100.64.0.0/10 carrier-grade NAT
+        || (first == 100 && second & 0xc0 == 64)
+        // 192.0.0.0/24 protocol assignments
+        || (first == 192 && second == 0 && third == 0)
+        // 198.18.0.0/15 benchmarking
+        || (first == 198 && second & 0xfe == 18)
+        // 240.0.0.0/4 reserved
+        || first >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || first & 0xfe00 == 0xfc00
+        // fe80::/10 link-local
+        || first & 0xffc0 == 0xfe80
+        // 2001:db8::/32 documentation
+        || (first == 0x2001 && second == 0x0db8)
+        // 64:ff9b::/96 NAT64, which reaches whatever IPv4 address it embeds
+        || (first == 0x64 && second == 0xff9b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::Context;
+use axum::body::{Body, Bytes};
+use axum::extract::{MatchedPath, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+// Failed requests are recorded as one JSON envelope per line. Envelopes keep
+// enough to re-issue the request (route, a safe subset of headers, multipart
+// layout and sizes) but never any field values or audio content. The route is
+// the matched pattern, so file names and ids stay out, and the query keeps its
+// keys but not their values.
+
+const RECORDED_HEADERS: &[&str] = &[
+    "accept",
+    "content-type",
+    "idempotency-key",
+    "if-modified-since",
+    "if-none-match",
+];
+
+// Query keys left out altogether, as even their presence pins a signed link
+const DROPPED_QUERY_KEYS: &[&str] = &["signature", "expires"];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PartEnvelope {
+    pub name: Option<String>,
+    pub has_file_name: bool,
+    pub content_type: Option<String>,
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestEnvelope {
+    pub method: String,
+    pub uri: String,
+    pub headers: BTreeMap<String, String>,
+    pub body_len: u64,
+    pub parts: Option<Vec<PartEnvelope>>,
+    pub status: u16,
+}
+
+// Reads the multipart layout from a copy of the body as the handler consumes it
+async fn multipart_layout(
+    chunks: mpsc::UnboundedReceiver<Bytes>,
+    boundary: String,
+) -> Vec<PartEnvelope> {
+    let mut multipart = multer::Multipart::new(chunks.map(Ok::<_, Infallible>), boundary);
+    let mut parts = Vec::new();
+    while let Ok(Some(mut field)) = multipart.next_field().await {
+        let mut part = PartEnvelope {
+            name: field.name().map(str::to_owned),
+            has_file_name: field.file_name().is_some(),
+            content_type: field.content_type().map(ToString::to_string),
+            len: 0,
+        };
+        while let Ok(Some(chunk)) = field.chunk().await {
+            part.len += chunk.len() as u64;
+        }
+        parts.push(part);
+    }
+    parts
+}
+
+// The route pattern with each query value blanked, e.g.
+// /audio/:file_name/peaks?resolution=
+fn redacted_uri(route: &str, query: Option<&str>) -> String {
+    let keys: Vec<&str> = query
+        .unwrap_or("")
+        .split('&')
+        .map(|pair| pair.split('=').next().unwrap_or(""))
+        .filter(|key| !key.is_empty() && !DROPPED_QUERY_KEYS.contains(key))
+        .collect();
+    if keys.is_empty() {
+        return route.to_owned();
+    }
+    let query: Vec<String> = keys.iter().map(|key| format!("{}=", key)).collect();
+    format!("{}?{}", route, query.join("&"))
+}
+
+/// Records requests that fail with a server error. Add it with
+/// `route_layer`, so the matched route is known.
+pub async fn record_failed_requests(
+    State(recording): State<Arc<PathBuf>>,
+    route: Option<MatchedPath>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let route = route.as_ref().map_or("", MatchedPath::as_str);
+    let mut envelope = RequestEnvelope {
+        method: parts.method.to_string(),
+        uri: redacted_uri(route, parts.uri.query()),
+        headers: RECORDED_HEADERS
+            .iter()
+            .filter_map(|&name| {
+                let value = parts.headers.get(name)?.to_str().ok()?;
+                Some((name.to_owned(), value.to_owned()))
+            })
+            .collect(),
+        body_len: 0,
+        parts: None,
+        status: 0,
+    };
+    let boundary = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| multer::parse_boundary(value).ok());
+    let (tx, rx) = mpsc::unbounded();
+    let layout = boundary.map(|boundary| tokio::spawn(multipart_layout(rx, boundary)));
+    let body_len = Arc::new(AtomicU64::new(0));
+    let counted = body_len.clone();
+    let body = body.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            counted.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            let _ = tx.unbounded_send(chunk.clone());
+        }
+        chunk
+    });
+    let response = next
+        .run(Request::from_parts(parts, Body::wrap_stream(body)))
+        .await;
+    if !response.status().is_server_error() {
+        return response;
+    }
+    envelope.status = response.status().as_u16();
+    envelope.body_len = body_len.load(Ordering::Relaxed);
+    if let Some(layout) = layout {
+        envelope.parts = layout.await.ok();
+    }
+    if let Err(e) = append_envelope(&recording, &envelope).await {
+        eprintln!("{:?}", e);
+    }
+    response
+}
+
+async fn append_envelope(path: &Path, envelope: &RequestEnvelope) -> Result<(), anyhow::Error> {
+    let mut line = serde_json::to_vec(envelope)?;
+    line.push(b'\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+// Field values aren't recorded, so text fields are replayed as runs of 'x' and
+// file contents as zeros of the original length.
+fn multipart_body(parts: &[PartEnvelope], boundary: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        let name = part.name.as_deref().unwrap_or("");
+        if part.has_file_name {
+            body.extend_from_slice(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"replay-{}\"\r\n",
+                    name, i
+                )
+                .as_bytes(),
+            );
+        } else {
+            body.extend_from_slice(
+                format!("Content-Disposition: form-data; name=\"{}\"\r\n", name).as_bytes(),
+            );
+        }
+        if let Some(content_type) = &part.content_type {
+            body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        let filler = if part.has_file_name { 0 } else { b'x' };
+        body.resize(body.len() + part.len as usize, filler);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+pub async fn replay(recording: &str, base_url: &str) -> Result<(), anyhow::Error> {
+    let contents = tokio::fs::read_to_string(recording)
+        .await
+        .with_context(|| format!("reading {}", recording))?;
+    let client = reqwest::Client::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let envelope = serde_json::from_str::<RequestEnvelope>(line)?;
+        let method = reqwest::Method::from_bytes(envelope.method.as_bytes())?;
+        let mut request = client.request(method, format!("{}{}", base_url, envelope.uri));
+        for (name, value) in &envelope.headers {
+            if name != "content-type" {
+                request = request.header(name, value);
+            }
+        }
+        request = match &envelope.parts {
+            Some(parts) => {
+                let boundary = "replay-boundary";
+                request
+                    .header(
+                        CONTENT_TYPE,
+                        format!("multipart/form-data; boundary={}", boundary),
+                    )
+                    .body(multipart_body(parts, boundary))
+            }
+            None => {
+                if let Some(content_type) = envelope.headers.get("content-type") {
+                    request = request.header(CONTENT_TYPE, content_type);
+                }
+                request.body(vec![0u8; envelope.body_len as usize])
+            }
+        };
+        let response = request.send().await?;
+        println!(
+            "{} {} -> {} (recorded {})",
+            envelope.method,
+            envelope.uri,
+            response.status().as_u16(),
+            envelope.status
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_uris_keep_the_route_and_query_keys_only() {
+        assert_eq!(redacted_uri("/audio/:file_name", None), "/audio/:file_name");
+        assert_eq!(
+            redacted_uri("/audio/:file_name/peaks", Some("resolution=50&user=ann")),
+            "/audio/:file_name/peaks?resolution=&user="
+        );
+        assert_eq!(
+            redacted_uri(
+                "/audio/download/:file_name",
+                Some("expires=1&signature=abc")
+            ),
+            "/audio/download/:file_name"
+        );
+    }
+}
@@ -0,0 +1,1335 @@
+use crate::db::{
+    AuditEntry, AuditQuery, BlobChanges, DbError, File, FileDetails, FileVersion, IdempotencyKey,
+    MaintenanceReport, NewAuditEntry, ReviewSession, TypeUsage, UploadChunk, UploadSession,
+    WaveformPeaks,
+};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Everything the handlers need from the metadata store. Handlers only see
+/// this trait, so tests can swap in [`MemoryRepository`] and other backends
+/// can be added without touching handler code.
+///
+/// With DATABASE_READ_URL set, listings, filters, searches, tag and metadata
+/// lookups and the audit log are read from a replica, which may lag behind.
+/// Whatever a write depends on is always read from the primary: files by
+/// name or id, the full listings reconciliation deletes from, sessions,
+/// idempotency keys and storage usage.
+#[async_trait]
+pub trait FileRepository: Send + Sync {
+    /// Inserts the file with its custom metadata, pointing it at already
+    /// stored identical bytes if its sha256 matches. Nothing is written unless
+    /// all of it is.
+    async fn insert_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError>;
+
+    /// Inserts new files with their custom metadata all together or not at
+    /// all, as [`insert_file`](Self::insert_file) would one at a time but in
+    /// as few statements as the database allows. Returns each row's changes
+    /// in order.
+    async fn insert_files(
+        &self,
+        files: &[(File, BTreeMap<String, String>)],
+    ) -> Result<Vec<BlobChanges>, DbError>;
+
+    /// Inserts the file, or replaces the row its owner already has under its
+    /// name, keeping the replaced content as a new version. The metadata replaces
+    /// whatever the file had, in the same transaction.
+    async fn replace_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError>;
+
+    /// Removes the file's row along with its versions, tags, metadata and
+    /// review session entries.
+    async fn delete_file(&self, id: &str) -> Result<BlobChanges, DbError>;
+
+    /// Adds a file under `destination`, for the same owner, sharing the
+    /// source's stored bytes, tags and metadata.
+    async fn copy_file(
+        &self,
+        source_id: &str,
+        destination: &str,
+        copied_at: i64,
+    ) -> Result<File, DbError>;
+
+    /// Gives a row from before sharding the object stored under its name as
+    /// its storage key, with a blob row like any other. Rows that have a key
+    /// are left alone.
+    async fn adopt_legacy_object(&self, id: &str) -> Result<(), DbError>;
+
+    /// Renames the file within its owner's names. Everything else hangs off
+    /// its id and stays put.
+    async fn rename_file(&self, id: &str, destination: &str) -> Result<File, DbError>;
+
+    /// The file's earlier contents, oldest first.
+    async fn list_file_versions(&self, file_id: &str) -> Result<Vec<FileVersion>, DbError>;
+
+    async fn list_all_versions(&self) -> Result<Vec<FileVersion>, DbError>;
+
+    async fn find_file_version(
+        &self,
+        file_id: &str,
+        version: i32,
+    ) -> Result<Option<FileVersion>, DbError>;
+
+    /// Makes a version's content current again. What it replaces becomes a
+    /// version in turn, so nothing is lost.
+    async fn restore_file_version(
+        &self,
+        file_id: &str,
+        version: i32,
+        restored_at: i64,
+    ) -> Result<File, DbError>;
+
+    async fn list_file_names(&self) -> Result<Vec<String>, DbError>;
+
+    async fn list_all_files(&self) -> Result<Vec<File>, DbError>;
+
+    async fn find_files_by_ids(&self, ids: &[String]) -> Result<Vec<File>, DbError>;
+
+    async fn count_files(&self) -> Result<i64, DbError>;
+
+    /// Bytes currently held in storage, counting shared objects once.
+    async fn storage_usage(&self) -> Result<i64, DbError>;
+
+    /// Per-type totals; objects shared by several files count once per file.
+    async fn usage_by_file_type(&self) -> Result<Vec<TypeUsage>, DbError>;
+
+    /// Every owner's file with this name.
+    async fn find_file_by_file_name(&self, file_name: &str) -> Result<Vec<File>, DbError>;
+
+    async fn find_file_by_id(&self, id: &str) -> Result<Option<File>, DbError>;
+
+    async fn find_file_by_file_type(&self, file_type: &str) -> Result<Vec<File>, DbError>;
+
+    async fn find_file_by_file_upload_date(
+        &self,
+        file_upload_date: &i64,
+    ) -> Result<Vec<File>, DbError>;
+
+    /// Both bounds are inclusive; rows without a recorded size never match.
+    async fn find_file_by_file_size_range(
+        &self,
+        min_size: Option<i64>,
+        max_size: Option<i64>,
+    ) -> Result<Vec<File>, DbError>;
+
+    /// Both bounds are inclusive; rows without a known duration never match.
+    async fn find_file_by_duration_range(
+        &self,
+        min_duration_ms: Option<i64>,
+        max_duration_ms: Option<i64>,
+    ) -> Result<Vec<File>, DbError>;
+
+    /// Files matching every given property exactly; rows that don't know a
+    /// property never match it.
+    async fn find_file_by_audio_format(
+        &self,
+        sample_rate: Option<i32>,
+        channels: Option<i32>,
+        bit_depth: Option<i32>,
+    ) -> Result<Vec<File>, DbError>;
+
+    async fn find_file_by_starred(&self, starred: bool) -> Result<Vec<File>, DbError>;
+
+    /// Files belonging to `owner` by name; `None` finds the unowned ones.
+    async fn find_file_by_owner(&self, owner: Option<&str>) -> Result<Vec<File>, DbError>;
+
+    /// Files not accessed since `cutoff`; never-accessed files count from their upload date.
+    async fn find_file_by_last_access_before(&self, cutoff: i64) -> Result<Vec<File>, DbError>;
+
+    /// Files past their own `expires_at`, or, for files without one, older
+    /// than the TTL for their type.
+    async fn find_expired_files(
+        &self,
+        now: i64,
+        file_type_ttls: &BTreeMap<String, i64>,
+    ) -> Result<Vec<File>, DbError>;
+
+    /// Applies `details`, and replaces the custom metadata if given, provided
+    /// the file is still at `revision`. Fails with [`DbError::Stale`] if it
+    /// has moved on, and otherwise returns the file at its next revision.
+    async fn update_file_details(
+        &self,
+        id: &str,
+        revision: i32,
+        details: &FileDetails,
+        metadata: Option<&BTreeMap<String, String>>,
+    ) -> Result<File, DbError>;
+
+    /// Flips the starred flag and returns the new value.
+    async fn toggle_starred(&self, id: &str) -> Result<bool, DbError>;
+
+    /// Bumps the download count and records when the file was last accessed.
+    async fn record_download(&self, id: &str, accessed_at: i64) -> Result<(), DbError>;
+
+    /// Files ordered by download count, most downloaded first.
+    async fn most_downloaded(&self, limit: Option<u32>) -> Result<Vec<File>, DbError>;
+
+    async fn create_upload_session(&self, session: &UploadSession) -> Result<(), DbError>;
+
+    async fn find_upload_session(&self, id: &str) -> Result<Option<UploadSession>, DbError>;
+
+    /// The session's received chunks, in byte order.
+    async fn list_upload_chunks(&self, id: &str) -> Result<Vec<UploadChunk>, DbError>;
+
+    /// Records a received chunk, replacing one sent before at the same
+    /// offset. Chunks overlapping any other are a conflict.
+    async fn record_upload_chunk(&self, chunk: &UploadChunk) -> Result<(), DbError>;
+
+    async fn delete_upload_session(&self, id: &str) -> Result<(), DbError>;
+
+    async fn find_expired_upload_sessions(&self, now: i64) -> Result<Vec<UploadSession>, DbError>;
+
+    async fn find_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyKey>, DbError>;
+
+    /// Reserves a key, failing with [`DbError::Duplicate`] if its owner
+    /// already holds it.
+    async fn insert_idempotency_key(&self, key: &IdempotencyKey) -> Result<(), DbError>;
+
+    /// Records the response of the upload a reserved key was held for.
+    async fn complete_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+        response: &str,
+    ) -> Result<(), DbError>;
+
+    async fn delete_idempotency_key(&self, owner: &str, key: &str) -> Result<(), DbError>;
+
+    /// Records a signed URL as used, failing with [`DbError::Duplicate`] if
+    /// it already was. Signatures expired by `now` can't be used again anyway
+    /// and are forgotten.
+    async fn consume_signature(
+        &self,
+        signature: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), DbError>;
+
+    /// Lets a signed URL be used again, after the request it was used for
+    /// failed.
+    async fn release_signature(&self, signature: &str) -> Result<(), DbError>;
+
+    /// Peaks cached for content with this hash at this resolution.
+    async fn find_waveform_peaks(
+        &self,
+        sha256: &str,
+        resolution: i32,
+    ) -> Result<Option<WaveformPeaks>, DbError>;
+
+    /// Caches peaks; when another request cached them first, theirs stay.
+    async fn insert_waveform_peaks(&self, peaks: &WaveformPeaks) -> Result<(), DbError>;
+
+    async fn add_file_tag(&self, file_id: &str, tag_name: &str) -> Result<(), DbError>;
+
+    /// Returns whether the tag was present on the file.
+    async fn remove_file_tag(&self, file_id: &str, tag_name: &str) -> Result<bool, DbError>;
+
+    async fn list_file_tags(&self, file_id: &str) -> Result<Vec<String>, DbError>;
+
+    async fn find_file_ids_by_tag(&self, tag_name: &str) -> Result<Vec<String>, DbError>;
+
+    /// Replaces all custom metadata stored for a file.
+    async fn set_file_metadata(
+        &self,
+        file_id: &str,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<(), DbError>;
+
+    async fn get_file_metadata(&self, file_id: &str) -> Result<BTreeMap<String, String>, DbError>;
+
+    async fn find_file_ids_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<String>, DbError>;
+
+    /// Ids of the files with a word starting with each of `terms` in their
+    /// name, title or description, best match first. Terms come from
+    /// [`search_terms`].
+    async fn search_files(&self, terms: &[String]) -> Result<Vec<String>, DbError>;
+
+    async fn create_review_session(
+        &self,
+        session: &ReviewSession,
+        file_ids: &[String],
+    ) -> Result<(), DbError>;
+
+    async fn find_review_session(&self, token: &str) -> Result<Option<ReviewSession>, DbError>;
+
+    /// Ids of the session's files.
+    async fn list_review_session_files(&self, token: &str) -> Result<Vec<String>, DbError>;
+
+    /// Reclaims the space deleted rows left and refreshes the planner's
+    /// statistics. Writers may wait while it runs.
+    async fn run_maintenance(&self) -> Result<MaintenanceReport, DbError>;
+
+    /// Adds an entry to the audit log, which is never changed afterwards.
+    async fn record_audit(&self, entry: &NewAuditEntry) -> Result<(), DbError>;
+
+    async fn find_audit_entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, DbError>;
+}
+
+pub type Repository = Arc<dyn FileRepository>;
+
+/// The lowercased words of a search, split on anything but letters and
+/// digits as the full-text indexes split what they index.
+pub fn search_terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+pub use memory::MemoryRepository;
+
+mod memory {
+    use super::{search_terms, FileRepository};
+    use crate::db::{
+        AuditEntry, AuditQuery, BlobChanges, DbError, File, FileDetails, FileVersion,
+        IdempotencyKey, MaintenanceReport, NewAuditEntry, ReviewSession, TypeUsage, UploadChunk,
+        UploadSession, WaveformPeaks,
+    };
+    use async_trait::async_trait;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct State {
+        // id -> file
+        files: BTreeMap<String, File>,
+        // storage_key -> (sha256, ref_count, stored_size)
+        blobs: BTreeMap<String, (Option<String>, i64, i64)>,
+        used_bytes: i64,
+        file_versions: BTreeMap<(String, i32), FileVersion>,
+        // (owner, key) -> key
+        idempotency_keys: BTreeMap<(String, String), IdempotencyKey>,
+        // signature -> expires_at
+        consumed_signatures: BTreeMap<String, i64>,
+        // (sha256, resolution) -> peaks
+        waveform_peaks: BTreeMap<(String, i32), WaveformPeaks>,
+        // (file_id, tag_name)
+        file_tags: BTreeSet<(String, String)>,
+        // file_id -> metadata
+        file_metadata: BTreeMap<String, BTreeMap<String, String>>,
+        review_sessions: BTreeMap<String, ReviewSession>,
+        // (token, file_id)
+        review_session_files: BTreeSet<(String, String)>,
+        upload_sessions: BTreeMap<String, UploadSession>,
+        // (session_id, byte_offset) -> chunk
+        upload_chunks: BTreeMap<(String, i64), UploadChunk>,
+        // Oldest first; ids count from 1
+        audit_log: Vec<AuditEntry>,
+    }
+
+    impl State {
+        // Whether another file of the same owner has `file`'s name
+        fn name_taken(&self, file: &File) -> bool {
+            self.files.values().any(|stored| {
+                stored.id != file.id
+                    && stored.owner == file.owner
+                    && stored.file_name == file.file_name
+            })
+        }
+
+        fn acquire_blob(&mut self, file: &mut File) {
+            let Some(mut key) = file.storage_key.clone() else {
+                return;
+            };
+            if let Some(existing) = self
+                .blobs
+                .iter()
+                .find(|(_, (sha256, _, _))| sha256.is_some() && *sha256 == file.sha256)
+                .map(|(key, _)| key.clone())
+            {
+                if let Some(stored) = self
+                    .files
+                    .values()
+                    .find(|stored| stored.storage_key.as_ref() == Some(&existing))
+                {
+                    file.compression = stored.compression.clone();
+                    file.stored_size = stored.stored_size;
+                }
+                key = existing;
+            }
+            let blob = self.blobs.entry(key.clone()).or_insert_with(|| {
+                self.used_bytes += file.stored_bytes();
+                (file.sha256.clone(), 0, file.stored_bytes())
+            });
+            blob.1 += 1;
+            file.storage_key = Some(key);
+        }
+
+        fn release_blob(&mut self, file: &File) -> Option<String> {
+            let Some(key) = &file.storage_key else {
+                self.used_bytes -= file.stored_bytes();
+                return Some(file.file_name.clone());
+            };
+            let (_, ref_count, stored_size) = self.blobs.get_mut(key)?;
+            *ref_count -= 1;
+            if *ref_count > 0 {
+                return None;
+            }
+            self.used_bytes -= *stored_size;
+            self.blobs.remove(key);
+            Some(key.clone())
+        }
+
+        fn adopt_legacy_object(&mut self, file: &File) -> String {
+            if let Some(key) = &file.storage_key {
+                return key.clone();
+            }
+            self.blobs.insert(
+                file.file_name.clone(),
+                (file.sha256.clone(), 1, file.stored_bytes()),
+            );
+            file.file_name.clone()
+        }
+
+        fn archive_version(&mut self, previous: &File, replaced_at: i64) {
+            let storage_key = self.adopt_legacy_object(previous);
+            let version = self
+                .versions_of(&previous.id)
+                .map(|version| version.version)
+                .max()
+                .unwrap_or(0)
+                + 1;
+            self.file_versions.insert(
+                (previous.id.clone(), version),
+                FileVersion {
+                    file_id: previous.id.clone(),
+                    version,
+                    file_type: previous.file_type.clone(),
+                    file_upload_date: previous.file_upload_date,
+                    file_size: previous.file_size,
+                    duration_ms: previous.duration_ms,
+                    storage_key,
+                    sha256: previous.sha256.clone(),
+                    compression: previous.compression.clone(),
+                    stored_size: previous.stored_size,
+                    replaced_at,
+                    sample_rate: previous.sample_rate,
+                    channels: previous.channels,
+                    bit_depth: previous.bit_depth,
+                },
+            );
+        }
+
+        fn versions_of<'a>(&'a self, file_id: &str) -> impl Iterator<Item = &'a FileVersion> {
+            let file_id = file_id.to_owned();
+            self.file_versions
+                .values()
+                .filter(move |version| version.file_id == file_id)
+        }
+    }
+
+    /// Keeps everything in memory; used by handler tests and `--demo`.
+    #[derive(Default)]
+    pub struct MemoryRepository {
+        state: Mutex<State>,
+    }
+
+    // What the databases report when a unique constraint turns a write away
+    fn conflict(column: &str) -> DbError {
+        DbError::Duplicate {
+            field: column.to_owned(),
+        }
+    }
+
+    #[async_trait]
+    impl FileRepository for MemoryRepository {
+        async fn insert_file(
+            &self,
+            file: &File,
+            metadata: &BTreeMap<String, String>,
+        ) -> Result<BlobChanges, DbError> {
+            let mut state = self.state.lock().unwrap();
+            if state.name_taken(file) {
+                return Err(conflict("file_name"));
+            }
+            let mut file = file.clone();
+            state.acquire_blob(&mut file);
+            state.files.insert(file.id.clone(), file.clone());
+            if !metadata.is_empty() {
+                state
+                    .file_metadata
+                    .insert(file.id.clone(), metadata.clone());
+            }
+            Ok(BlobChanges {
+                storage_key: file.storage_key,
+                compression: file.compression,
+                stored_size: file.stored_size,
+                id: Some(file.id),
+                revision: Some(file.revision),
+                unreferenced: vec![],
+            })
+        }
+
+        async fn insert_files(
+            &self,
+            files: &[(File, BTreeMap<String, String>)],
+        ) -> Result<Vec<BlobChanges>, DbError> {
+            let mut state = self.state.lock().unwrap();
+            // Checked up front, so a conflict leaves nothing written
+            let mut names = std::collections::BTreeSet::new();
+            if files.iter().any(|(file, _)| {
+                state.name_taken(file) || !names.insert((&file.owner, &file.file_name))
+            }) {
+                return Err(conflict("file_name"));
+            }
+            Ok(files
+                .iter()
+                .map(|(file, metadata)| {
+                    let mut file = file.clone();
+                    state.acquire_blob(&mut file);
+                    state.files.insert(file.id.clone(), file.clone());
+                    if !metadata.is_empty() {
+                        state
+                            .file_metadata
+                            .insert(file.id.clone(), metadata.clone());
+                    }
+                    BlobChanges::inserted(file)
+                })
+                .collect())
+        }
+
+        async fn replace_file(
+            &self,
+            file: &File,
+            metadata: &BTreeMap<String, String>,
+        ) -> Result<BlobChanges, DbError> {
+            let mut state = self.state.lock().unwrap();
+            let mut file = file.clone();
+            if let Some(previous) = state
+                .files
+                .values()
+                .find(|stored| stored.owner == file.owner && stored.file_name == file.file_name)
+            {
+                file.id = previous.id.clone();
+                file.revision = previous.revision + 1;
+            }
+            state.acquire_blob(&mut file);
+            let previous = state.files.insert(file.id.clone(), file.clone());
+            state
+                .file_metadata
+                .insert(file.id.clone(), metadata.clone());
+            let unreferenced = match previous {
+                Some(previous) if previous.sha256.is_some() && previous.sha256 == file.sha256 => {
+                    state.release_blob(&previous).into_iter().collect()
+                }
+                Some(previous) => {
+                    state.archive_version(&previous, file.file_upload_date);
+                    vec![]
+                }
+                None => vec![],
+            };
+            Ok(BlobChanges {
+                storage_key: file.storage_key,
+                compression: file.compression,
+                stored_size: file.stored_size,
+                id: Some(file.id),
+                revision: Some(file.revision),
+                unreferenced,
+            })
+        }
+
+        async fn delete_file(&self, id: &str) -> Result<BlobChanges, DbError> {
+            let mut state = self.state.lock().unwrap();
+            let file = state.files.remove(id).ok_or(DbError::NotFound)?;
+            state.file_tags.retain(|(tagged, _)| tagged != id);
+            state.file_metadata.remove(id);
+            state
+                .review_session_files
+                .retain(|(_, reviewed)| reviewed != id);
+            let versions: Vec<String> = state
+                .versions_of(id)
+                .map(|version| version.storage_key.clone())
+                .collect();
+            state
+                .file_versions
+                .retain(|(versioned, _), _| versioned != id);
+            let mut unreferenced: Vec<String> = state.release_blob(&file).into_iter().collect();
+            for storage_key in versions {
+                let version = File {
+                    storage_key: Some(storage_key),
+                    ..file.clone()
+                };
+                unreferenced.extend(state.release_blob(&version));
+            }
+            Ok(BlobChanges {
+                unreferenced,
+                ..Default::default()
+            })
+        }
+
+        async fn copy_file(
+            &self,
+            source_id: &str,
+            destination: &str,
+            copied_at: i64,
+        ) -> Result<File, DbError> {
+            let mut state = self.state.lock().unwrap();
+            let file = state
+                .files
+                .get(source_id)
+                .cloned()
+                .ok_or(DbError::NotFound)?;
+            let copy = File {
+                file_name: destination.to_owned(),
+                file_upload_date: copied_at,
+                starred: false,
+                download_count: 0,
+                last_accessed_at: None,
+                id: crate::db::new_file_id(),
+                revision: 1,
+                ..file.clone()
+            };
+            if state.name_taken(&copy) {
+                return Err(conflict("file_name"));
+            }
+            let key = state.adopt_legacy_object(&file);
+            if let Some(source_file) = state.files.get_mut(source_id) {
+                source_file.storage_key = Some(key.clone());
+            }
+            if let Some(blob) = state.blobs.get_mut(&key) {
+                blob.1 += 1;
+            }
+            let copy = File {
+                storage_key: Some(key),
+                ..copy
+            };
+            state.files.insert(copy.id.clone(), copy.clone());
+            let tags: Vec<(String, String)> = state
+                .file_tags
+                .iter()
+                .filter(|(tagged, _)| tagged == source_id)
+                .map(|(_, tag_name)| (copy.id.clone(), tag_name.clone()))
+                .collect();
+            state.file_tags.extend(tags);
+            if let Some(metadata) = state.file_metadata.get(source_id).cloned() {
+                state.file_metadata.insert(copy.id.clone(), metadata);
+            }
+            Ok(copy)
+        }
+
+        async fn adopt_legacy_object(&self, id: &str) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            let file = state.files.get(id).cloned().ok_or(DbError::NotFound)?;
+            let key = state.adopt_legacy_object(&file);
+            if let Some(file) = state.files.get_mut(id) {
+                file.storage_key = Some(key);
+            }
+            Ok(())
+        }
+
+        async fn rename_file(&self, id: &str, destination: &str) -> Result<File, DbError> {
+            let mut state = self.state.lock().unwrap();
+            let file = state.files.get(id).cloned().ok_or(DbError::NotFound)?;
+            let renamed = File {
+                file_name: destination.to_owned(),
+                ..file.clone()
+            };
+            if state.name_taken(&renamed) {
+                return Err(conflict("file_name"));
+            }
+            // A legacy object stays where it is; only the row's name changes
+            let renamed = File {
+                storage_key: Some(state.adopt_legacy_object(&file)),
+                ..renamed
+            };
+            state.files.insert(renamed.id.clone(), renamed.clone());
+            Ok(renamed)
+        }
+
+        async fn list_file_versions(&self, file_id: &str) -> Result<Vec<FileVersion>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state.versions_of(file_id).cloned().collect())
+        }
+
+        async fn list_all_versions(&self) -> Result<Vec<FileVersion>, DbError> {
+            Ok(self
+                .state
+                .lock()
+                .unwrap()
+                .file_versions
+                .values()
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_version(
+            &self,
+            file_id: &str,
+            version: i32,
+        ) -> Result<Option<FileVersion>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .file_versions
+                .get(&(file_id.to_owned(), version))
+                .cloned())
+        }
+
+        async fn restore_file_version(
+            &self,
+            file_id: &str,
+            version: i32,
+            restored_at: i64,
+        ) -> Result<File, DbError> {
+            let mut state = self.state.lock().unwrap();
+            let current = state.files.get(file_id).cloned().ok_or(DbError::NotFound)?;
+            let restored = state
+                .file_versions
+                .get(&(file_id.to_owned(), version))
+                .cloned()
+                .ok_or(DbError::NotFound)?;
+            if let Some(blob) = state.blobs.get_mut(&restored.storage_key) {
+                blob.1 += 1;
+            }
+            state.archive_version(&current, restored_at);
+            let file = File {
+                file_type: restored.file_type,
+                file_upload_date: restored_at,
+                file_size: restored.file_size,
+                duration_ms: restored.duration_ms,
+                sample_rate: restored.sample_rate,
+                channels: restored.channels,
+                bit_depth: restored.bit_depth,
+                storage_key: Some(restored.storage_key),
+                sha256: restored.sha256,
+                compression: restored.compression,
+                stored_size: restored.stored_size,
+                ..current
+            };
+            state.files.insert(file.id.clone(), file.clone());
+            Ok(file)
+        }
+
+        async fn list_file_names(&self) -> Result<Vec<String>, DbError> {
+            let state = self.state.lock().unwrap();
+            let mut file_names: Vec<String> = state
+                .files
+                .values()
+                .map(|file| file.file_name.clone())
+                .collect();
+            file_names.sort();
+            Ok(file_names)
+        }
+
+        async fn list_all_files(&self) -> Result<Vec<File>, DbError> {
+            Ok(self.state.lock().unwrap().files.values().cloned().collect())
+        }
+
+        async fn find_files_by_ids(&self, ids: &[String]) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(ids
+                .iter()
+                .filter_map(|id| state.files.get(id).cloned())
+                .collect())
+        }
+
+        async fn count_files(&self) -> Result<i64, DbError> {
+            Ok(self.state.lock().unwrap().files.len() as i64)
+        }
+
+        async fn storage_usage(&self) -> Result<i64, DbError> {
+            Ok(self.state.lock().unwrap().used_bytes)
+        }
+
+        async fn usage_by_file_type(&self) -> Result<Vec<TypeUsage>, DbError> {
+            let state = self.state.lock().unwrap();
+            let mut usage = BTreeMap::<Option<String>, (i64, i64)>::new();
+            for file in state.files.values() {
+                let entry = usage.entry(file.file_type.clone()).or_default();
+                entry.0 += 1;
+                entry.1 += file.stored_bytes();
+            }
+            Ok(usage
+                .into_iter()
+                .map(|(file_type, (files, bytes))| TypeUsage {
+                    file_type,
+                    files,
+                    bytes,
+                })
+                .collect())
+        }
+
+        async fn find_file_by_file_name(&self, file_name: &str) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| file.file_name == file_name)
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_by_id(&self, id: &str) -> Result<Option<File>, DbError> {
+            Ok(self.state.lock().unwrap().files.get(id).cloned())
+        }
+
+        async fn find_file_by_file_type(&self, file_type: &str) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| file.file_type.as_deref() == Some(file_type))
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_by_file_upload_date(
+            &self,
+            file_upload_date: &i64,
+        ) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| file.file_upload_date == *file_upload_date)
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_by_file_size_range(
+            &self,
+            min_size: Option<i64>,
+            max_size: Option<i64>,
+        ) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| {
+                    file.file_size.is_some_and(|size| {
+                        min_size.is_none_or(|min| size >= min)
+                            && max_size.is_none_or(|max| size <= max)
+                    })
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_by_duration_range(
+            &self,
+            min_duration_ms: Option<i64>,
+            max_duration_ms: Option<i64>,
+        ) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| {
+                    file.duration_ms.is_some_and(|duration| {
+                        min_duration_ms.is_none_or(|min| duration >= min)
+                            && max_duration_ms.is_none_or(|max| duration <= max)
+                    })
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_by_audio_format(
+            &self,
+            sample_rate: Option<i32>,
+            channels: Option<i32>,
+            bit_depth: Option<i32>,
+        ) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            let matches = |wanted: Option<i32>, actual: Option<i32>| {
+                wanted.is_none_or(|wanted| actual == Some(wanted))
+            };
+            Ok(state
+                .files
+                .values()
+                .filter(|file| {
+                    matches(sample_rate, file.sample_rate)
+                        && matches(channels, file.channels)
+                        && matches(bit_depth, file.bit_depth)
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_by_starred(&self, starred: bool) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| file.starred == starred)
+                .cloned()
+                .collect())
+        }
+
+        async fn find_file_by_owner(&self, owner: Option<&str>) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            let mut files: Vec<File> = state
+                .files
+                .values()
+                .filter(|file| file.owner.as_deref() == owner)
+                .cloned()
+                .collect();
+            files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+            Ok(files)
+        }
+
+        async fn find_file_by_last_access_before(&self, cutoff: i64) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| {
+                    file.last_accessed_at
+                        .map_or(file.file_upload_date, i64::from)
+                        < cutoff
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn find_expired_files(
+            &self,
+            now: i64,
+            file_type_ttls: &BTreeMap<String, i64>,
+        ) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .files
+                .values()
+                .filter(|file| match file.expires_at {
+                    Some(expires_at) => expires_at <= now,
+                    None => file
+                        .file_type
+                        .as_ref()
+                        .and_then(|file_type| file_type_ttls.get(file_type))
+                        .is_some_and(|ttl| file.file_upload_date <= now.saturating_sub(*ttl)),
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn update_file_details(
+            &self,
+            id: &str,
+            revision: i32,
+            details: &FileDetails,
+            metadata: Option<&BTreeMap<String, String>>,
+        ) -> Result<File, DbError> {
+            let mut state = self.state.lock().unwrap();
+            let file = state.files.get_mut(id).ok_or(DbError::NotFound)?;
+            if file.revision != revision {
+                return Err(DbError::Stale);
+            }
+            let details = details.clone();
+            file.title = details.title.or(file.title.take());
+            file.description = details.description.or(file.description.take());
+            file.language = details.language.or(file.language.take());
+            file.revision += 1;
+            let file = file.clone();
+            if let Some(metadata) = metadata {
+                state.file_metadata.insert(id.to_owned(), metadata.clone());
+            }
+            Ok(file)
+        }
+
+        async fn toggle_starred(&self, id: &str) -> Result<bool, DbError> {
+            let mut state = self.state.lock().unwrap();
+            let file = state.files.get_mut(id).ok_or(DbError::NotFound)?;
+            file.starred = !file.starred;
+            Ok(file.starred)
+        }
+
+        async fn record_download(&self, id: &str, accessed_at: i64) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            if let Some(file) = state.files.get_mut(id) {
+                file.download_count += 1;
+                file.last_accessed_at = Some(accessed_at);
+            }
+            Ok(())
+        }
+
+        async fn most_downloaded(&self, limit: Option<u32>) -> Result<Vec<File>, DbError> {
+            let state = self.state.lock().unwrap();
+            let mut files: Vec<File> = state.files.values().cloned().collect();
+            files.sort_by(|a, b| {
+                b.download_count
+                    .cmp(&a.download_count)
+                    .then_with(|| a.file_name.cmp(&b.file_name))
+            });
+            if let Some(limit) = limit {
+                files.truncate(limit as usize);
+            }
+            Ok(files)
+        }
+
+        async fn create_upload_session(&self, session: &UploadSession) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            if state.upload_sessions.contains_key(&session.id) {
+                return Err(conflict("id"));
+            }
+            state
+                .upload_sessions
+                .insert(session.id.clone(), session.clone());
+            Ok(())
+        }
+
+        async fn find_upload_session(&self, id: &str) -> Result<Option<UploadSession>, DbError> {
+            Ok(self.state.lock().unwrap().upload_sessions.get(id).cloned())
+        }
+
+        async fn list_upload_chunks(&self, id: &str) -> Result<Vec<UploadChunk>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .upload_chunks
+                .values()
+                .filter(|chunk| chunk.session_id == id)
+                .cloned()
+                .collect())
+        }
+
+        async fn record_upload_chunk(&self, chunk: &UploadChunk) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            if state.upload_chunks.values().any(|received| {
+                received.session_id == chunk.session_id
+                    && received.byte_offset != chunk.byte_offset
+                    && received.overlaps(chunk)
+            }) {
+                return Err(DbError::Conflict(
+                    "chunk overlaps one already received".to_owned(),
+                ));
+            }
+            state
+                .upload_chunks
+                .insert((chunk.session_id.clone(), chunk.byte_offset), chunk.clone());
+            Ok(())
+        }
+
+        async fn delete_upload_session(&self, id: &str) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            state
+                .upload_chunks
+                .retain(|(session_id, _), _| session_id != id);
+            state.upload_sessions.remove(id);
+            Ok(())
+        }
+
+        async fn find_expired_upload_sessions(
+            &self,
+            now: i64,
+        ) -> Result<Vec<UploadSession>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .upload_sessions
+                .values()
+                .filter(|session| session.expires_at <= now)
+                .cloned()
+                .collect())
+        }
+
+        async fn find_idempotency_key(
+            &self,
+            owner: &str,
+            key: &str,
+        ) -> Result<Option<IdempotencyKey>, DbError> {
+            Ok(self
+                .state
+                .lock()
+                .unwrap()
+                .idempotency_keys
+                .get(&(owner.to_owned(), key.to_owned()))
+                .cloned())
+        }
+
+        async fn insert_idempotency_key(&self, key: &IdempotencyKey) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            let id = (key.owner.clone(), key.idempotency_key.clone());
+            if state.idempotency_keys.contains_key(&id) {
+                return Err(conflict("owner, idempotency_key"));
+            }
+            state.idempotency_keys.insert(id, key.clone());
+            Ok(())
+        }
+
+        async fn complete_idempotency_key(
+            &self,
+            owner: &str,
+            key: &str,
+            response: &str,
+        ) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            let id = (owner.to_owned(), key.to_owned());
+            let reserved = state
+                .idempotency_keys
+                .get_mut(&id)
+                .ok_or(DbError::NotFound)?;
+            reserved.response = Some(response.to_owned());
+            Ok(())
+        }
+
+        async fn delete_idempotency_key(&self, owner: &str, key: &str) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            state
+                .idempotency_keys
+                .remove(&(owner.to_owned(), key.to_owned()));
+            Ok(())
+        }
+
+        async fn consume_signature(
+            &self,
+            signature: &str,
+            expires_at: i64,
+            now: i64,
+        ) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            state
+                .consumed_signatures
+                .retain(|_, expires_at| *expires_at >= now);
+            if state.consumed_signatures.contains_key(signature) {
+                return Err(conflict("signature"));
+            }
+            state
+                .consumed_signatures
+                .insert(signature.to_owned(), expires_at);
+            Ok(())
+        }
+
+        async fn release_signature(&self, signature: &str) -> Result<(), DbError> {
+            self.state
+                .lock()
+                .unwrap()
+                .consumed_signatures
+                .remove(signature);
+            Ok(())
+        }
+
+        async fn find_waveform_peaks(
+            &self,
+            sha256: &str,
+            resolution: i32,
+        ) -> Result<Option<WaveformPeaks>, DbError> {
+            Ok(self
+                .state
+                .lock()
+                .unwrap()
+                .waveform_peaks
+                .get(&(sha256.to_owned(), resolution))
+                .cloned())
+        }
+
+        async fn insert_waveform_peaks(&self, peaks: &WaveformPeaks) -> Result<(), DbError> {
+            self.state
+                .lock()
+                .unwrap()
+                .waveform_peaks
+                .entry((peaks.sha256.clone(), peaks.resolution))
+                .or_insert_with(|| peaks.clone());
+            Ok(())
+        }
+
+        async fn add_file_tag(&self, file_id: &str, tag_name: &str) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            state
+                .file_tags
+                .insert((file_id.to_owned(), tag_name.to_owned()));
+            Ok(())
+        }
+
+        async fn remove_file_tag(&self, file_id: &str, tag_name: &str) -> Result<bool, DbError> {
+            let mut state = self.state.lock().unwrap();
+            Ok(state
+                .file_tags
+                .remove(&(file_id.to_owned(), tag_name.to_owned())))
+        }
+
+        async fn list_file_tags(&self, file_id: &str) -> Result<Vec<String>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .file_tags
+                .iter()
+                .filter(|(tagged, _)| tagged == file_id)
+                .map(|(_, tag_name)| tag_name.clone())
+                .collect())
+        }
+
+        async fn find_file_ids_by_tag(&self, tag_name: &str) -> Result<Vec<String>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .file_tags
+                .iter()
+                .filter(|(_, tag)| tag == tag_name)
+                .map(|(file_id, _)| file_id.clone())
+                .collect())
+        }
+
+        async fn set_file_metadata(
+            &self,
+            file_id: &str,
+            metadata: &BTreeMap<String, String>,
+        ) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            state
+                .file_metadata
+                .insert(file_id.to_owned(), metadata.clone());
+            Ok(())
+        }
+
+        async fn get_file_metadata(
+            &self,
+            file_id: &str,
+        ) -> Result<BTreeMap<String, String>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .file_metadata
+                .get(file_id)
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        async fn find_file_ids_by_metadata(
+            &self,
+            key: &str,
+            value: &str,
+        ) -> Result<Vec<String>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .file_metadata
+                .iter()
+                .filter(|(_, metadata)| metadata.get(key).map(String::as_str) == Some(value))
+                .map(|(file_id, _)| file_id.clone())
+                .collect())
+        }
+
+        async fn search_files(&self, terms: &[String]) -> Result<Vec<String>, DbError> {
+            let state = self.state.lock().unwrap();
+            // Weighted like the databases' rankings: name, then title, then
+            // description
+            let score = |text: Option<&str>, weight: usize| {
+                let words = search_terms(text.unwrap_or_default());
+                terms
+                    .iter()
+                    .filter(|term| words.iter().any(|word| word.starts_with(term.as_str())))
+                    .count()
+                    * weight
+            };
+            let mut matches: Vec<(usize, &File)> = state
+                .files
+                .values()
+                .filter(|file| {
+                    let words = search_terms(&format!(
+                        "{} {} {}",
+                        file.file_name,
+                        file.title.as_deref().unwrap_or_default(),
+                        file.description.as_deref().unwrap_or_default()
+                    ));
+                    terms
+                        .iter()
+                        .all(|term| words.iter().any(|word| word.starts_with(term.as_str())))
+                })
+                .map(|file| {
+                    let score = score(Some(&file.file_name), 10)
+                        + score(file.title.as_deref(), 5)
+                        + score(file.description.as_deref(), 1);
+                    (score, file)
+                })
+                .collect();
+            matches.sort_by(|a, b| {
+                b.0.cmp(&a.0)
+                    .then_with(|| a.1.file_name.cmp(&b.1.file_name))
+            });
+            Ok(matches
+                .into_iter()
+                .map(|(_, file)| file.id.clone())
+                .collect())
+        }
+
+        async fn create_review_session(
+            &self,
+            session: &ReviewSession,
+            file_ids: &[String],
+        ) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            if state.review_sessions.contains_key(&session.token) {
+                return Err(conflict("token"));
+            }
+            state
+                .review_sessions
+                .insert(session.token.clone(), session.clone());
+            for file_id in file_ids {
+                state
+                    .review_session_files
+                    .insert((session.token.clone(), file_id.clone()));
+            }
+            Ok(())
+        }
+
+        async fn find_review_session(&self, token: &str) -> Result<Option<ReviewSession>, DbError> {
+            Ok(self
+                .state
+                .lock()
+                .unwrap()
+                .review_sessions
+                .get(token)
+                .cloned())
+        }
+
+        async fn list_review_session_files(&self, token: &str) -> Result<Vec<String>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .review_session_files
+                .iter()
+                .filter(|(session, _)| session == token)
+                .map(|(_, file_id)| file_id.clone())
+                .collect())
+        }
+
+        async fn run_maintenance(&self) -> Result<MaintenanceReport, DbError> {
+            Ok(MaintenanceReport::default())
+        }
+
+        async fn record_audit(&self, entry: &NewAuditEntry) -> Result<(), DbError> {
+            let mut state = self.state.lock().unwrap();
+            let id = state.audit_log.len() as i64 + 1;
+            state.audit_log.push(AuditEntry {
+                id,
+                occurred_at: entry.occurred_at,
+                actor: entry.actor.clone(),
+                client_ip: entry.client_ip.clone(),
+                forwarded_for: entry.forwarded_for.clone(),
+                method: entry.method.clone(),
+                path: entry.path.clone(),
+                status: entry.status,
+                changes: entry.changes.clone(),
+            });
+            Ok(())
+        }
+
+        async fn find_audit_entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, DbError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .audit_log
+                .iter()
+                .rev()
+                .filter(|entry| query.actor.is_none() || entry.actor == query.actor)
+                .filter(|entry| match &query.path {
+                    Some(path) => entry.path.contains(path.as_str()),
+                    None => true,
+                })
+                .filter(|entry| query.since.is_none_or(|since| entry.occurred_at >= since))
+                .filter(|entry| query.until.is_none_or(|until| entry.occurred_at < until))
+                .filter(|entry| query.before.is_none_or(|before| entry.id < before))
+                .take(query.limit() as usize)
+                .cloned()
+                .collect())
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::audit;
+use crate::db::DbError;
+use crate::repository::Repository;
+use crate::service;
+use crate::storage::SharedStorage;
+use crate::timestamp;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Files are deleted once past their own expires_at (set with ?ttl_seconds= on
+// upload), or, without one, once older than the TTL configured for their type.
+
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Seconds to keep each file type, from AUDIO_RETENTION, e.g.
+    /// `audio/wav=86400,audio/mpeg=604800`
+    pub file_type_ttls: BTreeMap<String, i64>,
+    /// How often to sweep, from RETENTION_SWEEP_SECONDS
+    pub sweep_interval: Duration,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let mut file_type_ttls = BTreeMap::new();
+        if let Ok(value) = std::env::var("AUDIO_RETENTION") {
+            for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+                let (file_type, ttl) = entry.split_once('=').with_context(|| {
+                    format!("AUDIO_RETENTION entry {:?} is not type=seconds", entry)
+                })?;
+                let ttl = ttl.trim().parse().with_context(|| {
+                    format!("AUDIO_RETENTION TTL for {} must be seconds", file_type)
+                })?;
+                file_type_ttls.insert(file_type.trim().to_owned(), ttl);
+            }
+        }
+        let sweep_interval = match std::env::var("RETENTION_SWEEP_SECONDS") {
+            Ok(value) => match value.parse() {
+                Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                _ => anyhow::bail!("RETENTION_SWEEP_SECONDS must be a positive number of seconds"),
+            },
+            Err(_) => DEFAULT_SWEEP_INTERVAL,
+        };
+        Ok(RetentionPolicy {
+            file_type_ttls,
+            sweep_interval,
+        })
+    }
+}
+
+/// Deletes every expired file and its stored bytes, returning the names
+/// purged. A file that fails to delete is logged and left for the next sweep.
+pub async fn sweep(
+    db: &Repository,
+    storage: &SharedStorage,
+    policy: &RetentionPolicy,
+) -> Result<Vec<String>, DbError> {
+    let expired = db
+        .find_expired_files(timestamp::now(), &policy.file_type_ttls)
+        .await?;
+    let mut purged = Vec::new();
+    for file in expired {
+        match service::delete_file(db, storage, &file.id).await {
+            Ok(()) => {
+                println!(
+                    "retention: purged {} ({} bytes)",
+                    file.file_name,
+                    file.file_size.unwrap_or(0)
+                );
+                audit::record_internal(db, "retention", "DELETE", format!("/audio/{}", file.id))
+                    .await;
+                purged.push(file.file_name);
+            }
+            // Deleted by someone else since the query
+            Err(DbError::NotFound) => {}
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+    Ok(purged)
+}
+
+/// Sweeps on the policy's interval for as long as the server runs.
+pub async fn run(db: Repository, storage: SharedStorage, policy: RetentionPolicy) {
+    let mut interval = tokio::time::interval(policy.sweep_interval);
+    loop {
+        interval.tick().await;
+        match sweep(&db, &storage, &policy).await {
+            Ok(purged) if !purged.is_empty() => {
+                println!("retention: purged {} expired files", purged.len())
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+}
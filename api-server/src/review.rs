@@ -0,0 +1,71 @@
+use crate::db::{File, ReviewSession};
+use crate::etag::epoch_seconds;
+use std::fmt::Write;
+
+// Review sessions share a fixed set of files behind one unguessable, expiring
+// token, with a bare read-only page for listening to them.
+
+/// 256 random bits, hex encoded.
+pub fn new_token() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(hex::encode(bytes))
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Percent-encodes everything but RFC 3986 unreserved characters
+pub fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+pub fn render_page(session: &ReviewSession, files: &[File]) -> String {
+    let mut page = String::new();
+    page.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    page.push_str("<title>Review session</title>\n</head>\n<body>\n<h1>Review session</h1>\n");
+    let _ = writeln!(
+        page,
+        "<p>Available until {}</p>",
+        httpdate::fmt_http_date(epoch_seconds(session.expires_at))
+    );
+    page.push_str("<ul>\n");
+    for file in files {
+        let heading = file.title.as_deref().unwrap_or(&file.file_name);
+        let _ = writeln!(page, "<li>\n<h2>{}</h2>", escape_html(heading));
+        if let Some(description) = &file.description {
+            let _ = writeln!(page, "<p>{}</p>", escape_html(description));
+        }
+        let _ = writeln!(
+            page,
+            "<audio controls preload=\"none\" src=\"/review-sessions/{}/audio/{}\"></audio>\n</li>",
+            session.token,
+            encode_path_segment(&file.id)
+        );
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    page
+}
@@ -1,9 +1,182 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    files (file_name) {
+    audit_log (id) {
+        id -> BigInt,
+        occurred_at -> BigInt,
+        actor -> Nullable<Text>,
+        client_ip -> Nullable<Text>,
+        forwarded_for -> Nullable<Text>,
+        method -> Text,
+        path -> Text,
+        status -> Nullable<Integer>,
+        changes -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    blobs (storage_key) {
+        storage_key -> Text,
+        sha256 -> Nullable<Text>,
+        ref_count -> BigInt,
+        stored_size -> BigInt,
+    }
+}
+
+diesel::table! {
+    consumed_signatures (signature) {
+        signature -> Text,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    file_metadata (file_id, meta_key) {
+        file_id -> Text,
+        meta_key -> Text,
+        meta_value -> Text,
+    }
+}
+
+diesel::table! {
+    file_tags (file_id, tag_name) {
+        file_id -> Text,
+        tag_name -> Text,
+    }
+}
+
+diesel::table! {
+    file_versions (file_id, version) {
+        file_id -> Text,
+        version -> Integer,
+        file_type -> Nullable<Text>,
+        file_upload_date -> BigInt,
+        file_size -> Nullable<BigInt>,
+        duration_ms -> Nullable<BigInt>,
+        storage_key -> Text,
+        sha256 -> Nullable<Text>,
+        compression -> Nullable<Text>,
+        stored_size -> Nullable<BigInt>,
+        replaced_at -> BigInt,
+        sample_rate -> Nullable<Integer>,
+        channels -> Nullable<Integer>,
+        bit_depth -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    files (id) {
         file_name -> Text,
         file_type -> Nullable<Text>,
-        file_upload_date -> Integer,
+        file_upload_date -> BigInt,
+        title -> Nullable<Text>,
+        description -> Nullable<Text>,
+        language -> Nullable<Text>,
+        file_size -> Nullable<BigInt>,
+        duration_ms -> Nullable<BigInt>,
+        starred -> Bool,
+        download_count -> BigInt,
+        last_accessed_at -> Nullable<BigInt>,
+        storage_key -> Nullable<Text>,
+        sha256 -> Nullable<Text>,
+        compression -> Nullable<Text>,
+        stored_size -> Nullable<BigInt>,
+        expires_at -> Nullable<BigInt>,
+        id -> Text,
+        revision -> Integer,
+        owner -> Nullable<Text>,
+        sample_rate -> Nullable<Integer>,
+        channels -> Nullable<Integer>,
+        bit_depth -> Nullable<Integer>,
     }
 }
+
+diesel::table! {
+    idempotency_keys (owner, idempotency_key) {
+        owner -> Text,
+        idempotency_key -> Text,
+        response -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    review_session_files (token, file_id) {
+        token -> Text,
+        file_id -> Text,
+    }
+}
+
+diesel::table! {
+    review_sessions (token) {
+        token -> Text,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    storage_usage (id) {
+        id -> Integer,
+        used_bytes -> BigInt,
+    }
+}
+
+diesel::table! {
+    tags (tag_name) {
+        tag_name -> Text,
+    }
+}
+
+diesel::table! {
+    upload_chunks (session_id, byte_offset) {
+        session_id -> Text,
+        byte_offset -> BigInt,
+        byte_length -> BigInt,
+    }
+}
+
+diesel::table! {
+    upload_sessions (id) {
+        id -> Text,
+        file_name -> Text,
+        file_type -> Nullable<Text>,
+        total_size -> BigInt,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    waveform_peaks (sha256, resolution) {
+        sha256 -> Text,
+        resolution -> Integer,
+        peaks -> Text,
+    }
+}
+
+diesel::joinable!(file_metadata -> files (file_id));
+diesel::joinable!(file_tags -> files (file_id));
+diesel::joinable!(file_tags -> tags (tag_name));
+diesel::joinable!(file_versions -> files (file_id));
+diesel::joinable!(review_session_files -> files (file_id));
+diesel::joinable!(review_session_files -> review_sessions (token));
+diesel::joinable!(upload_chunks -> upload_sessions (session_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    blobs,
+    consumed_signatures,
+    file_metadata,
+    file_tags,
+    file_versions,
+    files,
+    idempotency_keys,
+    review_session_files,
+    review_sessions,
+    storage_usage,
+    tags,
+    upload_chunks,
+    upload_sessions,
+    waveform_peaks,
+);
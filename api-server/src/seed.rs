@@ -0,0 +1,211 @@
+use crate::audio;
+use crate::db::{new_file_id, File};
+use crate::demo;
+use crate::repository::Repository;
+use crate::service;
+use crate::storage::{SharedStorage, StorageLayout};
+use crate::timestamp;
+use anyhow::Context;
+use axum::body::Bytes;
+use futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// `api-server seed <fixture.json>` fills the configured database from a
+// fixture, so demos and integration environments start with realistic data.
+// With --generate-audio every file also gets silent WAV audio of its length;
+// without it only the rows are written. Files already present are left alone,
+// so a fixture can be loaded again after it grows.
+
+const DEFAULT_SECONDS: u32 = 1;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    files: Vec<FixtureFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureFile {
+    file_name: String,
+    /// audio/wav when the audio is generated
+    file_type: Option<String>,
+    /// Defaults to now
+    #[serde(default, deserialize_with = "timestamp::deserialize_some")]
+    file_upload_date: Option<i64>,
+    title: Option<String>,
+    description: Option<String>,
+    language: Option<String>,
+    #[serde(default)]
+    starred: bool,
+    /// User the file belongs to; see crate::tenancy
+    owner: Option<String>,
+    /// Length of the generated audio
+    seconds: Option<u32>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+/// What loading a fixture did.
+#[derive(Debug, Default, PartialEq)]
+pub struct Seeded {
+    pub inserted: usize,
+    /// Files the database already had
+    pub skipped: usize,
+}
+
+/// Loads the fixture at `path` into `db`, storing generated audio in
+/// `storage` when `generate_audio` is set.
+pub async fn load_fixture(
+    db: &Repository,
+    storage: &SharedStorage,
+    layout: StorageLayout,
+    path: &Path,
+    generate_audio: bool,
+) -> Result<Seeded, anyhow::Error> {
+    let fixture = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("reading {:?}", path))?;
+    let fixture: Fixture =
+        serde_json::from_slice(&fixture).with_context(|| format!("parsing {:?}", path))?;
+    let mut seeded = Seeded::default();
+    for entry in fixture.files {
+        if db
+            .find_file_by_file_name(&entry.file_name)
+            .await?
+            .iter()
+            .any(|file| file.owner == entry.owner)
+        {
+            seeded.skipped += 1;
+            continue;
+        }
+        let mut file = File {
+            file_name: entry.file_name.clone(),
+            file_type: entry.file_type.clone(),
+            file_upload_date: entry.file_upload_date.unwrap_or_else(timestamp::now),
+            title: entry.title.clone(),
+            description: entry.description.clone(),
+            language: entry.language.clone(),
+            file_size: None,
+            duration_ms: None,
+            starred: entry.starred,
+            download_count: 0,
+            last_accessed_at: None,
+            storage_key: None,
+            sha256: None,
+            compression: None,
+            stored_size: None,
+            expires_at: None,
+            id: new_file_id(),
+            revision: 1,
+            owner: entry.owner.clone(),
+            sample_rate: None,
+            channels: None,
+            bit_depth: None,
+        };
+        if generate_audio {
+            store_silence(storage, layout, &mut file, &entry).await?;
+        }
+        let changes = match db.insert_file(&file, &entry.metadata).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                service::delete_objects(storage, file.storage_key.into_iter().collect()).await;
+                return Err(e).with_context(|| format!("inserting {}", entry.file_name));
+            }
+        };
+        // Silence of the same length was already stored for another file
+        if changes.storage_key != file.storage_key {
+            service::delete_objects(storage, file.storage_key.into_iter().collect()).await;
+        }
+        for tag in &entry.tags {
+            db.add_file_tag(&file.id, tag).await?;
+        }
+        seeded.inserted += 1;
+    }
+    Ok(seeded)
+}
+
+async fn store_silence(
+    storage: &SharedStorage,
+    layout: StorageLayout,
+    file: &mut File,
+    entry: &FixtureFile,
+) -> Result<(), anyhow::Error> {
+    let wav = demo::sine_wav(0, entry.seconds.unwrap_or(DEFAULT_SECONDS));
+    let file_type = file.file_type.get_or_insert_with(|| "audio/wav".to_owned());
+    let key = layout.object_key(&file.file_name, Some(file_type));
+    let size = wav.len() as i64;
+    file.file_size = Some(size);
+    file.stored_size = Some(size);
+    file.duration_ms = audio::probe_duration_ms(&wav, wav.len() as u64);
+    let format = audio::measure(std::io::Cursor::new(wav.clone()), &file.file_name);
+    file.sample_rate = format.sample_rate;
+    file.channels = format.channels;
+    file.bit_depth = format.bit_depth;
+    file.sha256 = Some(hex::encode(Sha256::digest(&wav)));
+    storage
+        .put(&key, stream::once(async { Ok(Bytes::from(wav)) }).boxed())
+        .await
+        .with_context(|| format!("storing audio for {}", file.file_name))?;
+    file.storage_key = Some(key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryRepository;
+    use crate::storage::memory::MemoryStorage;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn loading_again_skips_what_is_there() {
+        let path = std::env::temp_dir().join(format!("seed-{}.json", new_file_id()));
+        let fixture = r#"{"files": [
+            {"file_name": "standup.wav", "seconds": 2, "tags": ["meeting"],
+             "metadata": {"speaker": "Ana"}},
+            {"file_name": "retro.wav", "file_upload_date": "2026-10-01T09:00:00Z"}
+        ]}"#;
+        tokio::fs::write(&path, fixture).await.unwrap();
+        let db: Repository = Arc::new(MemoryRepository::default());
+        let storage: SharedStorage = Arc::new(MemoryStorage::default());
+        let load = || load_fixture(&db, &storage, StorageLayout::Sharded, &path, true);
+        let first = load().await.unwrap();
+        let second = load().await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            first,
+            Seeded {
+                inserted: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(
+            second,
+            Seeded {
+                inserted: 0,
+                skipped: 2
+            }
+        );
+        let standup = db.find_file_by_file_name("standup.wav").await.unwrap();
+        assert_eq!(standup[0].duration_ms, Some(2000));
+        // The format comes from demuxing, which only the decoding feature does
+        if cfg!(feature = "decoding") {
+            assert_eq!(standup[0].sample_rate, Some(8000));
+            assert_eq!(standup[0].channels, Some(1));
+            assert_eq!(standup[0].bit_depth, Some(16));
+        }
+        assert_eq!(standup[0].file_type.as_deref(), Some("audio/wav"));
+        assert_eq!(
+            db.list_file_tags(&standup[0].id).await.unwrap(),
+            ["meeting"]
+        );
+        let retro = db.find_file_by_file_name("retro.wav").await.unwrap();
+        assert_eq!(retro[0].file_upload_date, 1790845200);
+    }
+}
@@ -0,0 +1,30 @@
+use crate::db::DbError;
+use crate::repository::Repository;
+use crate::storage::SharedStorage;
+use std::io;
+
+// Operations every entry point (HTTP handlers, background tasks) shares, so
+// each behaves the same wherever it is called from. Errors stay domain errors;
+// callers turn them into their own kind of response.
+
+/// Best effort; an object that is already gone is fine.
+pub async fn delete_objects(storage: &SharedStorage, keys: Vec<String>) {
+    for key in keys {
+        if let Err(e) = storage.delete(&key).await {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
+
+/// Removes a file's row and whatever stored bytes no other row shares.
+pub async fn delete_file(
+    db: &Repository,
+    storage: &SharedStorage,
+    id: &str,
+) -> Result<(), DbError> {
+    let changes = db.delete_file(id).await?;
+    delete_objects(storage, changes.unreferenced).await;
+    Ok(())
+}
@@ -0,0 +1,307 @@
+use crate::audit::ACTOR_HEADER;
+use crate::db::DbError;
+use crate::repository::Repository;
+use crate::review::encode_path_segment;
+use crate::timestamp;
+use anyhow::Context;
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// Signed URLs let a client act on one file until an expiry without holding
+// any other credentials. The signature is an HMAC over what the URL is for,
+// what it names and the expiry, so none of them can be changed. Uploads name
+// the file they create; downloads name an existing file by id and revision,
+// so the link can't be followed to whatever later takes the file's name.
+//
+// An upload URL also names the user who asked for it, who owns the file it
+// creates, and works once: its signature is recorded as used when the upload
+// starts and only given back if the upload fails.
+
+/// Signs and checks URLs, keyed by URL_SIGNING_KEY.
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Arc<Vec<u8>>,
+    /// From SIGNED_UPLOADS_ONLY; rejects uploads to PUT /audio/:file_name
+    /// that don't carry a valid signature
+    signed_uploads_only: bool,
+}
+
+/// What a signed URL may be used for.
+#[derive(Debug, Clone, Copy)]
+enum Purpose {
+    Upload,
+    Download,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::Upload => "upload",
+            Purpose::Download => "download",
+        }
+    }
+}
+
+/// The query parameters a signed URL carries.
+#[derive(Debug, Default, Deserialize)]
+pub struct Signature {
+    expires: Option<i64>,
+    signature: Option<String>,
+    /// The revision of the file a download link was made for
+    revision: Option<i32>,
+    /// The user an upload URL was issued to
+    user: Option<String>,
+}
+
+/// The only query parameters a signed upload may carry. Upload options such
+/// as `overwrite`, `rename` and `ttl_seconds` aren't covered by the
+/// signature, so a URL can't be put to any use but the one it was signed for.
+const UPLOAD_PARAMETERS: [&str; 3] = ["expires", "signature", "user"];
+
+impl UrlSigner {
+    /// Without URL_SIGNING_KEY a random key is used, so URLs stop working
+    /// when the server restarts.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let key = match std::env::var("URL_SIGNING_KEY") {
+            Ok(key) if key.len() < 32 => {
+                anyhow::bail!("URL_SIGNING_KEY must be at least 32 characters")
+            }
+            Ok(key) => key.into_bytes(),
+            Err(_) => {
+                let mut key = vec![0u8; 32];
+                getrandom::getrandom(&mut key)
+                    .map_err(|e| anyhow::anyhow!(e))
+                    .context("generating a URL signing key")?;
+                key
+            }
+        };
+        Ok(UrlSigner {
+            key: Arc::new(key),
+            signed_uploads_only: std::env::var("SIGNED_UPLOADS_ONLY").is_ok(),
+        })
+    }
+
+    fn mac(&self, purpose: Purpose, subject: &str, expires: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key length");
+        mac.update(format!("{}\n{}\n{}", purpose.as_str(), subject, expires).as_bytes());
+        mac
+    }
+
+    fn signature(&self, purpose: Purpose, subject: &str, expires: i64) -> String {
+        hex::encode(self.mac(purpose, subject, expires).finalize().into_bytes())
+    }
+
+    fn verify_mac(&self, purpose: Purpose, subject: &str, query: &Signature) -> bool {
+        let (Some(expires), Some(signature)) = (query.expires, query.signature.as_deref()) else {
+            return false;
+        };
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        // verify_slice compares in constant time
+        expires >= timestamp::now()
+            && self
+                .mac(purpose, subject, expires)
+                .verify_slice(&signature)
+                .is_ok()
+    }
+
+    /// An upload path for `file_name`, with the expiry, the issuing user
+    /// and the signature in its query string.
+    pub fn sign(&self, path: &str, file_name: &str, user: Option<&str>, expires: i64) -> String {
+        let subject = format!("{}\n{}", user.unwrap_or_default(), file_name);
+        let mut url = format!(
+            "{}/{}?expires={}&signature={}",
+            path,
+            encode_path_segment(file_name),
+            expires,
+            self.signature(Purpose::Upload, &subject, expires)
+        );
+        if let Some(user) = user {
+            url.push_str("&user=");
+            url.push_str(&encode_path_segment(user));
+        }
+        url
+    }
+
+    /// Whether the query carries an unexpired upload signature for
+    /// `file_name`.
+    pub fn verify(&self, file_name: &str, query: &Signature) -> bool {
+        let subject = format!(
+            "{}\n{}",
+            query.user.as_deref().unwrap_or_default(),
+            file_name
+        );
+        self.verify_mac(Purpose::Upload, &subject, query)
+    }
+
+    /// A download path for the file with `id`, as it is at `revision`.
+    pub fn sign_file(&self, path: &str, id: &str, revision: i32, expires: i64) -> String {
+        let subject = format!("{}\n{}", id, revision);
+        format!(
+            "{}/{}?revision={}&expires={}&signature={}",
+            path,
+            encode_path_segment(id),
+            revision,
+            expires,
+            self.signature(Purpose::Download, &subject, expires)
+        )
+    }
+
+    /// The revision a download of the file with `id` was signed for, if the
+    /// query carries an unexpired signature.
+    pub fn verify_file(&self, id: &str, query: &Signature) -> Option<i32> {
+        let revision = query.revision?;
+        let subject = format!("{}\n{}", id, revision);
+        self.verify_mac(Purpose::Download, &subject, query)
+            .then_some(revision)
+    }
+}
+
+/// Checks the signature on uploads that carry one, spends it, and has the
+/// upload made as the user it was issued to.
+pub async fn verify_upload(
+    State((signer, db)): State<(UrlSigner, Repository)>,
+    Path(file_name): Path<String>,
+    Query(query): Query<Signature>,
+    Query(parameters): Query<BTreeMap<String, String>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (expires, signature) = match (query.expires, query.signature.as_deref()) {
+        (None, None) if !signer.signed_uploads_only => return next.run(request).await,
+        (None, None) => return StatusCode::UNAUTHORIZED.into_response(),
+        (Some(expires), Some(signature)) => (expires, signature),
+        _ => return StatusCode::FORBIDDEN.into_response(),
+    };
+    let signed_only = parameters
+        .keys()
+        .all(|parameter| UPLOAD_PARAMETERS.contains(&parameter.as_str()));
+    if !signed_only || !signer.verify(&file_name, &query) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    // Whatever user the client claims, the upload is the issuer's
+    let headers = request.headers_mut();
+    match query.user.as_deref().map(HeaderValue::from_str) {
+        Some(Ok(user)) => {
+            headers.insert(ACTOR_HEADER, user);
+        }
+        Some(Err(_)) => return StatusCode::BAD_REQUEST.into_response(),
+        None => {
+            headers.remove(ACTOR_HEADER);
+        }
+    }
+    match db
+        .consume_signature(signature, expires, timestamp::now())
+        .await
+    {
+        Ok(()) => {}
+        Err(DbError::Duplicate { .. }) => return StatusCode::FORBIDDEN.into_response(),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    let response = next.run(request).await;
+    // A rejected upload wrote nothing, so the URL can be tried again
+    if !response.status().is_success() {
+        if let Err(e) = db.release_signature(signature).await {
+            eprintln!("{:?}", e);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Uri;
+
+    fn signer() -> UrlSigner {
+        UrlSigner {
+            key: Arc::new(b"0123456789abcdef0123456789abcdef".to_vec()),
+            signed_uploads_only: false,
+        }
+    }
+
+    fn query_of(url: &str) -> Signature {
+        let uri: Uri = url.parse().unwrap();
+        Query::<Signature>::try_from_uri(&uri).unwrap().0
+    }
+
+    #[test]
+    fn upload_signatures_cover_the_name_user_and_expiry() {
+        let signer = signer();
+        let expires = timestamp::now() + 60;
+        let url = signer.sign("/audio", "a.wav", Some("ann"), expires);
+        let query = query_of(&url);
+        assert!(signer.verify("a.wav", &query));
+        assert!(!signer.verify("b.wav", &query));
+        let later = Signature {
+            expires: Some(expires + 3600),
+            ..query_of(&url)
+        };
+        assert!(!signer.verify("a.wav", &later));
+        let someone_else = Signature {
+            user: Some("bob".to_owned()),
+            ..query_of(&url)
+        };
+        assert!(!signer.verify("a.wav", &someone_else));
+        let nobody = Signature {
+            user: None,
+            ..query_of(&url)
+        };
+        assert!(!signer.verify("a.wav", &nobody));
+        let mut tampered = query_of(&url);
+        let signature = tampered.signature.as_mut().unwrap();
+        let last = if signature.ends_with('0') { "1" } else { "0" };
+        signature.replace_range(signature.len() - 1.., last);
+        assert!(!signer.verify("a.wav", &tampered));
+        let other_key = UrlSigner {
+            key: Arc::new(vec![7; 32]),
+            signed_uploads_only: false,
+        };
+        assert!(!other_key.verify("a.wav", &query));
+        let anonymous = signer.sign("/audio", "a.wav", None, expires);
+        assert!(!anonymous.contains("user="));
+        assert!(signer.verify("a.wav", &query_of(&anonymous)));
+    }
+
+    #[test]
+    fn download_signatures_cover_the_id_and_revision() {
+        let signer = signer();
+        let expires = timestamp::now() + 60;
+        let url = signer.sign_file("/shared", "1f0e", 3, expires);
+        assert!(url.starts_with("/shared/1f0e?"));
+        assert_eq!(signer.verify_file("1f0e", &query_of(&url)), Some(3));
+        assert_eq!(signer.verify_file("2a7c", &query_of(&url)), None);
+        let other_revision = Signature {
+            revision: Some(4),
+            ..query_of(&url)
+        };
+        assert_eq!(signer.verify_file("1f0e", &other_revision), None);
+        // Neither kind of signature stands in for the other
+        let upload = signer.sign("/audio", "1f0e", None, expires);
+        assert_eq!(signer.verify_file("1f0e", &query_of(&upload)), None);
+        assert!(!signer.verify("1f0e", &query_of(&url)));
+    }
+
+    #[test]
+    fn download_signatures_expire() {
+        let signer = signer();
+        let now = timestamp::now();
+        let current = query_of(&signer.sign_file("/shared", "1f0e", 1, now + 60));
+        assert_eq!(signer.verify_file("1f0e", &current), Some(1));
+        let expired = query_of(&signer.sign_file("/shared", "1f0e", 1, now - 1));
+        assert_eq!(signer.verify_file("1f0e", &expired), None);
+        assert_eq!(signer.verify_file("1f0e", &Signature::default()), None);
+    }
+}
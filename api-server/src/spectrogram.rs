@@ -0,0 +1,385 @@
+use crate::waveform;
+use std::f32::consts::PI;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+// Spectrograms for eyeballing a file's quality and content: time runs left to
+// right, frequency from 0 Hz at the bottom to half the sample rate at the
+// top, and brighter is louder. The audio is mixed down to mono and cut into
+// overlapping Hann windows, each transformed with an FFT; windows are then
+// averaged into the image's columns. Rendered images are cached on disk by
+// content hash, as they can take seconds to compute for long files.
+
+/// Image width in pixels, whatever the file's length.
+pub const WIDTH: usize = 800;
+
+// Samples per FFT window; the image has a row per frequency bin below Nyquist
+const FFT_SIZE: usize = 512;
+const HEIGHT: usize = FFT_SIZE / 2;
+// Half a window, so every sample counts fully in some window
+const HOP: usize = FFT_SIZE / 2;
+// Loudness from the loudest bin down to this many dB below it is shaded
+const DYNAMIC_RANGE_DB: f32 = 90.0;
+// Keeps silence from taking the log of zero
+const SILENCE_POWER: f32 = 1e-12;
+
+/// From SPECTROGRAM_CACHE_DIR, defaulting to ./spectrograms.
+pub fn cache_root() -> PathBuf {
+    std::env::var("SPECTROGRAM_CACHE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("spectrograms"))
+}
+
+/// Where the spectrogram of content with this hash is cached.
+pub fn cache_path(root: &Path, sha256: &str) -> PathBuf {
+    root.join(format!("{}.png", sha256))
+}
+
+/// Caches a rendered image, writing it aside first so a reader never sees
+/// half of one.
+pub async fn store(path: &Path, png: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = path.with_extension(format!("png.{}", uuid::Uuid::new_v4()));
+    tokio::fs::write(&partial, png).await?;
+    if let Err(e) = tokio::fs::rename(&partial, path).await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Decodes the whole file read from `source` and renders its spectrogram as
+/// a PNG image. `None` when it isn't audio a decoder is compiled in for, or
+/// is shorter than one window. Blocks, so run it off the runtime.
+pub fn render(source: impl Read + Send + Sync + 'static, file_name: &str) -> Option<Vec<u8>> {
+    let mut analyser = Analyser::new();
+    waveform::decode(source, file_name, |frame| {
+        analyser.push(frame.iter().sum::<f32>() / frame.len() as f32)
+    })?;
+    let columns = analyser.columns.into_columns();
+    if columns.is_empty() {
+        return None;
+    }
+    let levels: Vec<Vec<f32>> = columns
+        .iter()
+        .map(|column| column.iter().map(|power| 10.0 * power.log10()).collect())
+        .collect();
+    let loudest = levels
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f32::NEG_INFINITY, f32::max);
+    // Silence stays dark rather than being stretched over the whole range
+    let top = loudest.max(10.0 * SILENCE_POWER.log10() + DYNAMIC_RANGE_DB);
+    let mut pixels = Vec::with_capacity(WIDTH * HEIGHT);
+    for row in 0..HEIGHT {
+        let bin = HEIGHT - 1 - row;
+        pixels.extend(levels.iter().map(|column| {
+            let shade = (column[bin] - (top - DYNAMIC_RANGE_DB)) / DYNAMIC_RANGE_DB;
+            (shade.clamp(0.0, 1.0) * 255.0).round() as u8
+        }));
+    }
+    Some(encode_png(WIDTH, HEIGHT, &pixels, &palette()))
+}
+
+// Slides a window over the samples, transforming it every HOP samples
+struct Analyser {
+    window: Vec<f32>,
+    hann: Vec<f32>,
+    fft: Fft,
+    columns: Columns,
+}
+
+impl Analyser {
+    fn new() -> Self {
+        Analyser {
+            window: Vec::with_capacity(FFT_SIZE),
+            hann: (0..FFT_SIZE)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+                .collect(),
+            fft: Fft::new(FFT_SIZE),
+            columns: Columns::new(),
+        }
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.window.push(sample);
+        if self.window.len() < FFT_SIZE {
+            return;
+        }
+        let mut re: Vec<f32> = self
+            .window
+            .iter()
+            .zip(&self.hann)
+            .map(|(s, w)| s * w)
+            .collect();
+        let mut im = vec![0.0; FFT_SIZE];
+        self.fft.transform(&mut re, &mut im);
+        let power = re[..HEIGHT]
+            .iter()
+            .zip(&im[..HEIGHT])
+            .map(|(re, im)| re * re + im * im)
+            .collect();
+        self.columns.push(power);
+        self.window.drain(..HOP);
+    }
+}
+
+// The windows seen so far, summed into columns of a power-of-two number of
+// windows. Whenever there are twice as many columns as the image has,
+// neighbours merge and columns double in length, so memory stays bounded
+// however long the file.
+struct Columns {
+    windows_per_column: usize,
+    done: Vec<Vec<f32>>,
+    current: Option<Vec<f32>>,
+    windows_in_current: usize,
+}
+
+impl Columns {
+    fn new() -> Self {
+        Columns {
+            windows_per_column: 1,
+            done: Vec::new(),
+            current: None,
+            windows_in_current: 0,
+        }
+    }
+
+    fn push(&mut self, power: Vec<f32>) {
+        match &mut self.current {
+            Some(current) => add(current, &power),
+            None => self.current = Some(power),
+        }
+        self.windows_in_current += 1;
+        if self.windows_in_current < self.windows_per_column {
+            return;
+        }
+        self.done.extend(self.current.take());
+        self.windows_in_current = 0;
+        if self.done.len() >= 2 * WIDTH {
+            self.done = self
+                .done
+                .chunks(2)
+                .map(|pair| {
+                    let mut merged = pair[0].clone();
+                    pair.iter()
+                        .skip(1)
+                        .for_each(|other| add(&mut merged, other));
+                    merged
+                })
+                .collect();
+            self.windows_per_column *= 2;
+        }
+    }
+
+    // Exactly WIDTH columns of mean power per bin. A file with fewer windows
+    // than that has each stretched over several columns.
+    fn into_columns(self) -> Vec<Vec<f32>> {
+        // A partial column at the end is dropped rather than weighed as a
+        // whole one
+        let columns = self.done;
+        if columns.is_empty() {
+            return Vec::new();
+        }
+        let windows = self.windows_per_column as f32;
+        (0..WIDTH)
+            .map(|x| {
+                let start = x * columns.len() / WIDTH;
+                let end = ((x + 1) * columns.len() / WIDTH).max(start + 1);
+                let mut sum = vec![0.0; HEIGHT];
+                columns[start..end]
+                    .iter()
+                    .for_each(|column| add(&mut sum, column));
+                let count = (end - start) as f32 * windows;
+                sum.iter()
+                    .map(|power| power / count + SILENCE_POWER)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn add(sum: &mut [f32], other: &[f32]) {
+    sum.iter_mut()
+        .zip(other)
+        .for_each(|(sum, other)| *sum += other);
+}
+
+// An in-place radix-2 FFT of a fixed power-of-two size
+struct Fft {
+    size: usize,
+    cos: Vec<f32>,
+    sin: Vec<f32>,
+}
+
+impl Fft {
+    fn new(size: usize) -> Self {
+        let angle = |k: usize| -2.0 * PI * k as f32 / size as f32;
+        Fft {
+            size,
+            cos: (0..size / 2).map(|k| angle(k).cos()).collect(),
+            sin: (0..size / 2).map(|k| angle(k).sin()).collect(),
+        }
+    }
+
+    fn transform(&self, re: &mut [f32], im: &mut [f32]) {
+        let n = self.size;
+        let bits = n.trailing_zeros();
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let step = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (cos, sin) = (self.cos[k * step], self.sin[k * step]);
+                    let (a, b) = (start + k, start + k + len / 2);
+                    let t_re = re[b] * cos - im[b] * sin;
+                    let t_im = re[b] * sin + im[b] * cos;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+// Black through blue, magenta, red and yellow to white
+fn palette() -> Vec<[u8; 3]> {
+    const STOPS: [[f32; 3]; 6] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 0.5],
+        [0.6, 0.0, 0.6],
+        [0.9, 0.1, 0.1],
+        [1.0, 0.8, 0.0],
+        [1.0, 1.0, 1.0],
+    ];
+    (0..256)
+        .map(|i| {
+            let position = i as f32 / 255.0 * (STOPS.len() - 1) as f32;
+            let low = (position.floor() as usize).min(STOPS.len() - 2);
+            let along = position - low as f32;
+            let [from, to] = [STOPS[low], STOPS[low + 1]];
+            [0, 1, 2].map(|c| ((from[c] + (to[c] - from[c]) * along) * 255.0).round() as u8)
+        })
+        .collect()
+}
+
+// An 8-bit palette PNG. The pixel data is stored without compression, which
+// every decoder reads and needs no deflate implementation.
+fn encode_png(width: usize, height: usize, pixels: &[u8], palette: &[[u8; 3]]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, indexed colour, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+    // Each row starts with its filter type, none
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"PLTE", &palette.concat());
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demo;
+    use std::io::Cursor;
+
+    #[test]
+    fn checksums_match_their_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn a_tone_lights_up_its_own_row() {
+        let png = render(Cursor::new(demo::sine_wav(1000, 2)), "a.wav").unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(&png[16..24], &[0, 0, 3, 32, 0, 0, 1, 0]);
+        // The pixels sit uncompressed in the IDAT chunk, behind the zlib
+        // header and each deflate block's own
+        let idat = png.windows(4).position(|kind| kind == b"IDAT").unwrap() + 4;
+        let pixel = |x: usize, row: usize| {
+            let offset = row * (WIDTH + 1) + 1 + x;
+            png[idat + 2 + 5 * (offset / u16::MAX as usize + 1) + offset]
+        };
+        // 1 kHz sampled at 8 kHz is a quarter of the way to Nyquist
+        let tone = HEIGHT - 1 - HEIGHT / 4;
+        assert_eq!(pixel(WIDTH / 2, tone), 255);
+        assert!(pixel(WIDTH / 2, 0) < 64);
+    }
+
+    #[test]
+    fn anything_else_has_no_spectrogram() {
+        assert_eq!(render(Cursor::new(vec![7u8; 4096]), "a.wav"), None);
+    }
+}
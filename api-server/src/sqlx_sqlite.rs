@@ -0,0 +1,1490 @@
+use crate::blobs::{
+    acquire_blob, add_reference, adopt_legacy_object, release_file, restore_version,
+    retire_replaced, BlobQueries,
+};
+use crate::db::{
+    align_shared_objects, conflicting_field, new_file_id, AuditEntry, AuditQuery, BlobChanges,
+    DbError, File, FileDetails, FileVersion, IdempotencyKey, MaintenanceReport, NewAuditEntry,
+    ReviewSession, TypeUsage, UploadChunk, UploadSession, WaveformPeaks, BUSY_TIMEOUT_MS,
+    INSERT_BATCH_ROWS,
+};
+use crate::repository::FileRepository;
+use anyhow::Context;
+use async_trait::async_trait;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePool, SqlitePoolOptions,
+    SqliteSynchronous,
+};
+use sqlx::{QueryBuilder, Sqlite, Transaction};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// The SQLite repository again, on sqlx rather than diesel: queries run on
+// sqlx's own async driver instead of tokio's blocking threads, and the query
+// macros check every statement against the schema when the crate compiles.
+// Selected with DATABASE_DRIVER=sqlx in builds with the `sqlx` feature.
+//
+// The schema still comes from diesel's migrations. The macros check against
+// the cached descriptions in .sqlx, so builds need no database; after
+// changing a query or the schema, regenerate them against a migrated one:
+//
+//     SQLX_DATABASE_URL=sqlite:<migrated.db> SQLX_OFFLINE_DIR=.sqlx \
+//         cargo check --features sqlx
+
+/// Connections for [`SqlxRepository`], at most DATABASE_POOL_SIZE of them,
+/// opened as queries need them. Set up like diesel's, see
+/// [`crate::db::establish_pool`].
+pub fn establish_pool(database_url: &str) -> Result<SqlitePool, anyhow::Error> {
+    let options = SqliteConnectOptions::new()
+        .filename(database_url)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(Duration::from_millis(BUSY_TIMEOUT_MS.into()))
+        .foreign_keys(true);
+    let mut pool = SqlitePoolOptions::new();
+    if let Ok(size) = std::env::var("DATABASE_POOL_SIZE") {
+        let size = size
+            .parse()
+            .context("DATABASE_POOL_SIZE must be a number of connections")?;
+        pool = pool.max_connections(size);
+    }
+    Ok(pool.connect_lazy_with(options))
+}
+
+/// Read-only connections for [`SqlxRepository::with_read_pool`], as many as
+/// the main pool holds. Like diesel's, they set nothing that writes, since
+/// the file may be a replica another process keeps up to date.
+pub fn establish_read_pool(database_url: &str) -> Result<SqlitePool, anyhow::Error> {
+    let options = SqliteConnectOptions::new()
+        .filename(database_url)
+        .read_only(true)
+        .busy_timeout(Duration::from_millis(BUSY_TIMEOUT_MS.into()));
+    let mut pool = SqlitePoolOptions::new();
+    if let Ok(size) = std::env::var("DATABASE_POOL_SIZE") {
+        let size = size
+            .parse()
+            .context("DATABASE_POOL_SIZE must be a number of connections")?;
+        pool = pool.max_connections(size);
+    }
+    Ok(pool.connect_lazy_with(options))
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        use sqlx::error::ErrorKind;
+        match e {
+            sqlx::Error::RowNotFound => DbError::NotFound,
+            sqlx::Error::Database(ref info) => match info.kind() {
+                ErrorKind::UniqueViolation => DbError::Duplicate {
+                    field: conflicting_field(info.message(), None),
+                },
+                ErrorKind::ForeignKeyViolation => DbError::NotFound,
+                _ => {
+                    let message = info.message();
+                    if message.contains("database is locked")
+                        || message.contains("database is busy")
+                    {
+                        DbError::Busy
+                    } else if message.contains("malformed") || message.contains("not a database") {
+                        DbError::Corrupt(message.to_owned())
+                    } else {
+                        other(e)
+                    }
+                }
+            },
+            // Every connection is in use, as when deadpool runs out
+            sqlx::Error::PoolTimedOut => DbError::Busy,
+            e => other(e),
+        }
+    }
+}
+
+// DbError only carries diesel's errors, which can box any other
+fn other(e: sqlx::Error) -> DbError {
+    DbError::Other(diesel::result::Error::QueryBuilderError(Box::new(e)))
+}
+
+// Every column of files, so rows load into File
+macro_rules! select_files {
+    ($rest:literal $(, $arg:expr)* $(,)?) => {
+        sqlx::query_as!(
+            File,
+            r#"SELECT file_name, file_type, file_upload_date, title, description, language,
+                file_size, duration_ms, starred AS "starred: bool", download_count,
+                last_accessed_at AS "last_accessed_at: i64", storage_key, sha256, compression,
+                stored_size, expires_at AS "expires_at: i64", id, revision AS "revision: i32",
+                owner, sample_rate AS "sample_rate: i32", channels AS "channels: i32",
+                bit_depth AS "bit_depth: i32"
+            FROM files "# + $rest
+            $(, $arg)*
+        )
+    };
+}
+
+macro_rules! select_file_versions {
+    ($rest:literal $(, $arg:expr)* $(,)?) => {
+        sqlx::query_as!(
+            FileVersion,
+            r#"SELECT file_id, version AS "version: i32", file_type, file_upload_date,
+                file_size, duration_ms, storage_key, sha256, compression, stored_size,
+                replaced_at, sample_rate AS "sample_rate: i32", channels AS "channels: i32",
+                bit_depth AS "bit_depth: i32"
+            FROM file_versions "# + $rest
+            $(, $arg)*
+        )
+    };
+}
+
+/// sqlx/SQLite implementation of [`FileRepository`].
+pub struct SqlxRepository {
+    pool: SqlitePool,
+    /// Serves listings, filters and searches when set
+    read_pool: Option<SqlitePool>,
+}
+
+impl SqlxRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqlxRepository {
+            pool,
+            read_pool: None,
+        }
+    }
+
+    /// Sends the queries [`FileRepository`] says may read a replica to
+    /// `read_pool`, so they don't hold up writes.
+    pub fn with_read_pool(self, read_pool: SqlitePool) -> Self {
+        SqlxRepository {
+            read_pool: Some(read_pool),
+            ..self
+        }
+    }
+
+    fn reader(&self) -> &SqlitePool {
+        self.read_pool.as_ref().unwrap_or(&self.pool)
+    }
+
+    // Takes the write lock up front, as the diesel repository's writes do
+    async fn begin(&self) -> Result<Transaction<'static, Sqlite>, DbError> {
+        Ok(self.pool.begin_with("BEGIN IMMEDIATE").await?)
+    }
+}
+
+#[async_trait]
+impl BlobQueries for SqliteConnection {
+    type Error = sqlx::Error;
+
+    async fn blob_with_sha256(&mut self, sha256: &str) -> sqlx::Result<Option<String>> {
+        sqlx::query_scalar!("SELECT storage_key FROM blobs WHERE sha256 = ?", sha256)
+            .fetch_optional(self)
+            .await
+    }
+
+    async fn stored_format(
+        &mut self,
+        storage_key: &str,
+    ) -> sqlx::Result<Option<(Option<String>, Option<i64>)>> {
+        let stored = sqlx::query!(
+            "SELECT compression, stored_size FROM files WHERE storage_key = ?",
+            storage_key
+        )
+        .fetch_optional(self)
+        .await?;
+        Ok(stored.map(|stored| (stored.compression, stored.stored_size)))
+    }
+
+    async fn add_references(&mut self, storage_key: &str, delta: i64) -> sqlx::Result<bool> {
+        let updated = sqlx::query!(
+            "UPDATE blobs SET ref_count = ref_count + ? WHERE storage_key = ?",
+            delta,
+            storage_key
+        )
+        .execute(self)
+        .await?
+        .rows_affected();
+        Ok(updated > 0)
+    }
+
+    async fn insert_blob(
+        &mut self,
+        storage_key: &str,
+        sha256: Option<&str>,
+        stored_size: i64,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO blobs (storage_key, sha256, ref_count, stored_size) VALUES (?, ?, 1, ?)",
+            storage_key,
+            sha256,
+            stored_size
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn unreferenced_size(&mut self, storage_key: &str) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(
+            "SELECT stored_size FROM blobs WHERE storage_key = ? AND ref_count <= 0",
+            storage_key
+        )
+        .fetch_optional(self)
+        .await
+    }
+
+    async fn delete_blob(&mut self, storage_key: &str) -> sqlx::Result<()> {
+        sqlx::query!("DELETE FROM blobs WHERE storage_key = ?", storage_key)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_storage_usage(&mut self, bytes: i64) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE storage_usage SET used_bytes = used_bytes + ?",
+            bytes
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn latest_version(&mut self, file_id: &str) -> sqlx::Result<Option<i32>> {
+        sqlx::query_scalar!(
+            r#"SELECT MAX(version) AS "version: i32" FROM file_versions WHERE file_id = ?"#,
+            file_id
+        )
+        .fetch_one(self)
+        .await
+    }
+
+    async fn insert_version(&mut self, version: &FileVersion) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO file_versions (file_id, version, file_type, file_upload_date, \
+             file_size, duration_ms, storage_key, sha256, compression, stored_size, replaced_at, \
+             sample_rate, channels, bit_depth) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            version.file_id,
+            version.version,
+            version.file_type,
+            version.file_upload_date,
+            version.file_size,
+            version.duration_ms,
+            version.storage_key,
+            version.sha256,
+            version.compression,
+            version.stored_size,
+            version.replaced_at,
+            version.sample_rate,
+            version.channels,
+            version.bit_depth
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+}
+
+// Replaces the file's custom metadata with `metadata`
+async fn replace_metadata(
+    conn: &mut SqliteConnection,
+    file_id: &str,
+    metadata: &BTreeMap<String, String>,
+) -> sqlx::Result<()> {
+    sqlx::query!("DELETE FROM file_metadata WHERE file_id = ?", file_id)
+        .execute(&mut *conn)
+        .await?;
+    for (key, value) in metadata {
+        sqlx::query!(
+            "INSERT INTO file_metadata (file_id, meta_key, meta_value) VALUES (?, ?, ?)",
+            file_id,
+            key,
+            value
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn insert_file_row(conn: &mut SqliteConnection, file: &File) -> sqlx::Result<()> {
+    sqlx::query!(
+        "INSERT INTO files (file_name, file_type, file_upload_date, title, description, \
+         language, file_size, duration_ms, starred, download_count, last_accessed_at, \
+         storage_key, sha256, compression, stored_size, expires_at, id, revision, owner, \
+         sample_rate, channels, bit_depth) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        file.file_name,
+        file.file_type,
+        file.file_upload_date,
+        file.title,
+        file.description,
+        file.language,
+        file.file_size,
+        file.duration_ms,
+        file.starred,
+        file.download_count,
+        file.last_accessed_at,
+        file.storage_key,
+        file.sha256,
+        file.compression,
+        file.stored_size,
+        file.expires_at,
+        file.id,
+        file.revision,
+        file.owner,
+        file.sample_rate,
+        file.channels,
+        file.bit_depth
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Built at run time, as the query macros can't take a variable number of rows
+async fn insert_file_rows(conn: &mut SqliteConnection, rows: &[File]) -> sqlx::Result<()> {
+    for batch in rows.chunks(INSERT_BATCH_ROWS) {
+        QueryBuilder::<Sqlite>::new(
+            "INSERT INTO files (file_name, file_type, file_upload_date, title, description, \
+             language, file_size, duration_ms, starred, download_count, last_accessed_at, \
+             storage_key, sha256, compression, stored_size, expires_at, id, revision, owner, \
+             sample_rate, channels, bit_depth) ",
+        )
+        .push_values(batch, |mut row, file| {
+            row.push_bind(&file.file_name)
+                .push_bind(&file.file_type)
+                .push_bind(file.file_upload_date)
+                .push_bind(&file.title)
+                .push_bind(&file.description)
+                .push_bind(&file.language)
+                .push_bind(file.file_size)
+                .push_bind(file.duration_ms)
+                .push_bind(file.starred)
+                .push_bind(file.download_count)
+                .push_bind(file.last_accessed_at)
+                .push_bind(&file.storage_key)
+                .push_bind(&file.sha256)
+                .push_bind(&file.compression)
+                .push_bind(file.stored_size)
+                .push_bind(file.expires_at)
+                .push_bind(&file.id)
+                .push_bind(file.revision)
+                .push_bind(&file.owner)
+                .push_bind(file.sample_rate)
+                .push_bind(file.channels)
+                .push_bind(file.bit_depth);
+        })
+        .build()
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn find_file(conn: &mut SqliteConnection, target: &str) -> sqlx::Result<Option<File>> {
+    select_files!("WHERE id = ?", target)
+        .fetch_optional(conn)
+        .await
+}
+
+#[async_trait]
+impl FileRepository for SqlxRepository {
+    async fn insert_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError> {
+        let mut file = file.clone();
+        let mut tx = self.begin().await?;
+        acquire_blob(&mut *tx, &mut file).await?;
+        insert_file_row(&mut tx, &file).await?;
+        replace_metadata(&mut tx, &file.id, metadata).await?;
+        tx.commit().await?;
+        Ok(BlobChanges {
+            storage_key: file.storage_key,
+            compression: file.compression,
+            stored_size: file.stored_size,
+            id: Some(file.id),
+            revision: Some(file.revision),
+            unreferenced: vec![],
+        })
+    }
+
+    async fn insert_files(
+        &self,
+        files: &[(File, BTreeMap<String, String>)],
+    ) -> Result<Vec<BlobChanges>, DbError> {
+        let mut rows = files
+            .iter()
+            .map(|(file, _)| file.clone())
+            .collect::<Vec<_>>();
+        let mut tx = self.begin().await?;
+        for row in &mut rows {
+            acquire_blob(&mut *tx, row).await?;
+        }
+        align_shared_objects(&mut rows);
+        insert_file_rows(&mut tx, &rows).await?;
+        for (file, metadata) in files {
+            replace_metadata(&mut tx, &file.id, metadata).await?;
+        }
+        tx.commit().await?;
+        Ok(rows.into_iter().map(BlobChanges::inserted).collect())
+    }
+
+    async fn replace_file(
+        &self,
+        file: &File,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BlobChanges, DbError> {
+        let mut file = file.clone();
+        let mut tx = self.begin().await?;
+        let previous = select_files!(
+            "WHERE owner IS ? AND file_name = ?",
+            file.owner,
+            file.file_name
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(previous) = &previous {
+            file.id = previous.id.clone();
+            file.revision = previous.revision + 1;
+        }
+        acquire_blob(&mut *tx, &mut file).await?;
+        // Updated in place rather than replaced, as a delete would cascade
+        // to the file's tags, metadata and versions
+        if previous.is_some() {
+            sqlx::query!(
+                "UPDATE files SET file_type = ?, file_upload_date = ?, title = ?, \
+                 description = ?, language = ?, file_size = ?, duration_ms = ?, starred = ?, \
+                 download_count = ?, last_accessed_at = ?, storage_key = ?, sha256 = ?, \
+                 compression = ?, stored_size = ?, expires_at = ?, revision = ?, \
+                 sample_rate = ?, channels = ?, bit_depth = ? WHERE id = ?",
+                file.file_type,
+                file.file_upload_date,
+                file.title,
+                file.description,
+                file.language,
+                file.file_size,
+                file.duration_ms,
+                file.starred,
+                file.download_count,
+                file.last_accessed_at,
+                file.storage_key,
+                file.sha256,
+                file.compression,
+                file.stored_size,
+                file.expires_at,
+                file.revision,
+                file.sample_rate,
+                file.channels,
+                file.bit_depth,
+                file.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            insert_file_row(&mut tx, &file).await?;
+        }
+        replace_metadata(&mut tx, &file.id, metadata).await?;
+        let unreferenced = retire_replaced(&mut *tx, previous, &file).await?;
+        tx.commit().await?;
+        Ok(BlobChanges {
+            storage_key: file.storage_key,
+            compression: file.compression,
+            stored_size: file.stored_size,
+            id: Some(file.id),
+            revision: Some(file.revision),
+            unreferenced,
+        })
+    }
+
+    async fn delete_file(&self, target: &str) -> Result<BlobChanges, DbError> {
+        let mut tx = self.begin().await?;
+        let file = find_file(&mut tx, target).await?.ok_or(DbError::NotFound)?;
+        sqlx::query!("DELETE FROM file_tags WHERE file_id = ?", target)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM file_metadata WHERE file_id = ?", target)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM review_session_files WHERE file_id = ?", target)
+            .execute(&mut *tx)
+            .await?;
+        let versions = sqlx::query_scalar!(
+            "SELECT storage_key FROM file_versions WHERE file_id = ?",
+            target
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM file_versions WHERE file_id = ?", target)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM files WHERE id = ?", target)
+            .execute(&mut *tx)
+            .await?;
+        let unreferenced = release_file(&mut *tx, &file, versions).await?;
+        tx.commit().await?;
+        Ok(BlobChanges {
+            unreferenced,
+            ..Default::default()
+        })
+    }
+
+    async fn list_file_versions(&self, target: &str) -> Result<Vec<FileVersion>, DbError> {
+        Ok(
+            select_file_versions!("WHERE file_id = ? ORDER BY version", target)
+                .fetch_all(self.reader())
+                .await?,
+        )
+    }
+
+    async fn list_all_versions(&self) -> Result<Vec<FileVersion>, DbError> {
+        Ok(select_file_versions!("").fetch_all(&self.pool).await?)
+    }
+
+    async fn find_file_version(
+        &self,
+        target: &str,
+        target_version: i32,
+    ) -> Result<Option<FileVersion>, DbError> {
+        Ok(
+            select_file_versions!("WHERE file_id = ? AND version = ?", target, target_version)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn restore_file_version(
+        &self,
+        target: &str,
+        target_version: i32,
+        restored_at: i64,
+    ) -> Result<File, DbError> {
+        let mut tx = self.begin().await?;
+        let current = find_file(&mut tx, target).await?.ok_or(DbError::NotFound)?;
+        let restored =
+            select_file_versions!("WHERE file_id = ? AND version = ?", target, target_version)
+                .fetch_one(&mut *tx)
+                .await?;
+        let file = restore_version(&mut *tx, current, restored, restored_at).await?;
+        sqlx::query!(
+            "UPDATE files SET file_type = ?, file_upload_date = ?, file_size = ?, \
+             duration_ms = ?, storage_key = ?, sha256 = ?, compression = ?, stored_size = ?, \
+             sample_rate = ?, channels = ?, bit_depth = ? WHERE id = ?",
+            file.file_type,
+            file.file_upload_date,
+            file.file_size,
+            file.duration_ms,
+            file.storage_key,
+            file.sha256,
+            file.compression,
+            file.stored_size,
+            file.sample_rate,
+            file.channels,
+            file.bit_depth,
+            target
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(file)
+    }
+
+    async fn copy_file(
+        &self,
+        source: &str,
+        destination: &str,
+        copied_at: i64,
+    ) -> Result<File, DbError> {
+        let mut tx = self.begin().await?;
+        let file = find_file(&mut tx, source).await?.ok_or(DbError::NotFound)?;
+        let key = adopt_legacy_object(&mut *tx, &file).await?;
+        if file.storage_key.is_none() {
+            sqlx::query!("UPDATE files SET storage_key = ? WHERE id = ?", key, source)
+                .execute(&mut *tx)
+                .await?;
+        }
+        add_reference(&mut *tx, &key).await?;
+        let copy = File {
+            file_name: destination.to_owned(),
+            file_upload_date: copied_at,
+            starred: false,
+            download_count: 0,
+            last_accessed_at: None,
+            storage_key: Some(key),
+            id: new_file_id(),
+            revision: 1,
+            ..file
+        };
+        insert_file_row(&mut tx, &copy).await?;
+        sqlx::query!(
+            "INSERT INTO file_tags (file_id, tag_name) \
+             SELECT ?, tag_name FROM file_tags WHERE file_id = ?",
+            copy.id,
+            source
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO file_metadata (file_id, meta_key, meta_value) \
+             SELECT ?, meta_key, meta_value FROM file_metadata WHERE file_id = ?",
+            copy.id,
+            source
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(copy)
+    }
+
+    async fn adopt_legacy_object(&self, target: &str) -> Result<(), DbError> {
+        let mut tx = self.begin().await?;
+        let file = find_file(&mut tx, target).await?.ok_or(DbError::NotFound)?;
+        if file.storage_key.is_none() {
+            let key = adopt_legacy_object(&mut *tx, &file).await?;
+            sqlx::query!("UPDATE files SET storage_key = ? WHERE id = ?", key, target)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn rename_file(&self, target: &str, destination: &str) -> Result<File, DbError> {
+        let mut tx = self.begin().await?;
+        let file = find_file(&mut tx, target).await?.ok_or(DbError::NotFound)?;
+        // A legacy object stays where it is; only the row's name changes
+        let key = adopt_legacy_object(&mut *tx, &file).await?;
+        sqlx::query!(
+            "UPDATE files SET file_name = ?, storage_key = ? WHERE id = ?",
+            destination,
+            key,
+            target
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(File {
+            file_name: destination.to_owned(),
+            storage_key: Some(key),
+            ..file
+        })
+    }
+
+    async fn list_file_names(&self) -> Result<Vec<String>, DbError> {
+        Ok(sqlx::query_scalar!("SELECT file_name FROM files")
+            .fetch_all(self.reader())
+            .await?)
+    }
+
+    async fn list_all_files(&self) -> Result<Vec<File>, DbError> {
+        Ok(select_files!("").fetch_all(&self.pool).await?)
+    }
+
+    async fn find_files_by_ids(&self, targets: &[String]) -> Result<Vec<File>, DbError> {
+        // Passed as one JSON array, since a checked query has a fixed number
+        // of parameters
+        let targets = serde_json::to_string(targets).expect("strings serialize");
+        Ok(
+            select_files!("WHERE id IN (SELECT value FROM json_each(?))", targets)
+                .fetch_all(self.reader())
+                .await?,
+        )
+    }
+
+    async fn count_files(&self) -> Result<i64, DbError> {
+        Ok(
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!: i64" FROM files"#)
+                .fetch_one(self.reader())
+                .await?,
+        )
+    }
+
+    async fn storage_usage(&self) -> Result<i64, DbError> {
+        Ok(sqlx::query_scalar!("SELECT used_bytes FROM storage_usage")
+            .fetch_optional(&self.pool)
+            .await?
+            .unwrap_or(0))
+    }
+
+    async fn usage_by_file_type(&self) -> Result<Vec<TypeUsage>, DbError> {
+        Ok(sqlx::query_as!(
+            TypeUsage,
+            r#"SELECT file_type, COUNT(*) AS "files!: i64",
+                COALESCE(SUM(COALESCE(stored_size, file_size)), 0) AS "bytes!: i64"
+            FROM files GROUP BY file_type ORDER BY file_type"#
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn find_file_by_file_name(&self, target: &str) -> Result<Vec<File>, DbError> {
+        Ok(select_files!("WHERE file_name = ?", target)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn find_file_by_id(&self, target: &str) -> Result<Option<File>, DbError> {
+        Ok(select_files!("WHERE id = ?", target)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn find_file_by_file_type(&self, target: &str) -> Result<Vec<File>, DbError> {
+        Ok(select_files!("WHERE file_type = ?", target)
+            .fetch_all(self.reader())
+            .await?)
+    }
+
+    async fn find_file_by_file_upload_date(&self, target: &i64) -> Result<Vec<File>, DbError> {
+        Ok(select_files!("WHERE file_upload_date = ?", target)
+            .fetch_all(self.reader())
+            .await?)
+    }
+
+    async fn find_file_by_file_size_range(
+        &self,
+        min_size: Option<i64>,
+        max_size: Option<i64>,
+    ) -> Result<Vec<File>, DbError> {
+        Ok(select_files!(
+            "WHERE file_size IS NOT NULL \
+             AND (?1 IS NULL OR file_size >= ?1) AND (?2 IS NULL OR file_size <= ?2)",
+            min_size,
+            max_size
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn find_file_by_duration_range(
+        &self,
+        min_duration_ms: Option<i64>,
+        max_duration_ms: Option<i64>,
+    ) -> Result<Vec<File>, DbError> {
+        Ok(select_files!(
+            "WHERE duration_ms IS NOT NULL \
+             AND (?1 IS NULL OR duration_ms >= ?1) AND (?2 IS NULL OR duration_ms <= ?2)",
+            min_duration_ms,
+            max_duration_ms
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn find_file_by_audio_format(
+        &self,
+        sample_rate: Option<i32>,
+        channels: Option<i32>,
+        bit_depth: Option<i32>,
+    ) -> Result<Vec<File>, DbError> {
+        Ok(select_files!(
+            "WHERE (?1 IS NULL OR sample_rate = ?1) AND (?2 IS NULL OR channels = ?2) \
+             AND (?3 IS NULL OR bit_depth = ?3)",
+            sample_rate,
+            channels,
+            bit_depth
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn find_file_by_starred(&self, target: bool) -> Result<Vec<File>, DbError> {
+        Ok(select_files!("WHERE starred = ?", target)
+            .fetch_all(self.reader())
+            .await?)
+    }
+
+    // IS matches NULL against NULL, unlike =
+    async fn find_file_by_owner(&self, target: Option<&str>) -> Result<Vec<File>, DbError> {
+        Ok(select_files!("WHERE owner IS ? ORDER BY file_name", target)
+            .fetch_all(self.reader())
+            .await?)
+    }
+
+    async fn find_file_by_last_access_before(&self, cutoff: i64) -> Result<Vec<File>, DbError> {
+        Ok(select_files!(
+            "WHERE last_accessed_at < ?1 \
+             OR (last_accessed_at IS NULL AND file_upload_date < ?1)",
+            cutoff
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn find_expired_files(
+        &self,
+        now: i64,
+        file_type_ttls: &BTreeMap<String, i64>,
+    ) -> Result<Vec<File>, DbError> {
+        // The TTLs as one JSON object of file type to seconds
+        let ttls = serde_json::to_string(file_type_ttls).expect("TTLs serialize");
+        Ok(select_files!(
+            "WHERE expires_at <= ?1 OR (expires_at IS NULL AND EXISTS ( \
+                 SELECT 1 FROM json_each(?2) ttl \
+                 WHERE ttl.key = files.file_type AND files.file_upload_date <= ?1 - ttl.value))",
+            now,
+            ttls
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn update_file_details(
+        &self,
+        target: &str,
+        revision: i32,
+        details: &FileDetails,
+        metadata: Option<&BTreeMap<String, String>>,
+    ) -> Result<File, DbError> {
+        let mut tx = self.begin().await?;
+        // Fields left out keep their value
+        let updated = sqlx::query!(
+            "UPDATE files SET title = COALESCE(?, title), \
+             description = COALESCE(?, description), language = COALESCE(?, language), \
+             revision = revision + 1 \
+             WHERE id = ? AND revision = ?",
+            details.title,
+            details.description,
+            details.language,
+            target,
+            revision
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            // Missing altogether is NotFound rather than stale
+            find_file(&mut tx, target).await?.ok_or(DbError::NotFound)?;
+            return Err(DbError::Stale);
+        }
+        if let Some(metadata) = metadata {
+            replace_metadata(&mut tx, target, metadata).await?;
+        }
+        let file = find_file(&mut tx, target).await?.ok_or(DbError::NotFound)?;
+        tx.commit().await?;
+        Ok(file)
+    }
+
+    async fn toggle_starred(&self, target: &str) -> Result<bool, DbError> {
+        let starred = sqlx::query_scalar!(
+            r#"UPDATE files SET starred = NOT starred WHERE id = ?
+            RETURNING starred AS "starred: bool""#,
+            target
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        starred.ok_or(DbError::NotFound)
+    }
+
+    async fn record_download(&self, target: &str, accessed_at: i64) -> Result<(), DbError> {
+        sqlx::query!(
+            "UPDATE files SET download_count = download_count + 1, last_accessed_at = ? \
+             WHERE id = ?",
+            accessed_at,
+            target
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn most_downloaded(&self, limit: Option<u32>) -> Result<Vec<File>, DbError> {
+        // A negative limit is none at all
+        let limit = limit.map_or(-1, i64::from);
+        Ok(
+            select_files!("ORDER BY download_count DESC, file_name LIMIT ?", limit)
+                .fetch_all(self.reader())
+                .await?,
+        )
+    }
+
+    async fn create_upload_session(&self, session: &UploadSession) -> Result<(), DbError> {
+        sqlx::query!(
+            "INSERT INTO upload_sessions (id, file_name, file_type, total_size, created_at, \
+             expires_at) VALUES (?, ?, ?, ?, ?, ?)",
+            session.id,
+            session.file_name,
+            session.file_type,
+            session.total_size,
+            session.created_at,
+            session.expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_upload_session(&self, id: &str) -> Result<Option<UploadSession>, DbError> {
+        Ok(sqlx::query_as!(
+            UploadSession,
+            r#"SELECT id, file_name, file_type, total_size, created_at AS "created_at: i64",
+                expires_at AS "expires_at: i64"
+            FROM upload_sessions WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn list_upload_chunks(&self, id: &str) -> Result<Vec<UploadChunk>, DbError> {
+        Ok(sqlx::query_as!(
+            UploadChunk,
+            "SELECT session_id, byte_offset, byte_length FROM upload_chunks \
+             WHERE session_id = ? ORDER BY byte_offset",
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn record_upload_chunk(&self, chunk: &UploadChunk) -> Result<(), DbError> {
+        let mut tx = self.begin().await?;
+        let overlapping = sqlx::query_as!(
+            UploadChunk,
+            "SELECT session_id, byte_offset, byte_length FROM upload_chunks \
+             WHERE session_id = ? AND byte_offset != ?",
+            chunk.session_id,
+            chunk.byte_offset
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .any(|received| received.overlaps(chunk));
+        if overlapping {
+            return Err(DbError::Conflict(
+                "chunk overlaps one already received".to_owned(),
+            ));
+        }
+        sqlx::query!(
+            "REPLACE INTO upload_chunks (session_id, byte_offset, byte_length) VALUES (?, ?, ?)",
+            chunk.session_id,
+            chunk.byte_offset,
+            chunk.byte_length
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_upload_session(&self, id: &str) -> Result<(), DbError> {
+        let mut tx = self.begin().await?;
+        sqlx::query!("DELETE FROM upload_chunks WHERE session_id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM upload_sessions WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn find_expired_upload_sessions(&self, now: i64) -> Result<Vec<UploadSession>, DbError> {
+        Ok(sqlx::query_as!(
+            UploadSession,
+            r#"SELECT id, file_name, file_type, total_size, created_at AS "created_at: i64",
+                expires_at AS "expires_at: i64"
+            FROM upload_sessions WHERE expires_at <= ?"#,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn find_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyKey>, DbError> {
+        Ok(sqlx::query_as!(
+            IdempotencyKey,
+            r#"SELECT owner, idempotency_key, response, created_at AS "created_at: i64"
+            FROM idempotency_keys WHERE owner = ? AND idempotency_key = ?"#,
+            owner,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn insert_idempotency_key(&self, key: &IdempotencyKey) -> Result<(), DbError> {
+        sqlx::query!(
+            "INSERT INTO idempotency_keys (owner, idempotency_key, response, created_at) \
+             VALUES (?, ?, ?, ?)",
+            key.owner,
+            key.idempotency_key,
+            key.response,
+            key.created_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+        response: &str,
+    ) -> Result<(), DbError> {
+        let updated = sqlx::query!(
+            "UPDATE idempotency_keys SET response = ? WHERE owner = ? AND idempotency_key = ?",
+            response,
+            owner,
+            key
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn delete_idempotency_key(&self, owner: &str, key: &str) -> Result<(), DbError> {
+        sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE owner = ? AND idempotency_key = ?",
+            owner,
+            key
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn consume_signature(
+        &self,
+        signature: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), DbError> {
+        sqlx::query!("DELETE FROM consumed_signatures WHERE expires_at < ?", now)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query!(
+            "INSERT INTO consumed_signatures (signature, expires_at) VALUES (?, ?)",
+            signature,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn release_signature(&self, signature: &str) -> Result<(), DbError> {
+        sqlx::query!(
+            "DELETE FROM consumed_signatures WHERE signature = ?",
+            signature
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_waveform_peaks(
+        &self,
+        sha256: &str,
+        resolution: i32,
+    ) -> Result<Option<WaveformPeaks>, DbError> {
+        Ok(sqlx::query_as!(
+            WaveformPeaks,
+            r#"SELECT sha256, resolution AS "resolution: i32", peaks
+            FROM waveform_peaks WHERE sha256 = ? AND resolution = ?"#,
+            sha256,
+            resolution
+        )
+        .fetch_optional(self.reader())
+        .await?)
+    }
+
+    async fn insert_waveform_peaks(&self, peaks: &WaveformPeaks) -> Result<(), DbError> {
+        sqlx::query!(
+            "INSERT INTO waveform_peaks (sha256, resolution, peaks) VALUES (?, ?, ?) \
+             ON CONFLICT DO NOTHING",
+            peaks.sha256,
+            peaks.resolution,
+            peaks.peaks
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn add_file_tag(
+        &self,
+        target_file_id: &str,
+        target_tag_name: &str,
+    ) -> Result<(), DbError> {
+        let mut tx = self.begin().await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO tags (tag_name) VALUES (?)",
+            target_tag_name
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO file_tags (file_id, tag_name) VALUES (?, ?)",
+            target_file_id,
+            target_tag_name
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_file_tag(
+        &self,
+        target_file_id: &str,
+        target_tag_name: &str,
+    ) -> Result<bool, DbError> {
+        let deleted = sqlx::query!(
+            "DELETE FROM file_tags WHERE file_id = ? AND tag_name = ?",
+            target_file_id,
+            target_tag_name
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    async fn list_file_tags(&self, target: &str) -> Result<Vec<String>, DbError> {
+        Ok(sqlx::query_scalar!(
+            "SELECT tag_name FROM file_tags WHERE file_id = ? ORDER BY tag_name",
+            target
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn find_file_ids_by_tag(&self, target: &str) -> Result<Vec<String>, DbError> {
+        Ok(
+            sqlx::query_scalar!("SELECT file_id FROM file_tags WHERE tag_name = ?", target)
+                .fetch_all(self.reader())
+                .await?,
+        )
+    }
+
+    async fn set_file_metadata(
+        &self,
+        target: &str,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<(), DbError> {
+        let mut tx = self.begin().await?;
+        replace_metadata(&mut tx, target, metadata).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_file_metadata(&self, target: &str) -> Result<BTreeMap<String, String>, DbError> {
+        Ok(sqlx::query!(
+            "SELECT meta_key, meta_value FROM file_metadata WHERE file_id = ?",
+            target
+        )
+        .fetch_all(self.reader())
+        .await?
+        .into_iter()
+        .map(|row| (row.meta_key, row.meta_value))
+        .collect())
+    }
+
+    async fn find_file_ids_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<String>, DbError> {
+        Ok(sqlx::query_scalar!(
+            "SELECT file_id FROM file_metadata WHERE meta_key = ? AND meta_value = ?",
+            key,
+            value
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn search_files(&self, terms: &[String]) -> Result<Vec<String>, DbError> {
+        // Each term quoted and matched as a prefix, all of them required
+        let query = terms
+            .iter()
+            .map(|term| format!("\"{}\"*", term))
+            .collect::<Vec<_>>()
+            .join(" ");
+        // Cast, because the macros crash describing the index's untyped columns
+        Ok(sqlx::query_scalar!(
+            r#"SELECT CAST(file_id AS TEXT) AS "file_id!: String" FROM file_search
+            WHERE file_search MATCH ? ORDER BY bm25(file_search, 0.0, 10.0, 5.0, 1.0), file_name"#,
+            query
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+
+    async fn create_review_session(
+        &self,
+        session: &ReviewSession,
+        file_ids: &[String],
+    ) -> Result<(), DbError> {
+        let mut tx = self.begin().await?;
+        sqlx::query!(
+            "INSERT INTO review_sessions (token, created_at, expires_at) VALUES (?, ?, ?)",
+            session.token,
+            session.created_at,
+            session.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        for file_id in file_ids {
+            sqlx::query!(
+                "INSERT INTO review_session_files (token, file_id) VALUES (?, ?)",
+                session.token,
+                file_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn find_review_session(&self, target: &str) -> Result<Option<ReviewSession>, DbError> {
+        Ok(sqlx::query_as!(
+            ReviewSession,
+            r#"SELECT token, created_at AS "created_at: i64", expires_at AS "expires_at: i64"
+            FROM review_sessions WHERE token = ?"#,
+            target
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn list_review_session_files(&self, target: &str) -> Result<Vec<String>, DbError> {
+        Ok(sqlx::query_scalar!(
+            "SELECT file_id FROM review_session_files WHERE token = ?",
+            target
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    // Pragmas and VACUUM aren't queries the macros can describe, so these
+    // are the only unchecked statements
+    async fn run_maintenance(&self) -> Result<MaintenanceReport, DbError> {
+        let size = "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()";
+        let mut conn = self.pool.acquire().await?;
+        let size_before: i64 = sqlx::query_scalar(size).fetch_one(&mut *conn).await?;
+        // VACUUM rewrites the database through the log, so the checkpoint
+        // comes after it
+        sqlx::raw_sql("ANALYZE; VACUUM;")
+            .execute(&mut *conn)
+            .await?;
+        let (busy, _, checkpointed): (i32, i32, i32) =
+            sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)")
+                .fetch_one(&mut *conn)
+                .await?;
+        if busy != 0 {
+            eprintln!("maintenance: readers kept the write-ahead log from being truncated");
+        }
+        let size_after: i64 = sqlx::query_scalar(size).fetch_one(&mut *conn).await?;
+        Ok(MaintenanceReport {
+            size_before,
+            size_after,
+            reclaimed_bytes: size_before - size_after,
+            checkpointed_frames: (checkpointed >= 0).then_some(checkpointed as i64),
+        })
+    }
+
+    async fn record_audit(&self, entry: &NewAuditEntry) -> Result<(), DbError> {
+        sqlx::query!(
+            "INSERT INTO audit_log (occurred_at, actor, client_ip, forwarded_for, method, \
+             path, status, changes) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            entry.occurred_at,
+            entry.actor,
+            entry.client_ip,
+            entry.forwarded_for,
+            entry.method,
+            entry.path,
+            entry.status,
+            entry.changes
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_audit_entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, DbError> {
+        let path = query.path_pattern();
+        let limit = query.limit();
+        Ok(sqlx::query_as!(
+            AuditEntry,
+            r#"SELECT id, occurred_at, actor, client_ip, forwarded_for, method, path,
+                status AS "status: i32", changes
+            FROM audit_log
+            WHERE (?1 IS NULL OR actor = ?1) AND (?2 IS NULL OR path LIKE ?2 ESCAPE '\')
+                AND (?3 IS NULL OR occurred_at >= ?3) AND (?4 IS NULL OR occurred_at < ?4)
+                AND (?5 IS NULL OR id < ?5)
+            ORDER BY id DESC LIMIT ?6"#,
+            query.actor,
+            path,
+            query.since,
+            query.until,
+            query.before,
+            limit
+        )
+        .fetch_all(self.reader())
+        .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::run_migrations;
+
+    fn file(file_name: &str, sha256: &str, file_size: i64) -> File {
+        File {
+            file_name: file_name.to_owned(),
+            file_type: Some("audio/wav".to_owned()),
+            file_upload_date: 1_790_000_000,
+            title: Some(format!("{} title", file_name)),
+            description: None,
+            language: None,
+            file_size: Some(file_size),
+            duration_ms: Some(1000),
+            starred: false,
+            download_count: 0,
+            last_accessed_at: None,
+            storage_key: Some(format!("objects/{}", sha256)),
+            sha256: Some(sha256.to_owned()),
+            compression: None,
+            stored_size: Some(file_size),
+            expires_at: None,
+            id: new_file_id(),
+            revision: 1,
+            owner: None,
+            sample_rate: None,
+            channels: None,
+            bit_depth: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_and_releases_blobs_like_the_diesel_repository() {
+        let path = std::env::temp_dir().join(format!("sqlx-{}.db", new_file_id()));
+        let url = path.to_string_lossy().into_owned();
+        run_migrations(&url).unwrap();
+        let db = SqlxRepository::new(establish_pool(&url).unwrap());
+        let metadata = BTreeMap::from([("speaker".to_owned(), "Ana".to_owned())]);
+
+        let standup = db
+            .insert_file(&file("standup.wav", "aa", 100), &metadata)
+            .await
+            .unwrap()
+            .id
+            .unwrap();
+        // Same bytes under another name share the object
+        let changes = db
+            .insert_file(&file("copy.wav", "aa", 100), &BTreeMap::new())
+            .await
+            .unwrap();
+        let copy = changes.id.clone().unwrap();
+        assert_eq!(changes.storage_key.as_deref(), Some("objects/aa"));
+        assert_eq!(db.storage_usage().await.unwrap(), 100);
+        let duplicate = db
+            .insert_file(&file("copy.wav", "bb", 5), &BTreeMap::new())
+            .await;
+        assert!(matches!(duplicate, Err(DbError::Duplicate { .. })));
+
+        // Overwriting keeps the old content as a version
+        let changes = db
+            .replace_file(&file("standup.wav", "cc", 50), &metadata)
+            .await
+            .unwrap();
+        assert_eq!(changes.id.as_ref(), Some(&standup));
+        assert_eq!(changes.revision, Some(2));
+        let versions = db.list_file_versions(&standup).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].storage_key, "objects/aa");
+        assert_eq!(db.storage_usage().await.unwrap(), 150);
+
+        db.add_file_tag(&standup, "meeting").await.unwrap();
+        let renamed = db.rename_file(&standup, "daily.wav").await.unwrap();
+        assert_eq!(renamed.revision, 2);
+        assert_eq!(db.list_file_tags(&standup).await.unwrap(), ["meeting"]);
+        assert_eq!(db.get_file_metadata(&standup).await.unwrap(), metadata);
+        assert_eq!(db.list_file_versions(&standup).await.unwrap().len(), 1);
+        assert_eq!(
+            db.search_files(&["dai".to_owned()]).await.unwrap(),
+            std::slice::from_ref(&standup)
+        );
+        let found = db
+            .find_files_by_ids(&[standup.clone(), "gone".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(db.toggle_starred(&standup).await.unwrap());
+
+        // Deleting releases the file's own object; the version's is still
+        // held by copy.wav
+        let changes = db.delete_file(&standup).await.unwrap();
+        assert_eq!(changes.unreferenced, ["objects/cc"]);
+        let changes = db.delete_file(&copy).await.unwrap();
+        assert_eq!(changes.unreferenced, ["objects/aa"]);
+        assert_eq!(db.storage_usage().await.unwrap(), 0);
+        assert!(matches!(
+            db.delete_file(&copy).await,
+            Err(DbError::NotFound)
+        ));
+
+        db.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", url, suffix)).ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn inserts_a_batch_in_one_transaction() {
+        let path = std::env::temp_dir().join(format!("sqlx-{}.db", new_file_id()));
+        let url = path.to_string_lossy().into_owned();
+        run_migrations(&url).unwrap();
+        let db = SqlxRepository::new(establish_pool(&url).unwrap());
+        let metadata = BTreeMap::from([("artist".to_owned(), "Ana".to_owned())]);
+
+        let mut second = file("b.wav", "aa", 100);
+        second.storage_key = Some("objects/b".to_owned());
+        let third = file("c.wav", "cc", 5);
+        // Another owner's file may share a name
+        let mut theirs = file("a.wav", "dd", 1);
+        theirs.owner = Some("bob".to_owned());
+        let changes = db
+            .insert_files(&[
+                (file("a.wav", "aa", 100), BTreeMap::new()),
+                (second, BTreeMap::new()),
+                (third.clone(), metadata.clone()),
+                (theirs, BTreeMap::new()),
+            ])
+            .await
+            .unwrap();
+        // Rows in the same batch share content too
+        assert_eq!(changes[1].storage_key.as_deref(), Some("objects/aa"));
+        assert_eq!(db.count_files().await.unwrap(), 4);
+        assert_eq!(db.storage_usage().await.unwrap(), 106);
+        assert_eq!(db.get_file_metadata(&third.id).await.unwrap(), metadata);
+
+        // A conflict leaves none of the batch behind
+        let clash = db
+            .insert_files(&[
+                (file("d.wav", "dd", 1), BTreeMap::new()),
+                (file("a.wav", "ee", 1), BTreeMap::new()),
+            ])
+            .await;
+        assert!(matches!(clash, Err(DbError::Duplicate { .. })));
+        assert_eq!(db.count_files().await.unwrap(), 4);
+
+        db.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", url, suffix)).ok();
+        }
+    }
+}
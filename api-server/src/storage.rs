@@ -0,0 +1,336 @@
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use nix::sys::statvfs::statvfs;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::{create_dir_all, File};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+pub mod memory;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+pub type ByteStream<'a> = BoxStream<'a, io::Result<Bytes>>;
+
+static NEXT_UNIQUE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Unique within and across processes on this host, for temp file names.
+pub fn unique_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let id = NEXT_UNIQUE_ID.fetch_add(1, Ordering::Relaxed);
+    format!("{}.{}.{}", std::process::id(), nanos, id)
+}
+
+/// A fresh key for a new upload of `file_name`, spread over `ab/cd/<hash>`
+/// prefixes so no single directory ends up holding every upload. Keys are
+/// unique per upload, so a failed or replaced upload can always be deleted
+/// without touching anyone else's bytes.
+fn new_object_key(file_name: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(file_name.as_bytes());
+    hasher.update(unique_id().as_bytes());
+    let hash = hex::encode(hasher.finalize());
+    format!("{}/{}/{}", &hash[0..2], &hash[2..4], hash)
+}
+
+/// Whether `key` has a shape [`StorageLayout::object_key`] produces.
+pub fn is_object_key(key: &str) -> bool {
+    let parts: Vec<&str> = key.split('/').collect();
+    let is_hex = |part: &str| part.bytes().all(|b| b.is_ascii_hexdigit());
+    let sharded = match parts.as_slice() {
+        [a, b, hash] | [_, a, b, hash] => (a, b, hash),
+        _ => return false,
+    };
+    matches!(sharded, (a, b, hash)
+        if a.len() == 2 && b.len() == 2 && hash.len() == 64
+            && hash.starts_with(*a) && hash[2..].starts_with(*b)
+            && is_hex(hash))
+}
+
+/// How keys for new uploads are laid out, from AUDIO_LAYOUT. Every row
+/// records its own key, so switching layouts leaves existing files where
+/// they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageLayout {
+    /// `ab/cd/<hash>`
+    #[default]
+    Sharded,
+    /// `<type>/ab/cd/<hash>`, so each format can be browsed on disk or
+    /// mounted on its own volume
+    ByType,
+}
+
+impl StorageLayout {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        match std::env::var("AUDIO_LAYOUT").as_deref() {
+            Err(_) | Ok("sharded") => Ok(StorageLayout::Sharded),
+            Ok("by-type") => Ok(StorageLayout::ByType),
+            Ok(other) => bail!("unknown AUDIO_LAYOUT {:?}", other),
+        }
+    }
+
+    pub fn object_key(self, file_name: &str, file_type: Option<&str>) -> String {
+        let key = new_object_key(file_name);
+        match self {
+            StorageLayout::Sharded => key,
+            StorageLayout::ByType => format!("{}/{}", type_directory(file_type), key),
+        }
+    }
+}
+
+// "audio/mpeg" -> "mpeg". Anything but lowercase letters, digits, '+' and '-'
+// is replaced, which also keeps "." and ".." out of keys.
+fn type_directory(file_type: Option<&str>) -> String {
+    let subtype = file_type
+        .map(|file_type| file_type.rsplit('/').next().unwrap_or(file_type))
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if subtype.is_empty() {
+        return "unknown".to_owned();
+    }
+    subtype
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '+' | '-' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Where uploaded audio bytes live. Objects are addressed by key, unique per
+/// upload and recorded on the file's row, so handlers never build paths
+/// themselves and other backends can be added without touching handler code.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Short name of the backend, as reported by /capabilities.
+    fn backend(&self) -> &'static str;
+
+    /// Streams `data` into the object under `key`, replacing any existing object.
+    /// The object only appears once `data` has been read to the end.
+    async fn put(&self, key: &str, data: ByteStream<'_>) -> io::Result<()>;
+
+    /// Reads a whole object into memory; prefer [`Storage::stream`] for audio.
+    async fn get(&self, key: &str) -> io::Result<Bytes>;
+
+    async fn stream(&self, key: &str) -> io::Result<ByteStream<'static>>;
+
+    async fn stat(&self, key: &str) -> io::Result<ObjectInfo>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Every stored object, excluding uploads still being written.
+    async fn list(&self) -> io::Result<Vec<(String, ObjectInfo)>>;
+
+    /// Whether new objects can currently be written.
+    async fn writable(&self) -> io::Result<bool>;
+
+    /// Bytes left on the underlying volume, if it has a fixed size.
+    async fn free_space(&self) -> io::Result<Option<u64>>;
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// Stores each object as a file under `root`, keys used as relative paths.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        LocalStorage { root }
+    }
+
+    /// Creates the root if needed and checks it is writable with at least
+    /// `min_free_bytes` available, so a misconfigured deployment fails at
+    /// startup rather than on its first upload.
+    pub async fn validate(&self, min_free_bytes: u64) -> Result<(), anyhow::Error> {
+        create_dir_all(&self.root)
+            .await
+            .with_context(|| format!("creating {:?}", self.root))?;
+        let probe = self.root.join(".write-test");
+        File::create(&probe)
+            .await
+            .with_context(|| format!("{:?} is not writable", self.root))?;
+        tokio::fs::remove_file(&probe).await?;
+        let available = self
+            .available_bytes()
+            .with_context(|| format!("checking space in {:?}", self.root))?;
+        if available < min_free_bytes {
+            bail!(
+                "only {} bytes free in {:?}, need at least {}",
+                available,
+                self.root,
+                min_free_bytes
+            );
+        }
+        Ok(())
+    }
+
+    fn available_bytes(&self) -> io::Result<u64> {
+        let stat = statvfs(&self.root)?;
+        Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+    }
+
+    // Keys are relative paths that stay under the root: no absolute paths,
+    // "..", "." or empty components
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        let contained = !key.is_empty()
+            && !key.split('/').any(str::is_empty)
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !contained {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a storage key", key),
+            ));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    // Unique per write so concurrent uploads of one key never share a temp file
+    fn temp_path(&self, key: &str) -> io::Result<PathBuf> {
+        let mut path = self.path(key)?.into_os_string();
+        path.push(format!(".{}.tmp", unique_id()));
+        Ok(path.into())
+    }
+}
+
+// Removes a temp file unless it was renamed into place, including when the
+// upload future is dropped because the client went away mid-stream
+struct TempFile {
+    path: PathBuf,
+    renamed: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.renamed {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn backend(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, key: &str, mut data: ByteStream<'_>) -> io::Result<()> {
+        let path = self.path(key)?;
+        println!("writing file to path: {:?}", path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).await?;
+        }
+        // Written beside the final path and renamed into place, so a crash
+        // mid-upload never leaves a truncated file under the real name
+        let mut temp = TempFile {
+            path: self.temp_path(key)?,
+            renamed: false,
+        };
+        let mut file = File::create(&temp.path).await?;
+        while let Some(bytes) = data.next().await {
+            file.write_all(&bytes?).await?;
+        }
+        file.flush().await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp.path, &path).await?;
+        temp.renamed = true;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        Ok(tokio::fs::read(self.path(key)?).await?.into())
+    }
+
+    async fn stream(&self, key: &str) -> io::Result<ByteStream<'static>> {
+        let file = File::open(self.path(key)?).await?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<ObjectInfo> {
+        let metadata = tokio::fs::metadata(self.path(key)?).await?;
+        Ok(ObjectInfo {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        tokio::fs::try_exists(self.path(key)?).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        tokio::fs::remove_file(self.path(key)?).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, ObjectInfo)>> {
+        let mut objects = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let path = entry.path();
+                let Ok(relative) = path.strip_prefix(&self.root) else {
+                    continue;
+                };
+                let key = relative.to_string_lossy().into_owned();
+                // Temp files belong to uploads in flight or to the startup probe
+                if key.ends_with(".tmp") || key == ".write-test" {
+                    continue;
+                }
+                objects.push((
+                    key,
+                    ObjectInfo {
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                    },
+                ));
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn writable(&self) -> io::Result<bool> {
+        create_dir_all(&self.root).await?;
+        Ok(!tokio::fs::metadata(&self.root)
+            .await?
+            .permissions()
+            .readonly())
+    }
+
+    async fn free_space(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.available_bytes()?))
+    }
+}
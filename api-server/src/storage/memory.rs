@@ -0,0 +1,94 @@
+use super::{ByteStream, ObjectInfo, Storage};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Keeps every object in memory; used by `--demo`, so nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: Mutex<BTreeMap<String, (Bytes, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    fn object(&self, key: &str) -> io::Result<(Bytes, SystemTime)> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, key.to_owned()))
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn put(&self, key: &str, data: ByteStream<'_>) -> io::Result<()> {
+        let chunks: Vec<Bytes> = data.try_collect().await?;
+        let bytes = Bytes::from(chunks.concat());
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), (bytes, SystemTime::now()));
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        Ok(self.object(key)?.0)
+    }
+
+    async fn stream(&self, key: &str) -> io::Result<ByteStream<'static>> {
+        let (bytes, _) = self.object(key)?;
+        Ok(stream::once(async { Ok(bytes) }).boxed())
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<ObjectInfo> {
+        let (bytes, modified) = self.object(key)?;
+        Ok(ObjectInfo {
+            size: bytes.len() as u64,
+            modified,
+        })
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(key))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.objects.lock().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, key.to_owned())),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, ObjectInfo)>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, (bytes, modified))| {
+                let info = ObjectInfo {
+                    size: bytes.len() as u64,
+                    modified: *modified,
+                };
+                (key.clone(), info)
+            })
+            .collect())
+    }
+
+    async fn writable(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    async fn free_space(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
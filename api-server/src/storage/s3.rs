@@ -0,0 +1,138 @@
+use super::{ByteStream, ObjectInfo, Storage};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::{StreamExt, TryStreamExt};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, WriteMultipart};
+use std::io;
+
+// Objects at least this large are sent as a multipart upload in parts of this
+// size; anything smaller goes up in a single PUT.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const MAX_CONCURRENT_PARTS: usize = 4;
+
+/// S3-compatible object storage (AWS, MinIO, ...). Credentials, region and
+/// endpoint come from the standard `AWS_*` environment variables.
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+impl S3Storage {
+    pub fn from_env(bucket: &str) -> Result<Self, object_store::Error> {
+        let store = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()?;
+        Ok(S3Storage { store })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    fn backend(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, key: &str, mut data: ByteStream<'_>) -> io::Result<()> {
+        let path = ObjectPath::from(key);
+        let mut buffered = Vec::new();
+        while buffered.len() < PART_SIZE {
+            match data.next().await {
+                Some(bytes) => buffered.extend_from_slice(&bytes?),
+                None => {
+                    self.store.put(&path, buffered.into()).await?;
+                    return Ok(());
+                }
+            }
+        }
+        let upload = self.store.put_multipart(&path).await?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, PART_SIZE);
+        writer.write(&buffered);
+        let result: io::Result<()> = async {
+            while let Some(bytes) = data.next().await {
+                writer.wait_for_capacity(MAX_CONCURRENT_PARTS).await?;
+                writer.write(&bytes?);
+            }
+            Ok(())
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                writer.finish().await?;
+                Ok(())
+            }
+            Err(e) => {
+                // Incomplete multipart uploads are billed until aborted
+                if let Err(abort) = writer.abort().await {
+                    eprintln!("{:?}", abort);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        Ok(self
+            .store
+            .get(&ObjectPath::from(key))
+            .await?
+            .bytes()
+            .await?)
+    }
+
+    async fn stream(&self, key: &str) -> io::Result<ByteStream<'static>> {
+        let result = self.store.get(&ObjectPath::from(key)).await?;
+        Ok(result.into_stream().map_err(io::Error::from).boxed())
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<ObjectInfo> {
+        let meta = self.store.head(&ObjectPath::from(key)).await?;
+        Ok(ObjectInfo {
+            size: meta.size,
+            modified: meta.last_modified.into(),
+        })
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self.store.head(&ObjectPath::from(key)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        Ok(self.store.delete(&ObjectPath::from(key)).await?)
+    }
+
+    // Multipart uploads only become objects once completed, so nothing in
+    // flight shows up here
+    async fn list(&self) -> io::Result<Vec<(String, ObjectInfo)>> {
+        Ok(self
+            .store
+            .list(None)
+            .map_ok(|meta| {
+                (
+                    meta.location.to_string(),
+                    ObjectInfo {
+                        size: meta.size,
+                        modified: meta.last_modified.into(),
+                    },
+                )
+            })
+            .try_collect()
+            .await?)
+    }
+
+    // There's no cheap way to ask S3 about write permissions, so this only
+    // checks that the bucket is reachable.
+    async fn writable(&self) -> io::Result<bool> {
+        self.store.list_with_delimiter(None).await?;
+        Ok(true)
+    }
+
+    // Buckets don't fill up
+    async fn free_space(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,98 @@
+use crate::audit::ACTOR_HEADER;
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+// Several teams can share one server, each seeing only its own files. A file
+// belongs to the user who uploaded it, as named in X-Forwarded-User by the
+// authenticating proxy in front of the server, the same header the audit log
+// records. Files uploaded without the header, including every file from
+// before ownership, belong to nobody and are what anonymous callers see, so
+// a server used without a proxy works as it always has. Users listed in
+// ADMIN_USERS see every file and are the only ones let into /admin.
+//
+// The header is trusted as sent, so the server must only be reachable through
+// a proxy that sets it and strips whatever value the client sent; anyone who
+// can reach the server directly can claim to be an admin.
+//
+// Capability URLs (shared and review links, upload sessions) grant access by
+// themselves and aren't scoped.
+
+/// Users who see every file, from the comma separated ADMIN_USERS.
+#[derive(Debug, Clone, Default)]
+pub struct Admins(Arc<BTreeSet<String>>);
+
+impl Admins {
+    pub fn from_env() -> Self {
+        Admins::parse(&std::env::var("ADMIN_USERS").unwrap_or_default())
+    }
+
+    pub fn parse(users: &str) -> Self {
+        Admins(Arc::new(
+            users
+                .split(',')
+                .map(str::trim)
+                .filter(|user| !user.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+}
+
+/// Who a request is from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Caller {
+    pub user: Option<String>,
+    pub admin: bool,
+}
+
+impl Caller {
+    /// Whether the caller may see a file with this owner.
+    pub fn can_see(&self, owner: Option<&str>) -> bool {
+        self.admin || owner == self.user.as_deref()
+    }
+
+    /// The owner whose files the caller sees, or `None` for an admin, who
+    /// sees them all.
+    pub fn scope(&self) -> Option<Option<&str>> {
+        (!self.admin).then_some(self.user.as_deref())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Caller
+where
+    Admins: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = parts
+            .headers
+            .get(ACTOR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|user| !user.is_empty())
+            .map(str::to_owned);
+        let admin = user
+            .as_ref()
+            .is_some_and(|user| Admins::from_ref(state).0.contains(user));
+        Ok(Caller { user, admin })
+    }
+}
+
+/// Turns away everyone but admins with 403 Forbidden.
+pub async fn require_admin(caller: Caller, request: Request<Body>, next: Next<Body>) -> Response {
+    if !caller.admin {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
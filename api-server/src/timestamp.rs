@@ -0,0 +1,72 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::{self, Deserializer, Visitor};
+use serde::Serializer;
+use std::fmt;
+use std::time::SystemTime;
+
+// Times are kept as 64-bit seconds since the Unix epoch, so they last past
+// 2038. Upload dates are shown in JSON as RFC3339. Input takes either form, so
+// epoch filters written against older responses keep working.
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// `secs` in UTC, e.g. `2026-10-16T09:30:00Z`.
+pub fn to_rfc3339(secs: i64) -> String {
+    match DateTime::<Utc>::from_timestamp(secs, 0) {
+        Some(date) => date.to_rfc3339_opts(SecondsFormat::Secs, true),
+        // Beyond what chrono represents; no real upload gets here
+        None => secs.to_string(),
+    }
+}
+
+/// Epoch seconds, or an RFC3339 date with any offset.
+pub fn parse(value: &str) -> Option<i64> {
+    let value = value.trim();
+    value.parse().ok().or_else(|| {
+        DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|date| date.timestamp())
+    })
+}
+
+pub fn serialize<S: Serializer>(secs: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_rfc3339(*secs))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    deserializer.deserialize_any(TimestampVisitor)
+}
+
+/// For optional fields, which also need `#[serde(default)]`.
+pub fn deserialize_some<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<i64>, D::Error> {
+    deserialize(deserializer).map(Some)
+}
+
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = i64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("epoch seconds or an RFC3339 date")
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<i64, E> {
+        Ok(value)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<i64, E> {
+        i64::try_from(value).map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<i64, E> {
+        parse(value).ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
@@ -0,0 +1,200 @@
+use crate::db::UploadChunk;
+use crate::storage::ByteStream;
+use axum::body::Bytes;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+// Chunked uploads for clients that can't send a file in one request. Chunks
+// are staged on local disk under UPLOAD_SESSION_DIR, one file per chunk, and
+// only reach storage once the session completes and they are streamed
+// through the usual upload path in byte order.
+
+/// From UPLOAD_SESSION_DIR, defaulting to ./upload-sessions.
+pub fn staging_root() -> PathBuf {
+    std::env::var("UPLOAD_SESSION_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("upload-sessions"))
+}
+
+/// 256 random bits, hex encoded.
+pub fn new_session_id() -> Result<String, getrandom::Error> {
+    crate::review::new_token()
+}
+
+// Session ids are only ever generated by new_session_id, but they arrive in
+// paths, so anything else is refused before it can name a directory
+fn session_dir(root: &Path, id: &str) -> Option<PathBuf> {
+    let valid = !id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit());
+    valid.then(|| root.join(id))
+}
+
+fn chunk_path(dir: &Path, byte_offset: i64) -> PathBuf {
+    dir.join(byte_offset.to_string())
+}
+
+// Each write gets its own partial file, so a retry racing the original
+// request at the same offset can't interleave its bytes with the original's
+fn partial_path(dir: &Path, byte_offset: i64) -> PathBuf {
+    dir.join(format!(
+        "{}.{}.tmp",
+        byte_offset,
+        crate::storage::unique_id()
+    ))
+}
+
+/// A chunk received into its own partial file, not yet counted.
+#[derive(Debug)]
+pub struct PartialChunk {
+    path: PathBuf,
+    pub length: u64,
+}
+
+/// Receives a chunk into a partial file. Chunks longer than `max_length` are
+/// refused with `InvalidInput`. The chunk only counts once it is kept with
+/// [`keep_chunk`].
+pub async fn write_chunk<S>(
+    root: &Path,
+    id: &str,
+    byte_offset: i64,
+    max_length: u64,
+    data: S,
+) -> io::Result<PartialChunk>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    let dir = session_dir(root, id).ok_or(io::ErrorKind::NotFound)?;
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut partial = PartialChunk {
+        path: partial_path(&dir, byte_offset),
+        length: 0,
+    };
+    let mut file = tokio::fs::File::create(&partial.path).await?;
+    let received = async {
+        futures::pin_mut!(data);
+        while let Some(bytes) = data.next().await {
+            let bytes = bytes?;
+            partial.length += bytes.len() as u64;
+            if partial.length > max_length {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "chunk runs past the end of the file",
+                ));
+            }
+            file.write_all(&bytes).await?;
+        }
+        file.flush().await
+    }
+    .await;
+    drop(file);
+    match received {
+        Ok(()) => Ok(partial),
+        Err(e) => {
+            discard_chunk(partial).await;
+            Err(e)
+        }
+    }
+}
+
+/// Moves a received chunk into place, replacing one kept before at the same
+/// offset.
+pub async fn keep_chunk(
+    root: &Path,
+    id: &str,
+    byte_offset: i64,
+    partial: PartialChunk,
+) -> io::Result<()> {
+    let dir = session_dir(root, id).ok_or(io::ErrorKind::NotFound)?;
+    tokio::fs::rename(&partial.path, chunk_path(&dir, byte_offset)).await
+}
+
+pub async fn discard_chunk(partial: PartialChunk) {
+    tokio::fs::remove_file(partial.path).await.ok();
+}
+
+/// Byte ranges of `total_size` no chunk covers yet, as `[start, end)` pairs.
+pub fn missing_ranges(chunks: &[UploadChunk], total_size: i64) -> Vec<(i64, i64)> {
+    let mut missing = Vec::new();
+    let mut covered = 0;
+    for chunk in chunks {
+        if chunk.byte_offset > covered {
+            missing.push((covered, chunk.byte_offset));
+        }
+        covered = covered.max(chunk.end());
+    }
+    if covered < total_size {
+        missing.push((covered, total_size));
+    }
+    missing
+}
+
+/// The staged chunks read back to back. `chunks` must be in byte order and
+/// leave no gaps.
+pub fn assemble(root: &Path, id: &str, chunks: Vec<UploadChunk>) -> ByteStream<'static> {
+    let Some(dir) = session_dir(root, id) else {
+        return stream::once(async { Err(io::ErrorKind::NotFound.into()) }).boxed();
+    };
+    stream::iter(chunks)
+        .then(move |chunk| {
+            let path = chunk_path(&dir, chunk.byte_offset);
+            async move { tokio::fs::File::open(path).await.map(ReaderStream::new) }
+        })
+        .try_flatten()
+        .boxed()
+}
+
+/// Drops everything staged for the session.
+pub async fn remove_staged(root: &Path, id: &str) {
+    let Some(dir) = session_dir(root, id) else {
+        return;
+    };
+    match tokio::fs::remove_dir_all(&dir).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("{:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_racing_at_one_offset_keep_whole_chunks() {
+        let root = std::env::temp_dir().join(format!("sessions-{}", crate::storage::unique_id()));
+        let id = "ab12";
+        let body = |byte: u8| stream::iter((0..64).map(move |_| Ok(Bytes::from(vec![byte; 1024]))));
+        let (first, retry) = tokio::join!(
+            write_chunk(&root, id, 0, 1 << 20, body(1)),
+            write_chunk(&root, id, 0, 1 << 20, body(2)),
+        );
+        let (first, retry) = (first.unwrap(), retry.unwrap());
+        assert_ne!(first.path, retry.path);
+        keep_chunk(&root, id, 0, first).await.unwrap();
+        keep_chunk(&root, id, 0, retry).await.unwrap();
+        let chunk = UploadChunk {
+            session_id: id.to_owned(),
+            byte_offset: 0,
+            byte_length: 64 * 1024,
+        };
+        let kept: Vec<Bytes> = assemble(&root, id, vec![chunk])
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(kept.concat(), vec![2u8; 64 * 1024]);
+
+        // A chunk that runs too long leaves nothing behind
+        let refused = write_chunk(&root, id, 0, 1024, body(3)).await;
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let mut entries = tokio::fs::read_dir(root.join(id)).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, ["0"]);
+        remove_staged(&root, id).await;
+        tokio::fs::remove_dir(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+use crate::repository::Repository;
+use crate::storage::SharedStorage;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Optional boot-time warmup. Until it has run, /readyz reports the server as
+// not ready, so a load balancer keeps traffic on the old instance instead of
+// sending it requests that would pay for cold caches.
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Whether the server should receive traffic yet.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn mark_ready(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+}
+
+// Reading every row pulls the files table into SQLite's page cache, and the
+// storage check catches unreachable buckets before the first upload does
+async fn warm_up(db: &Repository, storage: &SharedStorage) -> Result<(), anyhow::Error> {
+    if !storage.writable().await? {
+        anyhow::bail!("storage is not writable");
+    }
+    let files = db.list_all_files().await?;
+    db.storage_usage().await?;
+    println!("warmup: loaded metadata for {} files", files.len());
+    Ok(())
+}
+
+/// Warms up, retrying until it succeeds, then marks the server ready.
+pub async fn run(db: Repository, storage: SharedStorage, readiness: Readiness) {
+    let started = Instant::now();
+    while let Err(e) = warm_up(&db, &storage).await {
+        eprintln!("{:?}", e);
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+    println!("warmup: ready after {:?}", started.elapsed());
+    readiness.mark_ready();
+}
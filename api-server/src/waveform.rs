@@ -0,0 +1,221 @@
+use crate::audio;
+use std::io::{self, Read};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+
+// Waveform overviews for drawing a file without downloading it: the audio is
+// decoded once and its samples folded into a fixed number of buckets, each
+// the lowest and highest amplitude across every channel over its stretch of
+// time. Amplitudes run from -1 to 1.
+
+/// Buckets returned when a request doesn't say.
+pub const DEFAULT_RESOLUTION: usize = 1000;
+
+/// The most buckets a request may ask for.
+pub const MAX_RESOLUTION: usize = 10_000;
+
+/// Decodes the whole file read from `source` into at most `resolution`
+/// (min, max) buckets of equal length; fewer when it has fewer frames than
+/// that. `None` when it isn't audio a decoder is compiled in for. Blocks, so
+/// run it off the runtime.
+pub fn peaks(
+    source: impl Read + Send + Sync + 'static,
+    file_name: &str,
+    resolution: usize,
+) -> Option<Vec<[f32; 2]>> {
+    let mut blocks = Blocks::new(resolution);
+    decode(source, file_name, |frame| {
+        let low = frame.iter().copied().fold(f32::INFINITY, f32::min);
+        let high = frame.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        blocks.push([low, high]);
+    })?;
+    blocks.into_buckets(resolution)
+}
+
+/// Decodes the default track of the file read from `source`, handing each
+/// frame, one sample per channel, to `frame` in order. `None` when it isn't
+/// audio a decoder is compiled in for. Blocks, so run it off the runtime.
+pub fn decode(
+    source: impl Read + Send + Sync + 'static,
+    file_name: &str,
+    mut frame: impl FnMut(&[f32]),
+) -> Option<()> {
+    let mut format = audio::open(source, file_name).ok()?.format;
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .ok()?;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => packet,
+            Ok(_) => continue,
+            Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                break;
+            }
+        };
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet leaves a gap rather than ending the decoding
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                break;
+            }
+        };
+        let channels = decoded.spec().channels.count().max(1);
+        let buffer = match &mut samples {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * channels => buffer,
+            _ => samples.insert(SampleBuffer::new(
+                decoded.capacity() as u64,
+                *decoded.spec(),
+            )),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        buffer.samples().chunks(channels).for_each(&mut frame);
+    }
+    Some(())
+}
+
+// How many blocks per bucket to keep at least, so buckets made of a whole
+// number of blocks differ in length by a small fraction
+const BLOCKS_PER_BUCKET: usize = 8;
+
+// The frames seen so far, folded into blocks of a power-of-two number of
+// frames. Whenever there are twice as many blocks as needed, neighbours merge
+// and blocks double in length, so memory stays bounded however long the file.
+struct Blocks {
+    limit: usize,
+    frames_per_block: usize,
+    done: Vec<[f32; 2]>,
+    current: Option<[f32; 2]>,
+    frames_in_current: usize,
+}
+
+impl Blocks {
+    fn new(resolution: usize) -> Self {
+        Blocks {
+            limit: 2 * BLOCKS_PER_BUCKET * resolution.max(1),
+            frames_per_block: 1,
+            done: Vec::new(),
+            current: None,
+            frames_in_current: 0,
+        }
+    }
+
+    fn push(&mut self, frame: [f32; 2]) {
+        self.current = Some(self.current.map_or(frame, |current| merge(current, frame)));
+        self.frames_in_current += 1;
+        if self.frames_in_current < self.frames_per_block {
+            return;
+        }
+        self.done.extend(self.current.take());
+        self.frames_in_current = 0;
+        if self.done.len() >= self.limit {
+            self.done = self
+                .done
+                .chunks(2)
+                .map(|pair| pair.iter().copied().reduce(merge).unwrap_or_default())
+                .collect();
+            self.frames_per_block *= 2;
+        }
+    }
+
+    fn into_buckets(mut self, resolution: usize) -> Option<Vec<[f32; 2]>> {
+        self.done.extend(self.current.take());
+        let blocks = self.done;
+        if blocks.is_empty() {
+            return None;
+        }
+        if blocks.len() <= resolution {
+            return Some(blocks.into_iter().map(round).collect());
+        }
+        Some(
+            (0..resolution)
+                .map(|bucket| {
+                    let start = bucket * blocks.len() / resolution;
+                    let end = (bucket + 1) * blocks.len() / resolution;
+                    blocks[start..end]
+                        .iter()
+                        .copied()
+                        .reduce(merge)
+                        .map(round)
+                        .unwrap_or_default()
+                })
+                .collect(),
+        )
+    }
+}
+
+fn merge([low, high]: [f32; 2], [other_low, other_high]: [f32; 2]) -> [f32; 2] {
+    [low.min(other_low), high.max(other_high)]
+}
+
+// Four decimals are finer than any screen, and keep the JSON short
+fn round(bucket: [f32; 2]) -> [f32; 2] {
+    bucket.map(|amplitude| (amplitude.clamp(-1.0, 1.0) * 10_000.0).round() / 10_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A mono 16-bit WAV file of `samples` at 8 kHz
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let data_len = samples.len() as u32 * 2;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&8000u32.to_le_bytes());
+        bytes.extend_from_slice(&16000u32.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn folds_samples_into_min_max_buckets() {
+        // A loud first half and a quiet second half
+        let samples: Vec<i16> = (0..81_920)
+            .map(|i| {
+                let level = if i < 40_960 { 16384 } else { 1638 };
+                if i % 2 == 0 {
+                    level
+                } else {
+                    -level
+                }
+            })
+            .collect();
+        let peaks = peaks(Cursor::new(wav(&samples)), "a.wav", 10).unwrap();
+        assert_eq!(peaks.len(), 10);
+        assert_eq!(peaks[0], [-0.5, 0.5]);
+        assert_eq!(peaks[4], [-0.5, 0.5]);
+        assert_eq!(peaks[5], [-0.05, 0.05]);
+        assert_eq!(peaks[9], [-0.05, 0.05]);
+    }
+
+    #[test]
+    fn short_files_have_a_bucket_per_frame() {
+        let peaks = peaks(Cursor::new(wav(&[0, 16384, -16384])), "a.wav", 100).unwrap();
+        assert_eq!(peaks, vec![[0.0, 0.0], [0.5, 0.5], [-0.5, -0.5]]);
+    }
+
+    #[test]
+    fn anything_else_has_no_peaks() {
+        assert_eq!(peaks(Cursor::new(vec![7u8; 4096]), "a.wav", 10), None);
+    }
+}
@@ -0,0 +1 @@
+curl -H "X-Forwarded-User: ${ADMIN_USER:-admin}" "localhost:8080/admin/audit?$1"
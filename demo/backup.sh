@@ -0,0 +1 @@
+curl -H "X-Forwarded-User: ${ADMIN_USER:-admin}" -X POST "localhost:8080/admin/backups?objects=true"
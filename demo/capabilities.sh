@@ -0,0 +1 @@
+curl localhost:8080/capabilities
@@ -0,0 +1 @@
+curl -X PUT -H "X-Checksum-SHA256: $(sha256sum $2 | cut -c1-64)" --data-binary @$2 localhost:8080/audio/$1
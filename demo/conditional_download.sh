@@ -0,0 +1 @@
+curl -i -H "If-None-Match: $2" localhost:8080/audio/download/$1
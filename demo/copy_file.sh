@@ -0,0 +1 @@
+curl -X POST -H 'content-type: application/json' -d '{"destination":"copy.wav"}' localhost:8080/audio/copy/test.wav
@@ -0,0 +1 @@
+curl -X DELETE localhost:8080/audio/$1
@@ -0,0 +1 @@
+wget -O $2 localhost:8080/audio/$1/download
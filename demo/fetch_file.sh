@@ -0,0 +1 @@
+curl -H "Content-Type: application/json" -d "{\"url\":\"$1\"}" localhost:8080/audio/fetch
@@ -0,0 +1 @@
+curl -H "X-Forwarded-User: $1" localhost:8080/audio
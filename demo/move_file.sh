@@ -0,0 +1 @@
+curl -X POST -H 'content-type: application/json' -d '{"destination":"renamed.wav"}' localhost:8080/audio/move/test.wav
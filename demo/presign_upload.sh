@@ -0,0 +1 @@
+curl -X POST -H 'content-type: application/json' -d '{"file_name":"test.wav"}' localhost:8080/audio/presign
@@ -0,0 +1 @@
+curl -T $2 -H "Content-Type: $3" localhost:8080/audio/$1
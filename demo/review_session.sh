@@ -0,0 +1 @@
+curl -H "Content-Type: application/json" -d "{\"file_names\":[\"$1\"]}" localhost:8080/review-sessions
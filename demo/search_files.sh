@@ -0,0 +1 @@
+curl -G --data-urlencode "q=$1" localhost:8080/audio/query
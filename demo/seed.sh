@@ -0,0 +1 @@
+cargo run -- seed ${1:-../demo/fixture.json} --generate-audio
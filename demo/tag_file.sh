@@ -0,0 +1 @@
+curl -X PUT localhost:8080/audio/tags/$1/$2
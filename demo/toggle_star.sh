@@ -0,0 +1 @@
+curl -X POST localhost:8080/audio/star/$1
@@ -0,0 +1 @@
+curl "localhost:8080/audio/top?limit=${1:-10}"
@@ -0,0 +1 @@
+curl -X PATCH -H "Content-Type: application/json" -d "{\"revision\":$2,\"title\":\"$3\"}" localhost:8080/audio/$1
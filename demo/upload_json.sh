@@ -0,0 +1 @@
+printf '{"file_name":"%s","file_type":"%s","data_base64":"%s"}' $1 $2 "$(base64 -w0 $3)" | curl -H "Content-Type: application/json" --data @- localhost:8080/audio/json
@@ -0,0 +1 @@
+curl localhost:8080/audio/uploads/$1/progress
@@ -0,0 +1 @@
+curl -X POST -H 'content-type: application/json' -d '{"file_name":"test.wav","total_size":1048576}' localhost:8080/upload-sessions
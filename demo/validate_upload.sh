@@ -0,0 +1 @@
+curl -H "Content-Type: application/json" -d "{\"file_name\":\"$(basename "$1")\",\"file_size\":$(stat -c %s "$1"),\"sample_base64\":\"$(head -c 65536 "$1" | base64 -w0)\"}" localhost:8080/audio/validate